@@ -1,12 +1,16 @@
 use crate::{shm::ShmAllocator, HashMap};
-use aici_abi::{ProcessResultOffset, StorageCmd, TokenId};
+use aici_abi::{Logprobs, ProcessResultOffset, StorageCmd, TokenId};
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 pub type ModuleInstId = usize;
 
-#[derive(Serialize, Deserialize, Clone)]
+/// Version of the protocol between the LLM engine and aicirt, reported by `capabilities`.
+/// Bump on incompatible changes to main channel messages or the bias shared memory layout.
+pub const AICI_PROTOCOL_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct InferenceCapabilities {
     #[serde(default)]
     pub backtrack: bool,
@@ -14,6 +18,50 @@ pub struct InferenceCapabilities {
     pub ff_tokens: bool,
     #[serde(default)]
     pub fork: bool,
+    /// The engine can pass logprobs of sampled tokens to controllers.
+    #[serde(default)]
+    pub logprobs: bool,
+    /// The engine can also pass the full distribution, when the controller asks for it.
+    #[serde(default)]
+    pub logprobs_full: bool,
+    /// The engine can exclude token ranges from attention.
+    #[serde(default)]
+    pub attention_mask: bool,
+}
+
+/// Limits in force for controllers; see `aicirt --help` for details.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LimitsInfo {
+    pub max_memory_bytes: usize,
+    pub max_step_ms: u64,
+    pub max_init_ms: u64,
+    pub max_timeout_steps: usize,
+    pub max_forks: usize,
+    pub max_step_fuel: u64,
+    pub max_init_fuel: u64,
+    pub json_size_bytes: usize,
+    pub bin_size_bytes: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CapabilitiesResp {
+    pub protocol_version: u32,
+    pub aicirt_version: String,
+    /// Type of logit bias written to the binary shared memory (see `--bias-dtype`).
+    pub bias_dtype: String,
+    pub supported_bias_dtypes: Vec<String>,
+    /// Whether the channels use futexes (`--futex`) rather than POSIX semaphores.
+    pub futex: bool,
+    /// Whether aicirt talks over a socket (`--listen`); logit biases then follow
+    /// `mid_process` responses as binary messages, instead of being put in shared memory.
+    #[serde(default)]
+    pub socket: bool,
+    /// Name of the engine session (see `--session`); empty for the default one.
+    #[serde(default)]
+    pub session: String,
+    pub supported_channels: Vec<String>,
+    pub inference_caps: InferenceCapabilities,
+    pub limits: LimitsInfo,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -30,6 +78,17 @@ pub struct AiciMidProcessResp {
     pub mask_num_bytes: usize,
     pub mask_num_elts: usize,
     pub num_masks: usize,
+    /// Sequences that are waiting for variable changes (StorageCmd::Watch).
+    /// They should not be scheduled (or passed to mid_process) until listed in `resume`.
+    #[serde(default)]
+    pub suspend: Vec<ModuleInstId>,
+    /// Previously suspended sequences whose watched variables have changed.
+    #[serde(default)]
+    pub resume: Vec<ModuleInstId>,
+    /// Suspended sequences that can never be resumed, since all other sequences
+    /// of the request are suspended too. Maps to description of what they wait for.
+    #[serde(default)]
+    pub deadlocked: HashMap<ModuleInstId, String>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -51,16 +110,26 @@ pub struct AiciMidOp {
     /// Can be more complex when splices are used.
     pub backtrack: u32,
     pub tokens: Vec<Token>,
+    /// Set iff the controller requested logprobs in the previous step.
+    #[serde(default)]
+    pub logprobs: Option<Logprobs>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SequenceResult<T = ()> {
     pub result: Option<T>,
     pub error: String,
-    // StorageCmd::ReadVar are not recorded
+    // StorageCmd::ReadVar are not recorded; StorageCmd::Watch only when it suspends the sequence
     pub storage: Vec<StorageCmd>,
     pub logs: String,
     pub micros: u64,
+    /// WASM fuel consumed; 0 when fuel metering is disabled.
+    /// For a step that timed out, split between the timeout responses and the late result.
+    #[serde(default)]
+    pub fuel: u64,
+    /// Events emitted by the controller, each `{ "kind": ..., "data": ... }`.
+    #[serde(default)]
+    pub events: Vec<Value>,
 }
 
 impl<T> SequenceResult<T> {
@@ -71,6 +140,8 @@ impl<T> SequenceResult<T> {
             result: None,
             storage: vec![],
             micros: 0,
+            fuel: 0,
+            events: vec![],
         }
     }
     pub fn clone_with<S>(&self, result: Option<S>) -> SequenceResult<S> {
@@ -80,6 +151,8 @@ impl<T> SequenceResult<T> {
             storage: self.storage.clone(),
             logs: self.logs.clone(),
             micros: self.micros,
+            fuel: self.fuel,
+            events: self.events.clone(),
         }
     }
     pub fn map_result<S, F>(self, f: F) -> SequenceResult<S>
@@ -92,6 +165,8 @@ impl<T> SequenceResult<T> {
             storage: self.storage,
             logs: self.logs,
             micros: self.micros,
+            fuel: self.fuel,
+            events: self.events,
         }
     }
 }
@@ -138,6 +213,13 @@ pub struct InstantiateReq {
     pub module_id: String, // or tag name
     #[serde(default)]
     pub module_arg: Value,
+    /// Use a different tokenizer (one of the built-in ones, see `--tokenizer list`)
+    /// than the one of the engine for this instance.
+    #[serde(default)]
+    pub tokenizer: Option<String>,
+    /// Seed for `aici_host_random_seed()`; random if not specified.
+    #[serde(default)]
+    pub seed: Option<u64>,
 }
 
 pub type Token = TokenId;
@@ -177,6 +259,10 @@ pub enum BiasType {
 }
 
 impl BiasType {
+    pub fn all() -> [BiasType; 4] {
+        [BiasType::F32, BiasType::F16, BiasType::BF16, BiasType::Bool]
+    }
+
     pub fn from_u32(v: u32) -> Result<Self> {
         match v {
             1 => Ok(BiasType::F32),
@@ -290,3 +376,81 @@ fn apply_to_slice<T: Copy>(src: &[u8], dst: &mut [T], allow: T, disallow: T) {
         dp += 1;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_result_without_fuel() {
+        // responses from before fuel metering don't have the field
+        let r: SequenceResult = serde_json::from_str(
+            r#"{"result":null,"error":"","storage":[],"logs":"","micros":10}"#,
+        )
+        .unwrap();
+        assert_eq!(r.fuel, 0);
+        assert_eq!(r.micros, 10);
+    }
+
+    #[test]
+    fn sequence_result_keeps_fuel() {
+        let mut r = SequenceResult::<()>::from_error("x".to_string());
+        r.fuel = 1234;
+        assert_eq!(r.clone_with(Some(1u32)).fuel, 1234);
+        assert_eq!(r.map_result(|_| 2u32).fuel, 1234);
+    }
+
+    #[test]
+    fn bias_type_names_round_trip() {
+        for b in BiasType::all() {
+            assert_eq!(
+                BiasType::from_str(&b.to_string()).unwrap().to_u32(),
+                b.to_u32()
+            );
+            assert_eq!(
+                BiasType::from_u32(b.to_u32()).unwrap().to_string(),
+                b.to_string()
+            );
+        }
+        assert!(BiasType::from_str("f64").is_err());
+    }
+
+    #[test]
+    fn capabilities_resp_round_trip() {
+        let caps = CapabilitiesResp {
+            protocol_version: AICI_PROTOCOL_VERSION,
+            aicirt_version: "0.1.0".to_string(),
+            bias_dtype: "f32".to_string(),
+            supported_bias_dtypes: BiasType::all().iter().map(|b| b.to_string()).collect(),
+            futex: true,
+            socket: false,
+            session: String::new(),
+            supported_channels: vec!["futex".to_string()],
+            inference_caps: InferenceCapabilities {
+                backtrack: true,
+                ff_tokens: false,
+                fork: true,
+                logprobs: false,
+                logprobs_full: false,
+                attention_mask: false,
+            },
+            limits: LimitsInfo {
+                max_memory_bytes: 1,
+                max_step_ms: 2,
+                max_init_ms: 3,
+                max_timeout_steps: 4,
+                max_forks: 5,
+                max_step_fuel: 6,
+                max_init_fuel: 7,
+                json_size_bytes: 8,
+                bin_size_bytes: 9,
+            },
+        };
+        let json = serde_json::to_value(&caps).unwrap();
+        assert_eq!(json["protocol_version"], AICI_PROTOCOL_VERSION);
+        assert_eq!(json["limits"]["json_size_bytes"], 8);
+        let caps2: CapabilitiesResp = serde_json::from_value(json).unwrap();
+        assert_eq!(caps2.supported_bias_dtypes, ["f32", "f16", "bf16", "bool"]);
+        assert!(caps2.inference_caps.fork && !caps2.inference_caps.ff_tokens);
+    }
+}
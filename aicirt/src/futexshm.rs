@@ -3,7 +3,7 @@ use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use std::{
     ptr,
-    sync::atomic::{AtomicU32, Ordering},
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
     time::{Duration, Instant},
 };
 
@@ -45,6 +45,14 @@ impl Channel {
         self.shm.size / 2 - 16
     }
 
+    /// Counter in the spare bytes at the end of the shared memory (past the maximal response),
+    /// which the server can update while still handling a request.
+    pub fn gauge(&self) -> &'static AtomicU64 {
+        let off = self.shm.size - 8;
+        assert!(off % 8 == 0);
+        unsafe { AtomicU64::from_ptr(self.shm.ptr_at(off) as *mut u64) }
+    }
+
     pub fn fits_msg(&self, msg: &[u8]) -> Result<()> {
         if msg.len() > self.max_msg_size() {
             return Err(anyhow!(
@@ -260,6 +268,10 @@ where
         let msg = bincode::serialize(&resp).unwrap();
         self.channel.send_resp(&msg).unwrap();
     }
+
+    pub fn gauge(&self) -> &'static AtomicU64 {
+        self.channel.channel.gauge()
+    }
 }
 
 pub struct TypedClient<Cmd, Resp> {
@@ -285,6 +297,10 @@ where
         self.channel.channel.wait_for_reception();
     }
 
+    pub fn gauge(&self) -> &'static AtomicU64 {
+        self.channel.channel.gauge()
+    }
+
     pub fn send_req(&mut self, cmd: Cmd) -> Result<()> {
         let msg = bincode::serialize(&cmd).unwrap();
         self.channel.send_req(&msg)
@@ -2,17 +2,19 @@ use crate::worker::{GroupCmd, GroupHandle, GroupResp, RtMidProcessArg};
 use aici_abi::{
     bytes::{clone_vec_as_bytes, limit_str, vec_from_bytes, U32Pair},
     toktrie::{TokRxInfo, TokTrie},
-    StorageCmd,
+    StorageCmd, StorageResp, StorageScope,
 };
 use aicirt::{
     api::{BiasType, InferenceCapabilities},
     shm::ShmAllocator,
+    storage::{BlobCache, PersistentStorage, StorageNamespace},
     user_error,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
 use std::{
     rc::Rc,
-    sync::Arc,
+    sync::{atomic::AtomicU64, Arc},
     time::{Duration, Instant},
 };
 use tokenizers::Tokenizer;
@@ -31,10 +33,21 @@ pub struct AiciLimits {
     pub busy_wait_duration: Duration,
     pub max_forks: usize,
 
+    /// Fuel (roughly, number of WASM instructions) allowed per mid_process() call; 0 means unlimited.
+    pub max_step_fuel: u64,
+    /// Fuel allowed for module initialization and init_prompt(); 0 means unlimited.
+    pub max_init_fuel: u64,
+
     pub module_upload: bool,
     pub gh_download: bool,
 }
 
+impl AiciLimits {
+    pub fn fuel_enabled(&self) -> bool {
+        self.max_step_fuel > 0 || self.max_init_fuel > 0
+    }
+}
+
 type ModuleInstId = crate::api::ModuleInstId;
 
 // this is available to functions called from wasm
@@ -45,23 +58,34 @@ pub struct ModuleData {
     pub globals: GlobalInfo,
     pub group_channel: GroupHandle,
     pub process_result: Vec<u8>,
-    pub logit_shm: Rc<ShmAllocator>,
+    pub logit_shm: Arc<ShmAllocator>,
     pub logit_offsets: Vec<u32>,
     pub limits: AiciLimits,
-    pub linker: Arc<wasmtime::Linker<ModuleData>>,
     pub instance: Option<wasmtime::Instance>,
     pub memory: Option<wasmtime::Memory>,
-    pub module: wasmtime::Module,
     pub store_limits: wasmtime::StoreLimits,
     pub had_error: bool,
     pub storage_log: Vec<StorageCmd>,
+    pub storage_ns: Option<StorageNamespace>,
     pub start_time: Instant,
+    pub seed: u64,
+    // children forked from this sequence so far; mixed into their seeds
+    pub num_forks: u64,
+    // fuel at the start of the current call, and where to publish the fuel it consumed so far
+    pub fuel_budget: u64,
+    pub fuel_gauge: Option<&'static AtomicU64>,
+    events: Vec<Value>,
+    events_bytes: usize,
     blobs: Vec<Rc<Vec<u8>>>,
 }
 
 const MAXLOG: usize = 64 * 1024;
+// limits on events emitted in a single step (init or mid_process call)
+const MAX_EVENT_BYTES: usize = 16 * 1024;
+const MAX_EVENTS_BYTES: usize = 64 * 1024;
+const MAX_EVENTS: usize = 256;
 
-pub struct BlobId(u32);
+pub struct BlobId(pub u32);
 
 impl BlobId {
     pub const MODULE_ARG: BlobId = BlobId(1);
@@ -69,6 +93,7 @@ impl BlobId {
     pub const TOKENS: BlobId = BlobId(3);
     pub const PROCESS_ARG: BlobId = BlobId(4);
     pub const STORAGE_RESULT: BlobId = BlobId(5);
+    pub const BLOB_CACHE: BlobId = BlobId(6);
 
     pub const MAX_BLOB_ID: u32 = 20;
 
@@ -80,12 +105,9 @@ impl ModuleData {
     pub fn new(
         id: ModuleInstId,
         limits: &AiciLimits,
-        module: &wasmtime::Module,
-        module_arg: String,
-        linker: &Arc<wasmtime::Linker<ModuleData>>,
         globals: GlobalInfo,
         group_channel: GroupHandle,
-        logit_shm: Rc<ShmAllocator>,
+        logit_shm: Arc<ShmAllocator>,
     ) -> Self {
         let store_limits = wasmtime::StoreLimitsBuilder::new()
             .memories(1)
@@ -95,15 +117,13 @@ impl ModuleData {
             .instances(1)
             .trap_on_grow_failure(true)
             .build();
-        let mut r = ModuleData {
+        ModuleData {
             id,
             log: Vec::new(),
             printed_log: 0,
             globals,
             group_channel,
-            module: module.clone(),
             limits: limits.clone(),
-            linker: linker.clone(),
             instance: None,
             memory: None,
             store_limits,
@@ -112,11 +132,20 @@ impl ModuleData {
             logit_offsets: Vec::new(),
             had_error: false,
             storage_log: Vec::new(),
+            storage_ns: None,
             start_time: Instant::now(),
+            seed: 0,
+            num_forks: 0,
+            fuel_budget: 0,
+            fuel_gauge: None,
+            events: Vec::new(),
+            events_bytes: 0,
             blobs: vec![Rc::new(Vec::new()); BlobId::MAX_BLOB_ID as usize],
-        };
-        r.set_blob(BlobId::MODULE_ARG, module_arg.as_bytes().to_vec());
-        r
+        }
+    }
+
+    pub fn set_module_arg(&mut self, module_arg: String) {
+        self.set_blob(BlobId::MODULE_ARG, module_arg.into_bytes());
     }
 
     fn clear_blob(&mut self, blob_id: BlobId) {
@@ -138,6 +167,17 @@ impl ModuleData {
         self.logit_offsets.clear();
     }
 
+    /// Contents of the blob, or None if `blob_id` is invalid.
+    pub fn blob(&self, blob_id: u32) -> Option<&[u8]> {
+        if blob_id == BlobId::TRIE.0 {
+            Some(&self.globals.trie_bytes)
+        } else if blob_id < BlobId::MAX_BLOB_ID {
+            Some(&self.blobs[blob_id as usize])
+        } else {
+            None
+        }
+    }
+
     pub fn tokenize_bytes(&mut self, s: &[u8]) -> Result<Vec<u32>> {
         Ok(self.globals.tok_trie.tokenize_with_greedy_fallback(s, |s| {
             self.globals
@@ -149,6 +189,61 @@ impl ModuleData {
         }))
     }
 
+    pub fn aici_host_tokenize(&mut self, s: &[u8]) -> BlobId {
+        match self.tokenize_bytes(s) {
+            Err(e) => {
+                self.warn(&format!("tokenize error: {e:?}"));
+                self.clear_blob(BlobId::TOKENIZE);
+            }
+            Ok(tokens) => {
+                self.set_blob(BlobId::TOKENIZE, clone_vec_as_bytes(&tokens));
+            }
+        }
+        BlobId::TOKENIZE
+    }
+
+    /// Size of the token bit-mask passed to aici_host_return_logit_bias().
+    pub fn logit_bias_bytes(&self) -> usize {
+        let numtok = self.globals.tokrx_info.vocab_size as usize;
+        4 * ((numtok + 31) / 32)
+    }
+
+    pub fn aici_host_return_logit_bias(&mut self, mask: &[u8]) -> u32 {
+        let shm = self.logit_shm.clone();
+        let id: u32 = self.id.try_into().unwrap();
+
+        let bias_type = BiasType::from_u32(shm.elt_type() & 0xf).unwrap();
+        let off = shm.alloc(id).unwrap();
+
+        bias_type.apply_to_shm_allocator(mask, &shm, off);
+
+        let off32: u32 = off.try_into().unwrap();
+        self.logit_offsets.push(off32);
+        off32
+    }
+
+    pub fn get_config(&self, name: &str) -> i32 {
+        if name == "blob_cache" {
+            return (self.globals.blob_cache.is_some() && self.storage_ns.is_some()) as i32;
+        }
+        let caps = serde_json::to_value(self.globals.inference_caps.clone()).unwrap();
+        if caps[name].as_bool().unwrap_or(false) {
+            return 1;
+        }
+        return 0;
+    }
+
+    /// Wall clock time, rounded down to timer resolution.
+    pub fn now_micros(&self) -> u64 {
+        let res = self.limits.timer_resolution_ns as u64;
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let nanos = if res == 0 { 0 } else { nanos / res * res };
+        nanos / 1000
+    }
+
     pub fn fatal(&mut self, msg: &str) {
         log::warn!("{}: fatal error {}", self.id, msg);
         let msg = format!("FATAL ERROR: {}\n", msg);
@@ -185,6 +280,40 @@ impl ModuleData {
         logs
     }
 
+    pub fn emit_event(&mut self, kind: &str, data: &[u8]) {
+        if data.len() > MAX_EVENT_BYTES {
+            self.warn(&format!(
+                "event {kind:?} dropped: {} bytes (limit {MAX_EVENT_BYTES})",
+                data.len()
+            ));
+            return;
+        }
+        if self.events.len() >= MAX_EVENTS || self.events_bytes + data.len() > MAX_EVENTS_BYTES {
+            self.warn(&format!(
+                "event {kind:?} dropped: too many events in this step"
+            ));
+            return;
+        }
+        match serde_json::from_slice::<Value>(data) {
+            Ok(value) => {
+                self.events_bytes += data.len();
+                self.events.push(json!({ "kind": kind, "data": value }));
+            }
+            Err(e) => self.warn(&format!("event {kind:?} dropped: invalid JSON: {e}")),
+        }
+    }
+
+    /// Called in the child process after a fork, see [fork_seed].
+    pub fn fork_seed(&mut self, fork_idx: usize) {
+        self.seed = fork_seed(self.seed, self.num_forks, fork_idx);
+        self.num_forks = 0;
+    }
+
+    pub fn take_events(&mut self) -> Vec<Value> {
+        self.events_bytes = 0;
+        std::mem::take(&mut self.events)
+    }
+
     pub fn flush_logs(&mut self, name: &str) {
         if !log::log_enabled!(log::Level::Debug) {
             return;
@@ -208,14 +337,25 @@ impl ModuleData {
         match serde_json::from_slice(&m) {
             Ok(cmd) => {
                 let save = match &cmd {
-                    StorageCmd::WriteVar { .. } => Some(cmd.clone()),
+                    StorageCmd::WriteVar { .. } | StorageCmd::Watch { .. } => Some(cmd.clone()),
                     StorageCmd::ReadVar { .. } => None,
                 };
-                let res = self.group_channel.send_cmd(GroupCmd::StorageCmd { cmd });
+                let res = match cmd.scope() {
+                    StorageScope::Request => self
+                        .group_channel
+                        .send_cmd(GroupCmd::StorageCmd { cmd })
+                        .map(|r| match r {
+                            GroupResp::StorageResp { resp } => resp,
+                        }),
+                    StorageScope::Persistent => self.persistent_storage_cmd(cmd),
+                };
                 match res {
-                    Ok(GroupResp::StorageResp { resp }) => {
+                    Ok(resp) => {
                         if let Some(log) = save {
-                            self.storage_log.push(log)
+                            // watches are only recorded if they suspend the sequence
+                            if !matches!(resp, StorageResp::Changed { .. }) {
+                                self.storage_log.push(log)
+                            }
                         }
                         let res_bytes = serde_json::to_vec(&resp).unwrap();
                         self.set_blob(BlobId::STORAGE_RESULT, res_bytes);
@@ -228,6 +368,42 @@ impl ModuleData {
         }
         BlobId::STORAGE_RESULT
     }
+
+    // the cache is best-effort, so if it's not enabled, the module just always misses
+    pub fn aici_host_blob_cache_get(&mut self, key: &[u8]) -> BlobId {
+        self.clear_blob(BlobId::BLOB_CACHE);
+        if let (Some(cache), Some(ns)) = (&self.globals.blob_cache, &self.storage_ns) {
+            match cache.get(&ns.module_id, &String::from_utf8_lossy(key)) {
+                Ok(Some(bytes)) => self.set_blob(BlobId::BLOB_CACHE, bytes),
+                Ok(None) => {}
+                Err(e) => self.warn(&format!("blob_cache_get error: {e}")),
+            }
+        }
+        BlobId::BLOB_CACHE
+    }
+
+    pub fn aici_host_blob_cache_put(&mut self, key: &[u8], data: &[u8]) {
+        if let (Some(cache), Some(ns)) = (&self.globals.blob_cache, &self.storage_ns) {
+            match cache.put(&ns.module_id, &String::from_utf8_lossy(key), data) {
+                Ok(true) => log::debug!(
+                    "{}: cached blob {:?}, {} bytes",
+                    self.id,
+                    String::from_utf8_lossy(key),
+                    data.len()
+                ),
+                Ok(false) => {}
+                Err(e) => self.warn(&format!("blob_cache_put error: {e}")),
+            }
+        }
+    }
+
+    fn persistent_storage_cmd(&self, cmd: StorageCmd) -> Result<StorageResp> {
+        match (&self.globals.persistent_storage, &self.storage_ns) {
+            (Some(storage), Some(ns)) => storage.process_cmd(ns, cmd),
+            (None, _) => Err(user_error!("persistent storage not enabled in this host")),
+            (_, None) => Err(anyhow!("storage namespace not set")),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -237,6 +413,8 @@ pub struct GlobalInfo {
     pub trie_bytes: Arc<Vec<u8>>,
     pub tok_trie: Arc<TokTrie>,
     pub hf_tokenizer: Arc<Tokenizer>,
+    pub persistent_storage: Option<PersistentStorage>,
+    pub blob_cache: Option<BlobCache>,
 }
 
 fn check_fatal(caller: &mut wasmtime::Caller<'_, ModuleData>) {
@@ -443,19 +621,7 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         "aici_host_tokenize",
         |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32, src_size: u32| {
             let m = read_caller_mem(&caller, src, src_size);
-            let tokens = caller.data_mut().tokenize_bytes(&m);
-            match tokens {
-                Err(e) => {
-                    caller.data_mut().warn(&format!("tokenize error: {e:?}"));
-                    caller.data_mut().clear_blob(BlobId::TOKENIZE);
-                }
-                Ok(tokens) => {
-                    caller
-                        .data_mut()
-                        .set_blob(BlobId::TOKENIZE, clone_vec_as_bytes(&tokens));
-                }
-            }
-            BlobId::TOKENIZE.0
+            caller.data_mut().aici_host_tokenize(&m).0
         },
     )?;
 
@@ -463,24 +629,11 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         "env",
         "aici_host_return_logit_bias",
         |mut caller: wasmtime::Caller<'_, ModuleData>, src: u32| {
-            let data = caller.data();
-
-            let numtok = data.globals.tokrx_info.vocab_size as usize;
-            let shm = data.logit_shm.clone();
-            let id: u32 = data.id.try_into().unwrap();
-            let numbytes = 4 * ((numtok + 31) / 32);
+            let numbytes = caller.data().logit_bias_bytes();
             let mem = caller.data().memory.unwrap();
+            let (mem, data) = mem.data_and_store_mut(&mut caller);
             let sptr = src as usize;
-            let slice = &mem.data(&caller)[sptr..sptr + numbytes];
-
-            let bias_type = BiasType::from_u32(shm.elt_type() & 0xf).unwrap();
-            let off = shm.alloc(id).unwrap();
-
-            bias_type.apply_to_shm_allocator(slice, &shm, off);
-
-            let off32: u32 = off.try_into().unwrap();
-            caller.data_mut().logit_offsets.push(off32);
-            off32
+            data.aici_host_return_logit_bias(&mem[sptr..sptr + numbytes])
         },
     )?;
 
@@ -496,14 +649,41 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         |caller: wasmtime::Caller<'_, ModuleData>, name: u32, name_size: u32| {
             let m = read_caller_mem(&caller, name, name_size);
             let name = String::from_utf8_lossy(&m);
-            let caps = serde_json::to_value(caller.data().globals.inference_caps.clone()).unwrap();
-            if caps[name.as_ref()].as_bool().unwrap_or(false) {
-                return 1;
+            caller.data().get_config(&name)
+        },
+    )?;
+
+    linker.func_wrap(
+        "env",
+        "aici_host_emit_event",
+        |mut caller: wasmtime::Caller<'_, ModuleData>,
+         kind: u32,
+         kind_size: u32,
+         data: u32,
+         data_size: u32| {
+            if kind_size > 256 {
+                caller.data_mut().warn("event kind too long");
+                return;
             }
-            return 0;
+            let kind = read_caller_mem(&caller, kind, kind_size);
+            let kind = String::from_utf8_lossy(&kind).to_string();
+            let data = read_caller_mem(&caller, data, data_size);
+            caller.data_mut().emit_event(&kind, &data);
         },
     )?;
 
+    linker.func_wrap(
+        "env",
+        "aici_host_now_micros",
+        |caller: wasmtime::Caller<'_, ModuleData>| caller.data().now_micros(),
+    )?;
+
+    linker.func_wrap(
+        "env",
+        "aici_host_random_seed",
+        |caller: wasmtime::Caller<'_, ModuleData>| caller.data().seed,
+    )?;
+
     linker.func_wrap(
         "env",
         "aici_host_eos_token",
@@ -530,6 +710,29 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
         },
     )?;
 
+    linker.func_wrap(
+        "env",
+        "aici_host_blob_cache_get",
+        |mut caller: wasmtime::Caller<'_, ModuleData>, key: u32, key_size: u32| {
+            let key = read_caller_mem(&caller, key, key_size);
+            caller.data_mut().aici_host_blob_cache_get(&key).0
+        },
+    )?;
+
+    linker.func_wrap(
+        "env",
+        "aici_host_blob_cache_put",
+        |mut caller: wasmtime::Caller<'_, ModuleData>,
+         key: u32,
+         key_size: u32,
+         data: u32,
+         data_size: u32| {
+            let key = read_caller_mem(&caller, key, key_size);
+            let data = read_caller_mem(&caller, data, data_size);
+            caller.data_mut().aici_host_blob_cache_put(&key, &data);
+        },
+    )?;
+
     linker.func_wrap("env", "aici_host_stop", || {
         Err::<(), _>(user_error!("*** aici_host_stop()"))
     })?;
@@ -537,3 +740,181 @@ pub fn setup_linker(engine: &wasmtime::Engine) -> Result<Arc<wasmtime::Linker<Mo
     let linker = Arc::new(linker);
     Ok(linker)
 }
+
+/// Derive seed for a forked sequence.
+/// `num_forks` is the number of children the parent forked before,
+/// so that children forked in different steps with the same `fork_idx` get different seeds.
+pub fn fork_seed(seed: u64, num_forks: u64, fork_idx: usize) -> u64 {
+    splitmix(splitmix(seed, num_forks), fork_idx as u64)
+}
+
+// splitmix64 finalizer
+fn splitmix(seed: u64, n: u64) -> u64 {
+    let mut z = seed.wrapping_add(n.wrapping_mul(0x9e3779b97f4a7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::moduleinstance::WasmContext;
+    use aicirt::{
+        bintokens::{find_tokenizer, ByteTokenizer},
+        shm::Shm,
+    };
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+
+    pub(crate) fn test_limits() -> AiciLimits {
+        AiciLimits {
+            ipc_shm_bytes: 1 << 20,
+            timer_resolution_ns: 0,
+            max_memory_bytes: 64 << 20,
+            max_step_ms: 50,
+            max_init_ms: 1000,
+            max_compile_ms: 10_000,
+            max_timeout_steps: 3,
+            logit_memory_bytes: 1 << 20,
+            busy_wait_duration: Duration::from_millis(1),
+            max_forks: 16,
+            max_step_fuel: 0,
+            max_init_fuel: 0,
+            module_upload: true,
+            gh_download: false,
+        }
+    }
+
+    /// Context with a tokenizer of single bytes, `ab` and EOS (257),
+    /// loaded from a temporary tiktoken file.
+    pub(crate) fn test_context() -> WasmContext {
+        test_context_with(test_limits())
+    }
+
+    pub(crate) fn test_context_with(limits: AiciLimits) -> WasmContext {
+        let caps = InferenceCapabilities {
+            backtrack: true,
+            ff_tokens: true,
+            fork: true,
+            logprobs: false,
+            logprobs_full: false,
+            attention_mask: false,
+        };
+        WasmContext::new(caps, limits, test_tokenizer(b"ab")).unwrap()
+    }
+
+    /// Tokenizer of single bytes, `last` and EOS.
+    pub(crate) fn test_tokenizer(last: &[u8]) -> ByteTokenizer {
+        let lines = (0..=255u8)
+            .map(|b| vec![b])
+            .chain([last.to_vec()])
+            .enumerate()
+            .map(|(rank, tok)| format!("{} {}\n", BASE64.encode(tok), rank))
+            .collect::<String>();
+        let path =
+            std::env::temp_dir().join(format!("aicirt-test-{}.tiktoken", uuid::Uuid::new_v4()));
+        std::fs::write(&path, lines).unwrap();
+        let tokenizer = find_tokenizer(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        tokenizer.unwrap()
+    }
+
+    pub(crate) fn test_logit_shm(ctx: &WasmContext) -> Arc<ShmAllocator> {
+        let vocab_size = ctx.globals.tokrx_info.vocab_size as usize;
+        let bias_type = BiasType::F32;
+        Arc::new(ShmAllocator::new(
+            Shm::anon(ctx.limits.logit_memory_bytes).unwrap(),
+            bias_type.size_in_bytes((vocab_size + 63) & !63),
+            bias_type.to_u32(),
+        ))
+    }
+
+    pub(crate) fn test_module_data(ctx: &WasmContext) -> ModuleData {
+        ModuleData::new(
+            1,
+            &ctx.limits,
+            ctx.globals.clone(),
+            GroupHandle::disconnected(&ctx.limits).unwrap(),
+            test_logit_shm(ctx),
+        )
+    }
+
+    #[test]
+    fn fuel_enabled_by_either_budget() {
+        let mut limits = test_limits();
+        assert!(!limits.fuel_enabled());
+        limits.max_step_fuel = 1000;
+        assert!(limits.fuel_enabled());
+        limits.max_step_fuel = 0;
+        limits.max_init_fuel = 1000;
+        assert!(limits.fuel_enabled());
+    }
+
+    #[test]
+    fn event_limits() {
+        let ctx = test_context();
+        let mut data = test_module_data(&ctx);
+
+        data.emit_event("ok", br#"{"a":1}"#);
+        data.emit_event("bad", b"{");
+        let big = format!("\"{}\"", "x".repeat(MAX_EVENT_BYTES));
+        data.emit_event("big", big.as_bytes());
+        assert_eq!(
+            data.take_events(),
+            vec![json!({ "kind": "ok", "data": { "a": 1 } })]
+        );
+        let log = data.string_log();
+        assert!(log.contains("event \"bad\" dropped: invalid JSON"), "{log}");
+        assert!(log.contains("event \"big\" dropped"), "{log}");
+
+        for i in 0..MAX_EVENTS + 1 {
+            data.emit_event("n", i.to_string().as_bytes());
+        }
+        assert_eq!(data.take_events().len(), MAX_EVENTS);
+        assert!(data.string_log().contains("too many events"));
+
+        // exactly MAX_EVENT_BYTES each
+        let chunk = format!("\"{}\"", "x".repeat(MAX_EVENT_BYTES - 2));
+        for _ in 0..MAX_EVENTS_BYTES / MAX_EVENT_BYTES + 1 {
+            data.emit_event("chunk", chunk.as_bytes());
+        }
+        assert_eq!(data.take_events().len(), MAX_EVENTS_BYTES / MAX_EVENT_BYTES);
+        assert!(data.string_log().contains("too many events"));
+
+        // the budget is per step
+        data.emit_event("chunk", chunk.as_bytes());
+        assert_eq!(data.take_events().len(), 1);
+    }
+
+    #[test]
+    fn fork_seeds_reproducible_and_distinct() {
+        assert_eq!(fork_seed(42, 0, 1), fork_seed(42, 0, 1));
+        assert_ne!(fork_seed(42, 0, 1), fork_seed(42, 0, 2));
+        assert_ne!(fork_seed(42, 0, 1), fork_seed(43, 0, 1));
+        // a fork of a fork doesn't collide with its siblings
+        assert_ne!(fork_seed(fork_seed(42, 0, 1), 0, 1), fork_seed(42, 0, 2));
+        // nor do children forked in later steps
+        assert_ne!(fork_seed(42, 0, 1), fork_seed(42, 1, 1));
+        assert_ne!(fork_seed(42, 1, 0), fork_seed(42, 0, 1));
+
+        let ctx = test_context();
+        let mut parent = test_module_data(&ctx);
+        parent.seed = 42;
+        let mut seeds = vec![];
+        for _step in 0..3 {
+            // the child gets a copy of the parent's data, then the parent counts the fork
+            let mut child = test_module_data(&ctx);
+            child.seed = parent.seed;
+            child.num_forks = parent.num_forks;
+            child.fork_seed(1);
+            parent.num_forks += 1;
+            assert_eq!(child.num_forks, 0);
+            seeds.push(child.seed);
+        }
+        assert_eq!(parent.seed, 42);
+        assert_eq!(seeds[0], fork_seed(42, 0, 1));
+        assert_ne!(seeds[0], seeds[1]);
+        assert_ne!(seeds[1], seeds[2]);
+        assert_ne!(seeds[0], seeds[2]);
+    }
+}
@@ -4,6 +4,8 @@ pub mod futexshm;
 pub mod msgchannel;
 pub mod semaphore;
 pub mod shm;
+pub mod storage;
+pub mod transport;
 
 pub use aici_native::*;
 
@@ -1,5 +1,8 @@
 mod hostimpl;
 mod moduleinstance;
+mod nativeinstance;
+mod session;
+mod warmpool;
 mod worker;
 
 use crate::{
@@ -12,10 +15,23 @@ use crate::{
     TimerSet,
 };
 use aici_abi::{
-    bytes::limit_str, toktrie::TokTrie, Branch, MidProcessArg, ProcessResultOffset, SeqId,
-    TokenizerEnv,
+    bytes::limit_str, toktrie::TokTrie, AttentionMask, Branch, MidProcessArg, ProcessResultOffset,
+    SeqId, StorageCmd, StorageScope, TokenizerEnv,
+};
+use aicirt::{
+    bintokens::find_tokenizer,
+    futexshm::ServerChannel,
+    shm::ShmAllocator,
+    storage::{
+        BlobCache, BlobQuota, PersistentStorage, StorageClearReq, StorageListReq, StorageNamespace,
+        StorageQuota,
+    },
+    transport::{
+        read_token_file, BusyWait, MsgReceiver, MsgSender, SocketAddr, SocketListener,
+        SocketReceiver, SocketSender,
+    },
+    *,
 };
-use aicirt::{bintokens::find_tokenizer, futexshm::ServerChannel, shm::ShmAllocator, *};
 use anyhow::{anyhow, ensure, Result};
 use base64::{self, Engine as _};
 use bintokens::ByteTokenizerEnv;
@@ -25,15 +41,19 @@ use hostimpl::GlobalInfo;
 use regex::Regex;
 use serde::Serialize;
 use serde_json::{json, Value};
+use session::{route_channel, ChannelSlot, Session, SessionConfig};
 use sha2::{Digest, Sha256};
 use std::{
     fs,
     ops::Sub,
     path::PathBuf,
-    rc::Rc,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant, SystemTime},
 };
+use warmpool::{WarmPool, WarmPoolConfig};
 use worker::SeqWorkerHandle;
 
 // percentage of available cores
@@ -107,6 +127,19 @@ struct Cli {
     #[arg(long)]
     cap_ff_tokens: bool,
 
+    /// The engine can pass logprobs of sampled tokens to controllers.
+    #[arg(long)]
+    cap_logprobs: bool,
+
+    /// The engine can pass the full distribution of logprobs, when requested by controllers
+    /// (it has to fit in --json-size).
+    #[arg(long)]
+    cap_logprobs_full: bool,
+
+    /// The engine can exclude tokens from attention, as requested by controllers.
+    #[arg(long)]
+    cap_attention_mask: bool,
+
     /// Specify the type of bias to pass using shared memory (f32, f16, bf16, bool)
     #[arg(long, default_value = "f32")]
     bias_dtype: String,
@@ -147,19 +180,78 @@ struct Cli {
     #[arg(long, default_value = "1000")]
     wasm_max_init_time: u64,
 
+    /// Maximum fuel (roughly, WASM instructions) a step can consume; 0 for no limit.
+    /// Fuel metering is only enabled when this or --wasm-max-init-fuel is set.
+    #[arg(long, default_value = "0")]
+    wasm_max_step_fuel: u64,
+
+    /// Maximum fuel initialization code can consume; 0 for no limit
+    #[arg(long, default_value = "0")]
+    wasm_max_init_fuel: u64,
+
     /// Resolution of timer exposed to WASM modules in microseconds; 0 to disable timer
     #[arg(long, default_value = "0")]
     wasm_timer_resolution_us: u64,
 
+    /// Allow controllers to keep variables across requests (in ./cache/storage)
+    #[arg(long)]
+    persistent_storage: bool,
+
+    /// Maximum number of persistent variables per user and module
+    #[arg(long, default_value = "1000")]
+    storage_max_vars: usize,
+
+    /// Maximum total size of persistent variables per user and module, in bytes
+    #[arg(long, default_value = "16777216")]
+    storage_max_bytes: usize,
+
+    /// Allow controllers to cache compiled artifacts, like grammars, across requests (in ./cache/blobs)
+    #[arg(long)]
+    blob_cache: bool,
+
+    /// Maximum total size of cached blobs of a single module, in bytes;
+    /// least recently used blobs are evicted first
+    #[arg(long, default_value = "268435456")]
+    blob_cache_max_module_bytes: usize,
+
+    /// Maximum total size of all cached blobs, in bytes
+    #[arg(long, default_value = "1073741824")]
+    blob_cache_max_bytes: usize,
+
+    /// Keep N pre-instantiated workers for the given tag, eg. --warm-pool pyctrl-latest=4;
+    /// can be specified multiple times.
+    #[arg(long)]
+    warm_pool: Vec<String>,
+
+    /// Register a trusted controller compiled as a native dynamic library, eg.
+    /// --native-controller declctrl=./libaici_declctrl_native.so; it's then used with
+    /// module_id native:declctrl. Native controllers are NOT sandboxed. Can be specified multiple times.
+    #[arg(long)]
+    native_controller: Vec<String>,
+
     /// Shm/semaphore name prefix
     #[arg(long, short, default_value = "/aici0-")]
     name: String,
-}
 
-impl Cli {
-    pub fn prefixed_name(&self, name: &str, name2: &str) -> String {
-        format!("{}{}{}", self.name, name, name2)
-    }
+    /// Talk to the LLM over a socket (unix:PATH or tcp:HOST:PORT) instead of shared memory
+    #[arg(long)]
+    listen: Option<String>,
+
+    /// File with a secret the LLM engine has to present when connecting to --listen;
+    /// required for tcp:, as the engine is trusted with the identity ($auth) of users
+    #[arg(long)]
+    listen_token_file: Option<String>,
+
+    /// Allow controllers to use this tokenizer (given in `instantiate`) instead of the default one,
+    /// eg. --extra-tokenizer llama; can be specified multiple times. Loaded at startup.
+    #[arg(long)]
+    extra_tokenizer: Vec<String>,
+
+    /// Serve an additional inference engine, eg. --session phi2=phi; can be specified multiple times.
+    /// Its channels and logit bias shm are prefixed with <--name>NAME- (or use channels
+    /// main:NAME and side:NAME over a socket).
+    #[arg(long)]
+    session: Vec<String>,
 }
 
 enum ModuleStatus {
@@ -178,15 +270,34 @@ struct ModuleRegistry {
     req_instances: Arc<Mutex<HashMap<String, SeqWorkerHandle>>>,
     // not sure Mutex is needed
     forker: Arc<Mutex<WorkerForker>>,
+    warm_pool: WarmPool,
+    // native controllers, by name (without the native: prefix)
+    native_modules: Arc<HashMap<String, PathBuf>>,
+    // which engine session the instantiated workers are for
+    session: usize,
+    // the default tokenizer of the session
+    tokenizer: String,
+    // other tokenizers instances can use (--extra-tokenizer)
+    extra_tokenizers: Arc<Vec<String>>,
+    // for each session, the extra tokenizers with the same tokens as its default one
+    matching_tokenizers: Arc<Vec<Vec<String>>>,
 }
 
 struct Stepper {
     req_instances: Arc<Mutex<HashMap<String, SeqWorkerHandle>>>,
     instances: HashMap<ModuleInstId, SeqWorkerHandle>,
     num_timeouts: HashMap<ModuleInstId, usize>,
+    // fuel already reported for steps that timed out
+    timeout_fuel: HashMap<ModuleInstId, u64>,
+    // sequences suspended with StorageCmd::Watch, and the variables they wait for
+    watches: HashMap<ModuleInstId, Vec<String>>,
+    capabilities: CapabilitiesResp,
+    // send logit biases after mid_process responses
+    inline_bias: bool,
+    pending_bias: Option<Vec<u8>>,
     limits: AiciLimits,
     globals: GlobalInfo,
-    shm: Rc<ShmAllocator>,
+    shm: Arc<ShmAllocator>,
     token_bytes: Vec<Vec<u8>>,
 }
 
@@ -207,18 +318,45 @@ fn write_json<T: Serialize>(filename: &PathBuf, json: &T) -> Result<()> {
 }
 
 impl ModuleRegistry {
-    pub fn new(wasm_ctx: WasmContext, shm: Rc<ShmAllocator>) -> Result<Self> {
-        let forker = WorkerForker::new(wasm_ctx.clone(), shm);
+    pub fn new(sessions: &[Session], extra_tokenizers: &[String]) -> Result<Self> {
+        let tokenizers = TokenizerCache::preload(&sessions[0].wasm_ctx, extra_tokenizers)?;
+        let matching_tokenizers = sessions
+            .iter()
+            .map(|s| tokenizers.matching(&s.wasm_ctx))
+            .collect();
+        let forker = WorkerForker::new(
+            sessions.iter().map(|s| s.forker_session()).collect(),
+            tokenizers,
+        );
 
         Ok(Self {
             forker: Arc::new(Mutex::new(forker)),
             cache_path: PathBuf::from("./cache"),
-            wasm_ctx: Arc::new(wasm_ctx),
+            wasm_ctx: Arc::new(sessions[0].wasm_ctx.clone()),
             modules: Arc::new(Mutex::new(HashMap::default())),
             req_instances: Arc::new(Mutex::new(HashMap::default())),
+            warm_pool: WarmPool::default(),
+            native_modules: Arc::new(HashMap::default()),
+            session: 0,
+            tokenizer: sessions[0].tokenizer.clone(),
+            extra_tokenizers: Arc::new(extra_tokenizers.to_vec()),
+            matching_tokenizers: Arc::new(matching_tokenizers),
         })
     }
 
+    /// Registry for an additional engine session; modules and tags are shared,
+    /// but instances are not. Warm pools are only kept for the default session.
+    fn for_session(&self, session: usize, sess: &Session) -> Self {
+        Self {
+            wasm_ctx: Arc::new(sess.wasm_ctx.clone()),
+            req_instances: Arc::new(Mutex::new(HashMap::default())),
+            warm_pool: WarmPool::default(),
+            session,
+            tokenizer: sess.tokenizer.clone(),
+            ..self.clone()
+        }
+    }
+
     fn module_needs_check(&self, module_id: &str) -> bool {
         loop {
             let mut lck = self.modules.lock().unwrap();
@@ -416,7 +554,10 @@ impl ModuleRegistry {
             let mut info = info.clone();
             info.tag = tagname.clone();
             write_json(&self.tag_path(tagname), &info)?;
-            resp.tags.push(info)
+            resp.tags.push(info);
+            if self.warm_pool.has_tag(tagname) {
+                self.spawn_refill(tagname.clone());
+            }
         }
 
         Ok(json!(resp))
@@ -572,25 +713,172 @@ impl ModuleRegistry {
         Ok(resp.module_id)
     }
 
-    fn instantiate(&mut self, mut req: InstantiateReq) -> Result<Value> {
-        req.module_id = self.resolve_gh_module(&req.module_id, None)?;
-        if valid_tagname(&req.module_id) {
-            let taginfo = self.read_tag(&req.module_id)?;
-            req.module_id = taginfo.module_id;
-        }
-        ensure!(is_hex_string(&req.module_id), "invalid module_id");
-        let module_path = self.ensure_module_in_fs(&req.module_id)?;
+    fn instantiate(&mut self, mut req: InstantiateReq, auth: AuthInfo) -> Result<Value> {
+        let native = req.module_id.starts_with("native:");
+        let module_path = if native {
+            match self.native_modules.get(&req.module_id["native:".len()..]) {
+                Some(p) => p.clone(),
+                None => bail_user!("unknown native controller {}", req.module_id),
+            }
+        } else {
+            req.module_id = self.resolve_gh_module(&req.module_id, None)?;
+            if valid_tagname(&req.module_id) {
+                let taginfo = self.read_tag(&req.module_id)?;
+                req.module_id = taginfo.module_id;
+            }
+            ensure!(is_hex_string(&req.module_id), "invalid module_id");
+            self.ensure_module_in_fs(&req.module_id)?
+        };
+        let tokenizer = self.resolve_tokenizer(&req)?;
         log::debug!("instance {} -> {}", req.module_id, req.req_id);
-        let (handle, res) = self
-            .forker
-            .lock()
-            .unwrap()
-            .instantiate(req.clone(), module_path)?;
+        let (pooled, refill_tag) = match tokenizer {
+            // pooled workers all use the default tokenizer
+            Some(_) => (None, None),
+            None => self.warm_pool.take(&req.module_id),
+        };
+        if let Some(tag) = refill_tag {
+            self.spawn_refill(tag);
+        }
+        let handle = match pooled {
+            Some(mut h) => {
+                log::debug!("warm pool hit for {}", req.req_id);
+                h.req_id = req.req_id.clone();
+                h
+            }
+            None => self.preload(&req.req_id, &req.module_id, module_path, native, tokenizer)?,
+        };
+        let storage_ns = StorageNamespace {
+            user: auth.user,
+            module_id: req.module_id.clone(),
+        };
+        let res = handle.setup(&req, storage_ns, &self.wasm_ctx.limits)?;
         let mut req_instances = self.req_instances.lock().unwrap();
         req_instances.insert(req.req_id, handle);
         Ok(serde_json::to_value(res)?)
     }
 
+    fn resolve_tokenizer<'a>(&self, req: &'a InstantiateReq) -> Result<Option<&'a str>> {
+        resolve_tokenizer(
+            &self.tokenizer,
+            &self.extra_tokenizers,
+            &self.matching_tokenizers[self.session],
+            req.tokenizer.as_deref(),
+        )
+    }
+
+    fn preload(
+        &self,
+        req_id: &str,
+        module_id: &str,
+        module_path: PathBuf,
+        native: bool,
+        tokenizer: Option<&str>,
+    ) -> Result<SeqWorkerHandle> {
+        // only hold the forker lock while forking
+        let handle = self
+            .forker
+            .lock()
+            .unwrap()
+            .new_worker(req_id, self.session, tokenizer)?;
+        handle.preload(module_id, module_path, native, &self.wasm_ctx.limits)?;
+        Ok(handle)
+    }
+
+    fn spawn_refill(&self, tag: String) {
+        let reg = self.clone();
+        rayon::spawn(move || reg.refill_warm_pool(&tag));
+    }
+
+    fn refill_warm_pool(&self, tag: &str) {
+        let module_id = match self.read_tag(tag) {
+            Ok(info) => info.module_id,
+            Err(e) => {
+                log::warn!("warm pool {tag}: {e}");
+                return;
+            }
+        };
+        let module_path = match self.ensure_module_in_fs(&module_id) {
+            Ok(p) => p,
+            Err(e) => {
+                log::warn!("warm pool {tag}: {e}");
+                return;
+            }
+        };
+        while self.warm_pool.reserve(tag, &module_id) {
+            let req_id = format!("warm-{tag}");
+            let r = self.preload(&req_id, &module_id, module_path.clone(), false, None);
+            let failed = r.is_err();
+            self.warm_pool.complete(tag, &module_id, r);
+            if failed {
+                // we'll try again upon next miss
+                break;
+            }
+        }
+    }
+
+    fn start_warm_pool(&mut self, specs: &[String]) -> Result<()> {
+        let configs = specs
+            .iter()
+            .map(|s| WarmPoolConfig::parse(s))
+            .collect::<Result<Vec<_>>>()?;
+        self.warm_pool = WarmPool::new(&configs);
+        for tag in self.warm_pool.tags() {
+            self.spawn_refill(tag);
+        }
+        Ok(())
+    }
+
+    /// Parse `NAME=PATH` specs of native controllers.
+    fn set_native_controllers(&mut self, specs: &[String]) -> Result<()> {
+        let mut modules = HashMap::default();
+        for spec in specs {
+            let (name, path) = spec.split_once('=').ok_or_else(|| {
+                anyhow!("invalid native controller {spec:?}, expecting NAME=PATH")
+            })?;
+            ensure!(
+                valid_tagname(name),
+                "invalid native controller name {name:?}"
+            );
+            let path = fs::canonicalize(path)
+                .map_err(|e| anyhow!("native controller {name}: {path}: {e}"))?;
+            log::info!("native controller native:{name} -> {}", path.display());
+            modules.insert(name.to_string(), path);
+        }
+        self.native_modules = Arc::new(modules);
+        Ok(())
+    }
+
+    fn persistent_storage(&self, auth: &AuthInfo) -> Result<&PersistentStorage> {
+        ensure_user!(auth.is_admin, "storage access requires admin");
+        match &self.wasm_ctx.globals.persistent_storage {
+            Some(s) => Ok(s),
+            None => bail_user!("persistent storage not enabled"),
+        }
+    }
+
+    fn storage_list(&self, req: StorageListReq, auth: AuthInfo) -> Result<Value> {
+        let storage = self.persistent_storage(&auth)?;
+        Ok(serde_json::to_value(storage.list(&req)?)?)
+    }
+
+    fn storage_clear(&self, req: StorageClearReq, auth: AuthInfo) -> Result<Value> {
+        let storage = self.persistent_storage(&auth)?;
+        let num_removed = storage.clear(&req)?;
+        log::info!(
+            "storage clear {}/{} {:?} by {}: {} removed",
+            req.user,
+            req.module_id,
+            req.name,
+            auth.user,
+            num_removed
+        );
+        Ok(json!({ "num_removed": num_removed }))
+    }
+
+    fn warm_pool_stats(&self, _req: Value) -> Result<Value> {
+        Ok(serde_json::to_value(self.warm_pool.stats())?)
+    }
+
     fn run_main(&self, req_id: &String) -> Result<()> {
         let req_instances = self.req_instances.lock().unwrap();
         let inst = req_instances
@@ -605,31 +893,16 @@ impl ModuleRegistry {
             let mut s2 = self.clone();
 
             //println!("exec side: {}", &String::from_utf8_lossy(&msg));
-            match &ch {
-                CmdRespChannel::Futex { resp_ch, .. } => {
-                    let resp_ch = resp_ch.clone();
-                    rayon::spawn(move || {
-                        let r = s2.exec_wrapped(&msg);
-                        //println!("resp side: {}", serde_json::to_string(&r).unwrap());
-                        resp_ch
-                            .lock()
-                            .unwrap()
-                            .send_resp(serde_json::to_vec(&r).unwrap().as_slice())
-                            .unwrap();
-                    });
-                }
-                CmdRespChannel::Sem { resp_ch, .. } => {
-                    let resp_ch = resp_ch.clone();
-                    rayon::spawn(move || {
-                        let r = s2.exec_wrapped(&msg);
-                        resp_ch
-                            .lock()
-                            .unwrap()
-                            .send(serde_json::to_vec(&r).unwrap().as_slice())
-                            .unwrap();
-                    });
+            let resp_ch = ch.resp_ch.clone();
+            rayon::spawn(move || {
+                let r = s2.exec_wrapped(&msg);
+                //println!("resp side: {}", serde_json::to_string(&r).unwrap());
+                let bytes = serde_json::to_vec(&r).unwrap();
+                if let Err(e) = resp_ch.lock().unwrap().send_msg(&bytes) {
+                    // the connection is gone; recv() will notice
+                    log::warn!("failed to send side response: {e}");
                 }
-            }
+            });
         }
     }
 }
@@ -638,13 +911,19 @@ impl Stepper {
     pub fn new(
         reg: &ModuleRegistry,
         limits: AiciLimits,
-        shm: Rc<ShmAllocator>,
+        capabilities: CapabilitiesResp,
+        shm: Arc<ShmAllocator>,
         token_bytes: Vec<Vec<u8>>,
     ) -> Result<Self> {
         Ok(Self {
             req_instances: reg.req_instances.clone(),
             instances: HashMap::default(),
             num_timeouts: HashMap::default(),
+            timeout_fuel: HashMap::default(),
+            watches: HashMap::default(),
+            capabilities,
+            inline_bias: false,
+            pending_bias: None,
             limits,
             globals: reg.wasm_ctx.globals.clone(),
             shm,
@@ -691,7 +970,7 @@ impl Stepper {
             }
             log::debug!("fork {} -> ({})", parent_id, id);
             // TODO the forks should be done in parallel, best in tree-like fashion
-            let h = parent.fork(id)?;
+            let h = parent.fork(id, op.clone_idx.unwrap_or(0))?;
             self.instances.insert(id, h);
             Ok(parent_id)
         } else {
@@ -777,6 +1056,7 @@ impl Stepper {
                         tokens: op.tokens.clone(),
                         sampled: op.sampled,
                         fork_group,
+                        logprobs: op.logprobs.clone(),
                     },
                 };
                 if self.num_timeouts.get(&instid).is_some() {
@@ -804,10 +1084,12 @@ impl Stepper {
 
         for id in used_ids {
             let prev_timeout = self.num_timeouts.remove(&id).unwrap_or(0);
+            let prev_fuel = self.timeout_fuel.remove(&id).unwrap_or(0);
             let h = self.get_worker(id).unwrap();
             let timeout = deadline.saturating_duration_since(Instant::now());
             match h.check_process(timeout) {
                 Ok(mut data) => {
+                    data.fuel = data.fuel.saturating_sub(prev_fuel);
                     if !self.globals.inference_caps.fork {
                         if let Some(r) = &data.result {
                             if r.branches.len() > 1 {
@@ -847,6 +1129,45 @@ impl Stepper {
                         }
                     }
 
+                    if !self.globals.inference_caps.logprobs {
+                        if let Some(r) = &data.result {
+                            if r.logprobs.is_some() {
+                                self.worker_error(
+                                    id,
+                                    &mut outputs,
+                                    user_error!("logprobs not enabled in this host"),
+                                );
+                                continue;
+                            }
+                        }
+                    }
+
+                    if !self.globals.inference_caps.logprobs_full {
+                        if let Some(r) = &data.result {
+                            if r.logprobs.is_some_and(|lp| lp.full) {
+                                self.worker_error(
+                                    id,
+                                    &mut outputs,
+                                    user_error!("full logprobs not enabled in this host"),
+                                );
+                                continue;
+                            }
+                        }
+                    }
+
+                    if !self.globals.inference_caps.attention_mask {
+                        if let Some(r) = &data.result {
+                            if !r.attention_mask.is_empty() {
+                                self.worker_error(
+                                    id,
+                                    &mut outputs,
+                                    user_error!("attention masking not enabled in this host"),
+                                );
+                                continue;
+                            }
+                        }
+                    }
+
                     if let Some(r) = &mut data.result {
                         r.branches = r
                             .branches
@@ -866,11 +1187,15 @@ impl Stepper {
                 }
                 Err(e) => {
                     if e.to_string() == "timeout" && prev_timeout < self.limits.max_timeout_steps {
+                        // the worker is still running; report what it has consumed so far
+                        let fuel = std::cmp::max(h.step_fuel(), prev_fuel);
                         outputs.insert(
                             id,
                             SequenceResult {
                                 result: Some(ProcessResultOffset {
                                     branches: vec![Branch::noop()],
+                                    attention_mask: AttentionMask::default(),
+                                    logprobs: None,
                                 }),
                                 error: String::new(),
                                 storage: vec![],
@@ -881,9 +1206,12 @@ impl Stepper {
                                     self.limits.max_timeout_steps
                                 ),
                                 micros: start_time.elapsed().as_micros() as u64,
+                                fuel: fuel - prev_fuel,
+                                events: vec![],
                             },
                         );
                         self.num_timeouts.insert(id, prev_timeout + 1);
+                        self.timeout_fuel.insert(id, fuel);
                     } else {
                         self.worker_error(id, &mut outputs, e)
                     }
@@ -891,11 +1219,16 @@ impl Stepper {
             }
         }
 
+        let (suspend, resume) = self.update_watches(&outputs);
+
         for id in req.freed {
             log::debug!("free module {}", id);
             self.instances.remove(&id);
+            self.watches.remove(&id);
         }
 
+        let deadlocked = self.find_deadlocks();
+
         self.shm.free(max_offset, |client_id| {
             let id = client_id as ModuleInstId;
             !self.num_timeouts.contains_key(&id)
@@ -903,6 +1236,14 @@ impl Stepper {
 
         let bias_type = BiasType::from_u32(self.shm.elt_type() & 0xf).unwrap();
 
+        if self.inline_bias {
+            let num_bytes = (max_idx + 1) * mask_num_bytes;
+            let bias = self
+                .shm
+                .slice_at_byte_offset::<u8>(first_mask_byte_offset, num_bytes);
+            self.pending_bias = Some(bias.to_vec());
+        }
+
         Ok(AiciMidProcessResp {
             seqs: outputs,
             mask_num_bytes,
@@ -910,9 +1251,54 @@ impl Stepper {
             num_masks: max_idx + 1,
             dtype: bias_type.to_string(),
             mask_num_elts: bias_type.bytes_to_elts(mask_num_bytes),
+            suspend,
+            resume,
+            deadlocked,
         })
     }
 
+    /// Register new watches and wake up sequences watching variables written in this step.
+    /// Returns ids of sequences to suspend and to resume.
+    fn update_watches(
+        &mut self,
+        outputs: &HashMap<ModuleInstId, SequenceResult<ProcessResultOffset>>,
+    ) -> (Vec<ModuleInstId>, Vec<ModuleInstId>) {
+        let mut suspend = Vec::new();
+        let mut written = Vec::new();
+        for (id, r) in outputs.iter() {
+            let req_id = match self.instances.get(id) {
+                Some(h) => &h.req_id,
+                None => continue,
+            };
+            let is_running = r.result.as_ref().map_or(false, |r| r.branches.len() > 0);
+            for cmd in &r.storage {
+                match cmd {
+                    StorageCmd::WriteVar {
+                        name,
+                        scope: StorageScope::Request,
+                        ..
+                    } => written.push((req_id.clone(), name.clone())),
+                    StorageCmd::Watch { names, .. } if is_running => {
+                        log::debug!("{id} waiting for {names:?}");
+                        self.watches.insert(*id, names.clone());
+                        suspend.push(*id);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let req_ids = req_ids(&self.instances);
+        let resume = wake_watches(&mut self.watches, &req_ids, &written);
+
+        (suspend, resume)
+    }
+
+    fn find_deadlocks(&mut self) -> HashMap<ModuleInstId, String> {
+        let req_ids = req_ids(&self.instances);
+        find_deadlocks(&mut self.watches, &req_ids)
+    }
+
     fn worker_error<T>(
         &mut self,
         instid: usize,
@@ -926,6 +1312,108 @@ impl Stepper {
     }
 }
 
+/// Returns None when the default tokenizer is to be used.
+fn resolve_tokenizer<'a>(
+    default: &str,
+    extra: &[String],
+    matching: &[String],
+    name: Option<&'a str>,
+) -> Result<Option<&'a str>> {
+    match name {
+        None => Ok(None),
+        Some(name) if name == default => Ok(None),
+        Some(name) => {
+            // don't let users load arbitrary files or download models
+            ensure_user!(
+                extra.iter().any(|t| t == name),
+                "tokenizer {name:?} not enabled in this host (see --extra-tokenizer)"
+            );
+            // otherwise the controller would produce masks for wrong token ids
+            ensure_user!(
+                matching.iter().any(|t| t == name),
+                "tokenizer {name:?} has different tokens than {default:?} of this engine"
+            );
+            Ok(Some(name))
+        }
+    }
+}
+
+fn req_ids(instances: &HashMap<ModuleInstId, SeqWorkerHandle>) -> HashMap<ModuleInstId, &str> {
+    instances
+        .iter()
+        .map(|(id, h)| (*id, h.req_id.as_str()))
+        .collect()
+}
+
+/// Remove watches of sequences woken up by writes of (req_id, name) variables.
+/// Returns ids of sequences to resume.
+fn wake_watches(
+    watches: &mut HashMap<ModuleInstId, Vec<String>>,
+    req_ids: &HashMap<ModuleInstId, &str>,
+    written: &[(String, String)],
+) -> Vec<ModuleInstId> {
+    let mut resume = Vec::new();
+    if written.len() > 0 {
+        // a watch and a write in the same step might yield a spurious wake-up;
+        // that's fine, as the controller will just check again
+        watches.retain(|id, names| {
+            let req_id = match req_ids.get(id) {
+                Some(r) => *r,
+                None => return false,
+            };
+            let woken = written
+                .iter()
+                .any(|(r, n)| r == req_id && names.contains(n));
+            if woken {
+                log::debug!("{id} resumed");
+                resume.push(*id);
+            }
+            !woken
+        });
+    }
+    resume
+}
+
+/// Find requests where all live sequences are suspended, and no-one is left to wake them.
+/// Watches of deadlocked sequences are removed.
+fn find_deadlocks(
+    watches: &mut HashMap<ModuleInstId, Vec<String>>,
+    req_ids: &HashMap<ModuleInstId, &str>,
+) -> HashMap<ModuleInstId, String> {
+    let mut res = HashMap::default();
+    if watches.is_empty() {
+        return res;
+    }
+
+    let mut by_req: HashMap<&str, Vec<ModuleInstId>> = HashMap::default();
+    for (id, req_id) in req_ids.iter() {
+        by_req.entry(req_id).or_default().push(*id);
+    }
+
+    for ids in by_req.values_mut() {
+        if !ids.iter().all(|id| watches.contains_key(id)) {
+            continue;
+        }
+        ids.sort();
+        let info = ids
+            .iter()
+            .map(|id| format!("#{id} waits for {:?}", watches[id]))
+            .collect::<Vec<_>>()
+            .join("; ");
+        for id in ids.iter() {
+            res.insert(
+                *id,
+                format!("deadlock: all sequences are suspended: {info}\n"),
+            );
+        }
+    }
+
+    for id in res.keys() {
+        watches.remove(id);
+    }
+    res
+}
+
 impl Exec for Stepper {
     #[inline(never)]
     fn exec(&mut self, json: Value, _auth: AuthInfo) -> Result<Value> {
@@ -934,12 +1422,17 @@ impl Exec for Stepper {
                 "vocab_size": self.globals.tokrx_info.vocab_size,
                 "eos_token_id": self.globals.tokrx_info.tok_eos,
             })),
+            Some("capabilities") => Ok(serde_json::to_value(&self.capabilities)?),
             Some("mid_process") => Ok(serde_json::to_value(
                 &self.aici_mid_process(serde_json::from_value(json)?)?,
             )?),
             _ => return Err(anyhow!("bad op")),
         }
     }
+
+    fn take_binary(&mut self) -> Option<Vec<u8>> {
+        self.pending_bias.take()
+    }
 }
 
 impl Exec for ModuleRegistry {
@@ -949,7 +1442,10 @@ impl Exec for ModuleRegistry {
             Some("set_tags") => self.set_tags(serde_json::from_value(json)?, auth),
             Some("get_tags") => self.get_tags(serde_json::from_value(json)?),
             Some("mk_module") => self.mk_module(serde_json::from_value(json)?, auth),
-            Some("instantiate") => self.instantiate(serde_json::from_value(json)?),
+            Some("instantiate") => self.instantiate(serde_json::from_value(json)?, auth),
+            Some("storage_list") => self.storage_list(serde_json::from_value(json)?, auth),
+            Some("storage_clear") => self.storage_clear(serde_json::from_value(json)?, auth),
+            Some("warm_pool_stats") => self.warm_pool_stats(json),
             _ => return Err(anyhow!("bad op")),
         }
     }
@@ -958,6 +1454,11 @@ impl Exec for ModuleRegistry {
 trait Exec {
     fn exec(&mut self, json: Value, auth: AuthInfo) -> Result<Value>;
 
+    /// Binary payload to send right after the last response (if any).
+    fn take_binary(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
     fn exec_wrapped(&mut self, msg: &[u8]) -> Value {
         match serde_json::from_slice::<Value>(msg) {
             Ok(json) => {
@@ -968,6 +1469,8 @@ trait Exec {
                     Some("ping") => Ok(json!({ "pong": 1 })),
                     Some("stop") => worker::stop_process(),
                     _ => {
+                        // $auth is set by the LLM engine, which is trusted: it either shares
+                        // memory with us, or had to present --listen-token-file to connect
                         let auth = if json["$auth"].as_object().is_none() {
                             Ok(AuthInfo::local_user())
                         } else {
@@ -1023,91 +1526,192 @@ trait Exec {
     }
 }
 
-enum CmdRespChannel {
-    Sem {
-        cmd_ch: MessageChannel,
-        resp_ch: Arc<Mutex<MessageChannel>>,
-        busy_wait_duration: Duration,
-    },
-    Futex {
-        cmd_ch: ServerChannel,
-        resp_ch: Arc<Mutex<ServerChannel>>,
-        busy_wait_duration: Duration,
-    },
+type SocketConn = (SocketSender, SocketReceiver);
+
+/// Limit on connections that haven't completed the handshake yet.
+const MAX_PENDING_HANDSHAKES: usize = 64;
+
+/// Main and side channels of a session.
+enum SessionChannels {
+    Shm(CmdRespChannel, CmdRespChannel),
+    Socket(Arc<ChannelSlot<SocketConn>>, Arc<ChannelSlot<SocketConn>>),
+}
+
+struct CmdRespChannel {
+    cmd_ch: Box<dyn MsgReceiver>,
+    resp_ch: Arc<Mutex<Box<dyn MsgSender>>>,
+    // for sockets, where the next connection comes from when this one fails
+    slot: Option<Arc<ChannelSlot<SocketConn>>>,
 }
 
 impl CmdRespChannel {
-    pub fn new(suff: &str, cli: &Cli) -> Result<Self> {
+    pub fn new(sess: &Session, suff: &str, cli: &Cli) -> Result<Self> {
         let busy_wait_duration = Duration::from_millis(cli.busy_wait_time);
-        if cli.futex {
+        let (cmd_ch, resp_ch): (Box<dyn MsgReceiver>, Box<dyn MsgSender>) = if cli.futex {
             let cmd_shm = Shm::new(
-                &cli.prefixed_name("cmd", suff),
+                &sess.shm_name("cmd", suff),
                 cli.json_size * MEGABYTE,
                 shm::Unlink::Post,
             )?;
             let resp_shm = Shm::new(
-                &cli.prefixed_name("resp", suff),
+                &sess.shm_name("resp", suff),
                 cli.json_size * MEGABYTE,
                 shm::Unlink::Post,
             )?;
-            Ok(Self::Futex {
-                cmd_ch: ServerChannel::new(cmd_shm),
-                resp_ch: Arc::new(Mutex::new(ServerChannel::new(resp_shm))),
-                busy_wait_duration,
-            })
+            (
+                Box::new(BusyWait {
+                    ch: ServerChannel::new(cmd_shm),
+                    busy_wait_duration,
+                }),
+                Box::new(ServerChannel::new(resp_shm)),
+            )
         } else {
             let cmd_ch =
-                MessageChannel::new(&cli.prefixed_name("cmd", suff), cli.json_size * MEGABYTE)?;
-            let resp_ch = Arc::new(Mutex::new(MessageChannel::new(
-                &cli.prefixed_name("resp", suff),
-                cli.json_size * MEGABYTE,
-            )?));
+                MessageChannel::new(&sess.shm_name("cmd", suff), cli.json_size * MEGABYTE)?;
+            let resp_ch =
+                MessageChannel::new(&sess.shm_name("resp", suff), cli.json_size * MEGABYTE)?;
+            (
+                Box::new(BusyWait {
+                    ch: cmd_ch,
+                    busy_wait_duration,
+                }),
+                Box::new(resp_ch),
+            )
+        };
+        Ok(Self {
+            cmd_ch,
+            resp_ch: Arc::new(Mutex::new(resp_ch)),
+            slot: None,
+        })
+    }
 
-            Ok(Self::Sem {
-                cmd_ch,
-                resp_ch,
-                busy_wait_duration,
-            })
+    /// Wait for a connection to the socket channel.
+    fn from_slot(slot: Arc<ChannelSlot<SocketConn>>) -> Self {
+        let (tx, rx) = slot.take();
+        Self {
+            cmd_ch: Box::new(rx),
+            resp_ch: Arc::new(Mutex::new(Box::new(tx))),
+            slot: Some(slot),
         }
     }
 
-    #[allow(dead_code)]
-    pub fn busy_reset(&self) {
-        match self {
-            Self::Sem {
-                cmd_ch, resp_ch, ..
-            } => {
-                cmd_ch.busy_reset();
-                resp_ch.lock().unwrap().busy_reset();
+    /// Main and side channels of a session, over shared memory.
+    pub fn for_session(sess: &Session, cli: &Cli) -> Result<SessionChannels> {
+        Ok(SessionChannels::Shm(
+            Self::new(sess, "", cli)?,
+            Self::new(sess, "-side", cli)?,
+        ))
+    }
+
+    /// Accept socket connections in the background, routing them to sessions by the channel name:
+    /// `main` and `side` for the default session, `main:NAME` and `side:NAME` for the others.
+    /// Handshakes are done on separate threads, so that a slow client doesn't hold up others.
+    /// A connection to a channel that is already connected is closed.
+    /// Commands larger than --json-size are rejected.
+    pub fn listen(addr: &str, cli: &Cli, sessions: &[Session]) -> Result<Vec<SessionChannels>> {
+        let addr = SocketAddr::parse(addr)?;
+        let token = match &cli.listen_token_file {
+            Some(path) => Some(read_token_file(path)?),
+            None => None,
+        };
+        let listener = SocketListener::bind(&addr, token, cli.json_size * MEGABYTE)?;
+        log::info!("listening on {addr:?}");
+        let names = sessions.iter().map(|s| s.name.clone()).collect::<Vec<_>>();
+        let slots = names
+            .iter()
+            .map(|_| (Arc::new(ChannelSlot::new()), Arc::new(ChannelSlot::new())))
+            .collect::<Vec<_>>();
+        let channels = slots
+            .iter()
+            .map(|(main, side)| SessionChannels::Socket(main.clone(), side.clone()))
+            .collect();
+        let routes = Arc::new((names, slots));
+        let num_pending = Arc::new(AtomicUsize::new(0));
+        std::thread::spawn(move || loop {
+            let conn = match listener.accept_pending() {
+                Ok(r) => r,
+                Err(e) => {
+                    log::warn!("accept failed: {e}");
+                    continue;
+                }
+            };
+            if num_pending.fetch_add(1, Ordering::SeqCst) >= MAX_PENDING_HANDSHAKES {
+                num_pending.fetch_sub(1, Ordering::SeqCst);
+                log::warn!("too many pending connections; closing new one");
+                continue;
             }
-            Self::Futex { .. } => {}
+            let routes = routes.clone();
+            let num_pending = num_pending.clone();
+            std::thread::spawn(move || {
+                let r = conn.handshake();
+                num_pending.fetch_sub(1, Ordering::SeqCst);
+                let (channel, tx, rx) = match r {
+                    Ok(r) => r,
+                    Err(e) => {
+                        log::warn!("handshake failed: {e}");
+                        return;
+                    }
+                };
+                let (names, slots) = &*routes;
+                match route_channel(&channel, names) {
+                    Some((is_main, idx)) => {
+                        let slot = if is_main {
+                            &slots[idx].0
+                        } else {
+                            &slots[idx].1
+                        };
+                        if slot.offer((tx, rx)).is_err() {
+                            log::warn!("channel {channel:?} is already connected; closing new one");
+                        }
+                    }
+                    None => log::warn!("invalid channel {channel:?}"),
+                }
+            });
+        });
+        Ok(channels)
+    }
+
+    /// Wait for both the main and side channels of a session.
+    pub fn wait_for_pair(chans: SessionChannels) -> (Self, Self) {
+        match chans {
+            SessionChannels::Shm(main, side) => (main, side),
+            SessionChannels::Socket(main, side) => (Self::from_slot(main), Self::from_slot(side)),
         }
     }
 
     pub fn respond(&self, json: Value) {
         let slice = serde_json::to_vec(&json).unwrap();
-        match self {
-            Self::Sem { resp_ch, .. } => {
-                resp_ch.lock().unwrap().send(&slice).unwrap();
-            }
-            Self::Futex { resp_ch, .. } => {
-                resp_ch.lock().unwrap().send_resp(&slice).unwrap();
-            }
+        self.send(&slice);
+    }
+
+    fn send(&self, msg: &[u8]) {
+        if let Err(e) = self.resp_ch.lock().unwrap().send_msg(msg) {
+            // the connection is gone; recv() will notice
+            log::warn!("failed to send response: {e}");
         }
     }
 
+    /// Wait for the next command.
+    /// When a socket connection fails (the LLM goes away, or sends a bad frame),
+    /// it's dropped, and the next connection to the channel is used.
+    /// Failure of a shared memory channel stops aicirt.
     pub fn recv(&mut self) -> Vec<u8> {
-        match self {
-            Self::Sem {
-                cmd_ch,
-                busy_wait_duration,
-                ..
-            } => cmd_ch.recv(busy_wait_duration).unwrap(),
-            Self::Futex {
-                cmd_ch,
-                busy_wait_duration,
-                ..
-            } => cmd_ch.recv_req(busy_wait_duration.clone()),
+        loop {
+            match self.cmd_ch.recv_msg() {
+                Ok(msg) => return msg,
+                Err(e) => match self.slot.clone() {
+                    Some(slot) => {
+                        log::warn!("dropping connection: {e}");
+                        slot.release();
+                        *self = Self::from_slot(slot);
+                        log::info!("channel reconnected");
+                    }
+                    None => {
+                        log::error!("channel failed: {e}");
+                        worker::stop_process()
+                    }
+                },
+            }
         }
     }
 
@@ -1117,7 +1721,10 @@ impl CmdRespChannel {
             //println!("exec main: {}", String::from_utf8_lossy(&msg));
             let val = exec.exec_wrapped(&msg);
             //println!("resp main: {}", serde_json::to_string(&val).unwrap());
-            self.respond(val)
+            self.respond(val);
+            if let Some(bin) = exec.take_binary() {
+                self.send(&bin);
+            }
         }
     }
 }
@@ -1185,9 +1792,9 @@ fn save_tokenizer(cli: &Cli) {
     }
 }
 
-fn install_from_cmdline(cli: &Cli, wasm_ctx: WasmContext, shm: Rc<ShmAllocator>) {
+fn install_from_cmdline(cli: &Cli, sessions: &[Session]) {
     let name = cli.module.as_deref().unwrap();
-    let mut reg = ModuleRegistry::new(wasm_ctx, shm).unwrap();
+    let mut reg = ModuleRegistry::new(sessions, &[]).unwrap();
     let module_id = if name.ends_with(".wasm") {
         let wasm_bytes = fs::read(name).unwrap();
         if let Some(gh) = &cli.gh_module {
@@ -1219,12 +1826,17 @@ fn install_from_cmdline(cli: &Cli, wasm_ctx: WasmContext, shm: Rc<ShmAllocator>)
             Some(ref path) => json!(fs::read_to_string(path).unwrap()),
             None => json!({"steps":[]}),
         };
-        reg.instantiate(InstantiateReq {
-            req_id: req_id.clone(),
-            prompt: json!(""),
-            module_id: module_id.clone(),
-            module_arg: arg,
-        })
+        reg.instantiate(
+            InstantiateReq {
+                req_id: req_id.clone(),
+                prompt: json!(""),
+                module_id: module_id.clone(),
+                module_arg: arg,
+                tokenizer: None,
+                seed: None,
+            },
+            AuthInfo::admin_user(),
+        )
         .unwrap();
         reg.run_main(&req_id).unwrap();
     }
@@ -1261,6 +1873,8 @@ fn main() -> () {
         logit_memory_bytes: cli.bin_size * MEGABYTE,
         busy_wait_duration: Duration::from_millis(cli.busy_wait_time),
         max_forks: cli.wasm_max_forks,
+        max_step_fuel: cli.wasm_max_step_fuel,
+        max_init_fuel: cli.wasm_max_init_fuel,
 
         module_upload: !cli.restricted,
         gh_download: !cli.restricted,
@@ -1275,6 +1889,35 @@ fn main() -> () {
         fork: cli.cap_fork,
         backtrack: cli.cap_backtrack,
         ff_tokens: cli.cap_ff_tokens,
+        logprobs: cli.cap_logprobs,
+        logprobs_full: cli.cap_logprobs_full,
+        attention_mask: cli.cap_attention_mask,
+    };
+
+    let capabilities = CapabilitiesResp {
+        protocol_version: AICI_PROTOCOL_VERSION,
+        aicirt_version: env!("CARGO_PKG_VERSION").to_string(),
+        bias_dtype: bias_type.to_string(),
+        supported_bias_dtypes: BiasType::all().iter().map(|b| b.to_string()).collect(),
+        futex: cli.futex,
+        socket: cli.listen.is_some(),
+        supported_channels: vec![
+            "futex".to_string(),
+            "semaphore".to_string(),
+            "socket".to_string(),
+        ],
+        inference_caps: inference_caps.clone(),
+        limits: LimitsInfo {
+            max_memory_bytes: limits.max_memory_bytes,
+            max_step_ms: limits.max_step_ms,
+            max_init_ms: limits.max_init_ms,
+            max_timeout_steps: limits.max_timeout_steps,
+            max_forks: limits.max_forks,
+            max_step_fuel: limits.max_step_fuel,
+            max_init_fuel: limits.max_init_fuel,
+            json_size_bytes: limits.ipc_shm_bytes,
+            bin_size_bytes: limits.logit_memory_bytes,
+        },
     };
 
     let mut tokenizer = find_tokenizer(&cli.tokenizer).unwrap();
@@ -1282,34 +1925,80 @@ fn main() -> () {
         tokenizer.add_missing_tokens(logits_size);
     }
     let token_bytes = tokenizer.token_bytes();
-    let wasm_ctx = WasmContext::new(inference_caps, limits.clone(), tokenizer).unwrap();
+    let mut wasm_ctx = WasmContext::new(inference_caps, limits.clone(), tokenizer).unwrap();
+    if cli.persistent_storage {
+        wasm_ctx.globals.persistent_storage = Some(PersistentStorage::new(
+            PathBuf::from("./cache/storage"),
+            StorageQuota {
+                max_vars: cli.storage_max_vars,
+                max_bytes: cli.storage_max_bytes,
+            },
+        ));
+    }
+    if cli.blob_cache {
+        wasm_ctx.globals.blob_cache = Some(BlobCache::new(
+            PathBuf::from("./cache/blobs"),
+            BlobQuota {
+                max_module_bytes: cli.blob_cache_max_module_bytes,
+                max_total_bytes: cli.blob_cache_max_bytes,
+            },
+        ));
+    }
 
     if cli.save_tokenizer.is_some() {
         save_tokenizer(&cli);
         return ();
     }
 
-    let bin_shm = Shm::new(
-        &MessageChannel::shm_name(&cli.prefixed_name("bin", "")),
-        limits.logit_memory_bytes,
-        if cli.module.is_none() {
-            shm::Unlink::None
-        } else {
-            shm::Unlink::Pre
-        },
-    )
-    .unwrap();
+    let unlink = if cli.module.is_none() {
+        shm::Unlink::None
+    } else {
+        shm::Unlink::Pre
+    };
+    let mk_shm = |prefix: &str, vocab_size: usize| -> Result<Arc<ShmAllocator>> {
+        let bin_shm = Shm::new(
+            &MessageChannel::shm_name(&format!("{prefix}bin")),
+            limits.logit_memory_bytes,
+            unlink,
+        )?;
+        Ok(Arc::new(ShmAllocator::new(
+            bin_shm,
+            // allow for a little leeway
+            bias_type.size_in_bytes((vocab_size + 1000) & !63),
+            bias_type.to_u32(),
+        )))
+    };
 
     let vocab_size = wasm_ctx.globals.tokrx_info.vocab_size as usize;
-    let shm_alloc = Rc::new(ShmAllocator::new(
-        bin_shm,
-        // allow for a little leeway
-        bias_type.size_in_bytes((vocab_size + 1000) & !63),
-        bias_type.to_u32(),
-    ));
+    let shm_alloc = mk_shm(&cli.name, vocab_size).unwrap();
+    let mut sessions = vec![Session::new(
+        "",
+        &cli.tokenizer,
+        cli.name.clone(),
+        wasm_ctx,
+        shm_alloc,
+        token_bytes,
+    )];
+    for spec in &cli.session {
+        let sess = SessionConfig::parse(spec).and_then(|cfg| {
+            ensure_user!(
+                sessions.iter().all(|s| s.name != cfg.name),
+                "duplicate session {}",
+                cfg.name
+            );
+            Session::from_config(&sessions[0], &cfg, &mk_shm)
+        });
+        match sess {
+            Ok(sess) => sessions.push(sess),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 
     if cli.module.is_some() {
-        install_from_cmdline(&cli, wasm_ctx, shm_alloc.clone());
+        install_from_cmdline(&cli, &sessions);
         return ();
     }
 
@@ -1322,19 +2011,78 @@ fn main() -> () {
 
     set_max_priority();
 
-    let reg = ModuleRegistry::new(wasm_ctx, shm_alloc.clone()).unwrap();
+    let mut reg = match ModuleRegistry::new(&sessions, &cli.extra_tokenizer) {
+        Ok(reg) => reg,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
     // needs to be done after WorkerForker is spawned
     setup_bg_worker_pool();
 
-    let exec = Stepper::new(&reg, limits, shm_alloc, token_bytes).unwrap();
-    let cli2 = cli.clone();
-    rayon::spawn(move || {
-        let reg_disp = CmdRespChannel::new("-side", &cli2).unwrap();
+    if let Err(e) = reg.set_native_controllers(&cli.native_controller) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = reg.start_warm_pool(&cli.warm_pool) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let mut channels = match &cli.listen {
+        Some(addr) => CmdRespChannel::listen(addr, &cli, &sessions).unwrap(),
+        None => sessions
+            .iter()
+            .map(|sess| CmdRespChannel::for_session(sess, &cli))
+            .collect::<Result<Vec<_>>>()
+            .unwrap(),
+    };
+
+    let mut default_session = None;
+    for (idx, sess) in sessions.iter().enumerate() {
+        let reg = if idx == 0 {
+            reg.clone()
+        } else {
+            reg.for_session(idx, sess)
+        };
+        let mut capabilities = capabilities.clone();
+        capabilities.session = sess.name.clone();
+        let mut exec = Stepper::new(
+            &reg,
+            limits.clone(),
+            capabilities,
+            sess.shm.clone(),
+            sess.token_bytes.clone(),
+        )
+        .unwrap();
+        // there is no shared memory with the LLM, so logit biases follow mid_process responses
+        exec.inline_bias = cli.listen.is_some();
+        let chans = channels.remove(0);
+        if idx == 0 {
+            default_session = Some((reg, exec, chans));
+        } else {
+            std::thread::spawn(move || {
+                set_max_priority();
+                run_session(reg, exec, chans)
+            });
+        }
+    }
+
+    let (reg, exec, chans) = default_session.unwrap();
+    run_session(reg, exec, chans);
+}
+
+fn run_session(reg: ModuleRegistry, exec: Stepper, chans: SessionChannels) -> ! {
+    let (mut exec_disp, reg_disp) = CmdRespChannel::wait_for_pair(chans);
+
+    std::thread::spawn(move || {
+        set_min_priority();
         reg.dispatch_loop(reg_disp);
     });
 
-    let mut exec_disp = CmdRespChannel::new("", &cli).unwrap();
     exec_disp.dispatch_loop(exec);
 }
 
@@ -1352,3 +2100,82 @@ pub fn setup_bg_worker_pool() {
         .build_global()
         .unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watches(entries: &[(ModuleInstId, &[&str])]) -> HashMap<ModuleInstId, Vec<String>> {
+        entries
+            .iter()
+            .map(|(id, names)| (*id, names.iter().map(|n| n.to_string()).collect()))
+            .collect()
+    }
+
+    fn written(entries: &[(&str, &str)]) -> Vec<(String, String)> {
+        entries
+            .iter()
+            .map(|(r, n)| (r.to_string(), n.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn only_extra_tokenizers_can_be_selected() {
+        let extra = vec!["llama".to_string(), "orca".to_string()];
+        let matching = vec!["llama".to_string()];
+        let resolve = |name| resolve_tokenizer("phi", &extra, &matching, name);
+        assert_eq!(resolve(None).unwrap(), None);
+        assert_eq!(resolve(Some("phi")).unwrap(), None);
+        assert_eq!(resolve(Some("llama")).unwrap(), Some("llama"));
+        let err = resolve(Some("gpt4")).unwrap_err();
+        assert!(UserError::is_self(&err));
+        assert!(resolve(Some("/etc/passwd")).is_err());
+        // enabled, but with other tokens than the engine's
+        let err = resolve(Some("orca")).unwrap_err();
+        assert!(UserError::is_self(&err));
+        assert!(err.to_string().contains("different tokens"), "{err}");
+    }
+
+    #[test]
+    fn writes_wake_watchers_of_the_same_request() {
+        let req_ids: HashMap<ModuleInstId, &str> =
+            [(1, "r1"), (2, "r1"), (3, "r2")].into_iter().collect();
+        let mut w = watches(&[(1, &["x", "y"]), (3, &["x"])]);
+
+        let resume = wake_watches(&mut w, &req_ids, &written(&[("r1", "z")]));
+        assert!(resume.is_empty());
+        assert_eq!(w.len(), 2);
+
+        // only the watcher from r1 is woken up
+        let resume = wake_watches(&mut w, &req_ids, &written(&[("r1", "x")]));
+        assert_eq!(resume, vec![1]);
+        assert!(w.contains_key(&3) && !w.contains_key(&1));
+    }
+
+    #[test]
+    fn watches_of_freed_sequences_are_dropped() {
+        let req_ids: HashMap<ModuleInstId, &str> = [(3, "r2")].into_iter().collect();
+        let mut w = watches(&[(1, &["x"]), (3, &["x"])]);
+        let resume = wake_watches(&mut w, &req_ids, &written(&[("r1", "x")]));
+        assert!(resume.is_empty());
+        assert_eq!(w.keys().copied().collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn deadlock_when_all_sequences_of_request_wait() {
+        let req_ids: HashMap<ModuleInstId, &str> =
+            [(1, "r1"), (2, "r1"), (3, "r2")].into_iter().collect();
+
+        // #2 is still running, so it can wake #1
+        let mut w = watches(&[(1, &["x"])]);
+        assert!(find_deadlocks(&mut w, &req_ids).is_empty());
+        assert_eq!(w.len(), 1);
+
+        let mut w = watches(&[(1, &["x"]), (2, &["y"])]);
+        let res = find_deadlocks(&mut w, &req_ids);
+        assert_eq!(res.len(), 2);
+        assert!(res[&1].contains("#1 waits for [\"x\"]; #2 waits for [\"y\"]"));
+        assert_eq!(res[&1], res[&2]);
+        assert!(w.is_empty());
+    }
+}
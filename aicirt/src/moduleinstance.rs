@@ -8,15 +8,42 @@ use aici_abi::{toktrie::TokTrie, InitPromptArg, InitPromptResult, ProcessResultO
 use aicirt::{
     api::{InferenceCapabilities, SequenceResult},
     bail_user,
-    bintokens::ByteTokenizer,
+    bintokens::{find_tokenizer, ByteTokenizer},
     shm::ShmAllocator,
-    user_error,
+    storage::StorageNamespace,
+    user_error, HashMap,
 };
 use anyhow::{anyhow, ensure, Result};
 use serde::Deserialize;
-use std::{path::PathBuf, rc::Rc, sync::Arc, time::Instant};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use wasmtime;
 
+// wasmtime keeps fuel as i64 internally
+const UNLIMITED_FUEL: u64 = i64::MAX as u64;
+
+/// Bump the engine epoch every millisecond, so that the fuel gauge is updated during long calls.
+/// Threads don't survive fork(), so this is done once in every worker process.
+fn start_epoch_ticker(engine: &wasmtime::Engine) {
+    static TICKER_PID: AtomicU32 = AtomicU32::new(0);
+    let pid = std::process::id();
+    if TICKER_PID.swap(pid, Ordering::Relaxed) != pid {
+        let engine = engine.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_millis(1));
+            engine.increment_epoch();
+        });
+    }
+}
+
 #[derive(Clone)]
 pub struct WasmContext {
     pub engine: wasmtime::Engine,
@@ -41,7 +68,8 @@ impl WasmContext {
         cfg.debug_info(false)
             .wasm_backtrace(true)
             .native_unwind_info(true)
-            .consume_fuel(false)
+            .consume_fuel(limits.fuel_enabled())
+            .epoch_interruption(limits.fuel_enabled())
             .max_wasm_stack(512 * 1024)
             .wasm_tail_call(false)
             .wasm_threads(false)
@@ -67,6 +95,33 @@ impl WasmContext {
         let engine = wasmtime::Engine::new(&cfg)?;
         let linker = setup_linker(&engine)?;
 
+        let globals = Self::mk_globals(inference_caps, tokenizer);
+
+        Ok(Self {
+            engine,
+            linker,
+            globals,
+            limits,
+            timers: TimerSet::new(),
+        })
+    }
+
+    /// Context sharing the engine (and thus compiled modules) with `self`,
+    /// but using a different tokenizer.
+    pub fn with_tokenizer(&self, tokenizer: ByteTokenizer) -> Self {
+        let mut globals = Self::mk_globals(self.globals.inference_caps.clone(), tokenizer);
+        globals.persistent_storage = self.globals.persistent_storage.clone();
+        globals.blob_cache = self.globals.blob_cache.clone();
+        Self {
+            engine: self.engine.clone(),
+            linker: self.linker.clone(),
+            globals,
+            limits: self.limits.clone(),
+            timers: self.timers.clone(),
+        }
+    }
+
+    fn mk_globals(inference_caps: InferenceCapabilities, tokenizer: ByteTokenizer) -> GlobalInfo {
         let tokens = tokenizer.token_bytes();
         let trie = TokTrie::from(&tokenizer.tokrx_info(), &tokens);
         trie.check_against(&tokens);
@@ -80,31 +135,106 @@ impl WasmContext {
         // let tokens = tok.encode("I am something", false).unwrap();
         // println!("tokens: {:?}", tokens);
 
-        let globals = GlobalInfo {
+        GlobalInfo {
             tokrx_info: tokenizer.tokrx_info(),
             tok_trie: Arc::new(trie2),
             trie_bytes: Arc::new(bytes),
             hf_tokenizer: Arc::new(tokenizer.hf_tokenizer),
             inference_caps,
-        };
+            persistent_storage: None,
+            blob_cache: None,
+        }
+    }
+}
 
-        Ok(Self {
-            engine,
-            linker,
-            globals,
-            limits,
-            timers: TimerSet::new(),
-        })
+/// Token tries (and other tokenizer data) for instances that don't use the default tokenizer.
+/// Only tokenizers enabled with `--extra-tokenizer` can be used; they are all loaded
+/// at startup, before the forker is spawned, so that workers inherit them,
+/// and the forker never downloads tokenizers or builds tries.
+#[derive(Default)]
+pub struct TokenizerCache {
+    globals: HashMap<String, GlobalInfo>,
+}
+
+impl TokenizerCache {
+    pub fn preload(ctx: &WasmContext, names: &[String]) -> Result<Self> {
+        let mut globals = HashMap::default();
+        for name in names {
+            let tokenizer = find_tokenizer(name)?;
+            log::info!("building token trie for {name}");
+            globals.insert(name.clone(), ctx.with_tokenizer(tokenizer).globals);
+        }
+        Ok(TokenizerCache { globals })
+    }
+
+    /// Names of tokenizers that give the same bytes to all their tokens as the one of `ctx`
+    /// (ie., the engine's), so that token ids and masks mean the same thing.
+    /// The engine tokenizer may have extra tokens at the end (see --logits-size).
+    pub fn matching(&self, ctx: &WasmContext) -> Vec<String> {
+        let trie = &ctx.globals.tok_trie;
+        let mut names = self
+            .globals
+            .iter()
+            .filter(|(_, g)| {
+                let n = g.tok_trie.vocab_size();
+                n <= trie.vocab_size() && tokens_hash(&g.tok_trie, n) == tokens_hash(trie, n)
+            })
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    pub fn context_for(&self, ctx: &WasmContext, name: &str) -> Result<WasmContext> {
+        match self.globals.get(name) {
+            Some(globals) => Ok(WasmContext {
+                globals: globals.clone(),
+                ..ctx.clone()
+            }),
+            None => bail_user!("tokenizer {name:?} not loaded"),
+        }
     }
 }
 
+/// Hash of the bytes of the first `num_tokens` tokens.
+fn tokens_hash(trie: &TokTrie, num_tokens: usize) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for tok in 0..num_tokens as TokenId {
+        trie.token(tok).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Controller running in a worker process; either a WASM module ([ModuleInstance])
+/// or a trusted native library ([crate::nativeinstance::NativeInstance]).
+pub trait ControllerInstance {
+    fn set_id(&mut self, id: ModuleInstId);
+    fn set_seed(&mut self, seed: u64);
+    /// Derive the seed of a forked sequence from the parent one; called in the child.
+    fn fork_seed(&mut self, fork_idx: usize);
+    /// Called in the parent after a fork.
+    fn count_fork(&mut self);
+    /// Where to publish the fuel consumed by the call in progress; called in every worker process.
+    fn set_fuel_gauge(&mut self, gauge: &'static AtomicU64);
+    fn run_main(&mut self) -> Result<()>;
+    fn group_channel(&self) -> &GroupHandle;
+    fn tokenize(&mut self, s: &str) -> Result<Vec<u32>>;
+    fn setup(
+        &mut self,
+        module_arg: String,
+        storage_ns: StorageNamespace,
+        prompt: Vec<TokenId>,
+    ) -> SequenceResult<InitPromptResult>;
+    fn mid_process(&mut self, op: RtMidProcessArg) -> SequenceResult<ProcessResultOffset>;
+}
+
 pub struct ModuleInstance {
     store: wasmtime::Store<ModuleData>,
     memory: wasmtime::Memory,
     instance: wasmtime::Instance,
     handle: WasmAici,
-    #[allow(dead_code)]
     limits: AiciLimits,
+    init_error: Option<anyhow::Error>,
 }
 type WasmPtr = u32;
 type WasmAici = u32;
@@ -128,7 +258,13 @@ impl ModuleInstance {
             Ok(r) => Ok(r),
             Err(e) => {
                 ctx.had_error = true;
-                if let Some(e) = e.downcast_ref::<UserError>() {
+                if let Some(wasmtime::Trap::OutOfFuel) = e.downcast_ref::<wasmtime::Trap>() {
+                    Err(user_error!(
+                        "{}\nfuel budget exhausted ({} units)",
+                        ctx.string_log(),
+                        ctx.fuel_budget
+                    ))
+                } else if let Some(e) = e.downcast_ref::<UserError>() {
                     Err(user_error!("{}\n{}", ctx.string_log(), e))
                 } else if let Some(bt) = e.downcast_ref::<wasmtime::WasmBacktrace>() {
                     Err(user_error!(
@@ -185,26 +321,29 @@ impl ModuleInstance {
         id: ModuleInstId,
         ctx: WasmContext,
         module: wasmtime::Module,
-        module_arg: String,
         group_channel: GroupHandle,
-        shm: Rc<ShmAllocator>,
+        shm: Arc<ShmAllocator>,
     ) -> Result<Self> {
         let engine = module.engine();
 
         let mut store = wasmtime::Store::new(
             engine,
-            ModuleData::new(
-                id,
-                &ctx.limits,
-                &module,
-                module_arg,
-                &ctx.linker,
-                ctx.globals,
-                group_channel,
-                shm,
-            ),
+            ModuleData::new(id, &ctx.limits, ctx.globals, group_channel, shm),
         );
         store.limiter(|state| &mut state.store_limits);
+        if ctx.limits.fuel_enabled() {
+            // instantiation may run the start function
+            store.set_fuel(UNLIMITED_FUEL)?;
+            store.data_mut().fuel_budget = UNLIMITED_FUEL;
+            store.set_epoch_deadline(1);
+            store.epoch_deadline_callback(|ctx| {
+                if let Some(gauge) = ctx.data().fuel_gauge {
+                    let used = ctx.data().fuel_budget - ctx.get_fuel()?;
+                    gauge.store(used, Ordering::Relaxed);
+                }
+                Ok(wasmtime::UpdateDeadline::Continue(1))
+            });
+        }
 
         let instance = ctx.linker.instantiate(&mut store, &module)?;
         let memory = instance
@@ -219,11 +358,41 @@ impl ModuleInstance {
             memory,
             instance,
             limits: ctx.limits,
+            init_error: None,
         })
     }
 
-    pub fn set_id(&mut self, id: ModuleInstId) {
-        self.store.data_mut().id = id;
+    /// Run `aici_init()` and `aici_warmup()` (if exported); these don't depend on
+    /// the module argument or prompt, so they can be done ahead of time (in the warm pool).
+    /// Any error is reported from `setup()`.
+    pub fn init(&mut self) {
+        let r = self
+            .reset_fuel(self.limits.max_init_fuel)
+            .and_then(|_| self.run_init())
+            .and_then(|_| self.run_warmup());
+        self.init_error = r.err();
+    }
+
+    /// Reset fuel before a top-level call; `budget == 0` means unlimited.
+    fn reset_fuel(&mut self, budget: u64) -> Result<()> {
+        if self.limits.fuel_enabled() {
+            let budget = if budget == 0 { UNLIMITED_FUEL } else { budget };
+            self.store.set_fuel(budget)?;
+            let ctx = self.store.data_mut();
+            ctx.fuel_budget = budget;
+            if let Some(gauge) = ctx.fuel_gauge {
+                gauge.store(0, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    fn consumed_fuel(&self) -> u64 {
+        if self.limits.fuel_enabled() {
+            self.store.data().fuel_budget - self.store.get_fuel().unwrap_or(0)
+        } else {
+            0
+        }
     }
 
     fn run_init(&mut self) -> Result<()> {
@@ -231,7 +400,84 @@ impl ModuleInstance {
         Ok(())
     }
 
-    pub fn run_main(&mut self) -> Result<()> {
+    fn run_warmup(&mut self) -> Result<()> {
+        if self
+            .instance
+            .get_export(&mut self.store, "aici_warmup")
+            .is_some()
+        {
+            self.call_func::<(), ()>("aici_warmup", ())?;
+        }
+        Ok(())
+    }
+
+    fn proc_result<T: for<'a> Deserialize<'a>>(&self) -> Result<T> {
+        proc_result(self.store.data())
+    }
+
+    fn do_mid_process(&mut self, op: RtMidProcessArg) -> Result<ProcessResultOffset> {
+        self.reset_fuel(self.limits.max_step_fuel)?;
+        self.store.data_mut().set_mid_process_data(op);
+        self.call_func::<WasmAici, ()>("aici_mid_process", self.handle)?;
+        let res: ProcessResultOffset = self.proc_result()?;
+        Ok(check_logit_offsets(self.store.data(), res))
+    }
+
+    fn seq_result<T>(&mut self, lbl: &str, t0: Instant, res: Result<T>) -> SequenceResult<T> {
+        let fuel = self.consumed_fuel();
+        seq_result(self.store.data_mut(), fuel, lbl, t0, res)
+    }
+
+    fn setup_inner(
+        &mut self,
+        module_arg: String,
+        storage_ns: StorageNamespace,
+        prompt: Vec<TokenId>,
+    ) -> Result<InitPromptResult> {
+        if let Some(e) = self.init_error.take() {
+            return Err(e);
+        }
+        self.reset_fuel(self.limits.max_init_fuel)?;
+        self.store.data_mut().set_module_arg(module_arg);
+        self.store.data_mut().storage_ns = Some(storage_ns);
+
+        self.handle = self.call_func::<(), WasmAici>("aici_create", ())?;
+
+        self.store
+            .data_mut()
+            .set_process_arg(serde_json::to_vec(&InitPromptArg { prompt })?);
+        self.call_func::<WasmAici, ()>("aici_init_prompt", self.handle)?;
+        let res: InitPromptResult = self.proc_result()?;
+        Ok(res)
+    }
+}
+
+impl ControllerInstance for ModuleInstance {
+    fn set_id(&mut self, id: ModuleInstId) {
+        self.store.data_mut().id = id;
+    }
+
+    fn set_seed(&mut self, seed: u64) {
+        self.store.data_mut().seed = seed;
+    }
+
+    fn fork_seed(&mut self, fork_idx: usize) {
+        self.store.data_mut().fork_seed(fork_idx);
+    }
+
+    fn count_fork(&mut self) {
+        self.store.data_mut().num_forks += 1;
+    }
+
+    fn set_fuel_gauge(&mut self, gauge: &'static AtomicU64) {
+        if self.limits.fuel_enabled() {
+            start_epoch_ticker(self.store.engine());
+            self.store.data_mut().fuel_gauge = Some(gauge);
+        }
+    }
+
+    fn run_main(&mut self) -> Result<()> {
+        self.reset_fuel(0)?;
         self.run_init()?;
         let t0 = Instant::now();
         if self
@@ -248,100 +494,228 @@ impl ModuleInstance {
         Ok(())
     }
 
-    pub fn group_channel(&self) -> &GroupHandle {
+    fn group_channel(&self) -> &GroupHandle {
         &self.store.data().group_channel
     }
 
-    fn proc_result<T: for<'a> Deserialize<'a>>(&self) -> Result<T> {
-        let bytes = &self.store.data().process_result;
-        if bytes.len() == 0 {
-            Err(anyhow!("aici_host_return_process_result not called"))
-        } else {
-            serde_json::from_slice::<T>(bytes).map_err(|e| e.into())
+    fn mid_process(&mut self, op: RtMidProcessArg) -> SequenceResult<ProcessResultOffset> {
+        let t0 = Instant::now();
+        let res = self.do_mid_process(op);
+        // log::info!("mid_process: {:?}", t0.elapsed());
+        self.seq_result("mid", t0, res)
+    }
+
+    fn tokenize(&mut self, s: &str) -> Result<Vec<u32>> {
+        self.store.data_mut().tokenize_bytes(s.as_bytes())
+    }
+
+    fn setup(
+        &mut self,
+        module_arg: String,
+        storage_ns: StorageNamespace,
+        prompt: Vec<TokenId>,
+    ) -> SequenceResult<InitPromptResult> {
+        let t0 = Instant::now();
+        match self.setup_inner(module_arg, storage_ns, prompt) {
+            Err(err) => self.seq_result("setup", t0, Err(err)),
+            Ok(res) => self.seq_result("setup", t0, Ok(res)),
         }
     }
+}
 
-    fn do_mid_process(&mut self, op: RtMidProcessArg) -> Result<ProcessResultOffset> {
-        self.store.data_mut().set_mid_process_data(op);
-        self.call_func::<WasmAici, ()>("aici_mid_process", self.handle)?;
-        let res: ProcessResultOffset = self.proc_result()?;
-        let offs = &self.store.data().logit_offsets;
-        let res = ProcessResultOffset {
-            branches: res
-                .branches
-                .iter()
-                .map(|b| {
-                    b.map_mask(|o| {
-                        let o32 = *o as u32;
-                        if !offs.contains(&o32) {
-                            panic!("logit offset not found: {}", o);
-                        }
-                        *o
-                    })
+pub(crate) fn proc_result<T: for<'a> Deserialize<'a>>(data: &ModuleData) -> Result<T> {
+    let bytes = &data.process_result;
+    if bytes.len() == 0 {
+        Err(anyhow!("aici_host_return_process_result not called"))
+    } else {
+        serde_json::from_slice::<T>(bytes).map_err(|e| e.into())
+    }
+}
+
+pub(crate) fn check_logit_offsets(
+    data: &ModuleData,
+    res: ProcessResultOffset,
+) -> ProcessResultOffset {
+    let offs = &data.logit_offsets;
+    ProcessResultOffset {
+        branches: res
+            .branches
+            .iter()
+            .map(|b| {
+                b.map_mask(|o| {
+                    let o32 = *o as u32;
+                    if !offs.contains(&o32) {
+                        panic!("logit offset not found: {}", o);
+                    }
+                    *o
                 })
-                .collect(),
-        };
-        Ok(res)
+            })
+            .collect(),
+        ..res
     }
+}
 
-    fn seq_result<T>(&mut self, lbl: &str, t0: Instant, res: Result<T>) -> SequenceResult<T> {
-        // 10us accuracy for Spectre mitigation
-        let micros = (t0.elapsed().as_micros() as u64 / 10) * 10;
-        let logs = self.store.data_mut().string_log();
-        let storage = std::mem::take(&mut self.store.data_mut().storage_log);
-        match res {
-            Ok(r) => SequenceResult {
-                error: String::new(),
+/// Collect logs, storage commands, and events of the last call.
+pub(crate) fn seq_result<T>(
+    data: &mut ModuleData,
+    fuel: u64,
+    lbl: &str,
+    t0: Instant,
+    res: Result<T>,
+) -> SequenceResult<T> {
+    // 10us accuracy for Spectre mitigation
+    let micros = (t0.elapsed().as_micros() as u64 / 10) * 10;
+    let logs = data.string_log();
+    let storage = std::mem::take(&mut data.storage_log);
+    let events = data.take_events();
+    match res {
+        Ok(r) => SequenceResult {
+            error: String::new(),
+            logs,
+            storage,
+            micros,
+            fuel,
+            events,
+            result: Some(r),
+        },
+
+        Err(e) => {
+            let error = format!("Error ({lbl}): {}", UserError::maybe_stacktrace(&e));
+            let logs = logs + "\n" + &error;
+            log::warn!("exec: {error}");
+            SequenceResult {
+                error,
                 logs,
                 storage,
                 micros,
-                result: Some(r),
-            },
-
-            Err(e) => {
-                let error = format!("Error ({lbl}): {}", UserError::maybe_stacktrace(&e));
-                let logs = logs + "\n" + &error;
-                log::warn!("exec: {error}");
-                SequenceResult {
-                    error,
-                    logs,
-                    storage,
-                    micros,
-                    result: None,
-                }
+                fuel,
+                events,
+                result: None,
             }
         }
     }
+}
 
-    pub fn mid_process(&mut self, op: RtMidProcessArg) -> SequenceResult<ProcessResultOffset> {
-        let t0 = Instant::now();
-        let res = self.do_mid_process(op);
-        // log::info!("mid_process: {:?}", t0.elapsed());
-        self.seq_result("mid", t0, res)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hostimpl::tests::{
+        test_context, test_context_with, test_limits, test_logit_shm, test_tokenizer,
+    };
+
+    // (module
+    //   (import "env" "aici_host_return_process_result" (func $ret (param i32 i32)))
+    //   (memory (export "memory") 1)
+    //   (data (i32.const 16) "{\"prompt\":[]}")
+    //   (func (export "aici_init"))
+    //   (func (export "aici_warmup") LOOP?)
+    //   (func (export "aici_create") (result i32) (i32.const 0))
+    //   (func (export "aici_init_prompt") (param i32) (call $ret (i32.const 16) (i32.const 13)))
+    //   (func (export "aici_mid_process") (param i32) LOOP?))
+    // with LOOP = (loop $l (call 1) (br $l)) either in aici_warmup or in aici_mid_process;
+    // the call makes the store fuel current, as seen by the epoch callback
+    fn test_module(warmup_loops: bool) -> Vec<u8> {
+        const EMPTY: &[u8] = b"\x02\x00\x0b";
+        const LOOP: &[u8] = b"\x09\x00\x03\x40\x10\x01\x0c\x00\x0b\x0b";
+        let (warmup, mid) = if warmup_loops {
+            (LOOP, EMPTY)
+        } else {
+            (EMPTY, LOOP)
+        };
+        let parts: [&[u8]; 12] = [
+            b"\x00asm\x01\x00\x00\x00",
+            // types: (i32 i32) -> (), () -> (), () -> i32, (i32) -> ()
+            b"\x01\x11\x04\x60\x02\x7f\x7f\x00\x60\x00\x00\x60\x00\x01\x7f\x60\x01\x7f\x00",
+            b"\x02\x27\x01\x03env\x1faici_host_return_process_result\x00\x00",
+            b"\x03\x06\x05\x01\x01\x02\x03\x03",
+            b"\x05\x03\x01\x00\x01",
+            b"\x07\x58\x06\x06memory\x02\x00\x09aici_init\x00\x01\x0baici_warmup\x00\x02",
+            b"\x0baici_create\x00\x03\x10aici_init_prompt\x00\x04\x10aici_mid_process\x00\x05",
+            b"\x0a\x1f\x05\x02\x00\x0b",
+            warmup,
+            b"\x04\x00\x41\x00\x0b\x08\x00\x41\x10\x41\x0d\x10\x00\x0b",
+            mid,
+            b"\x0b\x13\x01\x00\x41\x10\x0b\x0d{\"prompt\":[]}",
+        ];
+        parts.concat()
     }
 
-    pub fn tokenize(&mut self, s: &str) -> Result<Vec<u32>> {
-        self.store.data_mut().tokenize_bytes(s.as_bytes())
+    fn instance(ctx: &WasmContext, warmup_loops: bool) -> ModuleInstance {
+        let module = wasmtime::Module::new(&ctx.engine, test_module(warmup_loops)).unwrap();
+        let group = GroupHandle::disconnected(&ctx.limits).unwrap();
+        ModuleInstance::new(1, ctx.clone(), module, group, test_logit_shm(ctx)).unwrap()
     }
 
-    fn setup_inner(&mut self, prompt: Vec<TokenId>) -> Result<InitPromptResult> {
-        self.run_init()?;
+    fn setup(inst: &mut ModuleInstance) -> SequenceResult<InitPromptResult> {
+        let ns = StorageNamespace {
+            user: "test".to_string(),
+            module_id: "test".to_string(),
+        };
+        inst.setup("{}".to_string(), ns, vec![1, 2])
+    }
 
-        self.handle = self.call_func::<(), WasmAici>("aici_create", ())?;
+    #[test]
+    fn fuel_exhausted_in_warmup() {
+        let mut limits = test_limits();
+        limits.max_init_fuel = 10_000;
+        let ctx = test_context_with(limits);
+        let mut inst = instance(&ctx, true);
+        // the error is kept until setup(), like for workers in the warm pool
+        inst.init();
+        let res = setup(&mut inst);
+        assert!(res.result.is_none());
+        assert!(
+            res.error.contains("fuel budget exhausted (10000 units)"),
+            "{}",
+            res.error
+        );
+    }
 
-        self.store
-            .data_mut()
-            .set_process_arg(serde_json::to_vec(&InitPromptArg { prompt })?);
-        self.call_func::<WasmAici, ()>("aici_init_prompt", self.handle)?;
-        let res: InitPromptResult = self.proc_result()?;
-        Ok(res)
+    #[test]
+    fn fuel_exhausted_in_step() {
+        let mut limits = test_limits();
+        limits.max_step_fuel = 1 << 26;
+        let ctx = test_context_with(limits);
+        let mut inst = instance(&ctx, false);
+        let gauge: &'static AtomicU64 = Box::leak(Box::new(AtomicU64::new(0)));
+        inst.set_fuel_gauge(gauge);
+        inst.init();
+        let res = setup(&mut inst);
+        assert_eq!(res.error, "");
+        assert!(res.fuel > 0);
+
+        let res = inst.mid_process(RtMidProcessArg {
+            op: aici_abi::MidProcessArg {
+                backtrack: 0,
+                tokens: vec![],
+                sampled: None,
+                fork_group: vec![],
+                logprobs: None,
+            },
+        });
+        assert!(res.result.is_none());
+        assert!(
+            res.error.contains("fuel budget exhausted (67108864 units)"),
+            "{}",
+            res.error
+        );
+        assert_eq!(res.fuel, 1 << 26);
+        // updated while the step was running
+        let seen = gauge.load(Ordering::Relaxed);
+        assert!(seen > 0 && seen <= 1 << 26, "{seen}");
     }
 
-    pub fn setup(&mut self, prompt: Vec<TokenId>) -> SequenceResult<InitPromptResult> {
-        let t0 = Instant::now();
-        match self.setup_inner(prompt) {
-            Err(err) => self.seq_result("setup", t0, Err(err)),
-            Ok(res) => self.seq_result("setup", t0, Ok(res)),
-        }
+    #[test]
+    fn matching_tokenizers() {
+        let ctx = test_context();
+        let globals = [("same", b"ab"), ("other", b"ba")]
+            .into_iter()
+            .map(|(name, last)| {
+                let globals = ctx.with_tokenizer(test_tokenizer(last)).globals;
+                (name.to_string(), globals)
+            })
+            .collect();
+        let cache = TokenizerCache { globals };
+        assert_eq!(cache.matching(&ctx), vec!["same".to_string()]);
     }
 }
@@ -0,0 +1,569 @@
+//! Trusted controllers running natively, as dynamic libraries, instead of WASM modules.
+//!
+//! The library is loaded in the sequence worker process, after it has been forked for the request,
+//! so requests are isolated from each other the same way as with WASM.
+//! There is however no sandboxing: the controller can do anything the aicirt process can,
+//! and neither memory nor fuel limits apply (only timeouts).
+//! Thus, native controllers can only be registered by the administrator (`--native-controller`).
+
+use crate::{
+    api::ModuleInstId,
+    hostimpl::{BlobId, ModuleData},
+    moduleinstance::{
+        check_logit_offsets, proc_result, seq_result, ControllerInstance, WasmContext,
+    },
+    worker::{GroupHandle, RtMidProcessArg},
+};
+use aici_abi::{
+    native::{NativeHostFns, NATIVE_ABI_VERSION},
+    InitPromptArg, InitPromptResult, ProcessResultOffset, TokenId,
+};
+use aicirt::{
+    api::SequenceResult, bail_user, shm::ShmAllocator, storage::StorageNamespace, user_error,
+};
+use anyhow::{anyhow, bail, ensure, Result};
+use std::{
+    ffi::{c_void, CStr, CString},
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    os::{fd::AsRawFd, unix::ffi::OsStrExt},
+    panic::AssertUnwindSafe,
+    path::Path,
+    sync::{atomic::AtomicU64, Arc},
+    time::Instant,
+};
+
+type NativeAici = *mut c_void;
+type CreateFn = extern "C" fn() -> NativeAici;
+type WarmupFn = extern "C" fn();
+type HandleFn = extern "C" fn(NativeAici);
+type NativeInitFn = extern "C" fn(u32, *const NativeHostFns) -> u32;
+
+// data of the instance currently being called into; there is only one instance per worker process
+static mut CURRENT: *mut ModuleData = std::ptr::null_mut();
+
+// host functions are called through `extern "C"`, so they must not unwind into the controller
+fn with_current<R: Default>(f: impl FnOnce(&mut ModuleData) -> R) -> R {
+    let data = match unsafe { CURRENT.as_mut() } {
+        Some(data) => data,
+        None => {
+            eprintln!("host function called outside of controller");
+            std::process::abort();
+        }
+    };
+    match std::panic::catch_unwind(AssertUnwindSafe(|| f(&mut *data))) {
+        Ok(r) => r,
+        Err(_) => {
+            data.fatal("panic in host function");
+            R::default()
+        }
+    }
+}
+
+unsafe fn bytes<'a>(ptr: *const u8, len: u32) -> &'a [u8] {
+    if len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(ptr, len as usize)
+    }
+}
+
+extern "C" fn host_read_blob(blob: u32, dst: *mut u8, size: u32) -> u32 {
+    with_current(|data| match data.blob(blob) {
+        Some(bytes) => {
+            let n = std::cmp::min(size as usize, bytes.len());
+            unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), dst, n) };
+            bytes.len() as u32
+        }
+        None => {
+            data.fatal("invalid blob_id");
+            0
+        }
+    })
+}
+
+extern "C" fn host_token_trie() -> u32 {
+    BlobId::TRIE.0
+}
+
+extern "C" fn host_module_arg() -> u32 {
+    BlobId::MODULE_ARG.0
+}
+
+extern "C" fn host_process_arg() -> u32 {
+    BlobId::PROCESS_ARG.0
+}
+
+extern "C" fn host_tokenize(src: *const u8, src_size: u32) -> u32 {
+    let s = unsafe { bytes(src, src_size) };
+    with_current(|data| data.aici_host_tokenize(s).0)
+}
+
+extern "C" fn host_return_logit_bias(src: *const u32) -> u32 {
+    with_current(|data| {
+        let mask = unsafe { bytes(src as *const u8, data.logit_bias_bytes() as u32) };
+        data.aici_host_return_logit_bias(mask)
+    })
+}
+
+extern "C" fn host_self_seq_id() -> u32 {
+    with_current(|data| data.id as u32)
+}
+
+extern "C" fn host_return_process_result(res: *const u8, res_size: u32) {
+    let res = unsafe { bytes(res, res_size) }.to_vec();
+    with_current(|data| data.process_result = res)
+}
+
+extern "C" fn host_storage_cmd(cmd: *const u8, cmd_size: u32) -> u32 {
+    let cmd = unsafe { bytes(cmd, cmd_size) }.to_vec();
+    with_current(|data| data.aici_host_storage_cmd(cmd).0)
+}
+
+extern "C" fn host_eos_token() -> TokenId {
+    with_current(|data| data.globals.tokrx_info.tok_eos)
+}
+
+extern "C" fn host_get_config(src: *const u8, src_size: u32) -> i32 {
+    let name = String::from_utf8_lossy(unsafe { bytes(src, src_size) });
+    with_current(|data| data.get_config(&name))
+}
+
+extern "C" fn host_now_micros() -> u64 {
+    with_current(|data| data.now_micros())
+}
+
+extern "C" fn host_random_seed() -> u64 {
+    with_current(|data| data.seed)
+}
+
+extern "C" fn host_emit_event(kind: *const u8, kind_size: u32, src: *const u8, src_size: u32) {
+    with_current(|data| {
+        if kind_size > 256 {
+            data.warn("event kind too long");
+            return;
+        }
+        let kind = String::from_utf8_lossy(unsafe { bytes(kind, kind_size) });
+        data.emit_event(&kind, unsafe { bytes(src, src_size) })
+    })
+}
+
+extern "C" fn host_blob_cache_get(key: *const u8, key_size: u32) -> u32 {
+    let key = unsafe { bytes(key, key_size) };
+    with_current(|data| data.aici_host_blob_cache_get(key).0)
+}
+
+extern "C" fn host_blob_cache_put(key: *const u8, key_size: u32, src: *const u8, src_size: u32) {
+    let (key, src) = unsafe { (bytes(key, key_size), bytes(src, src_size)) };
+    with_current(|data| data.aici_host_blob_cache_put(key, src))
+}
+
+extern "C" fn host_stop() {
+    with_current(|data| {
+        if !data.had_error {
+            data.write_log(b"*** aici_host_stop()\n");
+            data.had_error = true;
+        }
+    })
+}
+
+static HOST_FNS: NativeHostFns = NativeHostFns {
+    read_blob: host_read_blob,
+    token_trie: host_token_trie,
+    module_arg: host_module_arg,
+    process_arg: host_process_arg,
+    tokenize: host_tokenize,
+    return_logit_bias: host_return_logit_bias,
+    self_seq_id: host_self_seq_id,
+    return_process_result: host_return_process_result,
+    storage_cmd: host_storage_cmd,
+    eos_token: host_eos_token,
+    get_config: host_get_config,
+    now_micros: host_now_micros,
+    random_seed: host_random_seed,
+    emit_event: host_emit_event,
+    blob_cache_get: host_blob_cache_get,
+    blob_cache_put: host_blob_cache_put,
+    stop: host_stop,
+};
+
+/// Native controllers print directly to stdout; in the worker it's redirected
+/// to an unlinked temporary file, which is moved to the logs after every call.
+struct StdoutCapture {
+    file: File,
+    pid: u32,
+}
+
+impl StdoutCapture {
+    fn new() -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("aici-stdout-{}", uuid::Uuid::new_v4()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create_new(true)
+            .open(&path)?;
+        std::fs::remove_file(&path)?;
+        ensure!(
+            unsafe { libc::dup2(file.as_raw_fd(), libc::STDOUT_FILENO) } >= 0,
+            "dup2() failed"
+        );
+        Ok(StdoutCapture {
+            file,
+            pid: std::process::id(),
+        })
+    }
+
+    /// After a fork, the file is shared with the parent; get a fresh one.
+    fn check_fork(&mut self) -> Result<()> {
+        if self.pid != std::process::id() {
+            *self = Self::new()?;
+        }
+        Ok(())
+    }
+
+    fn take(&mut self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut buf)?;
+        self.file.set_len(0)?;
+        Ok(buf)
+    }
+}
+
+fn dlerror() -> String {
+    let err = unsafe { libc::dlerror() };
+    if err.is_null() {
+        "unknown error".to_string()
+    } else {
+        unsafe { CStr::from_ptr(err) }.to_string_lossy().to_string()
+    }
+}
+
+pub struct NativeInstance {
+    data: Box<ModuleData>,
+    create: CreateFn,
+    init_prompt: HandleFn,
+    mid_process: HandleFn,
+    main: Option<HandleFn>,
+    warmup: Option<WarmupFn>,
+    handle: NativeAici,
+    stdout: StdoutCapture,
+}
+
+impl NativeInstance {
+    pub fn new(
+        id: ModuleInstId,
+        ctx: WasmContext,
+        lib_path: &Path,
+        group_channel: GroupHandle,
+        shm: Arc<ShmAllocator>,
+    ) -> Result<Self> {
+        let data = Box::new(ModuleData::new(
+            id,
+            &ctx.limits,
+            ctx.globals,
+            group_channel,
+            shm,
+        ));
+        let stdout = StdoutCapture::new()?;
+
+        let path = CString::new(lib_path.as_os_str().as_bytes())?;
+        // the library is never unloaded; the worker process exits with the request
+        let lib = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if lib.is_null() {
+            bail!("dlopen({}): {}", lib_path.display(), dlerror());
+        }
+        let sym = |name: &str| {
+            let cname = CString::new(name).unwrap();
+            let f = unsafe { libc::dlsym(lib, cname.as_ptr()) };
+            if f.is_null() {
+                None
+            } else {
+                Some(f)
+            }
+        };
+        let req_sym = |name: &str| {
+            sym(name).ok_or_else(|| anyhow!("{}: {} not exported", lib_path.display(), name))
+        };
+
+        let native_init: NativeInitFn =
+            unsafe { std::mem::transmute(req_sym("aici_native_init")?) };
+        let version = native_init(NATIVE_ABI_VERSION, &HOST_FNS);
+        ensure!(
+            version == NATIVE_ABI_VERSION,
+            "{}: native ABI version {}, expecting {}; rebuild with current aici_abi",
+            lib_path.display(),
+            version,
+            NATIVE_ABI_VERSION
+        );
+
+        unsafe {
+            Ok(NativeInstance {
+                data,
+                create: std::mem::transmute(req_sym("aici_create")?),
+                init_prompt: std::mem::transmute(req_sym("aici_init_prompt")?),
+                mid_process: std::mem::transmute(req_sym("aici_mid_process")?),
+                main: sym("aici_main").map(|f| std::mem::transmute::<_, HandleFn>(f)),
+                warmup: sym("aici_warmup").map(|f| std::mem::transmute::<_, WarmupFn>(f)),
+                handle: std::ptr::null_mut(),
+                stdout,
+            })
+        }
+    }
+
+    fn call<R>(&mut self, name: &str, f: impl FnOnce() -> R) -> Result<R> {
+        if self.data.had_error {
+            // same message as for WASM, clients look for it
+            bail_user!("Previous WASM Error");
+        }
+        self.stdout.check_fork()?;
+        unsafe { CURRENT = &mut *self.data };
+        let r = f();
+        unsafe { CURRENT = std::ptr::null_mut() };
+        let out = self.stdout.take()?;
+        self.data.write_log(&out);
+        self.data.flush_logs(name);
+        if self.data.had_error {
+            Err(user_error!("{}", self.data.string_log()))
+        } else {
+            Ok(r)
+        }
+    }
+
+    /// Run `aici_warmup()`, if exported; see [crate::moduleinstance::ModuleInstance::init()].
+    pub fn init(&mut self) -> Result<()> {
+        if let Some(warmup) = self.warmup {
+            self.call("aici_warmup", || warmup())?;
+        }
+        Ok(())
+    }
+
+    fn call_handle(&mut self, name: &str, f: HandleFn) -> Result<()> {
+        let handle = self.handle;
+        self.call(name, || f(handle))
+    }
+
+    fn do_mid_process(&mut self, op: RtMidProcessArg) -> Result<ProcessResultOffset> {
+        self.data.set_mid_process_data(op);
+        self.call_handle("aici_mid_process", self.mid_process)?;
+        let res: ProcessResultOffset = proc_result(&self.data)?;
+        Ok(check_logit_offsets(&self.data, res))
+    }
+
+    fn setup_inner(
+        &mut self,
+        module_arg: String,
+        storage_ns: StorageNamespace,
+        prompt: Vec<TokenId>,
+    ) -> Result<InitPromptResult> {
+        self.data.set_module_arg(module_arg);
+        self.data.storage_ns = Some(storage_ns);
+
+        let create = self.create;
+        self.handle = self.call("aici_create", || create())?;
+        ensure!(!self.handle.is_null(), "aici_create() failed");
+
+        self.data
+            .set_process_arg(serde_json::to_vec(&InitPromptArg { prompt })?);
+        self.call_handle("aici_init_prompt", self.init_prompt)?;
+        proc_result(&self.data)
+    }
+}
+
+impl ControllerInstance for NativeInstance {
+    fn set_id(&mut self, id: ModuleInstId) {
+        self.data.id = id;
+    }
+
+    fn set_seed(&mut self, seed: u64) {
+        self.data.seed = seed;
+    }
+
+    fn fork_seed(&mut self, fork_idx: usize) {
+        self.data.fork_seed(fork_idx);
+    }
+
+    fn count_fork(&mut self) {
+        self.data.num_forks += 1;
+    }
+
+    fn set_fuel_gauge(&mut self, _gauge: &'static AtomicU64) {
+        // no fuel metering for native code
+    }
+
+    fn run_main(&mut self) -> Result<()> {
+        let main = self
+            .main
+            .ok_or_else(|| user_error!("aici_main() not exported"))?;
+        let t0 = Instant::now();
+        self.call_handle("aici_main", main)?;
+        log::debug!("aici_main time: {:?}", t0.elapsed());
+        Ok(())
+    }
+
+    fn group_channel(&self) -> &GroupHandle {
+        &self.data.group_channel
+    }
+
+    fn tokenize(&mut self, s: &str) -> Result<Vec<u32>> {
+        self.data.tokenize_bytes(s.as_bytes())
+    }
+
+    fn setup(
+        &mut self,
+        module_arg: String,
+        storage_ns: StorageNamespace,
+        prompt: Vec<TokenId>,
+    ) -> SequenceResult<InitPromptResult> {
+        let t0 = Instant::now();
+        let res = self.setup_inner(module_arg, storage_ns, prompt);
+        seq_result(&mut self.data, 0, "setup", t0, res)
+    }
+
+    fn mid_process(&mut self, op: RtMidProcessArg) -> SequenceResult<ProcessResultOffset> {
+        let t0 = Instant::now();
+        let res = self.do_mid_process(op);
+        seq_result(&mut self.data, 0, "mid", t0, res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hostimpl::tests::{test_context, test_logit_shm};
+    use aici_abi::MidProcessArg;
+    use std::{path::PathBuf, process::Command};
+
+    // forces token 256 ("ab") in the first step, and stops in the second one;
+    // the host functions are indexed in the order of NativeHostFns
+    const CONTROLLER_C: &str = r#"
+#include <stdint.h>
+#include <stdio.h>
+#include <string.h>
+
+typedef void (*fn_t)(void);
+static fn_t *host;
+static int steps;
+
+uint32_t aici_native_init(uint32_t version, fn_t *fns) {
+    host = fns;
+    return version;
+}
+
+void *aici_create(void) { return &steps; }
+
+static void result(const char *s, uint32_t len) {
+    ((void (*)(const char *, uint32_t))host[7])(s, len);
+}
+
+void aici_init_prompt(void *self) {
+    static char buf[4096];
+    uint32_t id = ((uint32_t (*)(void))host[3])();
+    uint32_t n = ((uint32_t (*)(uint32_t, char *, uint32_t))host[0])(id, buf, sizeof(buf));
+    result(buf, n);
+}
+
+void aici_mid_process(void *self) {
+    if (steps++ > 0) {
+        ((void (*)(void))host[16])();
+        return;
+    }
+    printf("hello from C\n");
+    fflush(stdout);
+    ((void (*)(const char *, uint32_t, const char *, uint32_t))host[13])("test", 4, "{\"n\":1}", 7);
+    const char *r = "{\"branches\":[{\"sample_mask\":null,\"temperature\":null,"
+                    "\"splices\":[{\"when_sampled\":[],\"backtrack\":0,\"ff_tokens\":[256]}]}]}";
+    result(r, strlen(r));
+}
+"#;
+
+    fn build_controller() -> Option<PathBuf> {
+        let dir = std::env::temp_dir().join(format!("aicirt-native-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("ctrl.c");
+        let lib = dir.join("libctrl.so");
+        std::fs::write(&src, CONTROLLER_C).unwrap();
+        let status = Command::new("cc")
+            .args(["-shared", "-fPIC", "-o"])
+            .arg(&lib)
+            .arg(&src)
+            .status();
+        match status {
+            Ok(s) if s.success() => Some(lib),
+            _ => None,
+        }
+    }
+
+    // like in the sequence worker, the library is loaded (and stdout redirected) in a forked child
+    fn in_child(f: impl FnOnce()) {
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0, "fork failed");
+        if pid == 0 {
+            let ok = std::panic::catch_unwind(AssertUnwindSafe(f)).is_ok();
+            unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+        }
+        let mut status = 0;
+        unsafe { libc::waitpid(pid, &mut status, 0) };
+        assert!(
+            libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0,
+            "child failed, see stderr"
+        );
+    }
+
+    fn mid_arg() -> RtMidProcessArg {
+        RtMidProcessArg {
+            op: MidProcessArg {
+                backtrack: 0,
+                tokens: vec![],
+                sampled: None,
+                fork_group: vec![],
+                logprobs: None,
+            },
+        }
+    }
+
+    #[test]
+    fn load_and_run_native_controller() {
+        let lib = match build_controller() {
+            Some(lib) => lib,
+            None => {
+                eprintln!("skipping: can't build the test controller with cc");
+                return;
+            }
+        };
+        let ctx = test_context();
+        in_child(|| {
+            let group = GroupHandle::disconnected(&ctx.limits).unwrap();
+            let shm = test_logit_shm(&ctx);
+            let mut inst = NativeInstance::new(7, ctx.clone(), &lib, group, shm).unwrap();
+            inst.init().unwrap();
+
+            let ns = StorageNamespace {
+                user: "test".to_string(),
+                module_id: "native".to_string(),
+            };
+            let res = inst.setup("{}".to_string(), ns, vec![1, 2]);
+            assert_eq!(res.error, "");
+            assert_eq!(res.result.unwrap().prompt, vec![1, 2]);
+
+            let res = inst.mid_process(mid_arg());
+            assert_eq!(res.error, "");
+            assert!(res.logs.contains("hello from C"));
+            assert_eq!(res.events.len(), 1);
+            assert_eq!(res.events[0]["data"]["n"], 1);
+            let r = res.result.unwrap();
+            assert_eq!(r.branches.len(), 1);
+            assert_eq!(
+                r.branches[0].unconditional_splice().unwrap().ff_tokens,
+                vec![256]
+            );
+
+            let res = inst.mid_process(mid_arg());
+            assert!(res.result.is_none());
+            assert!(res.error.contains("aici_host_stop()"));
+            let res = inst.mid_process(mid_arg());
+            assert!(res.error.contains("Previous WASM Error"));
+        });
+        std::fs::remove_dir_all(lib.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+use crate::moduleinstance::WasmContext;
+use aicirt::{bail_user, bintokens::find_tokenizer, shm::ShmAllocator, valid_tagname};
+use anyhow::Result;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Arc, Mutex,
+};
+
+/// Parsed `--session NAME=TOKENIZER` argument.
+#[derive(Clone, Debug)]
+pub struct SessionConfig {
+    pub name: String,
+    pub tokenizer: String,
+}
+
+impl SessionConfig {
+    pub fn parse(s: &str) -> Result<Self> {
+        let (name, tokenizer) = match s.split_once('=') {
+            Some((name, tokenizer)) => (name, tokenizer),
+            None => bail_user!("invalid session spec {s:?}; expecting NAME=TOKENIZER"),
+        };
+        if !valid_tagname(name) {
+            bail_user!("invalid session name {name:?}");
+        }
+        Ok(SessionConfig {
+            name: name.to_string(),
+            tokenizer: tokenizer.to_string(),
+        })
+    }
+}
+
+/// Map socket channel name (`main`, `side`, `main:NAME`, `side:NAME`) to
+/// whether it's the main channel, and the index of the session in `names`.
+pub fn route_channel(channel: &str, names: &[String]) -> Option<(bool, usize)> {
+    let (kind, name) = channel.split_once(':').unwrap_or((channel, ""));
+    match (kind, names.iter().position(|n| n == name)) {
+        ("main" | "side", Some(idx)) => Some((kind == "main", idx)),
+        _ => None,
+    }
+}
+
+/// Main or side channel of a session, when connected over sockets.
+/// The listener offers new connections, and the session takes them.
+/// Only one connection is active at a time; others are rejected until the session
+/// releases the channel (because the connection failed).
+pub struct ChannelSlot<T> {
+    busy: AtomicBool,
+    tx: Mutex<mpsc::Sender<T>>,
+    rx: Mutex<mpsc::Receiver<T>>,
+}
+
+impl<T> ChannelSlot<T> {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        ChannelSlot {
+            busy: AtomicBool::new(false),
+            tx: Mutex::new(tx),
+            rx: Mutex::new(rx),
+        }
+    }
+
+    /// Returns the connection back if the channel is already connected.
+    pub fn offer(&self, conn: T) -> std::result::Result<(), T> {
+        if self.busy.swap(true, Ordering::SeqCst) {
+            return Err(conn);
+        }
+        // the receiver is owned by self, so this can't fail
+        self.tx.lock().unwrap().send(conn).unwrap();
+        Ok(())
+    }
+
+    /// Wait for a connection to be offered.
+    pub fn take(&self) -> T {
+        self.rx.lock().unwrap().recv().unwrap()
+    }
+
+    /// Mark the channel as disconnected, so that a new connection can be offered.
+    pub fn release(&self) {
+        self.busy.store(false, Ordering::SeqCst);
+    }
+}
+
+impl<T> Default for ChannelSlot<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// State of a single inference engine attached to aicirt.
+/// Compiled modules and tags are shared between sessions, while the tokenizer,
+/// the logit bias shm, and the channels (named with `prefix`) are per-session.
+pub struct Session {
+    /// Empty for the default session.
+    pub name: String,
+    pub tokenizer: String,
+    pub prefix: String,
+    pub wasm_ctx: WasmContext,
+    pub shm: Arc<ShmAllocator>,
+    pub token_bytes: Vec<Vec<u8>>,
+}
+
+impl Session {
+    pub fn new(
+        name: &str,
+        tokenizer: &str,
+        prefix: String,
+        wasm_ctx: WasmContext,
+        shm: Arc<ShmAllocator>,
+        token_bytes: Vec<Vec<u8>>,
+    ) -> Self {
+        Session {
+            name: name.to_string(),
+            tokenizer: tokenizer.to_string(),
+            prefix,
+            wasm_ctx,
+            shm,
+            token_bytes,
+        }
+    }
+
+    /// Create a session sharing the engine of the default session.
+    pub fn from_config(
+        default: &Session,
+        cfg: &SessionConfig,
+        mk_shm: impl FnOnce(&str, usize) -> Result<Arc<ShmAllocator>>,
+    ) -> Result<Self> {
+        let tokenizer = find_tokenizer(&cfg.tokenizer)?;
+        let token_bytes = tokenizer.token_bytes();
+        let wasm_ctx = default.wasm_ctx.with_tokenizer(tokenizer);
+        let prefix = format!("{}{}-", default.prefix, cfg.name);
+        let shm = mk_shm(&prefix, wasm_ctx.globals.tokrx_info.vocab_size as usize)?;
+        Ok(Self::new(
+            &cfg.name,
+            &cfg.tokenizer,
+            prefix,
+            wasm_ctx,
+            shm,
+            token_bytes,
+        ))
+    }
+
+    pub fn shm_name(&self, name: &str, suff: &str) -> String {
+        format!("{}{}{}", self.prefix, name, suff)
+    }
+
+    pub fn forker_session(&self) -> (WasmContext, Arc<ShmAllocator>) {
+        (self.wasm_ctx.clone(), self.shm.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_session_config() {
+        let cfg = SessionConfig::parse("phi2=microsoft/phi-2").unwrap();
+        assert_eq!(cfg.name, "phi2");
+        assert_eq!(cfg.tokenizer, "microsoft/phi-2");
+        assert!(SessionConfig::parse("phi2").is_err());
+        assert!(SessionConfig::parse("=phi").is_err());
+        assert!(SessionConfig::parse("a/b=phi").is_err());
+    }
+
+    #[test]
+    fn route_channels_to_sessions() {
+        let names = vec!["".to_string(), "phi2".to_string()];
+        assert_eq!(route_channel("main", &names), Some((true, 0)));
+        assert_eq!(route_channel("side", &names), Some((false, 0)));
+        assert_eq!(route_channel("main:phi2", &names), Some((true, 1)));
+        assert_eq!(route_channel("side:phi2", &names), Some((false, 1)));
+        assert_eq!(route_channel("main:llama", &names), None);
+        assert_eq!(route_channel("other", &names), None);
+        assert_eq!(route_channel("other:phi2", &names), None);
+    }
+
+    #[test]
+    fn channel_slot_takes_one_connection() {
+        let slot = ChannelSlot::new();
+        assert_eq!(slot.offer(1), Ok(()));
+        // already connected, even before the session takes it
+        assert_eq!(slot.offer(2), Err(2));
+        assert_eq!(slot.take(), 1);
+        assert_eq!(slot.offer(3), Err(3));
+        slot.release();
+        assert_eq!(slot.offer(4), Ok(()));
+        assert_eq!(slot.take(), 4);
+    }
+}
@@ -0,0 +1,731 @@
+use crate::{ensure_user, valid_module_id, variables::Variables};
+use aici_abi::{StorageCmd, StorageResp};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::Write,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Persistent variables are kept separately for every (user, module) pair.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StorageNamespace {
+    pub user: String,
+    pub module_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StorageVarInfo {
+    pub name: String,
+    pub version: u64,
+    pub size: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StorageNamespaceInfo {
+    pub user: String,
+    pub module_id: String,
+    pub variables: Vec<StorageVarInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StorageListReq {
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub module_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StorageListResp {
+    pub namespaces: Vec<StorageNamespaceInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StorageClearReq {
+    pub user: String,
+    pub module_id: String,
+    /// If not given, all variables in the namespace are removed.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// Limits on the variables kept in a single namespace; writes that would exceed
+/// them fail with a user error.
+#[derive(Clone, Debug)]
+pub struct StorageQuota {
+    pub max_vars: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for StorageQuota {
+    fn default() -> Self {
+        StorageQuota {
+            max_vars: 1000,
+            max_bytes: 16 << 20,
+        }
+    }
+}
+
+/// Variable names are stored hex-encoded in file names, so they can't be too long.
+const MAX_VAR_NAME_BYTES: usize = 100;
+
+/// Every variable file starts with the version (u64 LE), followed by the value.
+const VAR_HEADER_BYTES: usize = 8;
+
+const LOCK_FILE: &str = ".lock";
+
+/// File-backed store for `StorageScope::Persistent` variables.
+/// Each namespace is a directory with one file per variable, so that a write
+/// only touches the variable being written. The directory is `flock()`ed
+/// for every operation, so it can be safely used from all the worker processes.
+#[derive(Clone)]
+pub struct PersistentStorage {
+    root: PathBuf,
+    quota: StorageQuota,
+}
+
+// the lock is released when the file is closed
+struct NamespaceLock {
+    _file: fs::File,
+}
+
+impl NamespaceLock {
+    fn acquire(dir: &Path) -> Result<Self> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOCK_FILE))?;
+        let r = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) };
+        if r != 0 {
+            return Err(anyhow!("flock failed: {}", std::io::Error::last_os_error()));
+        }
+        Ok(NamespaceLock { _file: file })
+    }
+}
+
+fn var_file_name(name: &str) -> String {
+    format!("{}.var", hex::encode(name))
+}
+
+/// Returns the variable name for files that hold variables (and not locks or temp files).
+fn var_name_of_file(file_name: &str) -> Option<String> {
+    let hex_name = file_name.strip_suffix(".var")?;
+    hex::decode(hex_name)
+        .ok()
+        .and_then(|b| String::from_utf8(b).ok())
+}
+
+fn read_var(path: &Path) -> Result<Option<(u64, Vec<u8>)>> {
+    match fs::read(path) {
+        Ok(mut bytes) => {
+            if bytes.len() < VAR_HEADER_BYTES {
+                return Err(anyhow!("corrupt variable file {}", path.display()));
+            }
+            let version = u64::from_le_bytes(bytes[0..VAR_HEADER_BYTES].try_into().unwrap());
+            bytes.drain(0..VAR_HEADER_BYTES);
+            Ok(Some((version, bytes)))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_var(path: &Path, version: u64, value: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(&version.to_le_bytes())?;
+    file.write_all(value)?;
+    file.sync_data()?;
+    drop(file);
+    if let Err(e) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+fn list_vars(dir: &Path) -> Result<Vec<StorageVarInfo>> {
+    let mut variables = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some(name) = var_name_of_file(&entry.file_name().to_string_lossy()) {
+            if let Some((version, value)) = read_var(&entry.path())? {
+                variables.push(StorageVarInfo {
+                    name,
+                    version,
+                    size: value.len(),
+                });
+            }
+        }
+    }
+    variables.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(variables)
+}
+
+impl PersistentStorage {
+    pub fn new(root: PathBuf, quota: StorageQuota) -> Self {
+        PersistentStorage { root, quota }
+    }
+
+    fn ns_dir(&self, ns: &StorageNamespace) -> Result<PathBuf> {
+        if !valid_module_id(&ns.module_id) {
+            return Err(anyhow!("invalid module_id {:?}", ns.module_id));
+        }
+        // user names are arbitrary strings; hex keeps them reversible and file-name safe
+        Ok(self.root.join(hex::encode(&ns.user)).join(&ns.module_id))
+    }
+
+    fn check_quota(&self, dir: &Path, name: &str, size: usize) -> Result<()> {
+        let mut num_vars = 1;
+        let mut num_bytes = size;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            match var_name_of_file(&entry.file_name().to_string_lossy()) {
+                Some(other) if other != name => {
+                    num_vars += 1;
+                    num_bytes +=
+                        (entry.metadata()?.len() as usize).saturating_sub(VAR_HEADER_BYTES);
+                }
+                _ => {}
+            }
+        }
+        ensure_user!(
+            num_vars <= self.quota.max_vars,
+            "persistent storage quota exceeded: more than {} variables",
+            self.quota.max_vars
+        );
+        ensure_user!(
+            num_bytes <= self.quota.max_bytes,
+            "persistent storage quota exceeded: {} bytes (limit {})",
+            num_bytes,
+            self.quota.max_bytes
+        );
+        Ok(())
+    }
+
+    pub fn process_cmd(&self, ns: &StorageNamespace, cmd: StorageCmd) -> Result<StorageResp> {
+        let dir = self.ns_dir(ns)?;
+        let names = match &cmd {
+            StorageCmd::ReadVar { name, .. } | StorageCmd::WriteVar { name, .. } => {
+                vec![name.clone()]
+            }
+            StorageCmd::Watch { names, .. } => names.clone(),
+        };
+        for name in &names {
+            ensure_user!(
+                name.len() <= MAX_VAR_NAME_BYTES,
+                "persistent variable name too long ({} bytes, limit {})",
+                name.len(),
+                MAX_VAR_NAME_BYTES
+            );
+        }
+
+        let mut vars = Variables::default();
+
+        // don't create anything for reads of empty namespaces
+        if !matches!(cmd, StorageCmd::WriteVar { .. }) && !dir.exists() {
+            return Ok(vars.process_cmd(cmd));
+        }
+
+        fs::create_dir_all(&dir)?;
+        let _lock = NamespaceLock::acquire(&dir)?;
+        for name in &names {
+            if let Some(v) = read_var(&dir.join(var_file_name(name)))? {
+                vars.variables.insert(name.clone(), v);
+            }
+        }
+
+        let resp = vars.process_cmd(cmd);
+
+        if let StorageResp::WriteVar { .. } = resp {
+            let name = &names[0];
+            let (version, value) = &vars.variables[name];
+            self.check_quota(&dir, name, value.len())?;
+            write_var(&dir.join(var_file_name(name)), *version, value)?;
+        }
+
+        Ok(resp)
+    }
+
+    pub fn list(&self, req: &StorageListReq) -> Result<StorageListResp> {
+        let mut namespaces = vec![];
+        if !self.root.exists() {
+            return Ok(StorageListResp { namespaces });
+        }
+        for user_dir in fs::read_dir(&self.root)? {
+            let user_dir = user_dir?.path();
+            let user = match user_dir
+                .file_name()
+                .and_then(|n| hex::decode(n.to_string_lossy().as_bytes()).ok())
+                .and_then(|b| String::from_utf8(b).ok())
+            {
+                Some(user) if user_dir.is_dir() => user,
+                _ => continue,
+            };
+            if req.user.as_ref().is_some_and(|u| *u != user) {
+                continue;
+            }
+            for ns_dir in fs::read_dir(&user_dir)? {
+                let ns_dir = ns_dir?.path();
+                let module_id = ns_dir.file_name().unwrap().to_string_lossy().to_string();
+                if !ns_dir.is_dir()
+                    || !valid_module_id(&module_id)
+                    || req.module_id.as_ref().is_some_and(|m| *m != module_id)
+                {
+                    continue;
+                }
+                let variables = {
+                    let _lock = NamespaceLock::acquire(&ns_dir)?;
+                    list_vars(&ns_dir)?
+                };
+                if variables.is_empty() {
+                    continue;
+                }
+                namespaces.push(StorageNamespaceInfo {
+                    user: user.clone(),
+                    module_id,
+                    variables,
+                });
+            }
+        }
+        Ok(StorageListResp { namespaces })
+    }
+
+    /// Returns the number of removed variables.
+    pub fn clear(&self, req: &StorageClearReq) -> Result<usize> {
+        let ns = StorageNamespace {
+            user: req.user.clone(),
+            module_id: req.module_id.clone(),
+        };
+        let dir = self.ns_dir(&ns)?;
+        if !dir.exists() {
+            return Ok(0);
+        }
+        // we don't remove the directory, as someone else might be waiting on the lock
+        let _lock = NamespaceLock::acquire(&dir)?;
+        let names = match &req.name {
+            Some(name) => vec![name.clone()],
+            None => list_vars(&dir)?.into_iter().map(|v| v.name).collect(),
+        };
+        let mut num_removed = 0;
+        for name in names {
+            match fs::remove_file(dir.join(var_file_name(&name))) {
+                Ok(()) => num_removed += 1,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(num_removed)
+    }
+}
+
+/// Blobs larger than this are not cached.
+const MAX_BLOB_BYTES: usize = 64 << 20;
+
+pub fn valid_blob_key(key: &str) -> bool {
+    key.len() > 0
+        && key.len() <= 128
+        && !key.starts_with('.')
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Limits on the total size of cached blobs; when a new blob would exceed them,
+/// the least recently used blobs are removed first.
+#[derive(Clone, Debug)]
+pub struct BlobQuota {
+    pub max_module_bytes: usize,
+    pub max_total_bytes: usize,
+}
+
+impl Default for BlobQuota {
+    fn default() -> Self {
+        BlobQuota {
+            max_module_bytes: 256 << 20,
+            max_total_bytes: 1 << 30,
+        }
+    }
+}
+
+struct BlobInfo {
+    path: PathBuf,
+    module_dir: PathBuf,
+    size: usize,
+    last_used: SystemTime,
+}
+
+// mtime is used as the last use time; set it explicitly, since the file system clock can be coarse
+fn touch_blob(path: &Path) -> std::io::Result<()> {
+    fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+/// File-backed cache for `aici_host_blob_cache_get/put()`, meant for artifacts
+/// (like compiled grammars) that are expensive to compute, but are shared between requests.
+/// Blobs are kept separately for every module, since only the module understands their format.
+/// They are written to a temporary file first and then renamed, so that other worker
+/// processes never see partial blobs; the first blob written under a given key wins.
+/// Writes are serialized with a lock on the root directory, and evict least recently
+/// read or written blobs to keep within the [BlobQuota].
+#[derive(Clone)]
+pub struct BlobCache {
+    root: PathBuf,
+    quota: BlobQuota,
+}
+
+impl BlobCache {
+    pub fn new(root: PathBuf, quota: BlobQuota) -> Self {
+        BlobCache { root, quota }
+    }
+
+    fn module_dir(&self, module_id: &str) -> PathBuf {
+        // module_id can be also native:name
+        self.root.join(hex::encode(module_id))
+    }
+
+    fn blob_path(&self, module_id: &str, key: &str) -> Result<PathBuf> {
+        if !valid_blob_key(key) {
+            return Err(anyhow!("invalid blob key {:?}", key));
+        }
+        Ok(self.module_dir(module_id).join(key))
+    }
+
+    pub fn get(&self, module_id: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.blob_path(module_id, key)?;
+        match fs::read(&path) {
+            Ok(bytes) => {
+                // the blob might have been evicted in the meantime, which is fine
+                let _ = touch_blob(&path);
+                Ok(Some(bytes))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Returns false if the blob was not stored, because it's too large or already there.
+    pub fn put(&self, module_id: &str, key: &str, data: &[u8]) -> Result<bool> {
+        let path = self.blob_path(module_id, key)?;
+        if data.len() > MAX_BLOB_BYTES
+            || data.len() > self.quota.max_module_bytes
+            || data.len() > self.quota.max_total_bytes
+            || path.exists()
+        {
+            return Ok(false);
+        }
+        let module_dir = self.module_dir(module_id);
+        fs::create_dir_all(&module_dir)?;
+        let _lock = NamespaceLock::acquire(&self.root)?;
+        if path.exists() {
+            return Ok(false);
+        }
+        self.evict(&module_dir, data.len())?;
+        let tmp = path.with_file_name(format!(".{}.{}.tmp", key, std::process::id()));
+        let write_blob = || -> Result<()> {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(data)?;
+            file.set_modified(SystemTime::now())?;
+            drop(file);
+            fs::rename(&tmp, &path)?;
+            Ok(())
+        };
+        if let Err(e) = write_blob() {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    fn list_blobs(&self) -> Result<Vec<BlobInfo>> {
+        let mut blobs = vec![];
+        for module_dir in fs::read_dir(&self.root)? {
+            let module_dir = module_dir?.path();
+            if !module_dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&module_dir)? {
+                let entry = entry?;
+                // skip temporary files
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                let meta = match entry.metadata() {
+                    Ok(meta) => meta,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                blobs.push(BlobInfo {
+                    path: entry.path(),
+                    module_dir: module_dir.clone(),
+                    size: meta.len() as usize,
+                    last_used: meta.modified()?,
+                });
+            }
+        }
+        Ok(blobs)
+    }
+
+    // remove least recently used blobs, so that `incoming` more bytes fit in the quota
+    fn evict(&self, module_dir: &Path, incoming: usize) -> Result<()> {
+        let mut blobs = self.list_blobs()?;
+        blobs.sort_by_key(|b| b.last_used);
+        let mut module_bytes: usize = blobs
+            .iter()
+            .filter(|b| b.module_dir == module_dir)
+            .map(|b| b.size)
+            .sum();
+        let mut total_bytes: usize = blobs.iter().map(|b| b.size).sum();
+        for b in &blobs {
+            let same_module = b.module_dir == module_dir;
+            let over_module = same_module && module_bytes + incoming > self.quota.max_module_bytes;
+            let over_total = total_bytes + incoming > self.quota.max_total_bytes;
+            if !over_module && !over_total {
+                continue;
+            }
+            match fs::remove_file(&b.path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            log::debug!("evicted blob {}, {} bytes", b.path.display(), b.size);
+            total_bytes -= b.size;
+            if same_module {
+                module_bytes -= b.size;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UserError;
+    use aici_abi::{StorageOp, StorageScope};
+
+    const MODULE_ID: &str = "79c8dcb829ab3c0516524a0c2b37e5d8606b1986e39214da5d06820179465b2a";
+
+    fn test_root(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("aicirt-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        root
+    }
+
+    fn ns(user: &str) -> StorageNamespace {
+        StorageNamespace {
+            user: user.to_string(),
+            module_id: MODULE_ID.to_string(),
+        }
+    }
+
+    fn write(name: &str, value: &[u8], op: StorageOp) -> StorageCmd {
+        StorageCmd::WriteVar {
+            name: name.to_string(),
+            value: value.to_vec(),
+            op,
+            when_version_is: None,
+            scope: StorageScope::Persistent,
+        }
+    }
+
+    fn read(name: &str) -> StorageCmd {
+        StorageCmd::ReadVar {
+            name: name.to_string(),
+            scope: StorageScope::Persistent,
+        }
+    }
+
+    #[test]
+    fn write_append_read() {
+        let root = test_root("storage-rw");
+        let storage = PersistentStorage::new(root.clone(), StorageQuota::default());
+        let alice = ns("alice");
+        assert!(matches!(
+            storage.process_cmd(&alice, read("x")).unwrap(),
+            StorageResp::VariableMissing {}
+        ));
+        storage
+            .process_cmd(&alice, write("x", b"ab", StorageOp::Set))
+            .unwrap();
+        storage
+            .process_cmd(&alice, write("x", b"cd", StorageOp::Append))
+            .unwrap();
+        match storage.process_cmd(&alice, read("x")).unwrap() {
+            StorageResp::ReadVar { version, value } => {
+                assert_eq!(version, 2);
+                assert_eq!(value, b"abcd");
+            }
+            r => panic!("unexpected {r:?}"),
+        }
+        // namespaces are separate
+        assert!(matches!(
+            storage.process_cmd(&ns("bob"), read("x")).unwrap(),
+            StorageResp::VariableMissing {}
+        ));
+
+        let resp = storage
+            .list(&StorageListReq {
+                user: None,
+                module_id: None,
+            })
+            .unwrap();
+        assert_eq!(resp.namespaces.len(), 1);
+        assert_eq!(resp.namespaces[0].user, "alice");
+        assert_eq!(resp.namespaces[0].variables[0].name, "x");
+        assert_eq!(resp.namespaces[0].variables[0].size, 4);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn quota_is_enforced() {
+        let root = test_root("storage-quota");
+        let storage = PersistentStorage::new(
+            root.clone(),
+            StorageQuota {
+                max_vars: 2,
+                max_bytes: 10,
+            },
+        );
+        let alice = ns("alice");
+        storage
+            .process_cmd(&alice, write("a", b"12345", StorageOp::Set))
+            .unwrap();
+        storage
+            .process_cmd(&alice, write("b", b"123", StorageOp::Set))
+            .unwrap();
+        // overwriting doesn't count the old value
+        storage
+            .process_cmd(&alice, write("b", b"12345", StorageOp::Set))
+            .unwrap();
+
+        let err = storage
+            .process_cmd(&alice, write("b", b"!", StorageOp::Append))
+            .unwrap_err();
+        assert!(UserError::is_self(&err));
+        let err = storage
+            .process_cmd(&alice, write("c", b"", StorageOp::Set))
+            .unwrap_err();
+        assert!(UserError::is_self(&err));
+
+        // the failed writes didn't change anything
+        match storage.process_cmd(&alice, read("b")).unwrap() {
+            StorageResp::ReadVar { version, value } => {
+                assert_eq!(version, 2);
+                assert_eq!(value, b"12345");
+            }
+            r => panic!("unexpected {r:?}"),
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn clear_missing_namespace_is_noop() {
+        let root = test_root("storage-clear");
+        let storage = PersistentStorage::new(root.clone(), StorageQuota::default());
+        let mut req = StorageClearReq {
+            user: "alice".to_string(),
+            module_id: MODULE_ID.to_string(),
+            name: None,
+        };
+        assert_eq!(storage.clear(&req).unwrap(), 0);
+        assert!(!root.exists());
+
+        let alice = ns("alice");
+        for name in ["a", "b", ""] {
+            storage
+                .process_cmd(&alice, write(name, b"1", StorageOp::Set))
+                .unwrap();
+        }
+        req.name = Some("a".to_string());
+        assert_eq!(storage.clear(&req).unwrap(), 1);
+        assert_eq!(storage.clear(&req).unwrap(), 0);
+        req.name = None;
+        assert_eq!(storage.clear(&req).unwrap(), 2);
+        let resp = storage
+            .list(&StorageListReq {
+                user: Some("alice".to_string()),
+                module_id: None,
+            })
+            .unwrap();
+        assert_eq!(resp.namespaces.len(), 0);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn blob_cache(name: &str, max_module_bytes: usize, max_total_bytes: usize) -> BlobCache {
+        BlobCache::new(
+            test_root(name),
+            BlobQuota {
+                max_module_bytes,
+                max_total_bytes,
+            },
+        )
+    }
+
+    #[test]
+    fn blob_put_get() {
+        let cache = blob_cache("blob-rw", 100, 100);
+        assert_eq!(cache.get(MODULE_ID, "a").unwrap(), None);
+        assert!(cache.put(MODULE_ID, "a", b"hello").unwrap());
+        // the first write wins
+        assert!(!cache.put(MODULE_ID, "a", b"world").unwrap());
+        assert_eq!(cache.get(MODULE_ID, "a").unwrap().unwrap(), b"hello");
+        // blobs are per module
+        assert_eq!(cache.get("native:foo", "a").unwrap(), None);
+        // too large blobs are not stored
+        assert!(!cache.put(MODULE_ID, "b", &[0; 101]).unwrap());
+        assert!(cache.put(MODULE_ID, "b", &[0; 10]).is_ok());
+        for key in ["", ".a", "../a", "a/b", &"x".repeat(129)] {
+            assert!(cache.get(MODULE_ID, key).is_err());
+            assert!(cache.put(MODULE_ID, key, b"x").is_err());
+        }
+        fs::remove_dir_all(&cache.root).unwrap();
+    }
+
+    #[test]
+    fn blob_module_quota_evicts_lru() {
+        let cache = blob_cache("blob-module-lru", 30, 1000);
+        let other = "native:other";
+        assert!(cache.put(MODULE_ID, "a", &[1; 10]).unwrap());
+        assert!(cache.put(MODULE_ID, "b", &[2; 10]).unwrap());
+        assert!(cache.put(other, "x", &[3; 25]).unwrap());
+        assert!(cache.put(MODULE_ID, "c", &[4; 10]).unwrap());
+        // reading makes "a" the most recently used one
+        assert!(cache.get(MODULE_ID, "a").unwrap().is_some());
+        assert!(cache.put(MODULE_ID, "d", &[5; 10]).unwrap());
+        assert!(cache.get(MODULE_ID, "b").unwrap().is_none());
+        for key in ["a", "c", "d"] {
+            assert!(cache.get(MODULE_ID, key).unwrap().is_some());
+        }
+        // other modules are not affected
+        assert!(cache.get(other, "x").unwrap().is_some());
+        fs::remove_dir_all(&cache.root).unwrap();
+    }
+
+    #[test]
+    fn blob_total_quota_evicts_lru() {
+        let cache = blob_cache("blob-total-lru", 1000, 30);
+        let other = "native:other";
+        assert!(cache.put(other, "x", &[1; 10]).unwrap());
+        assert!(cache.put(MODULE_ID, "a", &[2; 10]).unwrap());
+        assert!(cache.put(other, "y", &[3; 10]).unwrap());
+        // needs two blobs evicted, the oldest ones, across modules
+        assert!(cache.put(MODULE_ID, "b", &[4; 20]).unwrap());
+        assert!(cache.get(other, "x").unwrap().is_none());
+        assert!(cache.get(MODULE_ID, "a").unwrap().is_none());
+        assert!(cache.get(other, "y").unwrap().is_some());
+        assert!(cache.get(MODULE_ID, "b").unwrap().is_some());
+        let total: usize = cache.list_blobs().unwrap().iter().map(|b| b.size).sum();
+        assert_eq!(total, 30);
+        fs::remove_dir_all(&cache.root).unwrap();
+    }
+}
@@ -0,0 +1,389 @@
+use crate::{
+    futexshm::{ClientChannel, ServerChannel},
+    msgchannel::MessageChannel,
+};
+use anyhow::{anyhow, bail, Result};
+use std::{
+    io::{BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    time::Duration,
+};
+
+/// Sending half of a message channel between the LLM engine and aicirt.
+pub trait MsgSender: Send {
+    fn send_msg(&mut self, msg: &[u8]) -> Result<()>;
+}
+
+/// Receiving half of a message channel; blocks until a message arrives.
+pub trait MsgReceiver: Send {
+    fn recv_msg(&mut self) -> Result<Vec<u8>>;
+}
+
+impl MsgSender for ServerChannel {
+    fn send_msg(&mut self, msg: &[u8]) -> Result<()> {
+        self.send_resp(msg)
+    }
+}
+
+impl MsgSender for ClientChannel {
+    fn send_msg(&mut self, msg: &[u8]) -> Result<()> {
+        self.send_req(msg)
+    }
+}
+
+impl MsgSender for MessageChannel {
+    fn send_msg(&mut self, msg: &[u8]) -> Result<()> {
+        self.send(msg)
+    }
+}
+
+/// Shm-based channel, which spins for `busy_wait_duration` before going to sleep.
+pub struct BusyWait<T> {
+    pub ch: T,
+    pub busy_wait_duration: Duration,
+}
+
+impl MsgReceiver for BusyWait<ServerChannel> {
+    fn recv_msg(&mut self) -> Result<Vec<u8>> {
+        Ok(self.ch.recv_req(self.busy_wait_duration))
+    }
+}
+
+impl MsgReceiver for BusyWait<ClientChannel> {
+    fn recv_msg(&mut self) -> Result<Vec<u8>> {
+        self.ch
+            .recv_resp2(self.busy_wait_duration, Duration::MAX)
+            .ok_or_else(|| anyhow!("no response"))
+    }
+}
+
+impl MsgReceiver for BusyWait<MessageChannel> {
+    fn recv_msg(&mut self) -> Result<Vec<u8>> {
+        self.ch.recv(&self.busy_wait_duration)
+    }
+}
+
+/// Address of aicirt listening on a socket: `unix:PATH` or `tcp:HOST:PORT`.
+#[derive(Debug, Clone)]
+pub enum SocketAddr {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+impl SocketAddr {
+    pub fn parse(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            Ok(SocketAddr::Unix(PathBuf::from(path)))
+        } else if let Some(addr) = s.strip_prefix("tcp:") {
+            Ok(SocketAddr::Tcp(addr.to_string()))
+        } else {
+            bail!("invalid socket address {s:?}; expecting unix:PATH or tcp:HOST:PORT")
+        }
+    }
+}
+
+// messages are framed as a 4-byte little-endian length followed by the payload
+
+/// Limit on the channel name and token messages sent when connecting.
+const HANDSHAKE_MSG_BYTES: usize = 1024;
+
+/// Time the client has to send the channel name and token after connecting.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct SocketSender {
+    stream: BufWriter<Box<dyn Write + Send>>,
+}
+
+impl MsgSender for SocketSender {
+    fn send_msg(&mut self, msg: &[u8]) -> Result<()> {
+        let len: u32 = msg.len().try_into()?;
+        self.stream.write_all(&len.to_le_bytes())?;
+        self.stream.write_all(msg)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+}
+
+pub struct SocketReceiver {
+    stream: BufReader<Box<dyn Read + Send>>,
+    max_msg_bytes: usize,
+}
+
+impl MsgReceiver for SocketReceiver {
+    fn recv_msg(&mut self) -> Result<Vec<u8>> {
+        let mut len = [0u8; 4];
+        self.stream.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > self.max_msg_bytes {
+            bail!(
+                "message too large: {len} bytes (limit {})",
+                self.max_msg_bytes
+            );
+        }
+        let mut msg = vec![0u8; len];
+        self.stream.read_exact(&mut msg)?;
+        Ok(msg)
+    }
+}
+
+fn split_stream<S: Read + Write + Send + 'static>(
+    rd: S,
+    wr: S,
+    max_msg_bytes: usize,
+) -> (SocketSender, SocketReceiver) {
+    let wr: Box<dyn Write + Send> = Box::new(wr);
+    let rd: Box<dyn Read + Send> = Box::new(rd);
+    (
+        SocketSender {
+            stream: BufWriter::new(wr),
+        },
+        SocketReceiver {
+            stream: BufReader::new(rd),
+            max_msg_bytes,
+        },
+    )
+}
+
+fn same_token(a: &[u8], b: &[u8]) -> bool {
+    // don't leak the matching prefix length through timing
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Read the token shared by aicirt and the LLM engine from a file; surrounding whitespace is ignored.
+pub fn read_token_file(path: &str) -> Result<String> {
+    let token = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("can't read token file {path}: {e}"))?
+        .trim()
+        .to_string();
+    if token.is_empty() {
+        bail!("token file {path} is empty");
+    }
+    Ok(token)
+}
+
+/// Connect to aicirt, and select the channel (`"main"` or `"side"`).
+/// The `token` has to match the one aicirt was started with (see `--listen-token-file`).
+/// Incoming messages larger than `max_msg_bytes` are rejected.
+pub fn socket_connect(
+    addr: &SocketAddr,
+    channel: &str,
+    token: &str,
+    max_msg_bytes: usize,
+) -> Result<(SocketSender, SocketReceiver)> {
+    let (mut tx, rx) = match addr {
+        SocketAddr::Unix(path) => {
+            let s = UnixStream::connect(path)?;
+            split_stream(s.try_clone()?, s, max_msg_bytes)
+        }
+        SocketAddr::Tcp(addr) => {
+            let s = TcpStream::connect(addr)?;
+            s.set_nodelay(true)?;
+            split_stream(s.try_clone()?, s, max_msg_bytes)
+        }
+    };
+    tx.send_msg(channel.as_bytes())?;
+    tx.send_msg(token.as_bytes())?;
+    Ok((tx, rx))
+}
+
+pub enum SocketListenerKind {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+/// Connections are only accepted from clients presenting the token (if any).
+/// TCP sockets require a token, since the clients are trusted with `$auth` of requests;
+/// Unix sockets are only accessible to the user running aicirt.
+pub struct SocketListener {
+    kind: SocketListenerKind,
+    token: Option<String>,
+    max_msg_bytes: usize,
+}
+
+impl SocketListener {
+    pub fn bind(addr: &SocketAddr, token: Option<String>, max_msg_bytes: usize) -> Result<Self> {
+        let kind = match addr {
+            SocketAddr::Unix(path) => {
+                // left over from a previous run
+                let _ = std::fs::remove_file(path);
+                let l = UnixListener::bind(path)?;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+                SocketListenerKind::Unix(l)
+            }
+            SocketAddr::Tcp(addr) => {
+                if token.is_none() {
+                    bail!("listening on tcp:{addr} requires a token");
+                }
+                SocketListenerKind::Tcp(TcpListener::bind(addr)?)
+            }
+        };
+        Ok(SocketListener {
+            kind,
+            token,
+            max_msg_bytes,
+        })
+    }
+
+    /// Accept a connection; the caller should then call [PendingConnection::handshake],
+    /// possibly on a different thread, since it waits for the client.
+    pub fn accept_pending(&self) -> Result<PendingConnection> {
+        let stream = match &self.kind {
+            SocketListenerKind::Unix(l) => {
+                let (s, _) = l.accept()?;
+                PendingStream::Unix(s)
+            }
+            SocketListenerKind::Tcp(l) => {
+                let (s, peer) = l.accept()?;
+                log::info!("connection from {peer}");
+                s.set_nodelay(true)?;
+                PendingStream::Tcp(s)
+            }
+        };
+        Ok(PendingConnection {
+            stream,
+            token: self.token.clone(),
+            max_msg_bytes: self.max_msg_bytes,
+        })
+    }
+
+    /// Accept a connection and wait for the handshake; returns the channel name sent by the client.
+    pub fn accept(&self) -> Result<(String, SocketSender, SocketReceiver)> {
+        self.accept_pending()?.handshake()
+    }
+}
+
+enum PendingStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+/// Connection accepted by [SocketListener::accept_pending], for which the client
+/// hasn't yet sent the channel name and token.
+pub struct PendingConnection {
+    stream: PendingStream,
+    token: Option<String>,
+    max_msg_bytes: usize,
+}
+
+impl PendingConnection {
+    /// Wait (at most 10s) for the client to send the channel name and token, and check the token.
+    /// Returns the channel name.
+    pub fn handshake(self) -> Result<(String, SocketSender, SocketReceiver)> {
+        let (tx, mut rx) = match &self.stream {
+            PendingStream::Unix(s) => {
+                s.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+                split_stream(s.try_clone()?, s.try_clone()?, 0)
+            }
+            PendingStream::Tcp(s) => {
+                s.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+                split_stream(s.try_clone()?, s.try_clone()?, 0)
+            }
+        };
+        rx.max_msg_bytes = HANDSHAKE_MSG_BYTES;
+        let channel = String::from_utf8(rx.recv_msg()?)?;
+        let token = rx.recv_msg()?;
+        if let Some(expected) = &self.token {
+            if !same_token(expected.as_bytes(), &token) {
+                bail!("invalid token for channel {channel:?}");
+            }
+        }
+        match &self.stream {
+            PendingStream::Unix(s) => s.set_read_timeout(None)?,
+            PendingStream::Tcp(s) => s.set_read_timeout(None)?,
+        }
+        rx.max_msg_bytes = self.max_msg_bytes;
+        Ok((channel, tx, rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_addr(name: &str) -> SocketAddr {
+        let path =
+            std::env::temp_dir().join(format!("aicirt-{}-{}.sock", name, std::process::id()));
+        SocketAddr::Unix(path)
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (mut tx, _) = split_stream(a.try_clone().unwrap(), a, 100);
+        let (_, mut rx) = split_stream(b.try_clone().unwrap(), b, 100);
+        tx.send_msg(&[1; 100]).unwrap();
+        assert_eq!(rx.recv_msg().unwrap().len(), 100);
+        tx.send_msg(&[1; 101]).unwrap();
+        assert!(rx.recv_msg().is_err());
+    }
+
+    #[test]
+    fn tcp_requires_token() {
+        let addr = SocketAddr::parse("tcp:127.0.0.1:0").unwrap();
+        assert!(SocketListener::bind(&addr, None, 100).is_err());
+        assert!(SocketListener::bind(&addr, Some("secret".to_string()), 100).is_ok());
+    }
+
+    #[test]
+    fn connections_need_the_token() {
+        let addr = test_addr("token");
+        let listener = SocketListener::bind(&addr, Some("secret".to_string()), 1000).unwrap();
+
+        let addr2 = addr.clone();
+        let client = std::thread::spawn(move || {
+            let (mut tx, mut rx) = socket_connect(&addr2, "main", "secret", 1000).unwrap();
+            tx.send_msg(b"hello").unwrap();
+            assert_eq!(rx.recv_msg().unwrap(), b"world");
+            let _ = socket_connect(&addr2, "side", "wrong", 1000).unwrap();
+        });
+
+        let (channel, mut tx, mut rx) = listener.accept().unwrap();
+        assert_eq!(channel, "main");
+        assert_eq!(rx.recv_msg().unwrap(), b"hello");
+        tx.send_msg(b"world").unwrap();
+        let err = listener.accept().err().unwrap();
+        assert!(err.to_string().contains("invalid token"));
+
+        client.join().unwrap();
+        if let SocketAddr::Unix(path) = addr {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn handshakes_are_independent() {
+        let addr = test_addr("pending");
+        let listener = SocketListener::bind(&addr, None, 1000).unwrap();
+
+        // connects, but never sends the channel name
+        let idle = match &addr {
+            SocketAddr::Unix(path) => UnixStream::connect(path).unwrap(),
+            _ => unreachable!(),
+        };
+        let idle_conn = listener.accept_pending().unwrap();
+        let idle_thread = std::thread::spawn(move || idle_conn.handshake());
+
+        let addr2 = addr.clone();
+        let client = std::thread::spawn(move || {
+            let (mut tx, _rx) = socket_connect(&addr2, "main", "", 1000).unwrap();
+            tx.send_msg(b"hello").unwrap();
+        });
+        let t0 = std::time::Instant::now();
+        let (channel, _tx, mut rx) = listener.accept_pending().unwrap().handshake().unwrap();
+        assert_eq!(channel, "main");
+        assert_eq!(rx.recv_msg().unwrap(), b"hello");
+        assert!(t0.elapsed() < HANDSHAKE_TIMEOUT);
+        client.join().unwrap();
+
+        drop(idle);
+        assert!(idle_thread.join().unwrap().is_err());
+        if let SocketAddr::Unix(path) = addr {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use crate::worker::SeqWorkerHandle;
+use aicirt::{bail_user, valid_tagname, HashMap};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+/// Parsed `--warm-pool TAG=N` argument.
+#[derive(Clone, Debug)]
+pub struct WarmPoolConfig {
+    pub tag: String,
+    pub size: usize,
+}
+
+impl WarmPoolConfig {
+    pub fn parse(s: &str) -> Result<Self> {
+        let (tag, size) = match s.split_once('=') {
+            Some((tag, size)) => (tag, size),
+            None => bail_user!("invalid warm pool spec {s:?}; expecting TAG=N"),
+        };
+        if !valid_tagname(tag) {
+            bail_user!("invalid tag name {tag:?} in warm pool spec");
+        }
+        let size = match size.parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => bail_user!("invalid size {size:?} in warm pool spec"),
+        };
+        Ok(WarmPoolConfig {
+            tag: tag.to_string(),
+            size,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WarmPoolStats {
+    pub tag: String,
+    /// Module the tag resolved to at last refill; empty before first refill.
+    pub module_id: String,
+    pub target_size: usize,
+    pub ready: usize,
+    pub pending: usize,
+    /// Instantiations served from the pool.
+    pub hits: u64,
+    /// Instantiations of this module that found the pool empty.
+    pub misses: u64,
+    /// Workers that failed to preload.
+    pub failures: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WarmPoolStatsResp {
+    pub pools: Vec<WarmPoolStats>,
+}
+
+struct PoolEntry {
+    stats: WarmPoolStats,
+    workers: Vec<SeqWorkerHandle>,
+}
+
+/// Per-tag pools of sequence workers, which have the module instantiated
+/// and `aici_init()` run, but are still waiting for `setup()`.
+#[derive(Clone, Default)]
+pub struct WarmPool {
+    pools: Arc<Mutex<HashMap<String, PoolEntry>>>,
+}
+
+impl WarmPool {
+    pub fn new(configs: &[WarmPoolConfig]) -> Self {
+        let pools = configs
+            .iter()
+            .filter(|c| c.size > 0)
+            .map(|c| {
+                let entry = PoolEntry {
+                    stats: WarmPoolStats {
+                        tag: c.tag.clone(),
+                        target_size: c.size,
+                        ..Default::default()
+                    },
+                    workers: vec![],
+                };
+                (c.tag.clone(), entry)
+            })
+            .collect();
+        WarmPool {
+            pools: Arc::new(Mutex::new(pools)),
+        }
+    }
+
+    pub fn tags(&self) -> Vec<String> {
+        self.pools.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.pools.lock().unwrap().contains_key(tag)
+    }
+
+    /// Take a preloaded worker for the given module.
+    /// Returns the worker (if any) and the tag of the pool that needs refilling.
+    pub fn take(&self, module_id: &str) -> (Option<SeqWorkerHandle>, Option<String>) {
+        let mut pools = self.pools.lock().unwrap();
+        let mut missed = None;
+        for entry in pools.values_mut() {
+            if entry.stats.module_id != module_id {
+                continue;
+            }
+            if let Some(w) = entry.workers.pop() {
+                entry.stats.hits += 1;
+                entry.stats.ready = entry.workers.len();
+                return (Some(w), Some(entry.stats.tag.clone()));
+            }
+            entry.stats.misses += 1;
+            missed = Some(entry.stats.tag.clone());
+        }
+        (None, missed)
+    }
+
+    /// Reserve a slot for a new worker of `module_id` in pool `tag`.
+    /// If the tag now points to a different module, the stale workers are dropped (killed).
+    /// Returns false if the pool is already full (counting pending workers).
+    pub fn reserve(&self, tag: &str, module_id: &str) -> bool {
+        let mut pools = self.pools.lock().unwrap();
+        let entry = match pools.get_mut(tag) {
+            Some(e) => e,
+            None => return false,
+        };
+        if entry.stats.module_id != module_id {
+            if entry.stats.module_id.len() > 0 {
+                log::info!("warm pool {tag}: {} -> {module_id}", entry.stats.module_id);
+            }
+            entry.stats.module_id = module_id.to_string();
+            entry.workers.clear();
+            entry.stats.ready = 0;
+        }
+        if entry.workers.len() + entry.stats.pending >= entry.stats.target_size {
+            return false;
+        }
+        entry.stats.pending += 1;
+        true
+    }
+
+    /// Finish a reservation made with `reserve()`.
+    pub fn complete(&self, tag: &str, module_id: &str, worker: Result<SeqWorkerHandle>) {
+        let mut pools = self.pools.lock().unwrap();
+        let entry = pools.get_mut(tag).unwrap();
+        entry.stats.pending -= 1;
+        match worker {
+            // if module changed in the meantime, the worker is dropped here
+            Ok(w) if entry.stats.module_id == module_id => {
+                entry.workers.push(w);
+                entry.stats.ready = entry.workers.len();
+            }
+            Ok(_) => {}
+            Err(e) => {
+                log::warn!("warm pool {tag}: preload failed: {e}");
+                entry.stats.failures += 1;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> WarmPoolStatsResp {
+        let pools = self.pools.lock().unwrap();
+        let mut pools = pools.values().map(|e| e.stats.clone()).collect::<Vec<_>>();
+        pools.sort_by(|a, b| a.tag.cmp(&b.tag));
+        WarmPoolStatsResp { pools }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hostimpl::tests::test_limits;
+    use anyhow::anyhow;
+
+    fn pool(spec: &str) -> WarmPool {
+        WarmPool::new(&[WarmPoolConfig::parse(spec).unwrap()])
+    }
+
+    #[test]
+    fn parse_config() {
+        let c = WarmPoolConfig::parse("pyctrl-latest=4").unwrap();
+        assert_eq!(c.tag, "pyctrl-latest");
+        assert_eq!(c.size, 4);
+        assert!(WarmPoolConfig::parse("pyctrl-latest").is_err());
+        assert!(WarmPoolConfig::parse("pyctrl-latest=x").is_err());
+        assert!(WarmPoolConfig::parse("=4").is_err());
+    }
+
+    #[test]
+    fn reserve_up_to_target_size() {
+        let pool = pool("t=2");
+        assert!(pool.reserve("t", "m1"));
+        assert!(pool.reserve("t", "m1"));
+        assert!(!pool.reserve("t", "m1"));
+        assert!(!pool.reserve("other", "m1"));
+
+        pool.complete("t", "m1", Err(anyhow!("failed")));
+        let stats = pool.stats().pools[0].clone();
+        assert_eq!(stats.pending, 1);
+        assert_eq!(stats.failures, 1);
+        assert_eq!(stats.ready, 0);
+        assert!(pool.reserve("t", "m1"));
+    }
+
+    #[test]
+    fn take_counts_misses() {
+        let pool = pool("t=1");
+        assert!(pool.reserve("t", "m1"));
+        let (w, tag) = pool.take("m1");
+        assert!(w.is_none());
+        assert_eq!(tag.as_deref(), Some("t"));
+        // other modules are not pooled at all
+        let (w, tag) = pool.take("m2");
+        assert!(w.is_none() && tag.is_none());
+        let stats = pool.stats().pools[0].clone();
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.hits, 0);
+    }
+
+    fn killed(pid: libc::pid_t) -> bool {
+        let mut status = 0;
+        let r = unsafe { libc::waitpid(pid, &mut status, 0) };
+        r == pid && libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGKILL
+    }
+
+    #[test]
+    fn take_reuses_ready_workers() {
+        let limits = test_limits();
+        let pool = pool("t=2");
+        assert!(pool.reserve("t", "m1"));
+        assert!(pool.reserve("t", "m1"));
+        let w1 = SeqWorkerHandle::idle("w1", &limits).unwrap();
+        let w1_pid = w1.pid();
+        pool.complete("t", "m1", Ok(w1));
+        pool.complete("t", "m1", SeqWorkerHandle::idle("w2", &limits));
+        assert_eq!(pool.stats().pools[0].ready, 2);
+
+        let (w, tag) = pool.take("m1");
+        assert_eq!(w.unwrap().req_id, "w2");
+        assert_eq!(tag.as_deref(), Some("t"));
+        let stats = pool.stats().pools[0].clone();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 0);
+        assert_eq!(stats.ready, 1);
+
+        // the worker left in the pool is killed when the tag moves to another module
+        assert!(pool.reserve("t", "m2"));
+        assert_eq!(pool.stats().pools[0].ready, 0);
+        assert!(killed(w1_pid));
+        let (w, tag) = pool.take("m1");
+        assert!(w.is_none() && tag.is_none());
+    }
+
+    #[test]
+    fn retag_switches_module() {
+        let pool = pool("t=1");
+        assert!(pool.reserve("t", "m1"));
+        // the pending m1 worker still counts against the size
+        assert!(!pool.reserve("t", "m2"));
+        assert_eq!(pool.stats().pools[0].module_id, "m2");
+        pool.complete("t", "m1", Err(anyhow!("failed")));
+        assert!(pool.reserve("t", "m2"));
+    }
+}
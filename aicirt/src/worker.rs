@@ -1,7 +1,8 @@
 use crate::{
     api::ModuleInstId,
     hostimpl::AiciLimits,
-    moduleinstance::{ModuleInstance, WasmContext},
+    moduleinstance::{ControllerInstance, ModuleInstance, TokenizerCache, WasmContext},
+    nativeinstance::NativeInstance,
     setup_bg_worker_pool,
     shm::Shm,
     InstantiateReq, UserError,
@@ -10,21 +11,24 @@ use aici_abi::{
     InitPromptResult, MidProcessArg, ProcessResultOffset, StorageCmd, StorageResp, TokenId,
 };
 use aicirt::{
-    api::SequenceResult,
+    api::{BiasType, SequenceResult},
     futexshm::{TypedClient, TypedClientHandle, TypedServer},
     set_max_priority,
     shm::{ShmAllocator, Unlink},
+    storage::StorageNamespace,
     user_error,
     variables::Variables,
 };
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, ensure, Result};
 use libc::pid_t;
 use serde::{Deserialize, Serialize};
 use std::{
     fmt::Debug,
     path::PathBuf,
-    rc::Rc,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
 
@@ -128,6 +132,10 @@ pub type GroupHandle = ProcessHandle<GroupCmd, GroupResp>;
 struct ForkerCmd {
     id: String,
     for_compile: bool,
+    // index into the sessions passed to WorkerForker::new()
+    session: usize,
+    // if not the session default
+    tokenizer: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -141,15 +149,22 @@ struct ForkerResp(WireSeqHandle);
 #[derive(Serialize, Deserialize, Debug)]
 enum SeqCmd {
     GetCommsPid {},
-    Instantiate {
+    Preload {
         module_path: PathBuf,
         module_id: String,
+        /// `module_path` is a native dynamic library, not a compiled WASM module.
+        native: bool,
+    },
+    Setup {
         module_arg: String,
+        storage_ns: StorageNamespace,
         prompt_str: Option<String>,
         prompt_toks: Option<Vec<TokenId>>,
+        seed: u64,
     },
     Fork {
         inst_id: ModuleInstId,
+        fork_idx: usize,
     },
     SetId {
         inst_id: ModuleInstId,
@@ -167,7 +182,8 @@ impl SeqCmd {
     pub fn tag(&self) -> &'static str {
         match self {
             SeqCmd::GetCommsPid {} => "get_comms_pid",
-            SeqCmd::Instantiate { .. } => "instantiate",
+            SeqCmd::Preload { .. } => "preload",
+            SeqCmd::Setup { .. } => "setup",
             SeqCmd::Fork { .. } => "fork",
             SeqCmd::SetId { .. } => "set_id",
             SeqCmd::MidProcess { .. } => "process",
@@ -235,6 +251,19 @@ where
         unsafe { libc::kill(self.pid, libc::SIGKILL) }
     }
 
+    /// Handle not connected to any process, for tests that never send commands.
+    #[cfg(test)]
+    pub(crate) fn disconnected(limits: &AiciLimits) -> Result<Self> {
+        Ok(ProcessHandle {
+            pid: 0,
+            cmd: Mutex::new(TypedClient::new(Shm::anon(limits.ipc_shm_bytes)?)),
+        })
+    }
+
+    fn gauge(&self) -> &'static AtomicU64 {
+        self.cmd.lock().unwrap().gauge()
+    }
+
     fn recv_with_timeout(&self, lbl: &str, timeout: Timeout) -> Result<Resp> {
         let t0 = Instant::now();
         let d = match timeout {
@@ -338,14 +367,20 @@ impl SeqCtx {
                 );
                 Ok(SeqResp::Compile { binary })
             }
-            SeqCmd::Fork { inst_id } => {
+            SeqCmd::Fork { inst_id, fork_idx } => {
                 match fork_child(&self.wasm_ctx.limits)? {
-                    ForkResult::Parent { handle } => Ok(SeqResp::Fork { handle }),
+                    ForkResult::Parent { handle } => {
+                        self.mutinst().count_fork();
+                        Ok(SeqResp::Fork { handle })
+                    }
                     ForkResult::Child { server } => {
                         set_max_priority();
                         self.server = server;
                         self.inst_id = inst_id;
                         self.mutinst().set_id(inst_id);
+                        self.mutinst().fork_seed(fork_idx);
+                        let gauge = self.server.gauge();
+                        self.mutinst().set_fuel_gauge(gauge);
                         // note that this is sent over the child channel
                         // we do it this way, so that we come back to dispatch_loop()
                         // and continue in the child with the same stack height as in the parent
@@ -353,24 +388,58 @@ impl SeqCtx {
                     }
                 }
             }
-            SeqCmd::Instantiate {
+            SeqCmd::Preload {
                 module_path,
                 module_id,
+                native,
+            } => {
+                if let Some(e) = &self.init_error {
+                    return Err(user_error!("{e}"));
+                }
+                let vocab_size = self.wasm_ctx.globals.tokrx_info.vocab_size as usize;
+                let bias_type = BiasType::from_u32(self.shm.elt_type() & 0xf)?;
+                if bias_type.bytes_to_elts(self.shm.elt_size()) < vocab_size {
+                    return Err(user_error!(
+                        "tokenizer vocabulary ({vocab_size}) too large for this engine"
+                    ));
+                }
+                let _ = module_id;
+                let ch = std::mem::take(&mut self.query);
+                let mut inst: Box<dyn ControllerInstance> = if native {
+                    let mut inst = NativeInstance::new(
+                        424242,
+                        self.wasm_ctx.clone(),
+                        &module_path,
+                        ch.unwrap(),
+                        self.shm.clone(),
+                    )?;
+                    inst.init()?;
+                    Box::new(inst)
+                } else {
+                    let module = self.wasm_ctx.deserialize_module(module_path).unwrap();
+                    let mut inst = ModuleInstance::new(
+                        424242,
+                        self.wasm_ctx.clone(),
+                        module,
+                        ch.unwrap(),
+                        self.shm.clone(),
+                    )?;
+                    inst.init();
+                    Box::new(inst)
+                };
+                inst.set_fuel_gauge(self.server.gauge());
+                self.modinst = Some(inst);
+                ok()
+            }
+            SeqCmd::Setup {
                 module_arg,
+                storage_ns,
                 prompt_str,
                 prompt_toks,
+                seed,
             } => {
-                let module = self.wasm_ctx.deserialize_module(module_path).unwrap();
-                let _ = module_id;
-                let ch = std::mem::take(&mut self.query);
-                let mut inst = ModuleInstance::new(
-                    424242,
-                    self.wasm_ctx.clone(),
-                    module,
-                    module_arg,
-                    ch.unwrap(),
-                    self.shm.clone(),
-                )?;
+                let inst = self.mutinst();
+                inst.set_seed(seed);
                 let prompt_toks = if let Some(t) = prompt_toks {
                     t
                 } else {
@@ -383,8 +452,7 @@ impl SeqCtx {
                     };
                     inst.tokenize(&p)?
                 };
-                self.modinst = Some(inst);
-                let r = self.mutinst().setup(prompt_toks);
+                let r = inst.setup(module_arg, storage_ns, prompt_toks);
                 Ok(SeqResp::InitPrompt {
                     json: serde_json::to_string(&r)?,
                 })
@@ -407,8 +475,8 @@ impl SeqCtx {
         }
     }
 
-    fn mutinst(&mut self) -> &mut ModuleInstance {
-        self.modinst.as_mut().unwrap()
+    fn mutinst(&mut self) -> &mut dyn ControllerInstance {
+        self.modinst.as_deref_mut().unwrap()
     }
 
     // we may want to do this in future, but for now only group cmd is storage
@@ -457,8 +525,10 @@ struct SeqCtx {
     wasm_ctx: WasmContext,
     query: Option<GroupHandle>,
     inst_id: ModuleInstId,
-    modinst: Option<ModuleInstance>,
-    shm: Rc<ShmAllocator>,
+    modinst: Option<Box<dyn ControllerInstance>>,
+    shm: Arc<ShmAllocator>,
+    // reported upon preload
+    init_error: Option<String>,
 }
 
 struct CommsPid {
@@ -477,6 +547,25 @@ pub struct SeqWorkerHandle {
     comms_pid: Option<Arc<CommsPid>>,
 }
 
+#[cfg(test)]
+impl SeqWorkerHandle {
+    /// Handle of a child that just sleeps, for tests that never send commands.
+    pub(crate) fn idle(req_id: &str, limits: &AiciLimits) -> Result<Self> {
+        let child = std::process::Command::new("sleep").arg("60").spawn()?;
+        let mut handle = SeqHandle::disconnected(limits)?;
+        handle.pid = child.id() as pid_t;
+        Ok(SeqWorkerHandle {
+            req_id: req_id.to_string(),
+            handle,
+            comms_pid: None,
+        })
+    }
+
+    pub(crate) fn pid(&self) -> pid_t {
+        self.handle.pid
+    }
+}
+
 impl Drop for SeqWorkerHandle {
     fn drop(&mut self) {
         self.handle.kill();
@@ -489,16 +578,92 @@ impl SeqWorkerHandle {
             .send_cmd_expect_ok(SeqCmd::SetId { inst_id: id }, Timeout::Quick)
     }
 
+    /// Instantiate the module in this (fresh) worker and run its `aici_init()`.
+    /// The worker then waits for `setup()`.
+    pub fn preload(
+        &self,
+        module_id: &str,
+        module_path: PathBuf,
+        native: bool,
+        limits: &AiciLimits,
+    ) -> Result<()> {
+        self.handle.send_cmd_expect_ok(
+            SeqCmd::Preload {
+                module_path,
+                module_id: module_id.to_string(),
+                native,
+            },
+            Timeout::from_millis(limits.max_init_ms),
+        )
+    }
+
+    /// Pass the module argument and prompt to a preloaded worker and run `init_prompt()`.
+    pub fn setup(
+        &self,
+        req: &InstantiateReq,
+        storage_ns: StorageNamespace,
+        limits: &AiciLimits,
+    ) -> Result<SequenceResult<InitPromptResult>> {
+        let module_arg = match req.module_arg.as_str() {
+            Some(a) => a.to_string(),
+            None => serde_json::to_string(&req.module_arg)?,
+        };
+
+        let (prompt_str, prompt_toks) = if req.prompt.is_string() {
+            (Some(req.prompt.as_str().unwrap().to_string()), None)
+        } else {
+            (
+                None,
+                Some(
+                    req.prompt
+                        .as_array()
+                        .ok_or_else(|| anyhow!("expecting string or int array as prompt"))?
+                        .iter()
+                        .map(|x| -> Result<u32> {
+                            x.as_u64()
+                                .ok_or_else(|| anyhow!("expecting number as token"))?
+                                .try_into()
+                                .map_err(|e: std::num::TryFromIntError| anyhow!(e))
+                        })
+                        .collect::<Result<Vec<u32>>>()?,
+                ),
+            )
+        };
+
+        // without explicit seed, each request gets a different one
+        let seed = req
+            .seed
+            .unwrap_or_else(|| uuid::Uuid::new_v4().as_u64_pair().0);
+
+        match self.handle.send_cmd_with_timeout(
+            SeqCmd::Setup {
+                module_arg,
+                storage_ns,
+                prompt_str,
+                prompt_toks,
+                seed,
+            },
+            Timeout::from_millis(limits.max_init_ms),
+        )? {
+            SeqResp::InitPrompt { json } => Ok(serde_json::from_str(&json)?),
+            r => Err(anyhow!("unexpected response (init prompt) {r:?}")),
+        }
+    }
+
     pub fn run_main(&self) -> Result<()> {
         self.handle
             .send_cmd_expect_ok(SeqCmd::RunMain {}, Timeout::from_millis(120_000))
     }
 
-    pub fn fork(&self, target_id: ModuleInstId) -> Result<SeqWorkerHandle> {
-        match self
-            .handle
-            .send_cmd_with_timeout(SeqCmd::Fork { inst_id: target_id }, Timeout::Quick)?
-        {
+    /// Fork the worker; `fork_idx` is the index of the new sequence among forks.
+    pub fn fork(&self, target_id: ModuleInstId, fork_idx: usize) -> Result<SeqWorkerHandle> {
+        match self.handle.send_cmd_with_timeout(
+            SeqCmd::Fork {
+                inst_id: target_id,
+                fork_idx,
+            },
+            Timeout::Quick,
+        )? {
             SeqResp::Fork { handle } => {
                 let res = SeqWorkerHandle {
                     req_id: self.req_id.clone(),
@@ -519,6 +684,12 @@ impl SeqWorkerHandle {
         Ok(())
     }
 
+    /// Fuel consumed so far by the step in progress (updated by the worker every millisecond or so);
+    /// 0 when fuel metering is disabled.
+    pub fn step_fuel(&self) -> u64 {
+        self.handle.gauge().load(Ordering::Relaxed)
+    }
+
     pub fn check_process(&self, timeout: Duration) -> Result<SequenceResult<ProcessResultOffset>> {
         match self
             .handle
@@ -555,14 +726,16 @@ impl GroupCtx {
 
 pub struct WorkerForker {
     limits: AiciLimits,
+    num_sessions: usize,
     fork_worker: ForkerHandle,
 }
 
 fn forker_dispatcher(
     mut server: TypedServer<ForkerCmd, ForkerResp>,
-    wasm_ctx: WasmContext,
-    shm: Rc<ShmAllocator>,
+    sessions: Vec<(WasmContext, Arc<ShmAllocator>)>,
+    tokenizers: TokenizerCache,
 ) -> ! {
+    let limits = sessions[0].0.limits.clone();
     set_process_name("aicirt-forker");
     loop {
         // wait for any children that might have exited to prevent zombies
@@ -582,12 +755,22 @@ fn forker_dispatcher(
             }
         }
 
-        let cmd = server.recv_req(wasm_ctx.limits.busy_wait_duration);
+        let cmd = server.recv_req(limits.busy_wait_duration);
         let cmd_id = cmd.id;
         let for_compile = cmd.for_compile;
 
+        // token tries of extra tokenizers were built before the forker started
+        let (wasm_ctx, shm) = sessions[cmd.session].clone();
+        let (wasm_ctx, init_error) = match &cmd.tokenizer {
+            Some(name) => match tokenizers.context_for(&wasm_ctx, name) {
+                Ok(ctx) => (ctx, None),
+                Err(e) => (wasm_ctx, Some(format!("tokenizer {name}: {e}"))),
+            },
+            None => (wasm_ctx, None),
+        };
+
         // fork the seq worker first
-        match fork_child(&wasm_ctx.limits).unwrap() {
+        match fork_child(&limits).unwrap() {
             ForkResult::Parent { handle } => {
                 server.send_resp(ForkerResp(handle));
             }
@@ -601,6 +784,7 @@ fn forker_dispatcher(
                     query: None,
                     inst_id: 424242,
                     modinst: None,
+                    init_error,
                 };
 
                 if for_compile {
@@ -669,7 +853,13 @@ pub fn stop_process() -> ! {
 // }
 
 impl WorkerForker {
-    pub fn new(wasm_ctx: WasmContext, shm: Rc<ShmAllocator>) -> Self {
+    /// Each session has its own tokenizer (in `WasmContext`) and logit bias shm;
+    /// workers are forked for a specific session.
+    /// Workers can also use any of the `tokenizers` instead of the one of the session.
+    pub fn new(
+        sessions: Vec<(WasmContext, Arc<ShmAllocator>)>,
+        tokenizers: TokenizerCache,
+    ) -> Self {
         // create a new process group
         let pid = unsafe { libc::getpid() };
         unsafe {
@@ -677,57 +867,40 @@ impl WorkerForker {
             assert!(r >= 0);
         };
 
-        let limits = wasm_ctx.limits.clone();
+        let limits = sessions[0].0.limits.clone();
+        let num_sessions = sessions.len();
 
         match fork_child(&limits).unwrap() {
             ForkResult::Parent { handle } => {
                 unsafe { libc::signal(libc::SIGUSR1, clean_exit as usize) };
                 WorkerForker {
                     fork_worker: handle.to_client(),
+                    num_sessions,
                     limits,
                 }
             }
-            ForkResult::Child { server } => forker_dispatcher(server, wasm_ctx, shm),
+            ForkResult::Child { server } => forker_dispatcher(server, sessions, tokenizers),
         }
     }
 
-    pub fn instantiate(
+    /// Fork a fresh sequence worker (with its communication process).
+    /// The module is loaded later with `SeqWorkerHandle::preload()`.
+    /// Workers use the tokenizer of the session, unless another one is given.
+    pub fn new_worker(
         &self,
-        req: InstantiateReq,
-        module_path: PathBuf,
-    ) -> Result<(SeqWorkerHandle, SequenceResult<InitPromptResult>)> {
-        let module_arg = match req.module_arg.as_str() {
-            Some(a) => a.to_string(),
-            None => serde_json::to_string(&req.module_arg)?,
-        };
-
-        let (prompt_str, prompt_toks) = if req.prompt.is_string() {
-            (Some(req.prompt.as_str().unwrap().to_string()), None)
-        } else {
-            (
-                None,
-                Some(
-                    req.prompt
-                        .as_array()
-                        .ok_or_else(|| anyhow!("expecting string or int array as prompt"))?
-                        .iter()
-                        .map(|x| -> Result<u32> {
-                            x.as_u64()
-                                .ok_or_else(|| anyhow!("expecting number as token"))?
-                                .try_into()
-                                .map_err(|e: std::num::TryFromIntError| anyhow!(e))
-                        })
-                        .collect::<Result<Vec<u32>>>()?,
-                ),
-            )
-        };
-
+        req_id: &str,
+        session: usize,
+        tokenizer: Option<&str>,
+    ) -> Result<SeqWorkerHandle> {
+        ensure!(session < self.num_sessions, "invalid session {session}");
         let resp = self.fork_worker.send_cmd(ForkerCmd {
-            id: req.req_id.clone(),
+            id: req_id.to_string(),
             for_compile: false,
+            session,
+            tokenizer: tokenizer.map(|s| s.to_string()),
         })?;
         let mut res = SeqWorkerHandle {
-            req_id: req.req_id.clone(),
+            req_id: req_id.to_string(),
             handle: resp.0.to_client(),
             comms_pid: None,
         };
@@ -739,22 +912,7 @@ impl WorkerForker {
             r => return Err(anyhow!("unexpected response (get comms pid) {r:?}")),
         };
         res.comms_pid = Some(Arc::new(CommsPid { pid: comms_pid }));
-        match res.handle.send_cmd_with_timeout(
-            SeqCmd::Instantiate {
-                module_path,
-                module_id: req.module_id.clone(),
-                module_arg,
-                prompt_str,
-                prompt_toks,
-            },
-            Timeout::from_millis(self.limits.max_init_ms),
-        )? {
-            SeqResp::InitPrompt { json } => {
-                let r: SequenceResult<InitPromptResult> = serde_json::from_str(&json)?;
-                Ok((res, r))
-            }
-            r => Err(anyhow!("unexpected response (init prompt) {r:?}")),
-        }
+        Ok(res)
     }
 
     pub fn compile(&self, wasm: Vec<u8>) -> Result<Vec<u8>> {
@@ -762,6 +920,8 @@ impl WorkerForker {
         let resp = self.fork_worker.send_cmd(ForkerCmd {
             id: id.clone(),
             for_compile: true,
+            session: 0,
+            tokenizer: None,
         })?;
 
         // res.drop() kills handle
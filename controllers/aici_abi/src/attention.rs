@@ -0,0 +1,147 @@
+//! Excluding parts of the sequence from attention.
+//! Token ranges are labeled with tags, and a generation step can then ask
+//! the host not to attend to tokens with certain tags.
+
+use serde::{Deserialize, Serialize};
+
+/// Token position ranges `[start, end)`, counted from the start of the prompt,
+/// which should be excluded from attention.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct AttentionMask {
+    pub ranges: Vec<(u32, u32)>,
+}
+
+impl AttentionMask {
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Mask positions `[start, end)`; adjacent and overlapping ranges are merged.
+    pub fn add(&mut self, start: u32, end: u32) {
+        if start >= end {
+            return;
+        }
+        self.ranges.push((start, end));
+        self.ranges.sort();
+        let mut merged: Vec<(u32, u32)> = Vec::with_capacity(self.ranges.len());
+        for (s, e) in self.ranges.drain(..) {
+            match merged.last_mut() {
+                Some(last) if s <= last.1 => last.1 = std::cmp::max(last.1, e),
+                _ => merged.push((s, e)),
+            }
+        }
+        self.ranges = merged;
+    }
+
+    /// Build mask from per-position flags (`true` meaning masked).
+    pub fn from_flags(masked: impl IntoIterator<Item = bool>) -> Self {
+        let mut r = Self::default();
+        let mut start = None;
+        let mut pos = 0;
+        for m in masked {
+            match (m, start) {
+                (true, None) => start = Some(pos),
+                (false, Some(s)) => {
+                    r.ranges.push((s, pos));
+                    start = None;
+                }
+                _ => {}
+            }
+            pos += 1;
+        }
+        if let Some(s) = start {
+            r.ranges.push((s, pos));
+        }
+        r
+    }
+}
+
+/// Tags attached to token ranges of a sequence.
+#[derive(Debug, Clone, Default)]
+pub struct TokenTags {
+    ranges: Vec<(u32, u32, String)>,
+}
+
+impl TokenTags {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Label tokens at positions `[start, end)` with `tag`.
+    /// A token can have several tags.
+    pub fn tag(&mut self, start: u32, end: u32, tag: &str) {
+        if start < end {
+            self.ranges.push((start, end, tag.to_string()));
+        }
+    }
+
+    /// Forget tags past `len` tokens, eg. after backtracking.
+    pub fn truncate(&mut self, len: u32) {
+        self.ranges.retain_mut(|(start, end, _)| {
+            *end = std::cmp::min(*end, len);
+            *start < *end
+        });
+    }
+
+    /// Tags of token at given position.
+    pub fn tags_at(&self, pos: u32) -> Vec<&str> {
+        self.ranges
+            .iter()
+            .filter(|(s, e, _)| *s <= pos && pos < *e)
+            .map(|(_, _, t)| t.as_str())
+            .collect()
+    }
+
+    /// Mask covering all tokens with any of the given tags.
+    pub fn mask(&self, tags: &[impl AsRef<str>]) -> AttentionMask {
+        let mut r = AttentionMask::default();
+        for (start, end, tag) in &self.ranges {
+            if tags.iter().any(|t| t.as_ref() == tag) {
+                r.add(*start, *end);
+            }
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_merges_ranges() {
+        let mut m = AttentionMask::default();
+        m.add(10, 12);
+        m.add(2, 4);
+        m.add(4, 6);
+        m.add(11, 15);
+        m.add(7, 7);
+        assert_eq!(m.ranges, vec![(2, 6), (10, 15)]);
+    }
+
+    #[test]
+    fn from_flags_ranges() {
+        let m = AttentionMask::from_flags([true, true, false, false, true, false, true]);
+        assert_eq!(m.ranges, vec![(0, 2), (4, 5), (6, 7)]);
+        assert!(AttentionMask::from_flags([false, false]).is_empty());
+    }
+
+    #[test]
+    fn tags_mask_and_truncate() {
+        let mut tags = TokenTags::new();
+        tags.tag(0, 5, "system");
+        tags.tag(3, 8, "scratch");
+        tags.tag(10, 12, "scratch");
+        assert_eq!(tags.tags_at(4), vec!["system", "scratch"]);
+        assert_eq!(tags.mask(&["scratch"]).ranges, vec![(3, 8), (10, 12)]);
+        assert_eq!(
+            tags.mask(&["system", "scratch"]).ranges,
+            vec![(0, 8), (10, 12)]
+        );
+
+        tags.truncate(6);
+        assert_eq!(tags.mask(&["scratch"]).ranges, vec![(3, 6)]);
+        assert!(tags.tags_at(10).is_empty());
+    }
+}
@@ -0,0 +1,129 @@
+use crate::TokenId;
+use serde::{Deserialize, Serialize};
+use std::ops::{Deref, DerefMut};
+
+pub use toktrie::Splice;
+
+/// Sampling parameters for a single branch, overriding the ones from the request.
+/// Temperature is set in the `Branch` itself; use temperature of `0.0` for greedy decoding.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct BranchSampling {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_p: Option<f32>,
+}
+
+impl BranchSampling {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// `toktrie::Branch` with additional sampling parameters.
+/// Fields and methods of the inner branch are available through `Deref`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Branch<S> {
+    #[serde(flatten)]
+    pub inner: toktrie::Branch<S>,
+    /// Override other sampling parameters of the request.
+    #[serde(default, skip_serializing_if = "BranchSampling::is_default")]
+    pub sampling: BranchSampling,
+}
+
+impl<S> From<toktrie::Branch<S>> for Branch<S> {
+    fn from(inner: toktrie::Branch<S>) -> Self {
+        Branch {
+            inner,
+            sampling: BranchSampling::default(),
+        }
+    }
+}
+
+impl<S> Deref for Branch<S> {
+    type Target = toktrie::Branch<S>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<S> DerefMut for Branch<S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<S> Branch<S> {
+    pub fn map_mask<F, T>(&self, f: F) -> Branch<T>
+    where
+        F: FnOnce(&S) -> T,
+    {
+        Branch {
+            inner: self.inner.map_mask(f),
+            sampling: self.sampling,
+        }
+    }
+
+    pub fn stop() -> Self {
+        toktrie::Branch::stop().into()
+    }
+
+    pub fn splice(backtrack: u32, ff_tokens: Vec<TokenId>) -> Self {
+        toktrie::Branch::splice(backtrack, ff_tokens).into()
+    }
+
+    pub fn noop() -> Self {
+        toktrie::Branch::noop().into()
+    }
+
+    pub fn sample(set: S, temperature: Option<f32>) -> Self {
+        toktrie::Branch::sample(set, temperature).into()
+    }
+
+    pub fn with_sampling(mut self, sampling: BranchSampling) -> Self {
+        self.sampling = sampling;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampling_round_trip() {
+        let b: Branch<usize> = Branch::sample(0, Some(0.5)).with_sampling(BranchSampling {
+            top_k: Some(40),
+            ..Default::default()
+        });
+        let s = serde_json::to_string(&b).unwrap();
+        assert!(s.contains("\"top_k\":40"));
+        // the inner branch is flattened, so the wire format is unchanged
+        assert!(s.starts_with("{\"sample_mask\":0,\"temperature\":0.5,"));
+        let b2: Branch<usize> = serde_json::from_str(&s).unwrap();
+        assert_eq!(b2.sampling.top_k, Some(40));
+        assert_eq!(b2.sampling.top_p, None);
+        assert_eq!(b2.map_mask(|o| *o + 1).sampling, b.sampling);
+    }
+
+    #[test]
+    fn sampling_defaults_when_missing() {
+        let s = serde_json::to_string(&Branch::<usize>::noop()).unwrap();
+        assert!(!s.contains("sampling"));
+        let b: Branch<usize> =
+            serde_json::from_str(r#"{"sample_mask":null,"temperature":null,"splices":[]}"#)
+                .unwrap();
+        assert!(b.sampling.is_default());
+        assert!(b.is_stop());
+    }
+
+    #[test]
+    fn from_toktrie_branch() {
+        let b: Branch<usize> = toktrie::Branch::splice(1, vec![2, 3]).into();
+        assert!(b.sampling.is_default());
+        assert_eq!(b.unconditional_splice().unwrap().ff_tokens, vec![2, 3]);
+    }
+}
@@ -1,17 +1,23 @@
-use crate::host::host_trie;
+use crate::earley::EarleyParser;
+use crate::host::{blob_cache_enabled, blob_cache_get, blob_cache_put, content_hash, host_trie};
+pub use crate::lark::lark_to_yacc;
 use crate::lex::{Lexer, LexerState, StateID, VobIdx, VobSet};
 use crate::{
-    toktrie::{Recognizer, SpecialToken},
+    toktrie::{Recognizer, SpecialToken, TokTrie},
     SimpleVob,
 };
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use cfgrammar::{
     yacc::{YaccGrammar, YaccKind},
     Span, Spanned, Symbol, TIdx,
 };
 use lrtable::{from_yacc, Action, Minimiser, StIdx, StateTable};
 use rustc_hash::FxHashMap;
-use std::{cell::RefCell, vec};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::{Cell, RefCell},
+    vec,
+};
 use vob::{vob, Vob};
 
 type StorageT = u32;
@@ -19,6 +25,9 @@ type PStack<StorageT> = Vec<StIdx<StorageT>>; // Parse stack
 
 const LOG_PARSER: bool = false;
 
+// each mask is vocab_size bits, so this is about 1000 masks for 128k vocab
+const MAX_CACHED_MASK_BYTES: usize = 16 << 20;
+
 #[derive(Debug, Clone, Copy)]
 enum ParseResult {
     Accept,
@@ -29,21 +38,59 @@ enum ParseResult {
 struct CfgStats {
     yacc_actions: usize,
     states_pushed: usize,
+    mask_hits: usize,
+    mask_misses: usize,
+}
+
+// state of the lexer at the top of byte stack
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+struct MaskKey {
+    lexer_state: StateID,
+    viable: VobIdx,
+}
+
+// tokens that span lexemes also depend on the parse stack, but only on the part
+// that the parser looked at while computing the mask
+struct CachedMask {
+    pstack_suffix: Vec<StorageT>,
+    mask: SimpleVob,
+}
+
+/// Parsing algorithm used by [CfgParser].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CfgMode {
+    /// LR(1) tables built with Pager's minimiser; fast, but the grammar has to be LR(1).
+    Lr,
+    /// Earley parser; accepts any context-free grammar, including ambiguous ones.
+    Earley,
+    /// LR if the grammar is LR(1) (the state table has no conflicts), otherwise Earley.
+    Auto,
+}
+
+enum ParserBackend {
+    Lr {
+        stable: Box<StateTable<StorageT>>,
+        parse_stacks: Vec<PStack<StorageT>>,
+        viable_vobidx_by_state: Vec<VobIdx>,
+    },
+    Earley(EarleyParser),
 }
 
 pub struct CfgParser {
     grm: YaccGrammar<StorageT>,
-    stable: StateTable<StorageT>,
+    backend: ParserBackend,
     lexer: Lexer,
     byte_states: Vec<ByteState>,
     pat_idx_to_tidx: Vec<TIdx<u32>>,
     vobset: VobSet,
     stats: RefCell<CfgStats>,
     tidx_to_pat_idx: FxHashMap<TIdx<u32>, usize>,
-    parse_stacks: Vec<Vec<StIdx<u32>>>,
     skip_patterns: Vob,
     friendly_pattern_names: Vec<String>,
-    viable_vobidx_by_state: Vec<VobIdx>,
+    mask_cache: FxHashMap<MaskKey, Vec<CachedMask>>,
+    mask_cache_bytes: usize,
+    // lowest parse stack position read by parse_lexeme() since last reset
+    pstack_low: Cell<usize>,
 }
 
 fn is_rx(name: &str) -> bool {
@@ -91,6 +138,68 @@ fn span_to_str(s: &Span, src: &str) -> String {
     format!("({},{})", line, column)
 }
 
+// bump when serialized form of CfgTables changes
+const CFG_FORMAT_VERSION: u32 = 1;
+
+// everything computed from the grammar, that doesn't change while parsing;
+// this is what's serialized in compiled grammars
+#[derive(Serialize, Deserialize)]
+struct CfgTables {
+    grm: YaccGrammar<StorageT>,
+    // None for Earley
+    stable: Option<StateTable<StorageT>>,
+    viable_vobidx_by_state: Vec<VobIdx>,
+    lexer: Lexer,
+    vobset: VobSet,
+    pat_idx_to_tidx: Vec<TIdx<StorageT>>,
+    skip_patterns: Vob,
+    friendly_pattern_names: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompiledCfg {
+    version: u32,
+    grammar: String,
+    tables: CfgTables,
+}
+
+impl CompiledCfg {
+    fn compile(grammar: &str, mode: CfgMode) -> Result<Self> {
+        Ok(CompiledCfg {
+            version: CFG_FORMAT_VERSION,
+            grammar: grammar.to_string(),
+            tables: CfgTables::compile(&grammar_to_yacc(grammar)?, mode)?,
+        })
+    }
+}
+
+fn decode_compiled(bytes: &[u8]) -> Result<CompiledCfg> {
+    // check the version first, the rest may not even deserialize
+    let version: u32 = bincode::deserialize(bytes)?;
+    if version != CFG_FORMAT_VERSION {
+        anyhow::bail!("compiled grammar has format {version}, expecting {CFG_FORMAT_VERSION}");
+    }
+    let compiled: CompiledCfg = bincode::deserialize(bytes)?;
+    compiled.tables.check()?;
+    Ok(compiled)
+}
+
+fn cache_key(grammar: &str) -> String {
+    format!(
+        "cfg{}-{:016x}",
+        CFG_FORMAT_VERSION,
+        content_hash(grammar.as_bytes())
+    )
+}
+
+fn grammar_to_yacc(grammar: &str) -> Result<String> {
+    if grammar.lines().any(|l| l.trim() == "%%") {
+        Ok(grammar.to_string())
+    } else {
+        lark_to_yacc(grammar).map_err(|e| anyhow::anyhow!("lark grammar error: {}", e))
+    }
+}
+
 pub(crate) fn parse_yacc(yacc: &str) -> Result<YaccGrammar> {
     let grmkind = YaccKind::Original(cfgrammar::yacc::YaccOriginalActionKind::NoAction);
     let grm = match YaccGrammar::new(grmkind, yacc) {
@@ -115,24 +224,99 @@ pub(crate) fn parse_yacc(yacc: &str) -> Result<YaccGrammar> {
     Ok(grm)
 }
 
-impl CfgParser {
-    pub fn from_yacc(yacc: &str) -> Result<Self> {
+impl CfgTables {
+    /// Check that tables loaded from bytes (which may come from a different build)
+    /// are consistent, so that they fail here and not when computing masks.
+    fn check(&self) -> Result<()> {
+        let num_patterns = self.pat_idx_to_tidx.len();
+        self.lexer.check_vobset(&self.vobset, num_patterns)?;
+        ensure!(
+            self.skip_patterns.len() == num_patterns
+                && self.friendly_pattern_names.len() == num_patterns
+                && self
+                    .pat_idx_to_tidx
+                    .iter()
+                    .all(|tidx| *tidx < self.grm.tokens_len()),
+            "lexer patterns don't match the grammar"
+        );
+        ensure!(
+            self.viable_vobidx_by_state
+                .iter()
+                .all(|v| self.vobset.contains(*v)),
+            "parser refers to missing token sets"
+        );
+        if let Some(stable) = &self.stable {
+            // every state the parser can get to needs its viable set
+            let mut seen = vec![false; self.viable_vobidx_by_state.len()];
+            let mut todo = vec![stable.start_state()];
+            while let Some(stidx) = todo.pop() {
+                let idx = stidx.as_storaget() as usize;
+                ensure!(
+                    idx < seen.len(),
+                    "LR state table doesn't match the viable sets"
+                );
+                if seen[idx] {
+                    continue;
+                }
+                seen[idx] = true;
+                for tidx in stable.state_actions(stidx) {
+                    if let Action::Shift(next) = stable.action(stidx, tidx) {
+                        todo.push(next);
+                    }
+                }
+                todo.extend(
+                    self.grm
+                        .iter_rules()
+                        .filter_map(|ridx| stable.goto(stidx, ridx)),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn compile(yacc: &str, mode: CfgMode) -> Result<Self> {
         let grm = parse_yacc(yacc)?;
         // TIME: all these annotation are for native release x86 build for C grammar
         // TIME: 27ms
-        let (sgraph, stable) = match from_yacc(&grm, Minimiser::Pager) {
-            Ok(r) => r,
-            Err(e) => {
-                if false {
-                    // not sure this works:
-                    anyhow::bail!("state table error:\n{e} on {:?}", grm.action(e.pidx));
+        let lr_tables = if mode == CfgMode::Earley {
+            None
+        } else {
+            match from_yacc(&grm, Minimiser::Pager) {
+                // shift/reduce and reduce/reduce conflicts are resolved silently
+                // (in favor of shift and the earlier production); not expected ones mean
+                // the grammar is not LR(1), and the table would reject valid input
+                Ok((_, stable))
+                    if stable.conflicts().is_some_and(|c| {
+                        c.sr_len() != grm.expect().unwrap_or(0)
+                            || c.rr_len() != grm.expectrr().unwrap_or(0)
+                    }) =>
+                {
+                    if mode == CfgMode::Lr {
+                        let c = stable.conflicts().unwrap();
+                        anyhow::bail!("grammar is not LR(1):\n{}{}", c.pp_sr(&grm), c.pp_rr(&grm));
+                    }
+                    println!("grammar is not LR(1); using Earley parser");
+                    None
+                }
+                Ok(r) => Some(r),
+                Err(e) => {
+                    if false {
+                        // not sure this works:
+                        anyhow::bail!("state table error:\n{e} on {:?}", grm.action(e.pidx));
+                    }
+                    if mode == CfgMode::Lr {
+                        anyhow::bail!("state table error:\n{e}");
+                    }
+                    println!("state table error: {e}; using Earley parser");
+                    None
                 }
-                anyhow::bail!("state table error:\n{e}");
             }
         };
 
         if false {
-            println!("core\n{}\n\n", sgraph.pp(&grm, true));
+            if let Some((sgraph, _)) = &lr_tables {
+                println!("core\n{}\n\n", sgraph.pp(&grm, true));
+            }
             for pidx in grm.iter_pidxs() {
                 let prod = grm.prod(pidx);
                 println!("{:?} -> {}", prod, prod.len());
@@ -193,65 +377,175 @@ impl CfgParser {
         let mut vobset = VobSet::new();
         // all-zero has to be inserted first
         let _all0 = vobset.insert_or_get(&vob![false; patterns.len()]);
-        let all1 = vobset.insert_or_get(&vob![true; patterns.len()]);
+        let _all1 = vobset.insert_or_get(&vob![true; patterns.len()]);
 
         // TIME: 27ms
-        let dfa = Lexer::from(patterns, &mut vobset);
+        let lexer = Lexer::from(patterns, &mut vobset);
 
-        let cfg_start = stable.start_state();
-        let parse_stacks = vec![vec![cfg_start]];
+        let (stable, viable_vobidx_by_state) = match lr_tables {
+            Some((sgraph, stable)) => {
+                let viable_vobidx_by_state = sgraph
+                    .iter_stidxs()
+                    .enumerate()
+                    .map(|(idx, stidx)| {
+                        assert!(idx == stidx.as_storaget() as usize);
 
-        let byte_state = ByteState {
-            lexer_state: dfa.file_start_state(),
-            parse_stack_idx: PStackIdx(0),
-            viable: all1,
+                        // skip patterns (whitespace) are always viable
+                        let mut r = skip_patterns.clone();
+                        for tidx in stable.state_actions(stidx) {
+                            match stable.action(stidx, tidx) {
+                                Action::Error => {}
+                                _ => {
+                                    if let Some(pat_idx) = tidx_to_pat_idx.get(&tidx) {
+                                        r.set(*pat_idx, true);
+                                    }
+                                }
+                            }
+                        }
+
+                        vobset.insert_or_get(&r)
+                    })
+                    .collect::<Vec<_>>();
+
+                (Some(stable), viable_vobidx_by_state)
+            }
+            None => (None, vec![]),
         };
 
-        let viable_vobidx_by_state = sgraph
-            .iter_stidxs()
-            .enumerate()
-            .map(|(idx, stidx)| {
-                assert!(idx == stidx.as_storaget() as usize);
+        Ok(CfgTables {
+            grm,
+            stable,
+            viable_vobidx_by_state,
+            lexer,
+            vobset,
+            pat_idx_to_tidx,
+            skip_patterns,
+            friendly_pattern_names,
+        })
+    }
+}
 
-                // skip patterns (whitespace) are always viable
-                let mut r = skip_patterns.clone();
-                for tidx in stable.state_actions(stidx) {
-                    match stable.action(stidx, tidx) {
-                        Action::Error => {}
-                        _ => {
-                            if let Some(pat_idx) = tidx_to_pat_idx.get(&tidx) {
-                                r.set(*pat_idx, true);
-                            }
-                        }
-                    }
+impl CfgParser {
+    /// Parse grammar either in yacc format (if it has a `%%` line) or in Lark format.
+    pub fn from_grammar(grammar: &str) -> Result<Self> {
+        Self::from_yacc(&grammar_to_yacc(grammar)?)
+    }
+
+    /// See [lark_to_yacc] for supported syntax.
+    pub fn from_lark(lark: &str) -> Result<Self> {
+        let yacc = lark_to_yacc(lark).map_err(|e| anyhow::anyhow!("lark grammar error: {}", e))?;
+        Self::from_yacc(&yacc)
+    }
+
+    /// Same as `from_yacc_with_mode(yacc, CfgMode::Auto)`.
+    pub fn from_yacc(yacc: &str) -> Result<Self> {
+        Self::from_yacc_with_mode(yacc, CfgMode::Auto)
+    }
+
+    pub fn from_yacc_with_mode(yacc: &str, mode: CfgMode) -> Result<Self> {
+        Ok(Self::from_tables(CfgTables::compile(yacc, mode)?))
+    }
+
+    /// Like [CfgParser::from_grammar], but first looks for the compiled grammar
+    /// in the host blob cache (see [crate::blob_cache_get]).
+    /// If it's not there, the grammar is compiled and stored in the cache,
+    /// so that following requests using the same grammar can skip compilation.
+    pub fn from_grammar_cached(grammar: &str) -> Result<Self> {
+        if !blob_cache_enabled() {
+            return Self::from_grammar(grammar);
+        }
+        let key = cache_key(grammar);
+        if let Some(bytes) = blob_cache_get(&key) {
+            match decode_compiled(&bytes) {
+                // the key is only a hash, so check for collisions
+                Ok(compiled) if compiled.grammar == grammar => {
+                    return Ok(Self::from_tables(compiled.tables))
                 }
+                Ok(_) => println!("cached grammar {key} is for a different grammar"),
+                Err(e) => println!("cached grammar {key}: {e}"),
+            }
+        }
+        let compiled = CompiledCfg::compile(grammar, CfgMode::Auto)?;
+        blob_cache_put(&key, &bincode::serialize(&compiled)?);
+        Ok(Self::from_tables(compiled.tables))
+    }
 
-                vobset.insert_or_get(&r)
-            })
-            .collect::<Vec<_>>();
+    /// Compile grammar (in any format accepted by [CfgParser::from_grammar])
+    /// to bytes, which can be later loaded with [CfgParser::from_bytes].
+    /// The bytes are only meant to be read by the same build of the controller.
+    pub fn compile_to_bytes(grammar: &str, mode: CfgMode) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&CompiledCfg::compile(grammar, mode)?)?)
+    }
 
-        let mut cfg = CfgParser {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(Self::from_tables(decode_compiled(bytes)?.tables))
+    }
+
+    fn from_tables(tables: CfgTables) -> Self {
+        let CfgTables {
             grm,
             stable,
-            lexer: dfa,
+            viable_vobidx_by_state,
+            lexer,
+            mut vobset,
+            pat_idx_to_tidx,
+            skip_patterns,
+            friendly_pattern_names,
+        } = tables;
+
+        let mut tidx_to_pat_idx = FxHashMap::default();
+        for (idx, tidx) in pat_idx_to_tidx.iter().enumerate() {
+            tidx_to_pat_idx.insert(*tidx, idx);
+        }
+
+        let byte_state = ByteState {
+            lexer_state: lexer.file_start_state(),
+            parse_stack_idx: PStackIdx(0),
+            viable: VobIdx::all_zero(),
+        };
+
+        let backend = match stable {
+            Some(stable) => {
+                let cfg_start = stable.start_state();
+                ParserBackend::Lr {
+                    stable: Box::new(stable),
+                    parse_stacks: vec![vec![cfg_start]],
+                    viable_vobidx_by_state,
+                }
+            }
+            None => ParserBackend::Earley(EarleyParser::new(
+                &grm,
+                &tidx_to_pat_idx,
+                &skip_patterns,
+                &mut vobset,
+            )),
+        };
+
+        let mut cfg = CfgParser {
+            grm,
+            backend,
+            lexer,
             byte_states: vec![byte_state],
             pat_idx_to_tidx,
             tidx_to_pat_idx,
-            viable_vobidx_by_state,
             skip_patterns,
             friendly_pattern_names,
-            parse_stacks,
             vobset,
+            mask_cache: FxHashMap::default(),
+            mask_cache_bytes: 0,
+            pstack_low: Cell::new(usize::MAX),
             stats: RefCell::new(CfgStats {
                 yacc_actions: 0,
                 states_pushed: 0,
+                mask_hits: 0,
+                mask_misses: 0,
             }),
         };
 
         cfg.vobset.pre_compute();
 
         // compute viable set of initial tokens
-        cfg.byte_states[0].viable = cfg.viable_vobidx(cfg_start);
+        cfg.byte_states[0].viable = cfg.viable_at(PStackIdx(0));
         if LOG_PARSER {
             println!(
                 "initial viable: {:?}",
@@ -259,11 +553,29 @@ impl CfgParser {
             );
         }
 
-        Ok(cfg)
+        cfg
+    }
+
+    pub fn mode(&self) -> CfgMode {
+        match self.backend {
+            ParserBackend::Lr { .. } => CfgMode::Lr,
+            ParserBackend::Earley(_) => CfgMode::Earley,
+        }
     }
 
-    fn viable_vobidx(&self, stidx: StIdx<StorageT>) -> VobIdx {
-        self.viable_vobidx_by_state[stidx.as_storaget() as usize]
+    // lexemes viable after given parse stack (LR) or chart row (Earley)
+    fn viable_at(&self, idx: PStackIdx) -> VobIdx {
+        match &self.backend {
+            ParserBackend::Lr {
+                parse_stacks,
+                viable_vobidx_by_state,
+                ..
+            } => {
+                let stidx = *parse_stacks[idx.0].last().unwrap();
+                viable_vobidx_by_state[stidx.as_storaget() as usize]
+            }
+            ParserBackend::Earley(earley) => earley.viable(idx.0),
+        }
     }
 
     #[allow(dead_code)]
@@ -277,11 +589,17 @@ impl CfgParser {
         }
     }
 
-    fn parse_lexeme(&self, lexeme: TIdx<StorageT>, pstack: &mut PStack<StorageT>) -> ParseResult {
+    fn parse_lexeme(
+        &self,
+        stable: &StateTable<StorageT>,
+        lexeme: TIdx<StorageT>,
+        pstack: &mut PStack<StorageT>,
+    ) -> ParseResult {
         loop {
+            self.note_pstack_read(pstack.len() - 1);
             let stidx = *pstack.last().unwrap();
 
-            let act = self.stable.action(stidx, lexeme);
+            let act = stable.action(stidx, lexeme);
 
             if LOG_PARSER {
                 println!(
@@ -297,8 +615,9 @@ impl CfgParser {
                     let ridx = self.grm.prod_to_rule(pidx);
                     let pop_idx = pstack.len() - self.grm.prod(pidx).len();
                     pstack.drain(pop_idx..);
+                    self.note_pstack_read(pop_idx - 1);
                     let prior = *pstack.last().unwrap();
-                    pstack.push(self.stable.goto(prior, ridx).unwrap());
+                    pstack.push(stable.goto(prior, ridx).unwrap());
                 }
                 Action::Shift(state_id) => {
                     pstack.push(state_id);
@@ -315,6 +634,12 @@ impl CfgParser {
         }
     }
 
+    fn note_pstack_read(&self, pos: usize) {
+        if pos < self.pstack_low.get() {
+            self.pstack_low.set(pos);
+        }
+    }
+
     #[allow(dead_code)]
     fn print_viable(&self, lbl: &str, vob: &Vob) {
         println!("viable tokens {}:", lbl);
@@ -358,17 +683,20 @@ impl CfgParser {
         res
     }
 
-    fn pstack_for(&self, top: &ByteState) -> &PStack<StorageT> {
-        &self.parse_stacks[top.parse_stack_idx.0]
+    fn push_pstack(&mut self, new_idx: PStackIdx, pstack: PStack<StorageT>) {
+        if let ParserBackend::Lr { parse_stacks, .. } = &mut self.backend {
+            if parse_stacks.len() <= new_idx.0 {
+                parse_stacks.push(Vec::new());
+            }
+            parse_stacks[new_idx.0] = pstack;
+        }
     }
 
-    fn push_pstack(&mut self, top: &ByteState, pstack: Vec<StIdx<u32>>) -> PStackIdx {
-        let new_idx = PStackIdx(top.parse_stack_idx.0 + 1);
-        if self.parse_stacks.len() <= new_idx.0 {
-            self.parse_stacks.push(Vec::new());
+    fn earley_scan(&mut self, idx: PStackIdx, tidx: TIdx<StorageT>) -> bool {
+        match &mut self.backend {
+            ParserBackend::Earley(earley) => earley.scan(idx.0, tidx, &mut self.vobset),
+            _ => unreachable!(),
         }
-        self.parse_stacks[new_idx.0] = pstack;
-        new_idx
     }
 
     fn run_parser(&mut self, pat_idx: usize, top: &ByteState, ls: LexerState) -> Option<ByteState> {
@@ -379,30 +707,60 @@ impl CfgParser {
         if LOG_PARSER {
             println!();
         }
-        let pstack = self.pstack_for(top);
         if self.skip_patterns[pat_idx] {
-            let stidx = *pstack.last().unwrap();
-            let viable = self.viable_vobidx(stidx);
+            let viable = self.viable_at(top.parse_stack_idx);
             //self.print_viable("reset", &viable);
             if LOG_PARSER {
-                println!("parse: {:?} skip", pstack);
+                println!("parse: {:?} skip", top.parse_stack_idx.0);
             }
             // reset viable states - they have been narrowed down to SKIP
-            self.mk_byte_state(ls, top.parse_stack_idx, viable)
-        } else {
-            let tidx = self.pat_idx_to_tidx[pat_idx];
-            let mut pstack = pstack.clone();
-            match self.parse_lexeme(tidx, &mut pstack) {
-                ParseResult::Accept => panic!("accept non EOF?"),
-                ParseResult::Continue => {
-                    let stidx = *pstack.last().unwrap();
-                    let viable = self.viable_vobidx(stidx);
-                    let new_idx = self.push_pstack(top, pstack);
-                    self.mk_byte_state(ls, new_idx, viable)
+            return self.mk_byte_state(ls, top.parse_stack_idx, viable);
+        }
+
+        let tidx = self.pat_idx_to_tidx[pat_idx];
+        let new_idx = PStackIdx(top.parse_stack_idx.0 + 1);
+        match &self.backend {
+            ParserBackend::Lr {
+                stable,
+                parse_stacks,
+                ..
+            } => {
+                let mut pstack = parse_stacks[top.parse_stack_idx.0].clone();
+                match self.parse_lexeme(stable, tidx, &mut pstack) {
+                    ParseResult::Accept => panic!("accept non EOF?"),
+                    ParseResult::Continue => self.push_pstack(new_idx, pstack),
+                    ParseResult::Error => return None,
+                }
+            }
+            ParserBackend::Earley(_) => {
+                if LOG_PARSER {
+                    println!("earley: {:?}", self.friendly_token_name(tidx));
+                }
+                if !self.earley_scan(top.parse_stack_idx, tidx) {
+                    return None;
                 }
-                ParseResult::Error => None,
             }
         }
+        let viable = self.viable_at(new_idx);
+        self.mk_byte_state(ls, new_idx, viable)
+    }
+
+    fn accepts_eof(&self, top: &ByteState) -> bool {
+        match &self.backend {
+            ParserBackend::Lr {
+                stable,
+                parse_stacks,
+                ..
+            } => {
+                let tidx = self.grm.eof_token_idx();
+                let mut pstack = parse_stacks[top.parse_stack_idx.0].clone();
+                matches!(
+                    self.parse_lexeme(stable, tidx, &mut pstack),
+                    ParseResult::Accept
+                )
+            }
+            ParserBackend::Earley(earley) => earley.accepts(top.parse_stack_idx.0),
+        }
     }
 
     #[allow(dead_code)]
@@ -413,12 +771,101 @@ impl CfgParser {
 
     pub fn get_stats(&self) -> String {
         let mut s = self.stats.borrow_mut();
-        let r = format!("yacc: {}/{}", s.yacc_actions, s.states_pushed);
+        let mut r = format!("yacc: {}/{}", s.yacc_actions, s.states_pushed);
+        if let ParserBackend::Earley(earley) = &self.backend {
+            r.push_str(&format!("; earley items: {}", earley.num_items()));
+        }
+        r.push_str(&format!(
+            "; masks: {}/{} hit, {}k cached",
+            s.mask_hits,
+            s.mask_hits + s.mask_misses,
+            self.mask_cache_bytes / 1024
+        ));
         s.yacc_actions = 0;
         s.states_pushed = 0;
+        s.mask_hits = 0;
+        s.mask_misses = 0;
         r
     }
 
+    fn mask_key(&self) -> Option<(MaskKey, &PStack<StorageT>)> {
+        let top = self.byte_states.last().unwrap();
+        match &self.backend {
+            ParserBackend::Lr { parse_stacks, .. } => Some((
+                MaskKey {
+                    lexer_state: top.lexer_state,
+                    viable: top.viable,
+                },
+                &parse_stacks[top.parse_stack_idx.0],
+            )),
+            // Earley state is the whole chart; not worth caching on
+            ParserBackend::Earley(_) => None,
+        }
+    }
+
+    fn cached_mask(&self, key: &MaskKey, pstack: &PStack<StorageT>) -> Option<&SimpleVob> {
+        let entries = self.mask_cache.get(key)?;
+        entries
+            .iter()
+            .find(|e| {
+                e.pstack_suffix.len() <= pstack.len()
+                    && pstack[pstack.len() - e.pstack_suffix.len()..]
+                        .iter()
+                        .zip(e.pstack_suffix.iter())
+                        .all(|(a, b)| a.as_storaget() == *b)
+            })
+            .map(|e| &e.mask)
+    }
+
+    /// Same as `trie.compute_bias(self, logits)`, but with mask caching (see [CfgParser::add_bias]).
+    pub fn compute_bias(&mut self, trie: &TokTrie, logits: &mut SimpleVob) {
+        logits.set_all(false);
+        self.add_bias(trie, logits);
+    }
+
+    /// Same as `trie.add_bias(self, toks, &[])`, but the resulting mask is cached,
+    /// keyed by the lexer state and viable lexemes, together with the top of the parse stack
+    /// that was inspected when computing it.
+    /// The cache is kept across steps, and forks start with a copy of it.
+    /// Only the LR backend is cached.
+    pub fn add_bias(&mut self, trie: &TokTrie, toks: &mut SimpleVob) {
+        let (key, pstack_len) = match self.mask_key() {
+            Some((key, pstack)) => {
+                if let Some(mask) = self.cached_mask(&key, pstack) {
+                    self.stats.borrow_mut().mask_hits += 1;
+                    toks.or(mask);
+                    return;
+                }
+                (key, pstack.len())
+            }
+            None => {
+                trie.add_bias(self, toks, &[]);
+                return;
+            }
+        };
+        self.stats.borrow_mut().mask_misses += 1;
+        let mut mask = trie.alloc_token_set();
+        self.pstack_low.set(pstack_len - 1);
+        trie.add_bias(self, &mut mask, &[]);
+        toks.or(&mask);
+
+        let low = self.pstack_low.replace(usize::MAX);
+        let pstack_suffix: Vec<StorageT> = match self.mask_key() {
+            Some((_, pstack)) => pstack[low..].iter().map(|s| s.as_storaget()).collect(),
+            None => unreachable!(),
+        };
+        let bytes = mask.len() / 8 + pstack_suffix.len() * std::mem::size_of::<StorageT>() + 64;
+        if self.mask_cache_bytes + bytes > MAX_CACHED_MASK_BYTES {
+            self.mask_cache.clear();
+            self.mask_cache_bytes = 0;
+        }
+        self.mask_cache_bytes += bytes;
+        self.mask_cache.entry(key).or_default().push(CachedMask {
+            pstack_suffix,
+            mask,
+        });
+    }
+
     fn mk_byte_state(
         &self,
         ls: LexerState,
@@ -471,12 +918,7 @@ impl Recognizer for CfgParser {
         match tok {
             SpecialToken::EndOfSentence => {
                 if let Some(st) = self.try_push(None) {
-                    let tidx = self.grm.eof_token_idx();
-                    let mut pstack = self.pstack_for(&st).clone();
-                    match self.parse_lexeme(tidx, &mut pstack) {
-                        ParseResult::Accept => true,
-                        _ => false,
-                    }
+                    self.accepts_eof(&st)
                 } else {
                     false
                 }
@@ -519,7 +961,7 @@ pub fn cfg_test() -> Result<()> {
 
         for tok in &toks[0..1000] {
             let tok = *tok;
-            trie.compute_bias(&mut cfg, &mut vob);
+            cfg.compute_bias(&trie, &mut vob);
             if !vob.is_allowed(tok) {
                 println!("reject, line={}, tok={:?}", line, trie.token_str(tok));
                 panic!();
@@ -595,3 +1037,224 @@ pub fn cfg_test() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accepts;
+    use crate::toktrie::{TokRxInfo, TokenId};
+
+    // reduce/reduce conflict on "x": A and B can only be told apart two lexemes later
+    const NOT_LR1: &str = r#"
+%start s
+%%
+SKIP: "/[ ]+/" ;
+s: a "x" "y" | b "x" "z" ;
+a: "w" ;
+b: "w" ;
+"#;
+
+    const AMBIGUOUS: &str = r#"
+%start expr
+%%
+SKIP: "/[ ]+/" ;
+expr: expr "+" expr | expr "*" expr | "(" expr ")" | "/[0-9]+/" ;
+"#;
+
+    // LR(1), so that masks are cached
+    const ARITH: &str = r#"
+%start expr
+%%
+SKIP: "/[ ]+/" ;
+expr: expr "+" term | term ;
+term: term "*" atom | atom ;
+atom: "(" expr ")" | "/[0-9]+/" ;
+"#;
+
+    fn test_trie() -> TokTrie {
+        let mut words = (0..=255u8).map(|b| vec![b]).collect::<Vec<_>>();
+        for w in ["((", "))", ")))", "))*", "12", "+(", "1)", " (", ")+"] {
+            words.push(w.as_bytes().to_vec());
+        }
+        let info = TokRxInfo {
+            vocab_size: words.len() as u32 + 1,
+            tok_eos: words.len() as TokenId,
+        };
+        words.push(vec![]);
+        TokTrie::from(&info, &words)
+    }
+
+    fn check_mask(trie: &TokTrie, cfg: &mut CfgParser) {
+        let mut cached = trie.alloc_token_set();
+        cfg.compute_bias(trie, &mut cached);
+        let mut uncached = trie.alloc_token_set();
+        trie.add_bias(cfg, &mut uncached, &[]);
+        for tok in 0..trie.vocab_size() as TokenId {
+            assert_eq!(
+                cached.is_allowed(tok),
+                uncached.is_allowed(tok),
+                "{:?}",
+                trie.token_str(tok)
+            );
+        }
+    }
+
+    fn run_cached(trie: &TokTrie, cfg: &mut CfgParser, s: &str) {
+        let mut off = 0;
+        for tok in trie.greedy_tokenize(s.as_bytes()) {
+            check_mask(trie, cfg);
+            // push the rest of the input and pop it back, like the trie walk does
+            let mut pushed = 0;
+            for b in &s.as_bytes()[off..] {
+                if !cfg.try_push_byte(*b) {
+                    break;
+                }
+                pushed += 1;
+            }
+            cfg.pop_bytes(pushed);
+            trie.append_token(cfg, tok).unwrap();
+            off += trie.token(tok).len();
+        }
+        check_mask(trie, cfg);
+    }
+
+    #[test]
+    fn mask_cache_matches_uncached() {
+        let trie = test_trie();
+        let mut cfg = CfgParser::from_yacc_with_mode(ARITH, CfgMode::Lr).unwrap();
+        run_cached(&trie, &mut cfg, "((1)+2)*3");
+        run_cached(&trie, &mut cfg, " + (12)");
+        assert!(cfg.mask_cache_bytes > 0);
+
+        // a forked sequence starts with a copy of the cache, but then goes elsewhere;
+        // "(1" and "((1" have the same lexer state, but only the second allows "))"
+        for s in ["(((1)))*(2)", "(1)", "((12+1))+1"] {
+            let mut fork = CfgParser::from_yacc_with_mode(ARITH, CfgMode::Lr).unwrap();
+            fork.mask_cache = std::mem::take(&mut cfg.mask_cache);
+            fork.mask_cache_bytes = cfg.mask_cache_bytes;
+            run_cached(&trie, &mut fork, s);
+            cfg = fork;
+        }
+        let (hits, misses) = {
+            let s = cfg.stats.borrow();
+            (s.mask_hits, s.mask_misses)
+        };
+        assert!(hits > 0 && misses > 0, "{hits} {misses}");
+    }
+
+    #[test]
+    fn earley_non_lr_grammar() {
+        let mut cfg = CfgParser::from_yacc_with_mode(NOT_LR1, CfgMode::Earley).unwrap();
+        assert_eq!(cfg.mode(), CfgMode::Earley);
+        assert!(accepts(&mut cfg, "w x y"));
+        assert!(accepts(&mut cfg, "w x z"));
+        assert!(!accepts(&mut cfg, "w x"));
+        assert!(!accepts(&mut cfg, "w y"));
+        assert!(!accepts(&mut cfg, "w x y z"));
+    }
+
+    #[test]
+    fn auto_non_lr_grammar() {
+        assert!(CfgParser::from_yacc_with_mode(NOT_LR1, CfgMode::Lr).is_err());
+        let mut cfg = CfgParser::from_yacc_with_mode(NOT_LR1, CfgMode::Auto).unwrap();
+        assert_eq!(cfg.mode(), CfgMode::Earley);
+        // the LR table resolves the reduce/reduce conflict on "w" in favor of `a`
+        assert!(accepts(&mut cfg, "w x z"));
+        assert!(accepts(&mut cfg, "w x y"));
+
+        let mut cfg = CfgParser::from_yacc_with_mode(AMBIGUOUS, CfgMode::Auto).unwrap();
+        assert_eq!(cfg.mode(), CfgMode::Earley);
+        assert!(accepts(&mut cfg, "1 + 2 * 3"));
+
+        let cfg = CfgParser::from_yacc_with_mode(ARITH, CfgMode::Auto).unwrap();
+        assert_eq!(cfg.mode(), CfgMode::Lr);
+    }
+
+    #[test]
+    fn earley_ambiguous_grammar() {
+        let mut cfg = CfgParser::from_yacc_with_mode(AMBIGUOUS, CfgMode::Earley).unwrap();
+        assert!(accepts(&mut cfg, "1"));
+        assert!(accepts(&mut cfg, "1 + 2 * 3"));
+        assert!(accepts(&mut cfg, "(1+2)*(3)"));
+        assert!(!accepts(&mut cfg, "1 +"));
+        assert!(!accepts(&mut cfg, "(1 + 2"));
+        assert!(!accepts(&mut cfg, "1 2"));
+    }
+
+    #[test]
+    fn lark_json() {
+        let mut cfg = CfgParser::from_grammar(
+            r#"
+start: value
+?value: object | array | ESCAPED_STRING | SIGNED_NUMBER | "true" | "false" | "null"
+array: "[" [value ("," value)*] "]"
+object: "{" [pair ("," pair)*] "}"
+pair: ESCAPED_STRING ":" value
+
+%import common (ESCAPED_STRING, SIGNED_NUMBER, WS)
+%ignore WS
+"#,
+        )
+        .unwrap();
+        assert!(accepts(&mut cfg, r#"{"a": [1, -2.5, true], "b\"": null}"#));
+        assert!(accepts(&mut cfg, "[]"));
+        assert!(!accepts(&mut cfg, r#"{"a" 1}"#));
+        assert!(!accepts(&mut cfg, "[1,]"));
+    }
+
+    #[test]
+    fn compiled_round_trip() {
+        for mode in [CfgMode::Lr, CfgMode::Earley] {
+            let bytes = CfgParser::compile_to_bytes(ARITH, mode).unwrap();
+            let mut cfg = CfgParser::from_bytes(&bytes).unwrap();
+            assert_eq!(cfg.mode(), mode);
+            assert!(accepts(&mut cfg, "1 * (2 + 3)"));
+            assert!(!accepts(&mut cfg, "1 * (2 + 3"));
+        }
+
+        let mut bytes = CfgParser::compile_to_bytes(NOT_LR1, CfgMode::Earley).unwrap();
+        bytes[0] ^= 0xff;
+        let err = CfgParser::from_bytes(&bytes).err().unwrap().to_string();
+        assert!(err.contains("expecting"), "{err}");
+    }
+
+    #[test]
+    fn compiled_mismatch() {
+        let bytes = CfgParser::compile_to_bytes(ARITH, CfgMode::Lr).unwrap();
+        let load = |f: &dyn Fn(&mut CfgTables)| {
+            let mut compiled = decode_compiled(&bytes).unwrap();
+            f(&mut compiled.tables);
+            let bytes = bincode::serialize(&compiled).unwrap();
+            CfgParser::from_bytes(&bytes).err().unwrap().to_string()
+        };
+        let err = load(&|t| t.viable_vobidx_by_state.truncate(1));
+        assert!(err.contains("viable sets"), "{err}");
+        let err = load(&|t| t.viable_vobidx_by_state[0] = VobIdx::new(10_000));
+        assert!(err.contains("missing token sets"), "{err}");
+        let err = load(&|t| t.vobset = VobSet::from_vobs(vec![]));
+        assert!(err.contains("token sets"), "{err}");
+        let err = load(&|t| {
+            t.pat_idx_to_tidx.pop();
+        });
+        assert!(err.contains("patterns"), "{err}");
+
+        let lexer = bincode::serialize(&decode_compiled(&bytes).unwrap().tables.lexer).unwrap();
+        let (dfa, mut offs): (Vec<u8>, Vec<VobIdx>) = bincode::deserialize(&lexer).unwrap();
+        offs.truncate(1);
+        let lexer = bincode::serialize(&(dfa, offs)).unwrap();
+        let err = bincode::deserialize::<Lexer>(&lexer)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("reachable sets"), "{err}");
+    }
+
+    #[test]
+    fn lr_and_earley_agree() {
+        let mut lr = CfgParser::from_yacc_with_mode(ARITH, CfgMode::Lr).unwrap();
+        let mut earley = CfgParser::from_yacc_with_mode(ARITH, CfgMode::Earley).unwrap();
+        for s in ["12", "1*2+3", "((4))", "1+", ")", "1 * (2 + 3) * 4"] {
+            assert_eq!(accepts(&mut lr, s), accepts(&mut earley, s), "{s:?}");
+        }
+    }
+}
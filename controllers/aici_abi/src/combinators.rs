@@ -0,0 +1,519 @@
+//! Combinators building new recognizers out of existing ones.
+//!
+//! The combined recognizer is walked over the token trie just like any other [Recognizer],
+//! so the token mask for e.g. `And` comes from a single trie walk,
+//! where a branch is cut as soon as either side rejects the byte,
+//! rather than from two full masks that are intersected afterwards.
+//!
+//! The parts are boxed, so they can be either concrete types or `dyn Recognizer`.
+
+use crate::toktrie::{Recognizer, SpecialToken};
+
+pub type BoxedRecognizer = Box<dyn Recognizer>;
+
+/// Bytes are allowed only when both recognizers allow them.
+pub struct And<A: ?Sized, B: ?Sized> {
+    a: Box<A>,
+    b: Box<B>,
+}
+
+impl<A: Recognizer + ?Sized, B: Recognizer + ?Sized> And<A, B> {
+    pub fn new(a: Box<A>, b: Box<B>) -> Self {
+        And { a, b }
+    }
+}
+
+impl<A: Recognizer + ?Sized, B: Recognizer + ?Sized> Recognizer for And<A, B> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.a.pop_bytes(num);
+        self.b.pop_bytes(num);
+    }
+
+    fn collapse(&mut self) {
+        self.a.collapse();
+        self.b.collapse();
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.a.special_allowed(tok) && self.b.special_allowed(tok)
+    }
+
+    fn trie_finished(&mut self) {
+        self.a.trie_finished();
+        self.b.trie_finished();
+    }
+
+    #[inline(always)]
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        if !self.a.try_push_byte(byte) {
+            return false;
+        }
+        if !self.b.try_push_byte(byte) {
+            self.a.pop_bytes(1);
+            return false;
+        }
+        true
+    }
+}
+
+/// Bytes are allowed when either recognizer allows them.
+/// Once a recognizer rejects a byte, it's not consulted anymore (until the byte is popped).
+pub struct Or<A: ?Sized, B: ?Sized> {
+    a: Box<A>,
+    b: Box<B>,
+    // which of a and b are still alive after each byte
+    stack: Vec<(bool, bool)>,
+}
+
+impl<A: Recognizer + ?Sized, B: Recognizer + ?Sized> Or<A, B> {
+    pub fn new(a: Box<A>, b: Box<B>) -> Self {
+        Or {
+            a,
+            b,
+            stack: vec![(true, true)],
+        }
+    }
+
+    fn top(&self) -> (bool, bool) {
+        *self.stack.last().unwrap()
+    }
+}
+
+impl<A: Recognizer + ?Sized, B: Recognizer + ?Sized> Recognizer for Or<A, B> {
+    fn pop_bytes(&mut self, num: usize) {
+        let len = self.stack.len() - num;
+        let num_a = self.stack[len..].iter().filter(|(a, _)| *a).count();
+        let num_b = self.stack[len..].iter().filter(|(_, b)| *b).count();
+        self.stack.truncate(len);
+        self.a.pop_bytes(num_a);
+        self.b.pop_bytes(num_b);
+    }
+
+    fn collapse(&mut self) {
+        let top = self.top();
+        self.a.collapse();
+        self.b.collapse();
+        self.stack.clear();
+        self.stack.push(top);
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        let (live_a, live_b) = self.top();
+        (live_a && self.a.special_allowed(tok)) || (live_b && self.b.special_allowed(tok))
+    }
+
+    fn trie_finished(&mut self) {
+        assert!(self.stack.len() == 1);
+        self.a.trie_finished();
+        self.b.trie_finished();
+    }
+
+    #[inline(always)]
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let (live_a, live_b) = self.top();
+        let live_a = live_a && self.a.try_push_byte(byte);
+        let live_b = live_b && self.b.try_push_byte(byte);
+        if live_a || live_b {
+            self.stack.push((live_a, live_b));
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Output of `a` followed by output of `b`.
+/// The switch to `b` is greedy: it happens on the first byte that `a` rejects,
+/// provided `a` allows EOS at that point.
+/// Thus, `a` shouldn't allow continuing with the bytes that `b` starts with.
+pub struct Seq<A: ?Sized, B: ?Sized> {
+    a: Box<A>,
+    b: Box<B>,
+    // was the byte passed to b?
+    stack: Vec<bool>,
+}
+
+impl<A: Recognizer + ?Sized, B: Recognizer + ?Sized> Seq<A, B> {
+    pub fn new(a: Box<A>, b: Box<B>) -> Self {
+        Seq {
+            a,
+            b,
+            stack: vec![false],
+        }
+    }
+
+    fn in_b(&self) -> bool {
+        *self.stack.last().unwrap()
+    }
+}
+
+impl<A: Recognizer + ?Sized, B: Recognizer + ?Sized> Recognizer for Seq<A, B> {
+    fn pop_bytes(&mut self, num: usize) {
+        let len = self.stack.len() - num;
+        let num_b = self.stack[len..].iter().filter(|in_b| **in_b).count();
+        self.stack.truncate(len);
+        self.a.pop_bytes(num - num_b);
+        self.b.pop_bytes(num_b);
+    }
+
+    fn collapse(&mut self) {
+        let in_b = self.in_b();
+        self.a.collapse();
+        self.b.collapse();
+        self.stack.clear();
+        self.stack.push(in_b);
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        if self.in_b() {
+            self.b.special_allowed(tok)
+        } else if tok == SpecialToken::EndOfSentence {
+            self.a.special_allowed(tok) && self.b.special_allowed(tok)
+        } else {
+            self.a.special_allowed(tok)
+        }
+    }
+
+    fn trie_finished(&mut self) {
+        assert!(self.stack.len() == 1);
+        self.a.trie_finished();
+        self.b.trie_finished();
+    }
+
+    #[inline(always)]
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let in_b = if self.in_b() {
+            if !self.b.try_push_byte(byte) {
+                return false;
+            }
+            true
+        } else if self.a.try_push_byte(byte) {
+            false
+        } else if self.a.special_allowed(SpecialToken::EndOfSentence) && self.b.try_push_byte(byte)
+        {
+            true
+        } else {
+            return false;
+        };
+        self.stack.push(in_b);
+        true
+    }
+}
+
+/// Output of `a` repeated between `min` and `max` times.
+/// As with [Seq], a new repetition starts only when `a` rejects a byte and allows EOS.
+///
+/// `a` is never collapsed, so that it can be popped back to its initial state
+/// when starting a new repetition; popping past the start of a repetition
+/// replays the bytes of the previous one.
+pub struct Repeat<A: ?Sized> {
+    a: Box<A>,
+    min: usize,
+    max: usize,
+    // bytes allowed in the initial state of a
+    first_bytes: Vec<bool>,
+    // bytes of repetitions started since last collapse (the last one is the current one)
+    iters: Vec<Vec<u8>>,
+    // number of repetitions before the ones in `iters`
+    num_collapsed: usize,
+    // did the byte start a new repetition?
+    stack: Vec<bool>,
+}
+
+impl<A: Recognizer + ?Sized> Repeat<A> {
+    /// `a` has to be in its initial state.
+    pub fn new(mut a: Box<A>, min: usize, max: Option<usize>) -> Self {
+        let first_bytes = (0..=255u8)
+            .map(|byte| {
+                if a.try_push_byte(byte) {
+                    a.pop_bytes(1);
+                    true
+                } else {
+                    false
+                }
+            })
+            .collect();
+        Repeat {
+            a,
+            min,
+            max: max.unwrap_or(usize::MAX),
+            first_bytes,
+            iters: vec![vec![]],
+            num_collapsed: 0,
+            stack: vec![],
+        }
+    }
+
+    fn curr_len(&self) -> usize {
+        self.iters.last().unwrap().len()
+    }
+
+    fn num_iters(&self) -> usize {
+        // the current repetition is empty only before the first byte
+        self.num_collapsed + self.iters.len() - (self.curr_len() == 0) as usize
+    }
+}
+
+impl<A: Recognizer + ?Sized> Recognizer for Repeat<A> {
+    fn pop_bytes(&mut self, num: usize) {
+        let mut num_a = 0;
+        for _ in 0..num {
+            if self.stack.pop().unwrap() {
+                self.a.pop_bytes(num_a + 1);
+                num_a = 0;
+                self.iters.pop();
+                for &byte in self.iters.last().unwrap() {
+                    let ok = self.a.try_push_byte(byte);
+                    assert!(ok);
+                }
+            } else {
+                self.iters.last_mut().unwrap().pop();
+                num_a += 1;
+            }
+        }
+        self.a.pop_bytes(num_a);
+    }
+
+    fn collapse(&mut self) {
+        let curr = self.iters.pop().unwrap();
+        self.num_collapsed += self.iters.len();
+        self.iters.clear();
+        self.iters.push(curr);
+        self.stack.clear();
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        match tok {
+            SpecialToken::EndOfSentence => {
+                if self.curr_len() == 0 {
+                    self.min == 0
+                } else {
+                    self.num_iters() >= self.min && self.a.special_allowed(tok)
+                }
+            }
+            _ => self.a.special_allowed(tok),
+        }
+    }
+
+    fn trie_finished(&mut self) {
+        // a is not collapsed, so its own check would fail
+        assert!(self.stack.is_empty());
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let curr_len = self.curr_len();
+        if curr_len == 0 && self.max == 0 {
+            return false;
+        }
+        if self.a.try_push_byte(byte) {
+            self.iters.last_mut().unwrap().push(byte);
+            self.stack.push(false);
+            return true;
+        }
+        if curr_len > 0
+            && self.first_bytes[byte as usize]
+            && self.num_iters() < self.max
+            && self.a.special_allowed(SpecialToken::EndOfSentence)
+        {
+            self.a.pop_bytes(curr_len);
+            let ok = self.a.try_push_byte(byte);
+            assert!(ok);
+            self.iters.push(vec![byte]);
+            self.stack.push(true);
+            return true;
+        }
+        false
+    }
+}
+
+/// Output of `a`, but no longer than `max_bytes`.
+pub struct MaxBytes<A: ?Sized> {
+    a: Box<A>,
+    max_bytes: usize,
+    num_bytes: usize,
+}
+
+impl<A: Recognizer + ?Sized> MaxBytes<A> {
+    pub fn new(a: Box<A>, max_bytes: usize) -> Self {
+        MaxBytes {
+            a,
+            max_bytes,
+            num_bytes: 0,
+        }
+    }
+}
+
+impl<A: Recognizer + ?Sized> Recognizer for MaxBytes<A> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.num_bytes -= num;
+        self.a.pop_bytes(num);
+    }
+
+    fn collapse(&mut self) {
+        self.a.collapse();
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.a.special_allowed(tok)
+    }
+
+    fn trie_finished(&mut self) {
+        self.a.trie_finished();
+    }
+
+    #[inline(always)]
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        if self.num_bytes < self.max_bytes && self.a.try_push_byte(byte) {
+            self.num_bytes += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accepts;
+
+    /// Accepts exactly the given string.
+    struct Lit {
+        s: Vec<u8>,
+        stack: Vec<usize>,
+    }
+
+    fn lit(s: &str) -> Box<Lit> {
+        Box::new(Lit {
+            s: s.as_bytes().to_vec(),
+            stack: vec![0],
+        })
+    }
+
+    impl Recognizer for Lit {
+        fn pop_bytes(&mut self, num: usize) {
+            self.stack.truncate(self.stack.len() - num);
+        }
+
+        fn collapse(&mut self) {
+            let top = *self.stack.last().unwrap();
+            self.stack.clear();
+            self.stack.push(top);
+        }
+
+        fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+            tok == SpecialToken::EndOfSentence && *self.stack.last().unwrap() == self.s.len()
+        }
+
+        fn trie_finished(&mut self) {
+            assert!(self.stack.len() == 1);
+        }
+
+        fn try_push_byte(&mut self, byte: u8) -> bool {
+            let pos = *self.stack.last().unwrap();
+            if self.s.get(pos) == Some(&byte) {
+                self.stack.push(pos + 1);
+                true
+            } else {
+                false
+            }
+        }
+    }
+
+    fn push_all(r: &mut dyn Recognizer, s: &str) -> Option<usize> {
+        for (idx, b) in s.bytes().enumerate() {
+            if !r.try_push_byte(b) {
+                r.pop_bytes(idx);
+                return None;
+            }
+        }
+        Some(s.len())
+    }
+
+    #[test]
+    fn and() {
+        let mut r = And::new(lit("ab"), lit("abc"));
+        assert!(!accepts(&mut r, "ab"));
+        assert!(!accepts(&mut r, "abc"));
+        let mut r = And::new(lit("ab"), lit("ab"));
+        assert!(accepts(&mut r, "ab"));
+        assert!(!accepts(&mut r, "a"));
+    }
+
+    #[test]
+    fn or() {
+        let mut r = Or::new(lit("ab"), lit("cd"));
+        assert!(accepts(&mut r, "ab"));
+        assert!(accepts(&mut r, "cd"));
+        assert!(!accepts(&mut r, "ac"));
+        assert!(!accepts(&mut r, "a"));
+        let mut r = Or::new(lit("ab"), lit("abc"));
+        assert!(accepts(&mut r, "ab"));
+        assert!(accepts(&mut r, "abc"));
+        assert!(!accepts(&mut r, "abcd"));
+    }
+
+    #[test]
+    fn seq() {
+        let mut r = Seq::new(lit("ab"), lit("cd"));
+        assert!(accepts(&mut r, "abcd"));
+        assert!(!accepts(&mut r, "ab"));
+        assert!(!accepts(&mut r, "abc"));
+        assert!(!accepts(&mut r, "cd"));
+        assert!(!accepts(&mut r, "acd"));
+    }
+
+    #[test]
+    fn repeat() {
+        let mut r = Repeat::new(lit("ab"), 2, Some(3));
+        assert!(!accepts(&mut r, ""));
+        assert!(!accepts(&mut r, "ab"));
+        assert!(!accepts(&mut r, "aba"));
+        assert!(accepts(&mut r, "abab"));
+        assert!(accepts(&mut r, "ababab"));
+        assert!(!accepts(&mut r, "abababab"));
+        let mut r = Repeat::new(lit("ab"), 0, None);
+        assert!(accepts(&mut r, ""));
+        assert!(accepts(&mut r, "abababab"));
+        let mut r = Repeat::new(lit("ab"), 0, Some(0));
+        assert!(accepts(&mut r, ""));
+        assert!(!accepts(&mut r, "ab"));
+    }
+
+    #[test]
+    fn repeat_pop_and_collapse() {
+        let mut r = Repeat::new(lit("ab"), 2, None);
+        assert_eq!(push_all(&mut r, "abab"), Some(4));
+        // back into the first repetition
+        r.pop_bytes(3);
+        assert!(!r.special_allowed(SpecialToken::EndOfSentence));
+        assert_eq!(push_all(&mut r, "bab"), Some(3));
+        assert!(r.special_allowed(SpecialToken::EndOfSentence));
+        r.pop_bytes(4);
+        r.trie_finished();
+
+        assert_eq!(push_all(&mut r, "aba"), Some(3));
+        r.collapse();
+        assert!(accepts(&mut r, "b"));
+        assert!(accepts(&mut r, "bab"));
+        assert!(!accepts(&mut r, "a"));
+    }
+
+    #[test]
+    fn max_bytes() {
+        let mut r = MaxBytes::new(Box::new(Repeat::new(lit("ab"), 1, None)), 4);
+        assert!(accepts(&mut r, "ab"));
+        assert!(accepts(&mut r, "abab"));
+        assert!(!accepts(&mut r, "ababab"));
+    }
+
+    #[test]
+    fn boxed() {
+        let a: BoxedRecognizer = lit("ab");
+        let b: BoxedRecognizer = Box::new(Or::new(lit("c"), lit("d")));
+        let mut r = Seq::new(a, b);
+        assert!(accepts(&mut r, "abc"));
+        assert!(accepts(&mut r, "abd"));
+        assert!(!accepts(&mut r, "abcd"));
+    }
+}
@@ -1,8 +1,9 @@
 use crate::{
-    recognizer::{FunctionalRecognizer, StackRecognizer},
+    recognizer::{FunctionalRecognizer, Recognizer},
     toktrie::SpecialToken,
     SimpleVob,
 };
+use std::{cell::RefCell, collections::HashMap};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct NodeId(u32);
@@ -170,30 +171,90 @@ impl Trie {
     }
 }
 
+/// Lexer allowing only entries from a (dynamic) list, separated by non-identifier characters.
+/// Entries can be single identifiers, or phrases containing spaces, punctuation etc.
+/// Identifier characters are ASCII letters, digits and `_`, non-ASCII (Unicode) letters,
+/// digits and combining marks, as well as the `additional_id_chars`.
+/// An entry can only end where the next character is not an identifier character
+/// (so "New York" doesn't match "New Yorker").
+/// Between entries, any characters that can't start an identifier (i.e., other than letters,
+/// `_` and `additional_id_chars`) are allowed.
+/// With `case_insensitive`, both entries and input are lowercased before matching.
 pub struct DynamicLexer {
     trie: Trie,
     id_start: SimpleVob,
     id_body: SimpleVob,
+    additional_id_chars: Vec<char>,
+    case_insensitive: bool,
+    node_sets: RefCell<NodeSets>,
+    node_sets_limit: usize,
+}
+
+// interned node sets are dropped on collapse() above this size
+const DEFAULT_NODE_SETS_LIMIT: usize = 4 << 20;
+
+// set of trie nodes (alternative matches so far); NodeId::ROOT means "between entries";
+// single nodes are stored directly, larger sets are interned in NodeSets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct NodeSet(u32);
+
+impl NodeSet {
+    const INTERNED: u32 = 1 << 31;
+
+    fn single(node_id: NodeId) -> Self {
+        NodeSet(node_id.0)
+    }
+}
+
+#[derive(Default)]
+struct NodeSets {
+    sets: Vec<Vec<NodeId>>,
+    ids: HashMap<Vec<NodeId>, u32>,
+    // approximate
+    bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CharClass {
+    // can start an identifier
+    Start,
+    // can only continue an identifier (eg., digits)
+    Body,
+    Other,
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct DState {
-    node_id: NodeId,
+    nodes: NodeSet,
+    // bytes of incomplete UTF-8 character
+    pending: [u8; 4],
+    pending_len: u8,
 }
 
 impl DState {
     const ROOT: DState = DState {
-        node_id: NodeId::ROOT,
+        nodes: NodeSet(NodeId::ROOT.0),
+        pending: [0; 4],
+        pending_len: 0,
     };
 }
 
-pub type DynamicLexerRec = StackRecognizer<DState, DynamicLexer>;
+fn is_combining_mark(c: char) -> bool {
+    // letters with Other_Alphabetic marks (eg., Indic vowel signs) are handled by is_alphabetic()
+    matches!(c,
+        '\u{0300}'..='\u{036F}'
+        | '\u{1AB0}'..='\u{1AFF}'
+        | '\u{1DC0}'..='\u{1DFF}'
+        | '\u{200C}'..='\u{200D}'
+        | '\u{20D0}'..='\u{20FF}'
+        | '\u{FE20}'..='\u{FE2F}')
+}
 
 impl DynamicLexer {
-    pub fn new(additional_id_chars: &Vec<char>) -> Self {
-        let mut id_start = SimpleVob::alloc(0x100);
-        let mut id_body = SimpleVob::alloc(0x100);
-        for i in 0..=255u8 {
+    pub fn new(additional_id_chars: &Vec<char>, case_insensitive: bool) -> Self {
+        let mut id_start = SimpleVob::alloc(0x80);
+        let mut id_body = SimpleVob::alloc(0x80);
+        for i in 0..0x80u8 {
             match i as char {
                 'a'..='z' | 'A'..='Z' | '_' => {
                     id_start.allow_token(i as u32);
@@ -206,61 +267,385 @@ impl DynamicLexer {
             }
         }
         for &c in additional_id_chars {
-            id_start.allow_token(c as u32);
-            id_body.allow_token(c as u32);
+            if c.is_ascii() {
+                id_start.allow_token(c as u32);
+                id_body.allow_token(c as u32);
+            }
         }
         DynamicLexer {
             trie: Trie::new(),
             id_start,
             id_body,
+            additional_id_chars: additional_id_chars.clone(),
+            case_insensitive,
+            node_sets: RefCell::new(NodeSets::default()),
+            node_sets_limit: DEFAULT_NODE_SETS_LIMIT,
         }
     }
 
-    pub fn to_stack_recognizer(self) -> StackRecognizer<DState, DynamicLexer> {
-        StackRecognizer::from(self)
+    pub fn to_stack_recognizer(self) -> DynamicLexerRec {
+        DynamicLexerRec {
+            lexer: self,
+            stack: vec![DState::ROOT],
+        }
     }
 
+    /// Allow given entry (identifier or phrase).
+    /// The bytes are expected to be UTF-8.
     pub fn add(&mut self, word: &[u8]) {
-        self.trie.add(word);
+        if self.case_insensitive {
+            let mut normalized = Vec::with_capacity(word.len());
+            let mut buf = [0u8; 16];
+            for c in String::from_utf8_lossy(word).chars() {
+                normalized.extend_from_slice(self.normalize(c, &mut buf));
+            }
+            self.trie.add(&normalized);
+        } else {
+            self.trie.add(word);
+        }
     }
-}
 
-impl FunctionalRecognizer<DState> for DynamicLexer {
-    fn initial(&self) -> DState {
-        DState::ROOT
+    /// Allow each non-empty line of `lines` (with surrounding whitespace removed) as an entry.
+    /// This is much faster than calling [DynamicLexer::add] for every line from Python or JS.
+    /// Returns the number of lines added.
+    pub fn add_lines(&mut self, lines: &str) -> usize {
+        let mut num_added = 0;
+        for line in lines.lines() {
+            let line = line.trim();
+            if !line.is_empty() {
+                self.add(line.as_bytes());
+                num_added += 1;
+            }
+        }
+        num_added
     }
 
-    fn try_append(&self, state: DState, byte: u8) -> Option<DState> {
-        if state.node_id == NodeId::ROOT {
-            if self.id_start.is_allowed(byte as u32) {
-                match self.trie.child_at(state.node_id, byte) {
-                    Some(node_id) => Some(DState { node_id }),
-                    None => None,
-                }
+    fn char_class(&self, c: char) -> CharClass {
+        if c.is_ascii() {
+            if self.id_start.is_allowed(c as u32) {
+                CharClass::Start
+            } else if self.id_body.is_allowed(c as u32) {
+                CharClass::Body
             } else {
-                Some(state)
+                CharClass::Other
+            }
+        } else if c.is_alphabetic() || self.additional_id_chars.contains(&c) {
+            CharClass::Start
+        } else if c.is_numeric() || is_combining_mark(c) {
+            CharClass::Body
+        } else {
+            CharClass::Other
+        }
+    }
+
+    // lowercase (when case-insensitive) and UTF-8 encode
+    fn normalize<'a>(&self, c: char, buf: &'a mut [u8; 16]) -> &'a [u8] {
+        if !self.case_insensitive {
+            c.encode_utf8(buf).as_bytes()
+        } else if c.is_ascii() {
+            buf[0] = c.to_ascii_lowercase() as u8;
+            &buf[0..1]
+        } else {
+            // at most 3 chars
+            let mut len = 0;
+            for lc in c.to_lowercase() {
+                len += lc.encode_utf8(&mut buf[len..]).len();
+            }
+            &buf[0..len]
+        }
+    }
+
+    fn is_terminal(&self, node_id: NodeId) -> bool {
+        self.trie.node_data(node_id).is_terminal
+    }
+
+    fn intern(&self, mut nodes: Vec<NodeId>) -> Option<NodeSet> {
+        nodes.sort_unstable();
+        nodes.dedup();
+        match nodes.len() {
+            0 => None,
+            1 => Some(NodeSet::single(nodes[0])),
+            _ => {
+                let mut node_sets = self.node_sets.borrow_mut();
+                if let Some(id) = node_sets.ids.get(&nodes) {
+                    return Some(NodeSet(*id | NodeSet::INTERNED));
+                }
+                let id = node_sets.sets.len() as u32;
+                node_sets.bytes += 2 * nodes.len() * std::mem::size_of::<NodeId>() + 64;
+                node_sets.sets.push(nodes.clone());
+                node_sets.ids.insert(nodes, id);
+                Some(NodeSet(id | NodeSet::INTERNED))
             }
+        }
+    }
+
+    /// Number of interned sets of alternative matches.
+    pub fn num_node_sets(&self) -> usize {
+        self.node_sets.borrow().sets.len()
+    }
+
+    /// Drop all interned node sets, except for the one of `live`, which is returned re-interned.
+    fn flush(&self, live: DState) -> DState {
+        let nodes = self.with_nodes(live.nodes, |nodes| nodes.to_vec());
+        *self.node_sets.borrow_mut() = NodeSets::default();
+        DState {
+            nodes: self.intern(nodes).unwrap(),
+            ..live
+        }
+    }
+
+    fn with_nodes<R>(&self, nodes: NodeSet, f: impl FnOnce(&[NodeId]) -> R) -> R {
+        if nodes.0 & NodeSet::INTERNED == 0 {
+            f(&[NodeId(nodes.0)])
         } else {
-            if self.id_body.is_allowed(byte as u32) {
-                match self.trie.child_at(state.node_id, byte) {
-                    Some(node_id) => Some(DState { node_id }),
-                    None => None,
+            let idx = (nodes.0 & !NodeSet::INTERNED) as usize;
+            f(&self.node_sets.borrow().sets[idx])
+        }
+    }
+
+    fn append_char(&self, nodes: NodeSet, c: char) -> Option<NodeSet> {
+        let class = self.char_class(c);
+        let mut buf = [0u8; 16];
+        let bytes = self.normalize(c, &mut buf);
+
+        // fast path - no alternatives
+        if nodes.0 & NodeSet::INTERNED == 0 {
+            let node_id = NodeId(nodes.0);
+            if node_id == NodeId::ROOT {
+                if class == CharClass::Start {
+                    return self.trie.lookup(node_id, bytes).map(NodeSet::single);
                 }
-            } else {
-                if self.trie.node_data(state.node_id).is_terminal {
-                    Some(DState::ROOT)
+            } else if class != CharClass::Other || !self.is_terminal(node_id) {
+                return self.trie.lookup(node_id, bytes).map(NodeSet::single);
+            }
+        }
+
+        let next = self.with_nodes(nodes, |nodes| {
+            let mut next = Vec::new();
+            let mut between = false;
+            for &node_id in nodes {
+                if node_id == NodeId::ROOT {
+                    between = true;
                 } else {
-                    None
+                    next.extend(self.trie.lookup(node_id, bytes));
+                    // the entry can end here
+                    if class == CharClass::Other && self.is_terminal(node_id) {
+                        between = true;
+                    }
+                }
+            }
+            if between {
+                // start a new entry, unless we're in the middle of a word
+                next.extend(self.trie.lookup(NodeId::ROOT, bytes));
+                if class != CharClass::Start {
+                    next.push(NodeId::ROOT);
                 }
             }
+            next
+        });
+        self.intern(next)
+    }
+}
+
+impl FunctionalRecognizer<DState> for DynamicLexer {
+    fn initial(&self) -> DState {
+        DState::ROOT
+    }
+
+    fn try_append(&self, state: DState, byte: u8) -> Option<DState> {
+        if state.pending_len == 0 && byte < 0x80 {
+            return self
+                .append_char(state.nodes, byte as char)
+                .map(|nodes| DState {
+                    nodes,
+                    ..DState::ROOT
+                });
+        }
+        let mut state = state;
+        state.pending[state.pending_len as usize] = byte;
+        state.pending_len += 1;
+        match std::str::from_utf8(&state.pending[0..state.pending_len as usize]) {
+            Ok(s) => {
+                let c = s.chars().next().unwrap();
+                self.append_char(state.nodes, c).map(|nodes| DState {
+                    nodes,
+                    ..DState::ROOT
+                })
+            }
+            // incomplete character
+            Err(e) if e.error_len().is_none() => Some(state),
+            Err(_) => None,
         }
     }
 
     fn special_allowed(&self, state: DState, tok: SpecialToken) -> bool {
+        // only right after an entry; not at the start, nor after separators
         if tok == SpecialToken::EndOfSentence {
-            self.trie.node_data(state.node_id).is_terminal
+            state.pending_len == 0
+                && self.with_nodes(state.nodes, |nodes| {
+                    nodes
+                        .iter()
+                        .any(|&n| n != NodeId::ROOT && self.is_terminal(n))
+                })
         } else {
             false
         }
     }
 }
+
+/// Stack-based recognizer for [DynamicLexer], to be used with trie walks.
+/// Unlike generic `StackRecognizer`, it drops interned node sets (when over the limit)
+/// on `collapse()`.
+pub struct DynamicLexerRec {
+    lexer: DynamicLexer,
+    stack: Vec<DState>,
+}
+
+impl DynamicLexerRec {
+    pub fn recognizer(&self) -> &DynamicLexer {
+        &self.lexer
+    }
+
+    pub fn recognizer_mut(&mut self) -> &mut DynamicLexer {
+        &mut self.lexer
+    }
+
+    fn top(&self) -> DState {
+        *self.stack.last().unwrap()
+    }
+}
+
+impl Recognizer for DynamicLexerRec {
+    #[inline(always)]
+    fn pop_bytes(&mut self, num: usize) {
+        self.stack.truncate(self.stack.len() - num);
+    }
+
+    fn collapse(&mut self) {
+        let mut state = self.top();
+        if self.lexer.node_sets.borrow().bytes > self.lexer.node_sets_limit {
+            state = self.lexer.flush(state);
+        }
+        self.stack.clear();
+        self.stack.push(state);
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.lexer.special_allowed(self.top(), tok)
+    }
+
+    fn trie_finished(&mut self) {
+        assert!(self.stack.len() == 1);
+    }
+
+    #[inline(always)]
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        match self.lexer.try_append(self.top(), byte) {
+            Some(next) => {
+                self.stack.push(next);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepts(lex: &DynamicLexer, s: &str) -> bool {
+        let mut state = lex.initial();
+        for b in s.bytes() {
+            match lex.try_append(state, b) {
+                Some(next) => state = next,
+                None => return false,
+            }
+        }
+        lex.special_allowed(state, SpecialToken::EndOfSentence)
+    }
+
+    #[test]
+    fn phrases() {
+        let mut lex = DynamicLexer::new(&vec![], false);
+        lex.add(b"New York");
+        lex.add(b"New");
+        assert!(accepts(&lex, "New York"));
+        assert!(accepts(&lex, "New York, New"));
+        assert!(accepts(&lex, "(New"));
+        assert!(!accepts(&lex, "New Yorker"));
+        assert!(!accepts(&lex, "Newark"));
+        assert!(!accepts(&lex, "York"));
+    }
+
+    #[test]
+    fn eos_only_after_entry() {
+        let mut lex = DynamicLexer::new(&vec![], false);
+        lex.add(b"New York");
+        lex.add(b"New");
+        assert!(!accepts(&lex, ""));
+        assert!(!accepts(&lex, " "));
+        assert!(!accepts(&lex, "(New)"));
+        assert!(!accepts(&lex, "New "));
+        assert!(!accepts(&lex, "New York."));
+    }
+
+    #[test]
+    fn node_sets_flushed_on_collapse() {
+        let mut lex = DynamicLexer::new(&vec![], false);
+        for w in ["ab x", "ab", "cd y", "cd"] {
+            lex.add(w.as_bytes());
+        }
+        let mut rec = lex.to_stack_recognizer();
+        let push = |rec: &mut DynamicLexerRec, s: &str| s.bytes().all(|b| rec.try_push_byte(b));
+
+        assert!(push(&mut rec, "ab "));
+        rec.pop_bytes(3);
+        assert!(push(&mut rec, "cd "));
+        assert_eq!(rec.recognizer().num_node_sets(), 2);
+        rec.collapse();
+        assert_eq!(rec.recognizer().num_node_sets(), 2);
+
+        rec.recognizer_mut().node_sets_limit = 0;
+        rec.collapse();
+        assert_eq!(rec.recognizer().num_node_sets(), 1);
+        assert!(push(&mut rec, "y"));
+        assert!(rec.special_allowed(SpecialToken::EndOfSentence));
+        rec.pop_bytes(1);
+        assert!(!rec.special_allowed(SpecialToken::EndOfSentence));
+        assert!(!push(&mut rec, "x"));
+    }
+
+    #[test]
+    fn unicode_entries() {
+        let mut lex = DynamicLexer::new(&vec![], false);
+        lex.add("Zürich".as_bytes());
+        assert!(accepts(&lex, "Zürich"));
+        assert!(accepts(&lex, "Zürich! Zürich"));
+        assert!(!accepts(&lex, "Zürichs"));
+        assert!(!accepts(&lex, "Zurich"));
+        // incomplete UTF-8 character
+        let state = lex.try_append(lex.initial(), b'Z').unwrap();
+        let state = lex.try_append(state, 0xC3).unwrap();
+        assert!(!lex.special_allowed(state, SpecialToken::EndOfSentence));
+        assert!(lex.try_append(state, b'x').is_none());
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let mut lex = DynamicLexer::new(&vec![], true);
+        lex.add("Éclair".as_bytes());
+        assert!(accepts(&lex, "éCLAIR"));
+        assert!(accepts(&lex, "Éclair"));
+        assert!(!accepts(&lex, "eclair"));
+    }
+
+    #[test]
+    fn add_lines() {
+        let mut lex = DynamicLexer::new(&vec!['-'], false);
+        assert_eq!(lex.add_lines("foo\n\n  bar baz \nx-ray\n"), 3);
+        assert!(accepts(&lex, "bar baz foo x-ray"));
+        assert!(!accepts(&lex, "bar"));
+        assert!(!accepts(&lex, "x"));
+    }
+}
@@ -0,0 +1,247 @@
+use crate::lex::{VobIdx, VobSet};
+use cfgrammar::{yacc::YaccGrammar, Symbol, TIdx};
+use rustc_hash::{FxHashMap, FxHashSet};
+use vob::Vob;
+
+type StorageT = u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Item {
+    prod: u32,
+    dot: u32,
+    origin: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Sym {
+    Term(u32),
+    Rule(u32),
+}
+
+struct Row {
+    items: Vec<Item>,
+    viable: VobIdx,
+}
+
+/// Earley parser over lexemes of a yacc grammar.
+/// Rows of the chart are kept in a stack: row `i` describes parser state after `i` lexemes,
+/// and scanning after row `i` replaces all rows after it
+/// (this matches how `CfgParser` numbers its parse stacks).
+pub(crate) struct EarleyParser {
+    prods: Vec<Vec<Sym>>,
+    prod_rule: Vec<u32>,
+    rule_prods: Vec<Vec<u32>>,
+    nullable: Vec<bool>,
+    start_prod: u32,
+    eof: u32,
+    pat_idx_by_tidx: Vec<Option<usize>>,
+    skip_patterns: Vob,
+    rows: Vec<Row>,
+}
+
+impl EarleyParser {
+    pub fn new(
+        grm: &YaccGrammar<StorageT>,
+        tidx_to_pat_idx: &FxHashMap<TIdx<StorageT>, usize>,
+        skip_patterns: &Vob,
+        vobset: &mut VobSet,
+    ) -> Self {
+        let prods = grm
+            .iter_pidxs()
+            .map(|pidx| {
+                grm.prod(pidx)
+                    .iter()
+                    .map(|sym| match sym {
+                        Symbol::Rule(ridx) => Sym::Rule(ridx.as_storaget()),
+                        Symbol::Token(tidx) => Sym::Term(tidx.as_storaget()),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let prod_rule = grm
+            .iter_pidxs()
+            .map(|pidx| grm.prod_to_rule(pidx).as_storaget())
+            .collect::<Vec<_>>();
+        let rule_prods = grm
+            .iter_rules()
+            .map(|ridx| {
+                grm.rule_to_prods(ridx)
+                    .iter()
+                    .map(|pidx| pidx.as_storaget())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let mut nullable = vec![false; rule_prods.len()];
+        loop {
+            let mut changed = false;
+            for (pidx, prod) in prods.iter().enumerate() {
+                let ridx = prod_rule[pidx] as usize;
+                if !nullable[ridx]
+                    && prod.iter().all(|sym| match *sym {
+                        Sym::Rule(r) => nullable[r as usize],
+                        Sym::Term(_) => false,
+                    })
+                {
+                    nullable[ridx] = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        let mut pat_idx_by_tidx = vec![None; grm.iter_tidxs().count()];
+        for (tidx, pat_idx) in tidx_to_pat_idx {
+            pat_idx_by_tidx[tidx.as_storaget() as usize] = Some(*pat_idx);
+        }
+
+        let mut parser = EarleyParser {
+            prods,
+            prod_rule,
+            rule_prods,
+            nullable,
+            start_prod: grm.start_prod().as_storaget(),
+            eof: grm.eof_token_idx().as_storaget(),
+            pat_idx_by_tidx,
+            skip_patterns: skip_patterns.clone(),
+            rows: Vec::new(),
+        };
+
+        let items = parser.complete_row(
+            0,
+            vec![Item {
+                prod: parser.start_prod,
+                dot: 0,
+                origin: 0,
+            }],
+        );
+        let viable = parser.viable_for(&items, vobset);
+        parser.rows.push(Row { items, viable });
+        parser
+    }
+
+    fn next_sym(&self, item: Item) -> Option<Sym> {
+        self.prods[item.prod as usize]
+            .get(item.dot as usize)
+            .copied()
+    }
+
+    fn advance(item: Item) -> Item {
+        Item {
+            dot: item.dot + 1,
+            ..item
+        }
+    }
+
+    // run prediction and completion on `seeds`, which start row `row_idx`
+    fn complete_row(&self, row_idx: usize, seeds: Vec<Item>) -> Vec<Item> {
+        let mut seen = FxHashSet::default();
+        let mut items = Vec::new();
+        let mut new_items = seeds;
+        let mut idx = 0;
+        loop {
+            for item in new_items.drain(..) {
+                if seen.insert(item) {
+                    items.push(item);
+                }
+            }
+            if idx >= items.len() {
+                break;
+            }
+            let item = items[idx];
+            idx += 1;
+            match self.next_sym(item) {
+                Some(Sym::Term(_)) => {}
+                Some(Sym::Rule(ridx)) => {
+                    for pidx in &self.rule_prods[ridx as usize] {
+                        new_items.push(Item {
+                            prod: *pidx,
+                            dot: 0,
+                            origin: row_idx as u32,
+                        });
+                    }
+                    // Aycock-Horspool: skip over nullable rules right away,
+                    // so that completions with origin in this row are not needed
+                    if self.nullable[ridx as usize] {
+                        new_items.push(Self::advance(item));
+                    }
+                }
+                None => {
+                    let origin = item.origin as usize;
+                    if origin < row_idx {
+                        let lhs = Sym::Rule(self.prod_rule[item.prod as usize]);
+                        for parent in &self.rows[origin].items {
+                            if self.next_sym(*parent) == Some(lhs) {
+                                new_items.push(Self::advance(*parent));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        items
+    }
+
+    fn viable_for(&self, items: &[Item], vobset: &mut VobSet) -> VobIdx {
+        // skip patterns (whitespace) are always viable
+        let mut r = self.skip_patterns.clone();
+        for item in items {
+            if let Some(Sym::Term(tidx)) = self.next_sym(*item) {
+                if let Some(pat_idx) = self.pat_idx_by_tidx[tidx as usize] {
+                    r.set(pat_idx, true);
+                }
+            }
+        }
+        // this may happen after vobset.pre_compute(); it's fine as long as PRECOMPUTE_AND is off
+        vobset.insert_or_get(&r)
+    }
+
+    fn scan_items(&self, row_idx: usize, tidx: u32) -> Vec<Item> {
+        self.rows[row_idx]
+            .items
+            .iter()
+            .filter(|item| self.next_sym(**item) == Some(Sym::Term(tidx)))
+            .map(|item| Self::advance(*item))
+            .collect()
+    }
+
+    /// Lexemes that can follow after row `row_idx`.
+    pub fn viable(&self, row_idx: usize) -> VobIdx {
+        self.rows[row_idx].viable
+    }
+
+    /// Scan lexeme `tidx` after row `row_idx`, creating row `row_idx + 1`.
+    /// Returns false if the lexeme is not allowed there.
+    pub fn scan(&mut self, row_idx: usize, tidx: TIdx<StorageT>, vobset: &mut VobSet) -> bool {
+        let seeds = self.scan_items(row_idx, tidx.as_storaget());
+        if seeds.is_empty() {
+            return false;
+        }
+        self.rows.truncate(row_idx + 1);
+        let items = self.complete_row(row_idx + 1, seeds);
+        let viable = self.viable_for(&items, vobset);
+        self.rows.push(Row { items, viable });
+        true
+    }
+
+    /// Check if the input can end after row `row_idx`.
+    pub fn accepts(&self, row_idx: usize) -> bool {
+        let is_done = |items: &[Item]| {
+            items.iter().any(|item| {
+                item.prod == self.start_prod && item.origin == 0 && self.next_sym(*item).is_none()
+            })
+        };
+        if is_done(&self.rows[row_idx].items) {
+            return true;
+        }
+        // in case the start production ends with explicit EOF
+        let seeds = self.scan_items(row_idx, self.eof);
+        !seeds.is_empty() && is_done(&self.complete_row(row_idx + 1, seeds))
+    }
+
+    pub fn num_items(&self) -> usize {
+        self.rows.iter().map(|r| r.items.len()).sum()
+    }
+}
@@ -0,0 +1,391 @@
+use crate::toktrie::{Recognizer, SpecialToken};
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+
+/// Upper limit on [FuzzySubStrOptions::max_edits].
+pub const MAX_FUZZY_EDITS: u8 = 16;
+
+/// Options for [FuzzySubStrMatcher].
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FuzzySubStrOptions {
+    /// Maximum number of edits (inserted, deleted or replaced bytes) in the generated substring.
+    /// At most [MAX_FUZZY_EDITS].
+    #[serde(default)]
+    pub max_edits: u8,
+    /// Treat any run of whitespace (in the source and in the output) as a single space.
+    #[serde(default)]
+    pub normalize_whitespace: bool,
+    /// Ignore case of ASCII letters.
+    #[serde(default)]
+    pub case_insensitive: bool,
+}
+
+impl FuzzySubStrOptions {
+    /// True if these options only allow exact matches.
+    pub fn is_exact(&self) -> bool {
+        self.max_edits == 0 && !self.normalize_whitespace && !self.case_insensitive
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.max_edits <= MAX_FUZZY_EDITS,
+            "max_edits={} is too large (at most {MAX_FUZZY_EDITS})",
+            self.max_edits
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Cell {
+    // offset in source right after the last matched byte
+    pos: u32,
+    edits: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    // index of the first cell of this frame in `cells`
+    cells_start: u32,
+    // number of bytes of end_str already generated, if any
+    end_off: Option<u32>,
+    // last generated byte was whitespace
+    prev_ws: bool,
+}
+
+/// Like [crate::substring::SubStrMatcher], forces the output to be a substring of the source
+/// starting and ending at word boundaries (spaces), followed by `end_str`.
+/// The substring can however differ from the source by up to `max_edits` bytes
+/// (Levenshtein distance over bytes, after the optional normalization of whitespace and case).
+/// The `end_str` itself has to be generated exactly.
+///
+/// The state is the set of source offsets the output can end at together with
+/// the number of edits needed to get there (a sparse column of the edit-distance matrix).
+/// It is not `Copy`, so this implements [Recognizer] directly,
+/// keeping columns for all bytes on the stack in one buffer.
+/// Each byte costs time proportional to the size of the column,
+/// which is up to the number of words in source times `max_edits + 1`;
+/// keep `max_edits` small for long sources.
+pub struct FuzzySubStrMatcher {
+    source: Vec<u8>,
+    end_str: Vec<u8>,
+    options: FuzzySubStrOptions,
+    cells: Vec<Cell>,
+    stack: Vec<Frame>,
+    scratch: Vec<Cell>,
+}
+
+fn is_ws(b: u8) -> bool {
+    b.is_ascii_whitespace()
+}
+
+impl FuzzySubStrMatcher {
+    pub fn new(source: &str, end_str: &str, options: FuzzySubStrOptions) -> Result<Self> {
+        options.validate()?;
+        let mut source = if options.normalize_whitespace {
+            source
+                .split_ascii_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        } else {
+            source.to_string()
+        };
+        if options.case_insensitive {
+            source.make_ascii_lowercase();
+        }
+        source.push(' ');
+
+        let mut r = FuzzySubStrMatcher {
+            source: source.into_bytes(),
+            end_str: end_str.as_bytes().to_vec(),
+            options,
+            cells: Vec::new(),
+            stack: vec![Frame {
+                cells_start: 0,
+                end_off: None,
+                prev_ws: false,
+            }],
+            scratch: Vec::new(),
+        };
+
+        let mut starts = vec![Cell { pos: 0, edits: 0 }];
+        for (i, b) in r.source.iter().enumerate() {
+            if *b == b' ' && i + 1 < r.source.len() {
+                starts.push(Cell {
+                    pos: i as u32 + 1,
+                    edits: 0,
+                });
+            }
+        }
+        r.push_column(&starts);
+        Ok(r)
+    }
+
+    fn normalize(&self, b: u8) -> u8 {
+        if self.options.normalize_whitespace && is_ws(b) {
+            b' '
+        } else if self.options.case_insensitive {
+            b.to_ascii_lowercase()
+        } else {
+            b
+        }
+    }
+
+    fn top(&self) -> Frame {
+        *self.stack.last().unwrap()
+    }
+
+    fn top_cells(&self) -> &[Cell] {
+        &self.cells[self.top().cells_start as usize..]
+    }
+
+    // can the substring end here (i.e., is the source at a word end)?
+    fn at_word_end(&self) -> bool {
+        self.top().end_off.is_none()
+            && self
+                .top_cells()
+                .iter()
+                .any(|c| self.source.get(c.pos as usize) == Some(&b' '))
+    }
+
+    /// Append a new column built from `cands` (sorted by `pos`), adding deletions
+    /// (skipping source bytes) and keeping the minimal number of edits for each offset.
+    fn push_column(&mut self, cands: &[Cell]) {
+        let k = self.options.max_edits;
+        let max_pos = self.source.len() as u32;
+        let base = self.cells.len();
+        let push_deletions_until = |cells: &mut Vec<Cell>, limit: u32| {
+            if cells.len() > base {
+                let last = *cells.last().unwrap();
+                let mut c = Cell {
+                    pos: last.pos + 1,
+                    edits: last.edits + 1,
+                };
+                while c.pos < limit && c.edits <= k {
+                    cells.push(c);
+                    c.pos += 1;
+                    c.edits += 1;
+                }
+            }
+        };
+        for &c in cands {
+            push_deletions_until(&mut self.cells, c.pos + 1);
+            if self.cells.len() > base {
+                let last = self.cells.last_mut().unwrap();
+                if last.pos == c.pos {
+                    last.edits = std::cmp::min(last.edits, c.edits);
+                    continue;
+                }
+            }
+            self.cells.push(c);
+        }
+        push_deletions_until(&mut self.cells, max_pos + 1);
+    }
+
+    fn push_byte(&mut self, byte: u8) -> bool {
+        let k = self.options.max_edits;
+        let mut cands = std::mem::take(&mut self.scratch);
+        cands.clear();
+        for c in self.top_cells() {
+            // match or substitution
+            if let Some(&s) = self.source.get(c.pos as usize) {
+                let edits = c.edits + (s != byte) as u8;
+                if edits <= k {
+                    cands.push(Cell {
+                        pos: c.pos + 1,
+                        edits,
+                    });
+                }
+            }
+            // insertion
+            if c.edits < k {
+                cands.push(Cell {
+                    pos: c.pos,
+                    edits: c.edits + 1,
+                });
+            }
+        }
+        cands.sort_unstable_by_key(|c| (c.pos, c.edits));
+        let cells_start = self.cells.len();
+        self.push_column(&cands);
+        self.scratch = cands;
+        self.cells.len() > cells_start
+    }
+}
+
+impl Recognizer for FuzzySubStrMatcher {
+    fn pop_bytes(&mut self, num: usize) {
+        if num == 0 {
+            return;
+        }
+        let len = self.stack.len() - num;
+        let cells_start = self.stack[len].cells_start;
+        self.stack.truncate(len);
+        self.cells.truncate(cells_start as usize);
+    }
+
+    fn collapse(&mut self) {
+        let top = self.top();
+        self.cells.drain(..top.cells_start as usize);
+        self.stack.clear();
+        self.stack.push(Frame {
+            cells_start: 0,
+            ..top
+        });
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        match tok {
+            SpecialToken::EndOfSentence => {
+                if self.end_str.is_empty() {
+                    self.at_word_end()
+                } else {
+                    self.top().end_off == Some(self.end_str.len() as u32)
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn trie_finished(&mut self) {
+        assert!(self.stack.len() == 1);
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let top = self.top();
+        let cells_start = self.cells.len() as u32;
+        let frame = if let Some(off) = top.end_off {
+            if self.end_str.get(off as usize) != Some(&byte) {
+                return false;
+            }
+            Frame {
+                cells_start,
+                end_off: Some(off + 1),
+                prev_ws: false,
+            }
+        } else if self.end_str.first() == Some(&byte) && self.at_word_end() {
+            Frame {
+                cells_start,
+                end_off: Some(1),
+                prev_ws: false,
+            }
+        } else {
+            let byte = self.normalize(byte);
+            let is_space = self.options.normalize_whitespace && byte == b' ';
+            if is_space && top.prev_ws {
+                // more whitespace after whitespace doesn't change anything
+                self.cells
+                    .extend_from_within(top.cells_start as usize..cells_start as usize);
+            } else if !self.push_byte(byte) {
+                return false;
+            }
+            Frame {
+                cells_start,
+                end_off: None,
+                prev_ws: is_space,
+            }
+        };
+        self.stack.push(frame);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accepts;
+
+    const SOURCE: &str = "the quick brown fox";
+
+    fn matcher(end_str: &str, options: FuzzySubStrOptions) -> FuzzySubStrMatcher {
+        FuzzySubStrMatcher::new(SOURCE, end_str, options).unwrap()
+    }
+
+    fn edits(max_edits: u8) -> FuzzySubStrOptions {
+        FuzzySubStrOptions {
+            max_edits,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn exact() {
+        let mut m = matcher("", Default::default());
+        assert!(accepts(&mut m, "quick brown"));
+        assert!(accepts(&mut m, "the"));
+        assert!(accepts(&mut m, "fox"));
+        assert!(!accepts(&mut m, "quic"));
+        assert!(!accepts(&mut m, "uick"));
+        assert!(!accepts(&mut m, "quick brwn"));
+        assert!(!accepts(&mut m, "Quick"));
+    }
+
+    #[test]
+    fn edit_budget() {
+        let mut m = matcher("", edits(1));
+        assert!(accepts(&mut m, "quick brwn"));
+        assert!(accepts(&mut m, "quickk"));
+        assert!(accepts(&mut m, "quack"));
+        assert!(!accepts(&mut m, "qick brwn"));
+        let mut m = matcher("", edits(2));
+        assert!(accepts(&mut m, "qick brwn"));
+        assert!(!accepts(&mut m, "qck brwn"));
+    }
+
+    #[test]
+    fn normalization() {
+        let mut m = matcher("", Default::default());
+        assert!(!accepts(&mut m, "quick  brown"));
+        let mut m = matcher(
+            "",
+            FuzzySubStrOptions {
+                normalize_whitespace: true,
+                case_insensitive: true,
+                ..Default::default()
+            },
+        );
+        assert!(accepts(&mut m, "quick  brown"));
+        assert!(accepts(&mut m, "Quick\nBROWN"));
+        assert!(!accepts(&mut m, "quickbrown"));
+    }
+
+    #[test]
+    fn end_str() {
+        let mut m = matcher("\"", edits(1));
+        assert!(accepts(&mut m, "brown fox\""));
+        assert!(accepts(&mut m, "brwn\""));
+        assert!(!accepts(&mut m, "brown"));
+        assert!(!accepts(&mut m, "bro\""));
+        assert!(!accepts(&mut m, "fox\"\""));
+    }
+
+    #[test]
+    fn collapse_keeps_state() {
+        let mut m = matcher("", edits(1));
+        for b in "quick br".bytes() {
+            assert!(m.try_push_byte(b));
+        }
+        m.collapse();
+        assert!(accepts(&mut m, "wn"));
+        assert!(accepts(&mut m, "own"));
+        assert!(!accepts(&mut m, "wn fx"));
+    }
+
+    #[test]
+    fn options() {
+        assert!(FuzzySubStrOptions::default().is_exact());
+        assert!(!edits(1).is_exact());
+        let opts: FuzzySubStrOptions = serde_json::from_str(r#"{"max_edits":2}"#).unwrap();
+        assert_eq!(opts, edits(2));
+    }
+
+    #[test]
+    fn max_edits_limit() {
+        let mut m = matcher("", edits(MAX_FUZZY_EDITS));
+        assert!(accepts(&mut m, "the quick brown fox"));
+        assert!(accepts(&mut m, "xxxxxxxxxxxxxxxx"));
+        assert!(!accepts(&mut m, "xxxxxxxxxxxxxxxxxxxxxxxx"));
+        assert!(edits(MAX_FUZZY_EDITS + 1).validate().is_err());
+        assert!(FuzzySubStrMatcher::new(SOURCE, "", edits(255)).is_err());
+    }
+}
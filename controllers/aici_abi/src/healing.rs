@@ -0,0 +1,173 @@
+//! Token healing.
+//!
+//! When text is forced (in the prompt, or with a splice) and the model generates after it,
+//! the boundary often falls in the middle of what would normally be a single token
+//! (e.g., forced `"http:"` is tokenized as `"http" ":"`, while the model would use `"://"`).
+//! Healing removes the last forced token(s), and makes the generation start
+//! with the removed bytes, letting the model pick a token that covers them.
+
+use crate::{
+    combinators::Seq,
+    toktrie::{Recognizer, SpecialToken, TokTrie},
+    TokenId,
+};
+
+/// Allows exactly the given bytes.
+pub struct ForcedPrefix {
+    prefix: Vec<u8>,
+    num_bytes: usize,
+}
+
+impl ForcedPrefix {
+    pub fn new(prefix: Vec<u8>) -> Self {
+        ForcedPrefix {
+            prefix,
+            num_bytes: 0,
+        }
+    }
+}
+
+impl Recognizer for ForcedPrefix {
+    fn pop_bytes(&mut self, num: usize) {
+        self.num_bytes -= num;
+    }
+
+    fn collapse(&mut self) {}
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        tok == SpecialToken::EndOfSentence && self.num_bytes == self.prefix.len()
+    }
+
+    fn trie_finished(&mut self) {}
+
+    #[inline(always)]
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        if self.prefix.get(self.num_bytes) == Some(&byte) {
+            self.num_bytes += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Output of `R` after the healed bytes.
+pub type Healed<R> = Seq<ForcedPrefix, R>;
+
+/// Make the generation constrained by `inner` start with `prefix` (as returned by [heal_tokens]).
+/// The resulting token mask allows tokens that start with `prefix` and continue
+/// with something `inner` allows (as well as tokens that are a prefix of `prefix`).
+/// The generated text includes `prefix`, while `inner` only sees what comes after it.
+pub fn healed<R: Recognizer + ?Sized>(prefix: Vec<u8>, inner: Box<R>) -> Healed<R> {
+    Seq::new(Box::new(ForcedPrefix::new(prefix)), inner)
+}
+
+// Records whether any token in the trie extends the prefix.
+struct ExtensionProbe<'a> {
+    prefix: &'a [u8],
+    num_bytes: usize,
+    found: bool,
+}
+
+impl Recognizer for ExtensionProbe<'_> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.num_bytes -= num;
+    }
+
+    fn collapse(&mut self) {}
+
+    fn special_allowed(&mut self, _tok: SpecialToken) -> bool {
+        false
+    }
+
+    fn trie_finished(&mut self) {}
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        if self.num_bytes == self.prefix.len() {
+            // the trie only asks about bytes with a node, so there is a longer token
+            self.found = true;
+            false
+        } else if self.prefix[self.num_bytes] == byte {
+            self.num_bytes += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Check if there is a token that starts with `bytes` and is longer.
+pub fn has_longer_token(trie: &TokTrie, bytes: &[u8]) -> bool {
+    let mut probe = ExtensionProbe {
+        prefix: bytes,
+        num_bytes: 0,
+        found: false,
+    };
+    let mut toks = trie.alloc_token_set();
+    trie.add_bias(&mut probe, &mut toks, &[]);
+    probe.found
+}
+
+/// Decide how many tokens (at most `max_tokens`) to remove from the end of `tokens`.
+/// A token is only removed if there is a longer token starting with its bytes
+/// (together with bytes of already removed tokens), so that healing can change something.
+/// Returns the number of tokens to remove and their bytes.
+pub fn heal_tokens(trie: &TokTrie, tokens: &[TokenId], max_tokens: usize) -> (usize, Vec<u8>) {
+    let eos = trie.special_token(SpecialToken::EndOfSentence);
+    let mut prefix = Vec::new();
+    let mut num_tokens = 0;
+    for &tok in tokens.iter().rev().take(max_tokens) {
+        let bytes = trie.token(tok);
+        if tok == eos || bytes.is_empty() {
+            break;
+        }
+        let candidate = [bytes, prefix.as_slice()].concat();
+        if !has_longer_token(trie, &candidate) {
+            break;
+        }
+        prefix = candidate;
+        num_tokens += 1;
+    }
+    (num_tokens, prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accepts;
+
+    #[test]
+    fn forced_prefix() {
+        let mut r = ForcedPrefix::new(b"http".to_vec());
+        assert!(accepts(&mut r, "http"));
+        assert!(!accepts(&mut r, "htt"));
+        assert!(!accepts(&mut r, "https"));
+        assert!(!accepts(&mut r, "ftp"));
+        let mut r = ForcedPrefix::new(vec![]);
+        assert!(accepts(&mut r, ""));
+        assert!(!accepts(&mut r, "a"));
+    }
+
+    #[test]
+    fn healed_prefix() {
+        let inner = Box::new(ForcedPrefix::new(b"//".to_vec()));
+        let mut r = healed(b"http:".to_vec(), inner);
+        assert!(accepts(&mut r, "http://"));
+        assert!(!accepts(&mut r, "http:"));
+        assert!(!accepts(&mut r, "//"));
+        assert!(!accepts(&mut r, "http:/"));
+        assert!(!accepts(&mut r, "http:://"));
+
+        // the inner recognizer only starts after the whole prefix
+        let inner = Box::new(ForcedPrefix::new(b"tp".to_vec()));
+        let mut r = healed(b"ht".to_vec(), inner);
+        assert!(accepts(&mut r, "http"));
+        assert!(!accepts(&mut r, "htp"));
+
+        let inner = Box::new(ForcedPrefix::new(vec![]));
+        let mut r = healed(b"ab".to_vec(), inner);
+        assert!(accepts(&mut r, "ab"));
+        assert!(!accepts(&mut r, "a"));
+        assert!(!accepts(&mut r, "abc"));
+    }
+}
@@ -4,8 +4,13 @@ use toktrie::TokenizerEnv;
 
 #[repr(transparent)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-struct BlobId(u32);
+pub(crate) struct BlobId(pub(crate) u32);
 
+// when running natively, these are provided by the host via aici_native_init()
+#[cfg(not(target_arch = "wasm32"))]
+use crate::native::*;
+
+#[cfg(target_arch = "wasm32")]
 #[allow(dead_code)]
 extern "C" {
     // Read binary blob.
@@ -40,6 +45,21 @@ extern "C" {
     // Get value of configuration parameters, like "fork".
     fn aici_host_get_config(src: *const u8, src_size: u32) -> i32;
 
+    // Wall clock time (microseconds since Unix epoch), quantized by the host.
+    fn aici_host_now_micros() -> u64;
+
+    // Seed derived from the request seed and the fork index.
+    fn aici_host_random_seed() -> u64;
+
+    // Emit a structured event; `data` is JSON.
+    fn aici_host_emit_event(kind: *const u8, kind_size: u32, data: *const u8, data_size: u32);
+
+    // Look up blob stored with aici_host_blob_cache_put(); returns an empty blob if not found.
+    fn aici_host_blob_cache_get(key: *const u8, key_size: u32) -> BlobId;
+
+    // Store blob in a cache shared by all instances of the current module.
+    fn aici_host_blob_cache_put(key: *const u8, key_size: u32, data: *const u8, data_size: u32);
+
     // Stop the program - any error info is assumed to have been printed already.
     // Backtraces will be limited.
     fn aici_host_stop();
@@ -58,7 +78,6 @@ fn read_blob(blob: BlobId, prefetch_size: usize) -> Vec<u8> {
     buffer
 }
 
-#[cfg(target_arch = "wasm32")]
 fn init_panic() {
     std::panic::set_hook(Box::new(|info| {
         // skip 'run with `RUST_BACKTRACE=1`' message (not relevant for remote running)
@@ -73,6 +92,33 @@ pub extern "C" fn aici_init() {
     set_host(Box::new(WasmHost {}));
 }
 
+/// Entry point for hosts loading the controller as a native library.
+/// Returns the ABI version of the library; it's only initialized if it matches `version`.
+#[cfg(not(target_arch = "wasm32"))]
+#[no_mangle]
+pub extern "C" fn aici_native_init(version: u32, fns: *const crate::native::NativeHostFns) -> u32 {
+    if version == crate::native::NATIVE_ABI_VERSION {
+        crate::native::set_host_fns(unsafe { *fns });
+        init_panic();
+        set_host(Box::new(WasmHost {}));
+    }
+    crate::native::NATIVE_ABI_VERSION
+}
+
+/// Used by [crate::aici_expose_all] to wrap exported functions.
+/// When running natively, this keeps panics in the controller from unwinding into the host;
+/// the host is told with `aici_host_stop()` instead.
+#[doc(hidden)]
+pub fn export_guard<R>(f: impl FnOnce() -> R) -> Option<R> {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(r) => Some(r),
+        Err(_) => {
+            unsafe { aici_host_stop() };
+            None
+        }
+    }
+}
+
 pub struct WasmTokenizerEnv {
     toktrie: TokTrie,
 }
@@ -114,6 +160,11 @@ pub trait HostInterface {
     fn self_seq_id(&self) -> SeqId;
     fn eos_token(&self) -> TokenId;
     fn get_config(&self, name: &str) -> i32;
+    fn now_micros(&self) -> u64;
+    fn random_seed(&self) -> u64;
+    fn emit_event(&self, kind: &str, data: &[u8]);
+    fn blob_cache_get(&self, key: &str) -> Option<Vec<u8>>;
+    fn blob_cache_put(&self, key: &str, data: &[u8]);
     fn stop(&self) -> !;
 }
 
@@ -153,7 +204,8 @@ impl HostInterface for WasmHost {
 
     fn stop(&self) -> ! {
         unsafe { aici_host_stop() };
-        panic!("didn't stop")
+        // only reached when running natively; unwind to export_guard() without printing a panic
+        std::panic::resume_unwind(Box::new("aici_host_stop()"))
     }
 
     fn tokenize_bytes(&self, s: &[u8]) -> Vec<TokenId> {
@@ -181,6 +233,46 @@ impl HostInterface for WasmHost {
         let res = unsafe { aici_host_get_config(name_bytes.as_ptr(), name_bytes.len() as u32) };
         res
     }
+
+    fn now_micros(&self) -> u64 {
+        unsafe { aici_host_now_micros() }
+    }
+
+    fn random_seed(&self) -> u64 {
+        unsafe { aici_host_random_seed() }
+    }
+
+    fn emit_event(&self, kind: &str, data: &[u8]) {
+        unsafe {
+            aici_host_emit_event(
+                kind.as_ptr(),
+                kind.len() as u32,
+                data.as_ptr(),
+                data.len() as u32,
+            )
+        }
+    }
+
+    fn blob_cache_get(&self, key: &str) -> Option<Vec<u8>> {
+        let id = unsafe { aici_host_blob_cache_get(key.as_ptr(), key.len() as u32) };
+        let r = read_blob(id, 0);
+        if r.is_empty() {
+            None
+        } else {
+            Some(r)
+        }
+    }
+
+    fn blob_cache_put(&self, key: &str, data: &[u8]) {
+        unsafe {
+            aici_host_blob_cache_put(
+                key.as_ptr(),
+                key.len() as u32,
+                data.as_ptr(),
+                data.len() as u32,
+            )
+        }
+    }
 }
 
 fn get_host() -> &'static Box<dyn HostInterface> {
@@ -266,10 +358,30 @@ pub mod hex_string {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageScope {
+    /// Variables shared by all forks of the current request; dropped when the request finishes.
+    #[default]
+    Request,
+    /// Variables kept by the host across requests, separately for each user and module.
+    /// The host has to enable it (see `aicirt --persistent-storage`).
+    Persistent,
+}
+
+impl StorageScope {
+    fn is_request(&self) -> bool {
+        *self == StorageScope::Request
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum StorageCmd {
     /// Read variable. Returns StorageResp::ReadVar or StorageResp::VariableMissing.
-    ReadVar { name: String },
+    ReadVar {
+        name: String,
+        #[serde(default, skip_serializing_if = "StorageScope::is_request")]
+        scope: StorageScope,
+    },
 
     /// Write variable.
     /// If `when_version_is == None`, always writes the variable and returns StorageResp::WriteVar.
@@ -283,9 +395,31 @@ pub enum StorageCmd {
         value: Vec<u8>,
         op: StorageOp,
         when_version_is: Option<u64>,
+        #[serde(default, skip_serializing_if = "StorageScope::is_request")]
+        scope: StorageScope,
+    },
+
+    /// Suspend the current sequence until any of the listed (request-scoped) variables
+    /// is written with version greater than `after_version` (a missing variable has version 0).
+    /// If that is already the case, returns StorageResp::Changed, and the sequence is not suspended.
+    /// Otherwise, returns StorageResp::Watching, and the sequence is suspended after
+    /// the current mid_process() returns; it will be called again (with no tokens) upon change.
+    Watch {
+        names: Vec<String>,
+        after_version: u64,
     },
 }
 
+impl StorageCmd {
+    pub fn scope(&self) -> StorageScope {
+        match self {
+            StorageCmd::ReadVar { scope, .. } => *scope,
+            StorageCmd::WriteVar { scope, .. } => *scope,
+            StorageCmd::Watch { .. } => StorageScope::Request,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum StorageResp {
     /// Upon handling the request the variable had the specified value and version number.
@@ -298,6 +432,10 @@ pub enum StorageResp {
     VariableMissing {},
     /// The variable has been written, and the new version is returned.
     WriteVar { version: u64 },
+    /// Response to Watch: the variable already has a newer version.
+    Changed { name: String, version: u64 },
+    /// Response to Watch: the sequence will be suspended.
+    Watching {},
 }
 
 pub fn storage_cmd(cmd: StorageCmd) -> StorageResp {
@@ -310,13 +448,23 @@ pub fn storage_cmd(cmd: StorageCmd) -> StorageResp {
 // Public APIs
 
 pub struct VariableStorage {
-    // no fields (yet?)
+    scope: StorageScope,
 }
 
 impl VariableStorage {
-    /// Create a new instance of VariableStorage. It currently has no fields.
+    /// Create a new instance of VariableStorage, for variables shared by forks of the current request.
     pub fn new() -> Self {
-        VariableStorage {}
+        VariableStorage {
+            scope: StorageScope::Request,
+        }
+    }
+
+    /// Access variables persisted across requests (scoped to current user and module).
+    /// Only works if the host enabled persistent storage.
+    pub fn persistent() -> Self {
+        VariableStorage {
+            scope: StorageScope::Persistent,
+        }
     }
 
     /// Read variable. Returns None if the variable is unset.
@@ -334,25 +482,70 @@ impl VariableStorage {
         let _ver = self.write_var(name, value, StorageOp::Append);
     }
 
+    /// Write variable, but only if it currently has the specified version.
+    /// Returns the new version, or the current version and value (if any) upon conflict.
+    pub fn set_if_version(
+        &self,
+        name: &str,
+        value: Vec<u8>,
+        version: u64,
+    ) -> Result<u64, Option<(u64, Vec<u8>)>> {
+        match storage_cmd(StorageCmd::WriteVar {
+            name: name.to_string(),
+            value,
+            op: StorageOp::Set,
+            when_version_is: Some(version),
+            scope: self.scope,
+        }) {
+            StorageResp::WriteVar { version } => Ok(version),
+            StorageResp::ReadVar { version, value } => Err(Some((version, value))),
+            StorageResp::VariableMissing {} => Err(None),
+            _ => panic!("unexpected response to write var"),
+        }
+    }
+
+    /// Suspend the current sequence until any of the variables is written
+    /// (gets version greater than `after_version`; use 0 to wait for variables to be set).
+    /// Returns the name and version of a variable that has already changed, in which case
+    /// the sequence is not suspended. Otherwise, the current mid_process() should return
+    /// `MidProcessResult::noop()`, and will be called again once something changes.
+    pub fn watch(&self, names: &[&str], after_version: u64) -> Option<(String, u64)> {
+        assert!(
+            self.scope == StorageScope::Request,
+            "only request-scoped variables can be watched"
+        );
+        match storage_cmd(StorageCmd::Watch {
+            names: names.iter().map(|s| s.to_string()).collect(),
+            after_version,
+        }) {
+            StorageResp::Changed { name, version } => Some((name, version)),
+            StorageResp::Watching {} => None,
+            _ => panic!("unexpected response to watch"),
+        }
+    }
+
     fn write_var(&self, name: &str, value: Vec<u8>, op: StorageOp) -> u64 {
         match storage_cmd(StorageCmd::WriteVar {
             name: name.to_string(),
             value,
             op,
             when_version_is: None,
+            scope: self.scope,
         }) {
             StorageResp::WriteVar { version } => version,
             _ => panic!("unexpected response to write var"),
         }
     }
 
-    fn get_with_version(&self, name: &str) -> Option<(u64, Vec<u8>)> {
+    /// Read variable along with its version. Returns None if the variable is unset.
+    pub fn get_with_version(&self, name: &str) -> Option<(u64, Vec<u8>)> {
         match storage_cmd(StorageCmd::ReadVar {
             name: name.to_string(),
+            scope: self.scope,
         }) {
             StorageResp::ReadVar { version, value } => Some((version, value)),
             StorageResp::VariableMissing {} => None,
-            StorageResp::WriteVar { .. } => panic!("unexpected response to read var"),
+            _ => panic!("unexpected response to read var"),
         }
     }
 }
@@ -377,6 +570,66 @@ pub fn eos_token() -> TokenId {
     get_host().eos_token()
 }
 
+/// Return current wall clock time in microseconds since Unix epoch.
+/// The host rounds it down to its timer resolution (which may make it always 0).
+pub fn now_micros() -> u64 {
+    get_host().now_micros()
+}
+
+/// Return a seed for random number generation.
+/// It is derived from the seed of the request (if any) and the position of the current
+/// sequence among forks, so it is reproducible, but different in each fork.
+/// Call again after forking to get a seed for the new branch.
+pub fn random_seed() -> u64 {
+    get_host().random_seed()
+}
+
+/// Emit a structured event, returned to the client as `{ "kind": kind, "data": data }`
+/// in the `events` list of the response.
+/// The host limits the size of events; events over the limit are dropped with a warning in logs.
+pub fn emit_event<T: Serialize>(kind: &str, data: &T) {
+    emit_event_json(kind, serde_json::to_string(data).unwrap().as_bytes())
+}
+
+/// Like [emit_event], but with `data` already serialized to JSON.
+pub fn emit_event_json(kind: &str, data: &[u8]) {
+    get_host().emit_event(kind, data)
+}
+
+/// Look up a blob stored with [blob_cache_put], possibly by another request to the same module.
+/// Returns None if it's not there, or if the host doesn't keep a blob cache.
+/// The host doesn't check the contents in any way, so it's best to include a format version
+/// in the key, and verify the blob after reading.
+pub fn blob_cache_get(key: &str) -> Option<Vec<u8>> {
+    get_host().blob_cache_get(key)
+}
+
+/// Check if the host keeps a blob cache for this module; if not, [blob_cache_get] always
+/// returns None and [blob_cache_put] does nothing, so it's not worth preparing blobs for it.
+pub fn blob_cache_enabled() -> bool {
+    get_config("blob_cache") != 0
+}
+
+/// Store a blob (eg. a compiled grammar) under `key`, for [blob_cache_get].
+/// Keys consist of ASCII letters, digits, `-`, `_` and `.`; they are meant to be derived
+/// from the contents the blob was computed from.
+/// Blobs are never overwritten, so the first value stored under a key is kept.
+/// This is best-effort: the host may ignore the blob (eg., when it's too large).
+pub fn blob_cache_put(key: &str, data: &[u8]) {
+    get_host().blob_cache_put(key, data)
+}
+
+/// FNV-1a hash, to derive blob cache keys from the contents the blob is computed from.
+#[cfg(any(feature = "cfg", feature = "rx"))]
+pub(crate) fn content_hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in data {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
 /// Stop the program - any error info is assumed to have been printed already.
 pub fn aici_stop() -> ! {
     get_host().stop();
@@ -0,0 +1,620 @@
+//! Compiling a subset of JSON Schema to a regular expression, and thus to a recognizer.
+//!
+//! Supported are `type` (also a list of types), `properties` with `required`, `enum`, `const`,
+//! `anyOf`/`oneOf`, `allOf` with a single element, `items` with `minItems`/`maxItems`,
+//! `pattern`/`minLength`/`maxLength` for strings, and `$ref` to anything within the schema
+//! (eg. `#`, `#/$defs/foo`, or `#/definitions/foo`).
+//! As in JSON Schema, a `pattern` can match anywhere in the string, unless anchored with a leading `^`
+//! and/or trailing `$` (no other anchors are supported).
+//! Only characters that don't need escaping in JSON (no `"`, `\`, or control characters)
+//! are generated for strings with a `pattern`.
+//! Properties are generated in the order they are listed, and no other properties are allowed.
+//! Numeric bounds and `format` are ignored.
+//!
+//! Recursive `$ref`s are expanded a few times; after that only the alternatives that
+//! don't recurse remain (an optional property is left out, an array is empty, etc.).
+//!
+//! The generated JSON is compact, except that a single space is allowed after `:` and `,`.
+
+use crate::rx::{RecRx, RxStackRecognizer};
+use anyhow::{anyhow, bail, ensure, Result};
+use regex_syntax::hir::{
+    Class, ClassBytes, ClassBytesRange, ClassUnicode, ClassUnicodeRange, Hir, HirKind, Look,
+    Repetition,
+};
+use regex_syntax::utf8::Utf8Sequences;
+use serde_json::{Map, Value};
+
+/// How many times the same `$ref` can be expanded within itself.
+const MAX_REF_DEPTH: usize = 3;
+/// Nesting of arrays and objects for schemas that allow any value.
+const ANY_VALUE_DEPTH: usize = 2;
+
+const STRING_CHAR: &str = r#"(?:[^"\\\x00-\x1F]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})"#;
+const INTEGER: &str = r"-?(?:0|[1-9][0-9]*)";
+const NUMBER: &str = r"-?(?:0|[1-9][0-9]*)(?:\.[0-9]+)?(?:[eE][+-]?[0-9]+)?";
+const COMMA: &str = ",[ ]?";
+const COLON: &str = ":[ ]?";
+
+/// Regex matching JSON documents valid according to the schema.
+pub fn json_schema_to_regex(schema: &Value) -> Result<String> {
+    let mut compiler = Compiler {
+        root: schema,
+        ref_stack: Vec::new(),
+    };
+    match compiler.compile(schema)? {
+        Some(rx) => Ok(format!("(?:{rx})")),
+        None => bail!("no JSON value matches the schema"),
+    }
+}
+
+/// Recognizer of JSON documents valid according to the schema.
+pub fn json_schema_recognizer(schema: &Value) -> Result<RxStackRecognizer> {
+    let rx = json_schema_to_regex(schema)?;
+    Ok(RecRx::from_rx_cached(&rx, None)?.to_stack_recognizer())
+}
+
+/// Same as [json_schema_recognizer()], with the schema given as JSON text.
+pub fn json_schema_recognizer_from_str(schema: &str) -> Result<RxStackRecognizer> {
+    let schema: Value =
+        serde_json::from_str(schema).map_err(|e| anyhow!("invalid JSON schema: {e}"))?;
+    json_schema_recognizer(&schema)
+}
+
+struct Compiler<'a> {
+    root: &'a Value,
+    // $refs currently being expanded
+    ref_stack: Vec<&'a str>,
+}
+
+// Results are None when no value matches (eg. after too much recursion).
+impl<'a> Compiler<'a> {
+    fn compile(&mut self, schema: &'a Value) -> Result<Option<String>> {
+        let obj = match schema {
+            Value::Bool(true) => return Ok(Some(any_value(ANY_VALUE_DEPTH))),
+            Value::Bool(false) => return Ok(None),
+            Value::Object(obj) => obj,
+            _ => bail!("invalid schema: {schema}"),
+        };
+
+        if let Some(r) = obj.get("$ref") {
+            let r = r.as_str().ok_or_else(|| anyhow!("$ref must be a string"))?;
+            if self.ref_stack.iter().filter(|x| **x == r).count() >= MAX_REF_DEPTH {
+                return Ok(None);
+            }
+            let target = self.resolve(r)?;
+            self.ref_stack.push(r);
+            let res = self.compile(target);
+            self.ref_stack.pop();
+            return res;
+        }
+
+        if let Some(v) = obj.get("const") {
+            return Ok(Some(json_literal(v)));
+        }
+
+        if let Some(v) = obj.get("enum") {
+            let values = v
+                .as_array()
+                .ok_or_else(|| anyhow!("enum must be an array"))?;
+            return Ok(alternatives(
+                values.iter().map(|v| Some(json_literal(v))).collect(),
+            ));
+        }
+
+        for key in ["anyOf", "oneOf"] {
+            if let Some(v) = obj.get(key) {
+                let schemas = v
+                    .as_array()
+                    .ok_or_else(|| anyhow!("{key} must be an array"))?;
+                let options = schemas
+                    .iter()
+                    .map(|s| self.compile(s))
+                    .collect::<Result<Vec<_>>>()?;
+                return Ok(alternatives(options));
+            }
+        }
+
+        if let Some(v) = obj.get("allOf") {
+            match v.as_array() {
+                Some(schemas) if schemas.len() == 1 => return self.compile(&schemas[0]),
+                _ => bail!("allOf is only supported with a single element"),
+            }
+        }
+
+        let types = match obj.get("type") {
+            Some(Value::String(t)) => vec![t.as_str()],
+            Some(Value::Array(types)) => types
+                .iter()
+                .map(|t| t.as_str().ok_or_else(|| anyhow!("invalid type: {t}")))
+                .collect::<Result<Vec<_>>>()?,
+            Some(t) => bail!("invalid type: {t}"),
+            None => {
+                if obj.contains_key("properties") || obj.contains_key("required") {
+                    vec!["object"]
+                } else if obj.contains_key("items") {
+                    vec!["array"]
+                } else if obj.contains_key("pattern")
+                    || obj.contains_key("minLength")
+                    || obj.contains_key("maxLength")
+                {
+                    vec!["string"]
+                } else {
+                    return Ok(Some(any_value(ANY_VALUE_DEPTH)));
+                }
+            }
+        };
+
+        let options = types
+            .iter()
+            .map(|t| self.compile_type(t, obj))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(alternatives(options))
+    }
+
+    fn resolve(&self, r: &str) -> Result<&'a Value> {
+        let pointer = r
+            .strip_prefix('#')
+            .ok_or_else(|| anyhow!("only local $ref is supported: {r}"))?;
+        self.root
+            .pointer(pointer)
+            .ok_or_else(|| anyhow!("$ref not found: {r}"))
+    }
+
+    fn compile_type(&mut self, tp: &str, obj: &'a Map<String, Value>) -> Result<Option<String>> {
+        match tp {
+            "null" => Ok(Some("null".to_string())),
+            "boolean" => Ok(Some("(?:true|false)".to_string())),
+            "integer" => Ok(Some(INTEGER.to_string())),
+            "number" => Ok(Some(NUMBER.to_string())),
+            "string" => compile_string(obj),
+            "array" => self.compile_array(obj),
+            "object" => self.compile_object(obj),
+            _ => bail!("unknown type: {tp:?}"),
+        }
+    }
+
+    fn compile_array(&mut self, obj: &'a Map<String, Value>) -> Result<Option<String>> {
+        let item = match obj.get("items") {
+            Some(s) => self.compile(s)?,
+            None => Some(any_value(ANY_VALUE_DEPTH)),
+        };
+        let min = get_usize(obj, "minItems")?.unwrap_or(0);
+        let max = get_usize(obj, "maxItems")?;
+        if max.is_some_and(|max| max < min) {
+            return Ok(None);
+        }
+        let item = match item {
+            Some(item) if max != Some(0) => item,
+            _ if min == 0 => return Ok(Some(r"\[\]".to_string())),
+            _ => return Ok(None),
+        };
+        let rest = repeat(min.saturating_sub(1), max.map(|m| m - 1));
+        let items = format!("{item}(?:{COMMA}{item}){rest}");
+        if min == 0 {
+            Ok(Some(format!(r"\[(?:{items})?\]")))
+        } else {
+            Ok(Some(format!(r"\[{items}\]")))
+        }
+    }
+
+    fn compile_object(&mut self, obj: &'a Map<String, Value>) -> Result<Option<String>> {
+        let props = match obj.get("properties") {
+            Some(Value::Object(props)) => props,
+            Some(_) => bail!("properties must be an object"),
+            None => return Ok(Some(any_object(ANY_VALUE_DEPTH))),
+        };
+        let required = match obj.get("required") {
+            Some(Value::Array(req)) => req
+                .iter()
+                .map(|r| r.as_str().ok_or_else(|| anyhow!("invalid required: {r}")))
+                .collect::<Result<Vec<_>>>()?,
+            Some(_) => bail!("required must be an array"),
+            None => vec![],
+        };
+        for r in &required {
+            ensure!(
+                props.contains_key(*r),
+                "required property {r:?} not in properties"
+            );
+        }
+
+        let mut entries = vec![];
+        for (name, schema) in props {
+            let is_required = required.contains(&name.as_str());
+            match self.compile(schema)? {
+                Some(value) => {
+                    let key = json_literal(&Value::String(name.clone()));
+                    entries.push((format!("{key}{COLON}{value}"), is_required));
+                }
+                None if is_required => return Ok(None),
+                None => {}
+            }
+        }
+
+        // all properties after idx, each preceded by a comma
+        let tail = |idx: usize| {
+            entries[idx..]
+                .iter()
+                .map(|(e, is_required)| {
+                    if *is_required {
+                        format!("{COMMA}{e}")
+                    } else {
+                        format!("(?:{COMMA}{e})?")
+                    }
+                })
+                .collect::<String>()
+        };
+        // the first property present is not preceded by a comma
+        let mut firsts = vec![];
+        for (idx, (e, is_required)) in entries.iter().enumerate() {
+            firsts.push(Some(format!("{e}{}", tail(idx + 1))));
+            if *is_required {
+                break;
+            }
+        }
+        let body = match alternatives(firsts) {
+            Some(body) if entries.iter().all(|(_, r)| !r) => format!("(?:{body})?"),
+            Some(body) => body,
+            None => String::new(),
+        };
+        Ok(Some(format!(r"\{{{body}\}}")))
+    }
+}
+
+fn compile_string(obj: &Map<String, Value>) -> Result<Option<String>> {
+    if let Some(pattern) = obj.get("pattern") {
+        let pattern = pattern
+            .as_str()
+            .ok_or_else(|| anyhow!("pattern must be a string"))?;
+        let pattern = json_string_pattern(pattern)?;
+        return Ok(Some(format!("\"(?:{pattern})\"")));
+    }
+    let min = get_usize(obj, "minLength")?.unwrap_or(0);
+    let max = get_usize(obj, "maxLength")?;
+    if max.is_some_and(|max| max < min) {
+        return Ok(None);
+    }
+    Ok(Some(format!("\"{STRING_CHAR}{}\"", repeat(min, max))))
+}
+
+/// Restrict `pattern` to characters allowed unescaped in a JSON string,
+/// so that it can't match past the closing quote.
+/// The result is a byte-level regex, as expected by [RecRx::from_rx].
+fn json_string_pattern(pattern: &str) -> Result<String> {
+    let hir = regex_syntax::Parser::new()
+        .parse(pattern)
+        .map_err(|e| anyhow!("invalid pattern {pattern:?}: {e}"))?;
+    let mut items = match hir.kind() {
+        HirKind::Concat(items) => items.clone(),
+        _ => vec![hir],
+    };
+    let any_chars = || {
+        Hir::repetition(Repetition {
+            min: 0,
+            max: None,
+            greedy: true,
+            sub: Box::new(utf8_class(&json_string_chars())),
+        })
+    };
+    // unanchored patterns can match anywhere in the string
+    if items
+        .first()
+        .is_some_and(|h| matches!(h.kind(), HirKind::Look(Look::Start)))
+    {
+        items.remove(0);
+    } else {
+        items.insert(0, any_chars());
+    }
+    if items
+        .last()
+        .is_some_and(|h| matches!(h.kind(), HirKind::Look(Look::End)))
+    {
+        items.pop();
+    } else {
+        items.push(any_chars());
+    }
+    Ok(json_string_hir(&Hir::concat(items))?.to_string())
+}
+
+/// Characters allowed unescaped in a JSON string.
+fn json_string_chars() -> ClassUnicode {
+    ClassUnicode::new([
+        ClassUnicodeRange::new(' ', '!'),
+        ClassUnicodeRange::new('#', '['),
+        ClassUnicodeRange::new(']', char::MAX),
+    ])
+}
+
+/// Alternation of UTF-8 byte sequences matching the characters in `cls`.
+fn utf8_class(cls: &ClassUnicode) -> Hir {
+    let mut alts = vec![];
+    for range in cls.iter() {
+        for seq in Utf8Sequences::new(range.start(), range.end()) {
+            let bytes = seq
+                .as_slice()
+                .iter()
+                .map(|r| {
+                    let cls = ClassBytes::new([ClassBytesRange::new(r.start, r.end)]);
+                    Hir::class(Class::Bytes(cls))
+                })
+                .collect();
+            alts.push(Hir::concat(bytes));
+        }
+    }
+    Hir::alternation(alts)
+}
+
+fn json_string_hir(hir: &Hir) -> Result<Hir> {
+    let r = match hir.kind() {
+        HirKind::Empty => Hir::empty(),
+        HirKind::Literal(lit) => {
+            if lit.0.iter().any(|&b| b == b'"' || b == b'\\' || b < 0x20) {
+                Hir::fail()
+            } else {
+                hir.clone()
+            }
+        }
+        HirKind::Class(Class::Unicode(cls)) => {
+            let mut cls = cls.clone();
+            cls.intersect(&json_string_chars());
+            utf8_class(&cls)
+        }
+        HirKind::Class(Class::Bytes(cls)) => {
+            let mut cls = cls.clone();
+            cls.intersect(&ClassBytes::new([
+                ClassBytesRange::new(b' ', b'!'),
+                ClassBytesRange::new(b'#', b'['),
+                ClassBytesRange::new(b']', 0xFF),
+            ]));
+            Hir::class(Class::Bytes(cls))
+        }
+        HirKind::Look(look) => bail!("{look:?} not supported in pattern"),
+        HirKind::Repetition(rep) => Hir::repetition(Repetition {
+            sub: Box::new(json_string_hir(&rep.sub)?),
+            ..rep.clone()
+        }),
+        HirKind::Capture(cap) => json_string_hir(&cap.sub)?,
+        HirKind::Concat(items) => Hir::concat(
+            items
+                .iter()
+                .map(json_string_hir)
+                .collect::<Result<Vec<_>>>()?,
+        ),
+        HirKind::Alternation(items) => Hir::alternation(
+            items
+                .iter()
+                .map(json_string_hir)
+                .collect::<Result<Vec<_>>>()?,
+        ),
+    };
+    Ok(r)
+}
+
+fn any_string() -> String {
+    format!("\"{STRING_CHAR}*\"")
+}
+
+fn any_object(depth: usize) -> String {
+    let kv = format!("{}{COLON}{}", any_string(), any_value(depth - 1));
+    format!(r"\{{(?:{kv}(?:{COMMA}{kv})*)?\}}")
+}
+
+fn any_value(depth: usize) -> String {
+    let mut options = vec![
+        "null".to_string(),
+        "true".to_string(),
+        "false".to_string(),
+        NUMBER.to_string(),
+        any_string(),
+    ];
+    if depth > 0 {
+        let v = any_value(depth - 1);
+        options.push(format!(r"\[(?:{v}(?:{COMMA}{v})*)?\]"));
+        options.push(any_object(depth));
+    }
+    alternatives(options.into_iter().map(Some).collect()).unwrap()
+}
+
+fn alternatives(options: Vec<Option<String>>) -> Option<String> {
+    let options = options.into_iter().flatten().collect::<Vec<_>>();
+    match options.len() {
+        0 => None,
+        1 => options.into_iter().next(),
+        _ => Some(format!("(?:{})", options.join("|"))),
+    }
+}
+
+fn repeat(min: usize, max: Option<usize>) -> String {
+    match (min, max) {
+        (0, None) => "*".to_string(),
+        (1, None) => "+".to_string(),
+        (min, None) => format!("{{{min},}}"),
+        (min, Some(max)) => format!("{{{min},{max}}}"),
+    }
+}
+
+fn get_usize(obj: &Map<String, Value>, key: &str) -> Result<Option<usize>> {
+    match obj.get(key) {
+        None => Ok(None),
+        Some(v) => v
+            .as_u64()
+            .map(|v| Some(v as usize))
+            .ok_or_else(|| anyhow!("{key} must be a non-negative integer")),
+    }
+}
+
+/// Regex matching exactly the (compact) serialization of the value.
+fn json_literal(v: &Value) -> String {
+    let mut r = String::new();
+    for b in serde_json::to_string(v).unwrap().bytes() {
+        if b"\\.+*?()|[]{}^$#&-~".contains(&b) {
+            r.push('\\');
+            r.push(b as char);
+        } else if b.is_ascii_graphic() || b == b' ' {
+            r.push(b as char);
+        } else {
+            r.push_str(&format!("\\x{:02X}", b));
+        }
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn matches(schema: &Value, s: &str) -> bool {
+        let mut rx = RecRx::from_rx(&json_schema_to_regex(schema).unwrap(), None).unwrap();
+        let mut state = rx.initial();
+        for b in s.bytes() {
+            match rx.try_append(state, b) {
+                Some(next) => state = next,
+                None => return false,
+            }
+        }
+        rx.is_accepting(state)
+    }
+
+    #[test]
+    fn object_properties() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "b": { "type": "integer" },
+                "a": { "type": "string", "maxLength": 3 }
+            },
+            "required": ["b"]
+        });
+        assert!(matches(&schema, r#"{"b":12}"#));
+        assert!(matches(&schema, r#"{"b":-1,"a":"xyz"}"#));
+        assert!(matches(&schema, r#"{"b": 0, "a": ""}"#));
+        // properties come in the order they are listed
+        assert!(!matches(&schema, r#"{"a":"x","b":1}"#));
+        assert!(!matches(&schema, r#"{"b":1,"a":"wxyz"}"#));
+        assert!(!matches(&schema, r#"{"b":1.5}"#));
+        assert!(!matches(&schema, r#"{}"#));
+    }
+
+    #[test]
+    fn optional_properties() {
+        let schema = json!({
+            "properties": {
+                "x": { "type": "boolean" },
+                "y": { "type": "null" }
+            }
+        });
+        assert!(matches(&schema, r#"{}"#));
+        assert!(matches(&schema, r#"{"y":null}"#));
+        assert!(matches(&schema, r#"{"x":true,"y":null}"#));
+        assert!(!matches(&schema, r#"{,"y":null}"#));
+    }
+
+    #[test]
+    fn arrays_and_enums() {
+        let schema = json!({
+            "type": "array",
+            "items": { "enum": ["x", 1, null, "é"] },
+            "minItems": 1,
+            "maxItems": 2
+        });
+        assert!(matches(&schema, r#"["x"]"#));
+        assert!(matches(&schema, r#"[1, null]"#));
+        assert!(matches(&schema, r#"["é"]"#));
+        assert!(!matches(&schema, r#"[]"#));
+        assert!(!matches(&schema, r#"["x",1,1]"#));
+        assert!(!matches(&schema, r#"["y"]"#));
+    }
+
+    #[test]
+    fn string_pattern() {
+        let schema = json!({ "type": "string", "pattern": "^[a-z]+$" });
+        assert!(matches(&schema, r#""abc""#));
+        assert!(!matches(&schema, r#""aBc""#));
+        assert!(!matches(&schema, r#""""#));
+    }
+
+    #[test]
+    fn string_pattern_stays_in_string() {
+        let schema = json!({ "type": "string", "pattern": ".*" });
+        assert!(matches(&schema, r#""a b""#));
+        assert!(!matches(&schema, r#""a"b""#));
+        assert!(!matches(&schema, r#""a\""#));
+        let schema = json!({ "type": "string", "pattern": "(?s)[\\x00-~]*" });
+        assert!(matches(&schema, r#""a~""#));
+        assert!(!matches(&schema, "\"a\n\""));
+        assert!(!matches(&schema, r#""a"b""#));
+    }
+
+    #[test]
+    fn string_pattern_anchors() {
+        // `\$` is a literal dollar
+        let schema = json!({ "type": "string", "pattern": r"^a\$" });
+        assert!(matches(&schema, r#""a$""#));
+        assert!(!matches(&schema, r#""a""#));
+        // `\\$` is a literal backslash (which is not allowed), followed by an anchor
+        let schema = json!({ "type": "string", "pattern": r"^(a|b\\)$" });
+        assert!(matches(&schema, r#""a""#));
+        assert!(!matches(&schema, r#""b\""#));
+        let schema = json!({ "type": "string", "pattern": r"a\bb" });
+        assert!(json_schema_to_regex(&schema).is_err());
+    }
+
+    #[test]
+    fn string_pattern_unanchored() {
+        let schema = json!({ "type": "string", "pattern": "ab" });
+        assert!(matches(&schema, r#""ab""#));
+        assert!(matches(&schema, r#""xaby""#));
+        assert!(!matches(&schema, r#""a b""#));
+        assert!(!matches(&schema, r#""a"ab""#));
+        let schema = json!({ "type": "string", "pattern": "^ab" });
+        assert!(matches(&schema, r#""aby""#));
+        assert!(!matches(&schema, r#""xab""#));
+        let schema = json!({ "type": "string", "pattern": "ab$" });
+        assert!(matches(&schema, r#""xab""#));
+        assert!(!matches(&schema, r#""aby""#));
+    }
+
+    #[test]
+    fn string_pattern_classes() {
+        let schema = json!({ "type": "string", "pattern": r"^\d+-\w+\s[^x]$" });
+        assert!(matches(&schema, r#""12-ab_c y""#));
+        assert!(matches(&schema, r#""1-żółw ą""#));
+        assert!(!matches(&schema, r#""12-ab x""#));
+        assert!(!matches(&schema, r#""12-ab ""#));
+        assert!(!matches(&schema, "\"1-a\t\\\"\""));
+        let schema = json!({ "type": "string", "pattern": "^.$" });
+        assert!(matches(&schema, r#""ł""#));
+        assert!(matches(&schema, r#""😀""#));
+        assert!(!matches(&schema, "\"\u{1}\""));
+        assert!(!matches(&schema, r#""ab""#));
+    }
+
+    #[test]
+    fn refs() {
+        let schema = json!({
+            "$defs": { "n": { "type": "null" } },
+            "anyOf": [{ "$ref": "#/$defs/n" }, { "type": "number" }]
+        });
+        assert!(matches(&schema, "null"));
+        assert!(matches(&schema, "1.5e3"));
+        assert!(!matches(&schema, "true"));
+
+        let list = json!({
+            "type": "object",
+            "properties": { "next": { "$ref": "#" } }
+        });
+        assert!(matches(&list, r#"{"next":{"next":{}}}"#));
+        assert!(json_schema_to_regex(&json!({ "$ref": "#/missing" })).is_err());
+    }
+
+    #[test]
+    fn unsatisfiable() {
+        assert!(json_schema_to_regex(&json!(false)).is_err());
+        assert!(
+            json_schema_to_regex(&json!({ "type": "array", "minItems": 3, "maxItems": 2 }))
+                .is_err()
+        );
+    }
+}
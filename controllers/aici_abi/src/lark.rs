@@ -0,0 +1,930 @@
+use anyhow::{bail, Result};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+/// Translate a Lark-style grammar into yacc syntax understood by [crate::cfg::CfgParser].
+///
+/// Supported are rules (lowercase) and terminals (uppercase) with `|`, `( )`, `[ ]`,
+/// `?`, `*`, `+`, `~ n` and `~ n..m` (`m` at most [MAX_REPEAT]) operators,
+/// inline `"strings"` (optionally `"..."i`) and `/regexes/flags`,
+/// `%ignore` and `%import common.NAME` for the usual terminals.
+/// Aliases (`-> name`), rule modifiers (`?rule`, `!rule`) and priorities are accepted and ignored.
+/// The start rule is `start`.
+pub fn lark_to_yacc(src: &str) -> Result<String> {
+    let tokens = tokenize(src)?;
+    let grammar = Parser {
+        tokens,
+        pos: 0,
+        grammar: Grammar::default(),
+    }
+    .parse()?;
+    Lowering::new(grammar).lower()
+}
+
+/// Upper bound on `m` in `item ~ n..m`; repetitions are expanded in the grammar.
+pub const MAX_REPEAT: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Ident(String),
+    Str(String, bool),
+    Regex(String, String),
+    Number(usize),
+    Directive(String),
+    Colon,
+    Pipe,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Question,
+    Bang,
+    Star,
+    Plus,
+    Tilde,
+    Arrow,
+    Dot,
+    DotDot,
+    Comma,
+    Eof,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Pos {
+    line: usize,
+    column: usize,
+}
+
+fn err_at<T>(pos: Pos, msg: String) -> Result<T> {
+    bail!("({},{}): {}", pos.line, pos.column, msg)
+}
+
+fn tokenize(src: &str) -> Result<Vec<(Tok, Pos)>> {
+    let chars = src.chars().collect::<Vec<_>>();
+    let mut res = Vec::new();
+    let mut idx = 0;
+    let mut line = 1;
+    let mut line_start = 0;
+    while idx < chars.len() {
+        let ch = chars[idx];
+        let pos = Pos {
+            line,
+            column: idx - line_start + 1,
+        };
+        if ch == '\n' {
+            idx += 1;
+            line += 1;
+            line_start = idx;
+            continue;
+        }
+        if ch.is_whitespace() {
+            idx += 1;
+            continue;
+        }
+        if ch == '/' && chars.get(idx + 1) == Some(&'/') {
+            while idx < chars.len() && chars[idx] != '\n' {
+                idx += 1;
+            }
+            continue;
+        }
+        let simple = match ch {
+            ':' => Some(Tok::Colon),
+            '|' => Some(Tok::Pipe),
+            '(' => Some(Tok::LParen),
+            ')' => Some(Tok::RParen),
+            '[' => Some(Tok::LBracket),
+            ']' => Some(Tok::RBracket),
+            '?' => Some(Tok::Question),
+            '!' => Some(Tok::Bang),
+            '*' => Some(Tok::Star),
+            '+' => Some(Tok::Plus),
+            '~' => Some(Tok::Tilde),
+            ',' => Some(Tok::Comma),
+            _ => None,
+        };
+        if let Some(tok) = simple {
+            res.push((tok, pos));
+            idx += 1;
+        } else if ch == '-' && chars.get(idx + 1) == Some(&'>') {
+            res.push((Tok::Arrow, pos));
+            idx += 2;
+        } else if ch == '.' {
+            if chars.get(idx + 1) == Some(&'.') {
+                res.push((Tok::DotDot, pos));
+                idx += 2;
+            } else {
+                res.push((Tok::Dot, pos));
+                idx += 1;
+            }
+        } else if ch.is_ascii_digit() {
+            let start = idx;
+            while idx < chars.len() && chars[idx].is_ascii_digit() {
+                idx += 1;
+            }
+            let s = chars[start..idx].iter().collect::<String>();
+            res.push((Tok::Number(s.parse()?), pos));
+        } else if ch == '_' || ch.is_ascii_alphabetic() || ch == '%' {
+            let start = idx;
+            idx += 1;
+            while idx < chars.len() && (chars[idx] == '_' || chars[idx].is_ascii_alphanumeric()) {
+                idx += 1;
+            }
+            let s = chars[start..idx].iter().collect::<String>();
+            if ch == '%' {
+                res.push((Tok::Directive(s[1..].to_string()), pos));
+            } else {
+                res.push((Tok::Ident(s), pos));
+            }
+        } else if ch == '"' || ch == '\'' {
+            idx += 1;
+            let mut s = String::new();
+            loop {
+                match chars.get(idx) {
+                    None | Some('\n') => return err_at(pos, "unterminated string".to_string()),
+                    Some(c) if *c == ch => break,
+                    Some('\\') => {
+                        idx += 1;
+                        let c = chars.get(idx).copied().unwrap_or('\\');
+                        match c {
+                            'n' => s.push('\n'),
+                            't' => s.push('\t'),
+                            'r' => s.push('\r'),
+                            '0' => s.push('\0'),
+                            'x' | 'u' => {
+                                let len = if c == 'x' { 2 } else { 4 };
+                                let hex = chars[idx + 1..std::cmp::min(idx + 1 + len, chars.len())]
+                                    .iter()
+                                    .collect::<String>();
+                                match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                                    Some(c) if hex.len() == len => s.push(c),
+                                    _ => return err_at(pos, format!("invalid escape \\{c}{hex}")),
+                                }
+                                idx += len;
+                            }
+                            _ => s.push(c),
+                        }
+                    }
+                    Some(c) => s.push(*c),
+                }
+                idx += 1;
+            }
+            idx += 1;
+            let icase = chars.get(idx) == Some(&'i');
+            if icase {
+                idx += 1;
+            }
+            res.push((Tok::Str(s, icase), pos));
+        } else if ch == '/' {
+            idx += 1;
+            let mut s = String::new();
+            let mut in_class = false;
+            loop {
+                match chars.get(idx) {
+                    None | Some('\n') => return err_at(pos, "unterminated regex".to_string()),
+                    Some('/') if !in_class => break,
+                    Some('\\') => {
+                        let c = chars.get(idx + 1).copied().unwrap_or('\\');
+                        if c != '/' {
+                            s.push('\\');
+                        }
+                        s.push(c);
+                        idx += 1;
+                    }
+                    Some(c) => {
+                        if *c == '[' {
+                            in_class = true;
+                        } else if *c == ']' {
+                            in_class = false;
+                        }
+                        s.push(*c)
+                    }
+                }
+                idx += 1;
+            }
+            idx += 1;
+            let mut flags = String::new();
+            while idx < chars.len() && "imslux".contains(chars[idx]) {
+                flags.push(chars[idx]);
+                idx += 1;
+            }
+            res.push((Tok::Regex(s, flags), pos));
+        } else {
+            return err_at(pos, format!("unexpected character {:?}", ch));
+        }
+    }
+    res.push((
+        Tok::Eof,
+        Pos {
+            line,
+            column: idx - line_start + 1,
+        },
+    ));
+    Ok(res)
+}
+
+#[derive(Debug, Clone)]
+enum Atom {
+    Str(String, bool),
+    Regex(String, String),
+    Name(String, Pos),
+    Group(Alts),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    One,
+    Opt,
+    Star,
+    Plus,
+    Range(usize, usize),
+}
+
+#[derive(Debug, Clone)]
+struct Item {
+    atom: Atom,
+    op: Op,
+}
+
+type Alts = Vec<Vec<Item>>;
+
+#[derive(Default)]
+struct Grammar {
+    rules: Vec<(String, Alts)>,
+    terminals: FxHashMap<String, Alts>,
+    ignore: Vec<Item>,
+}
+
+fn is_terminal_name(name: &str) -> bool {
+    !name.chars().any(|c| c.is_ascii_lowercase())
+}
+
+struct Parser {
+    tokens: Vec<(Tok, Pos)>,
+    pos: usize,
+    grammar: Grammar,
+}
+
+impl Parser {
+    fn peek_at(&self, off: usize) -> &Tok {
+        let idx = std::cmp::min(self.pos + off, self.tokens.len() - 1);
+        &self.tokens[idx].0
+    }
+
+    fn peek(&self) -> &Tok {
+        self.peek_at(0)
+    }
+
+    fn cur_pos(&self) -> Pos {
+        self.tokens[self.pos].1
+    }
+
+    fn next(&mut self) -> Tok {
+        let t = self.peek().clone();
+        if self.pos < self.tokens.len() - 1 {
+            self.pos += 1;
+        }
+        t
+    }
+
+    fn error<T>(&self, msg: &str) -> Result<T> {
+        err_at(self.cur_pos(), format!("{}; found {:?}", msg, self.peek()))
+    }
+
+    fn expect(&mut self, tok: Tok, msg: &str) -> Result<()> {
+        if self.peek() == &tok {
+            self.next();
+            Ok(())
+        } else {
+            self.error(msg)
+        }
+    }
+
+    // length of definition header (`?name.2:`) starting at current position, if any
+    fn def_start_len(&self) -> Option<usize> {
+        let mut off = 0;
+        if matches!(self.peek(), Tok::Question | Tok::Bang) {
+            off += 1;
+        }
+        if !matches!(self.peek_at(off), Tok::Ident(_)) {
+            return None;
+        }
+        off += 1;
+        if self.peek_at(off) == &Tok::Dot {
+            if !matches!(self.peek_at(off + 1), Tok::Number(_)) {
+                return None;
+            }
+            off += 2;
+        }
+        if self.peek_at(off) == &Tok::Colon {
+            Some(off + 1)
+        } else {
+            None
+        }
+    }
+
+    fn parse(mut self) -> Result<Grammar> {
+        loop {
+            let pos = self.cur_pos();
+            match self.peek().clone() {
+                Tok::Eof => break,
+                Tok::Directive(d) => {
+                    self.next();
+                    match d.as_str() {
+                        "ignore" => {
+                            let item = self.parse_item()?;
+                            match item {
+                                Some(item) => self.grammar.ignore.push(item),
+                                None => return self.error("expecting terminal after %ignore"),
+                            }
+                        }
+                        "import" => self.parse_import()?,
+                        _ => return err_at(pos, format!("unsupported directive %{}", d)),
+                    }
+                }
+                _ => {
+                    if self.def_start_len().is_none() {
+                        return self.error("expecting rule or terminal definition");
+                    }
+                    if matches!(self.peek(), Tok::Question | Tok::Bang) {
+                        self.next();
+                    }
+                    let name = match self.next() {
+                        Tok::Ident(n) => n,
+                        _ => unreachable!(),
+                    };
+                    if self.peek() == &Tok::Dot {
+                        self.next();
+                        self.next();
+                    }
+                    self.expect(Tok::Colon, "expecting ':'")?;
+                    let alts = self.parse_alts()?;
+                    if is_terminal_name(&name) {
+                        if self.grammar.terminals.insert(name.clone(), alts).is_some() {
+                            return err_at(pos, format!("terminal {} defined twice", name));
+                        }
+                    } else {
+                        if self.grammar.rules.iter().any(|(n, _)| n == &name) {
+                            return err_at(pos, format!("rule {} defined twice", name));
+                        }
+                        self.grammar.rules.push((name, alts));
+                    }
+                }
+            }
+        }
+        Ok(self.grammar)
+    }
+
+    fn parse_import(&mut self) -> Result<()> {
+        let pos = self.cur_pos();
+        let mut path = Vec::new();
+        loop {
+            match self.next() {
+                Tok::Ident(n) => path.push(n),
+                _ => return err_at(pos, "expecting module path".to_string()),
+            }
+            if self.peek() == &Tok::Dot {
+                self.next();
+            } else {
+                break;
+            }
+        }
+        let mut names = Vec::new();
+        if self.peek() == &Tok::LParen {
+            self.next();
+            loop {
+                match self.next() {
+                    Tok::Ident(n) => names.push(n),
+                    _ => return err_at(pos, "expecting name in %import list".to_string()),
+                }
+                match self.next() {
+                    Tok::Comma => {}
+                    Tok::RParen => break,
+                    _ => return err_at(pos, "expecting ',' or ')'".to_string()),
+                }
+            }
+        } else if path.len() >= 2 {
+            names.push(path.pop().unwrap());
+        }
+        if path != ["common"] {
+            return err_at(pos, "only %import common is supported".to_string());
+        }
+        for name in names {
+            match common_terminal(&name) {
+                Some(rx) => {
+                    let item = Item {
+                        atom: Atom::Regex(rx.to_string(), String::new()),
+                        op: Op::One,
+                    };
+                    self.grammar.terminals.insert(name, vec![vec![item]]);
+                }
+                None => return err_at(pos, format!("unknown terminal common.{}", name)),
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_alts(&mut self) -> Result<Alts> {
+        let mut alts = vec![self.parse_seq()?];
+        while self.peek() == &Tok::Pipe {
+            self.next();
+            alts.push(self.parse_seq()?);
+        }
+        Ok(alts)
+    }
+
+    fn parse_seq(&mut self) -> Result<Vec<Item>> {
+        let mut items = Vec::new();
+        while let Some(item) = self.parse_item()? {
+            items.push(item);
+        }
+        if self.peek() == &Tok::Arrow {
+            self.next();
+            match self.next() {
+                Tok::Ident(_) => {}
+                _ => return self.error("expecting alias name after '->'"),
+            }
+        }
+        Ok(items)
+    }
+
+    fn parse_item(&mut self) -> Result<Option<Item>> {
+        if self.def_start_len().is_some() {
+            return Ok(None);
+        }
+        let pos = self.cur_pos();
+        let mut op = Op::One;
+        let atom = match self.peek().clone() {
+            Tok::Str(s, icase) => {
+                if s.is_empty() {
+                    return err_at(pos, "empty string literal".to_string());
+                }
+                if self.peek_at(1) == &Tok::DotDot {
+                    // "a".."z" character range
+                    self.next();
+                    self.next();
+                    let end = match self.peek() {
+                        Tok::Str(e, _) => e.clone(),
+                        _ => return self.error("expecting string after '..'"),
+                    };
+                    match (single_ascii(&s), single_ascii(&end)) {
+                        (Some(a), Some(b)) if a <= b => Atom::Regex(
+                            format!("[\\x{:02x}-\\x{:02x}]", a as u32, b as u32),
+                            String::new(),
+                        ),
+                        _ => return err_at(pos, "invalid character range".to_string()),
+                    }
+                } else {
+                    Atom::Str(s, icase)
+                }
+            }
+            Tok::Regex(rx, flags) => Atom::Regex(rx, flags),
+            Tok::Ident(name) => Atom::Name(name, pos),
+            Tok::LParen | Tok::LBracket => {
+                let close = if self.next() == Tok::LParen {
+                    Tok::RParen
+                } else {
+                    op = Op::Opt;
+                    Tok::RBracket
+                };
+                let alts = self.parse_alts()?;
+                if self.peek() != &close {
+                    return self.error(&format!("expecting {:?}", close));
+                }
+                Atom::Group(alts)
+            }
+            _ => return Ok(None),
+        };
+        self.next();
+
+        if let Op::One = op {
+            // '?' followed by a name and ':' starts the next rule
+            op = match self.peek() {
+                Tok::Question if self.def_start_len().is_none() => Op::Opt,
+                Tok::Star => Op::Star,
+                Tok::Plus => Op::Plus,
+                Tok::Tilde => {
+                    self.next();
+                    let min = match self.peek() {
+                        Tok::Number(n) => *n,
+                        _ => return self.error("expecting number after '~'"),
+                    };
+                    let mut max = min;
+                    if self.peek_at(1) == &Tok::DotDot {
+                        self.next();
+                        self.next();
+                        max = match self.peek() {
+                            Tok::Number(n) if *n >= min => *n,
+                            _ => return self.error("expecting range end"),
+                        };
+                    }
+                    if max > MAX_REPEAT {
+                        return self.error(&format!("repetition count above {MAX_REPEAT}"));
+                    }
+                    Op::Range(min, max)
+                }
+                _ => Op::One,
+            };
+            if let Op::One = op {
+            } else {
+                self.next();
+            }
+        }
+
+        Ok(Some(Item { atom, op }))
+    }
+}
+
+fn single_ascii(s: &str) -> Option<char> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii() => Some(c),
+        _ => None,
+    }
+}
+
+fn common_terminal(name: &str) -> Option<&'static str> {
+    let rx = match name {
+        "DIGIT" => r"[0-9]",
+        "HEXDIGIT" => r"[a-fA-F0-9]",
+        "INT" => r"[0-9]+",
+        "SIGNED_INT" => r"[+\-]?[0-9]+",
+        "DECIMAL" => r"[0-9]+\.[0-9]*|\.[0-9]+",
+        "FLOAT" => r"[0-9]+[eE][+\-]?[0-9]+|([0-9]+\.[0-9]*|\.[0-9]+)([eE][+\-]?[0-9]+)?",
+        "SIGNED_FLOAT" => {
+            r"[+\-]?([0-9]+[eE][+\-]?[0-9]+|([0-9]+\.[0-9]*|\.[0-9]+)([eE][+\-]?[0-9]+)?)"
+        }
+        "NUMBER" => r"[0-9]+([eE][+\-]?[0-9]+)?|([0-9]+\.[0-9]*|\.[0-9]+)([eE][+\-]?[0-9]+)?",
+        "SIGNED_NUMBER" => {
+            r"[+\-]?([0-9]+([eE][+\-]?[0-9]+)?|([0-9]+\.[0-9]*|\.[0-9]+)([eE][+\-]?[0-9]+)?)"
+        }
+        "ESCAPED_STRING" => r#""(\\.|[^"\\\n])*""#,
+        "LCASE_LETTER" => r"[a-z]",
+        "UCASE_LETTER" => r"[A-Z]",
+        "LETTER" => r"[a-zA-Z]",
+        "WORD" => r"[a-zA-Z]+",
+        "CNAME" => r"[_a-zA-Z][_a-zA-Z0-9]*",
+        "WS_INLINE" => r"[ \t]+",
+        "WS" => r"[ \t\f\r\n]+",
+        "CR" => r"\r",
+        "LF" => r"\n",
+        "NEWLINE" => r"(\r?\n)+",
+        "SH_COMMENT" => r"#[^\n]*",
+        "CPP_COMMENT" => r"//[^\n]*",
+        "C_COMMENT" => r"/\*[^*]*\*+([^/*][^*]*\*+)*/",
+        _ => return None,
+    };
+    Some(rx)
+}
+
+// quotes are replaced with hex escapes, so that the regex can be put inside "/.../" yacc token
+fn sanitize_rx(rx: &str) -> String {
+    let mut res = String::new();
+    let mut chars = rx.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => res.push_str(r"\x22"),
+            '\'' => res.push_str(r"\x27"),
+            '\\' => match chars.next() {
+                Some('"') => res.push_str(r"\x22"),
+                Some('\'') => res.push_str(r"\x27"),
+                Some(c) => {
+                    res.push('\\');
+                    res.push(c);
+                }
+                None => res.push('\\'),
+            },
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+fn escape_rx(s: &str) -> String {
+    let mut res = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b == b'_' {
+            res.push(b as char);
+        } else if b"\\.+*?()|[]{}^$#&-~/".contains(&b) {
+            res.push('\\');
+            res.push(b as char);
+        } else if b.is_ascii_graphic() && b != b'"' && b != b'\'' {
+            res.push(b as char);
+        } else {
+            res.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    res
+}
+
+fn flags_rx(rx: &str, flags: &str) -> String {
+    let flags = flags
+        .chars()
+        .filter(|c| "imsx".contains(*c))
+        .collect::<String>();
+    format!("(?{}:{})", flags, rx)
+}
+
+fn literal_rx(s: &str, icase: bool) -> String {
+    if icase {
+        flags_rx(&escape_rx(s), "i")
+    } else {
+        escape_rx(s)
+    }
+}
+
+struct Lowering {
+    grammar: Grammar,
+    output: Vec<String>,
+    num_anon: usize,
+    used_terminals: Vec<String>,
+    terminal_rx: FxHashMap<String, String>,
+}
+
+impl Lowering {
+    fn new(grammar: Grammar) -> Self {
+        Lowering {
+            grammar,
+            output: Vec::new(),
+            num_anon: 0,
+            used_terminals: Vec::new(),
+            terminal_rx: FxHashMap::default(),
+        }
+    }
+
+    fn lower(mut self) -> Result<String> {
+        if !self.grammar.rules.iter().any(|(n, _)| n == "start") {
+            bail!("no 'start' rule");
+        }
+        let mut res = "%start start\n%%\n\n".to_string();
+
+        let rules = self.grammar.rules.clone();
+        for (name, alts) in &rules {
+            self.add_rule(name, alts)?;
+        }
+
+        let ignore = std::mem::take(&mut self.grammar.ignore);
+        if !ignore.is_empty() {
+            let mut skip = Vec::new();
+            for item in &ignore {
+                let tok = match &item.atom {
+                    Atom::Name(n, pos) if is_terminal_name(n) => self.terminal_token(n, *pos)?,
+                    _ => {
+                        let rx = self.item_rx(item, &mut vec![])?;
+                        format!("\"/{}/\"", rx)
+                    }
+                };
+                skip.push(tok);
+            }
+            self.output
+                .push(format!("SKIP\n    : {}\n    ;\n", skip.join("\n    | ")));
+        }
+
+        let mut done = FxHashSet::default();
+        let mut idx = 0;
+        while idx < self.used_terminals.len() {
+            let name = self.used_terminals[idx].clone();
+            idx += 1;
+            if !done.insert(name.clone()) {
+                continue;
+            }
+            let tok = self.terminal_token_def(&name)?;
+            self.output.push(format!("{}: {} ;\n", name, tok));
+        }
+
+        res.push_str(&self.output.join("\n"));
+        Ok(res)
+    }
+
+    fn add_rule(&mut self, name: &str, alts: &Alts) -> Result<()> {
+        let mut lines = Vec::new();
+        for seq in alts {
+            let mut syms = Vec::new();
+            for item in seq {
+                self.lower_item(item, &mut syms)?;
+            }
+            lines.push(syms.join(" "));
+        }
+        self.push_rule(name, &lines);
+        Ok(())
+    }
+
+    fn push_rule(&mut self, name: &str, alts: &[String]) {
+        self.output.push(format!(
+            "{}\n    : {}\n    ;\n",
+            name,
+            alts.join("\n    | ")
+        ));
+    }
+
+    fn fresh_name(&mut self) -> String {
+        self.num_anon += 1;
+        format!("__lark_{}", self.num_anon)
+    }
+
+    fn anon_rule(&mut self, alts: Vec<String>) -> String {
+        let name = self.fresh_name();
+        self.push_rule(&name, &alts);
+        name
+    }
+
+    fn lower_item(&mut self, item: &Item, syms: &mut Vec<String>) -> Result<()> {
+        let sym = match &item.atom {
+            Atom::Str(s, icase) => {
+                if !icase
+                    && s.chars()
+                        .all(|c| c.is_ascii_graphic() && c != '\\' && c != '"' && c != '\'')
+                {
+                    // keywords take priority over regexes in the lexer
+                    format!("\"{}\"", s)
+                } else {
+                    format!("\"/{}/\"", literal_rx(s, *icase))
+                }
+            }
+            Atom::Regex(rx, flags) => format!("\"/{}/\"", flags_rx(&sanitize_rx(rx), flags)),
+            Atom::Name(n, pos) => {
+                if is_terminal_name(n) {
+                    if !self.grammar.terminals.contains_key(n) {
+                        return err_at(*pos, format!("undefined terminal {}", n));
+                    }
+                    self.used_terminals.push(n.clone());
+                } else if !self.grammar.rules.iter().any(|(r, _)| r == n) {
+                    return err_at(*pos, format!("undefined rule {}", n));
+                }
+                n.clone()
+            }
+            Atom::Group(alts) => {
+                let mut lines = Vec::new();
+                for seq in alts {
+                    let mut syms = Vec::new();
+                    for item in seq {
+                        self.lower_item(item, &mut syms)?;
+                    }
+                    lines.push(syms.join(" "));
+                }
+                if lines.len() == 1 && !lines[0].is_empty() && !lines[0].contains(' ') {
+                    lines.pop().unwrap()
+                } else {
+                    self.anon_rule(lines)
+                }
+            }
+        };
+        match item.op {
+            Op::One => syms.push(sym),
+            Op::Opt => syms.push(self.anon_rule(vec![String::new(), sym])),
+            Op::Star | Op::Plus => {
+                // left recursion keeps LR stacks and Earley charts small
+                let name = self.fresh_name();
+                let first = if let Op::Star = item.op {
+                    String::new()
+                } else {
+                    sym.clone()
+                };
+                self.push_rule(&name, &[first, format!("{} {}", name, sym)]);
+                syms.push(name);
+            }
+            Op::Range(min, max) => {
+                for _ in 0..min {
+                    syms.push(sym.clone());
+                }
+                // x{0,3} is (x (x (x)?)?)? - unlike x? x? x? this is not ambiguous
+                let mut opt = None;
+                for _ in min..max {
+                    let seq = match opt {
+                        Some(inner) => format!("{} {}", sym, inner),
+                        None => sym.clone(),
+                    };
+                    opt = Some(self.anon_rule(vec![String::new(), seq]));
+                }
+                syms.extend(opt);
+            }
+        }
+        Ok(())
+    }
+
+    fn terminal_token(&mut self, name: &str, pos: Pos) -> Result<String> {
+        if !self.grammar.terminals.contains_key(name) {
+            return err_at(pos, format!("undefined terminal {}", name));
+        }
+        self.terminal_token_def(name)
+    }
+
+    // yacc token for terminal definition; plain string terminals become keywords
+    fn terminal_token_def(&mut self, name: &str) -> Result<String> {
+        let alts = self.grammar.terminals.get(name).unwrap().clone();
+        if let [seq] = alts.as_slice() {
+            if let [item] = seq.as_slice() {
+                if let (Atom::Str(_, _), Op::One) = (&item.atom, item.op) {
+                    let mut syms = Vec::new();
+                    self.lower_item(item, &mut syms)?;
+                    return Ok(syms.pop().unwrap());
+                }
+            }
+        }
+        let rx = self.terminal_rx(name, &mut vec![])?;
+        Ok(format!("\"/{}/\"", rx))
+    }
+
+    fn terminal_rx(&mut self, name: &str, stack: &mut Vec<String>) -> Result<String> {
+        if let Some(rx) = self.terminal_rx.get(name) {
+            return Ok(rx.clone());
+        }
+        let alts = self.grammar.terminals.get(name).unwrap().clone();
+        stack.push(name.to_string());
+        let rx = self.alts_rx(&alts, stack)?;
+        stack.pop();
+        self.terminal_rx.insert(name.to_string(), rx.clone());
+        Ok(rx)
+    }
+
+    fn alts_rx(&mut self, alts: &Alts, stack: &mut Vec<String>) -> Result<String> {
+        let mut res = Vec::new();
+        for seq in alts {
+            let mut s = String::new();
+            for item in seq {
+                s.push_str(&self.item_rx(item, stack)?);
+            }
+            res.push(s);
+        }
+        Ok(res.join("|"))
+    }
+
+    fn item_rx(&mut self, item: &Item, stack: &mut Vec<String>) -> Result<String> {
+        let rx = match &item.atom {
+            Atom::Str(s, false) => format!("(?:{})", escape_rx(s)),
+            Atom::Str(s, true) => literal_rx(s, true),
+            Atom::Regex(rx, flags) => flags_rx(&sanitize_rx(rx), flags),
+            Atom::Name(n, pos) => {
+                if !is_terminal_name(n) {
+                    return err_at(*pos, format!("rule {} used in a terminal", n));
+                }
+                if stack.contains(n) {
+                    return err_at(*pos, format!("terminal {} is recursive", n));
+                }
+                if !self.grammar.terminals.contains_key(n) {
+                    return err_at(*pos, format!("undefined terminal {}", n));
+                }
+                format!("(?:{})", self.terminal_rx(n, stack)?)
+            }
+            Atom::Group(alts) => format!("(?:{})", self.alts_rx(alts, stack)?),
+        };
+        Ok(match item.op {
+            Op::One => rx,
+            Op::Opt => format!("{}?", rx),
+            Op::Star => format!("{}*", rx),
+            Op::Plus => format!("{}+", rx),
+            Op::Range(min, max) => format!("{}{{{},{}}}", rx, min, max),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn err(src: &str) -> String {
+        lark_to_yacc(src).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn lowering() {
+        let yacc = lark_to_yacc(
+            r#"
+start: "a" b? B+
+b: "c" ~ 2
+B: /[0-9]+/
+%ignore " "
+"#,
+        )
+        .unwrap();
+        assert!(yacc.starts_with("%start start\n%%\n"));
+        assert!(yacc.contains("b\n    : \"c\" \"c\"\n    ;\n"));
+        assert!(yacc.contains("B: \"/(?:[0-9]+)/\" ;"));
+        assert!(yacc.contains("SKIP\n    : \"/(?:\\x20)/\""));
+    }
+
+    #[test]
+    fn range_lowering() {
+        let yacc = lark_to_yacc("start: \"a\" ~ 1..3\n").unwrap();
+        assert!(yacc.contains("__lark_1\n    : \n    | \"a\"\n    ;\n"));
+        assert!(yacc.contains("__lark_2\n    : \n    | \"a\" __lark_1\n    ;\n"));
+        assert!(yacc.contains("start\n    : \"a\" __lark_2\n    ;\n"));
+        assert!(!yacc.contains("__lark_3"));
+
+        // x? x? would have LR conflicts
+        let cfg = crate::cfg::CfgParser::from_lark("start: \"a\" ~ 1..3\n").unwrap();
+        assert_eq!(cfg.mode(), crate::cfg::CfgMode::Lr);
+
+        assert!(lark_to_yacc(&format!("start: \"a\" ~ {MAX_REPEAT}\n")).is_ok());
+        assert!(err(&format!("start: \"a\" ~ 1..{}\n", MAX_REPEAT + 1))
+            .contains("repetition count above"));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(err("start: foo\n").contains("(1,8): undefined rule foo"));
+        assert!(err("start: X\n").contains("(1,8): undefined terminal X"));
+        assert!(err("start: \"a\"\nstart: \"b\"\n").contains("(2,1): rule start defined twice"));
+        assert!(err("start: \"abc\n").contains("(1,8): unterminated string"));
+        assert!(err("start: A\nA: A \"x\"\n").contains("terminal A is recursive"));
+        assert!(err("foo: \"a\"\n").contains("no 'start' rule"));
+    }
+}
@@ -1,8 +1,10 @@
+use anyhow::{anyhow, ensure, Result};
 use regex_automata::{
     dfa::{dense, Automaton},
     util::syntax,
 };
 use rustc_hash::FxHashMap;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
 use std::{hash::Hash, vec};
 use vob::{vob, Vob};
 
@@ -29,7 +31,7 @@ impl LexerState {
     }
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, Copy)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Copy, Serialize, Deserialize)]
 pub struct VobIdx {
     v: u32,
 }
@@ -81,6 +83,18 @@ impl VobSet {
         idx
     }
 
+    pub fn from_vobs(vobs: Vec<Vob>) -> Self {
+        let mut r = VobSet::new();
+        for v in &vobs {
+            r.insert_or_get(v);
+        }
+        r
+    }
+
+    pub fn contains(&self, idx: VobIdx) -> bool {
+        idx.as_usize() < self.vobs.len()
+    }
+
     pub fn resolve(&self, idx: VobIdx) -> &Vob {
         &self.vobs[idx.as_usize()]
     }
@@ -114,6 +128,23 @@ impl VobSet {
     }
 }
 
+// only the VOBs are serialized; the rest is re-computed
+impl Serialize for VobSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.vobs.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for VobSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let vobs = Vec::<Vob>::deserialize(deserializer)?;
+        if !vobs.is_empty() && !vob_is_zero(&vobs[0]) {
+            return Err(D::Error::custom("first vob must be empty"));
+        }
+        Ok(VobSet::from_vobs(vobs))
+    }
+}
+
 pub struct Lexer {
     dfa: dense::DFA<Vec<u32>>,
     initial: LexerState,
@@ -240,6 +271,65 @@ impl Lexer {
         lex
     }
 
+    fn from_dfa_bytes(bytes: &[u8], vobidx_by_state_off: Vec<VobIdx>) -> Result<Self> {
+        // DFA::from_bytes() needs u32-aligned input
+        let mut buf = vec![0u32; bytes.len().div_ceil(4)];
+        bytemuck::cast_slice_mut::<u32, u8>(&mut buf)[..bytes.len()].copy_from_slice(bytes);
+        let (dfa, _) = dense::DFA::from_bytes(bytemuck::cast_slice(&buf))
+            .map_err(|e| anyhow!("invalid lexer DFA: {e}"))?;
+        let dfa = dfa.to_owned();
+        let initial = dfa
+            .universal_start_state(regex_automata::Anchored::Yes)
+            .ok_or_else(|| anyhow!("lexer DFA has no universal start state"))?;
+        let mut lex = Lexer {
+            dfa,
+            vobidx_by_state_off,
+            initial: LexerState::fake(),
+        };
+        lex.check_states(initial)?;
+        lex.initial = lex.mk_state(initial);
+        Ok(lex)
+    }
+
+    // all states reachable from the initial one need their reachable sets
+    fn check_states(&self, initial: StateID) -> Result<()> {
+        let shift = self.dfa.stride2();
+        let mut seen = vec![false; self.vobidx_by_state_off.len()];
+        let mut todo = vec![initial];
+        while let Some(s) = todo.pop() {
+            let off = s.as_usize() >> shift;
+            ensure!(
+                off < seen.len(),
+                "lexer DFA doesn't match the reachable sets"
+            );
+            if !seen[off] {
+                seen[off] = true;
+                todo.extend((0..=255).map(|b| self.dfa.next_state(s, b)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the lexer was built for `num_patterns` tokens, with reachable sets
+    /// in `vobset` (when both are loaded from bytes).
+    pub fn check_vobset(&self, vobset: &VobSet, num_patterns: usize) -> Result<()> {
+        ensure!(
+            self.dfa.pattern_len() == num_patterns,
+            "lexer DFA has {} patterns, expecting {num_patterns}",
+            self.dfa.pattern_len()
+        );
+        ensure!(
+            vobset.contains(VobIdx::all_zero())
+                && vobset.vobs.iter().all(|v| v.len() == num_patterns),
+            "token sets don't match the patterns"
+        );
+        ensure!(
+            self.vobidx_by_state_off.iter().all(|v| vobset.contains(*v)),
+            "lexer refers to missing token sets"
+        );
+        Ok(())
+    }
+
     pub fn file_start_state(&self) -> StateID {
         self.initial.state
         // pretend we've just seen a newline at the beginning of the file
@@ -329,6 +419,21 @@ impl Lexer {
     }
 }
 
+// the DFA is serialized in native endianness; artifacts are not meant to move between hosts
+impl Serialize for Lexer {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (bytes, pad) = self.dfa.to_bytes_native_endian();
+        (&bytes[pad..], &self.vobidx_by_state_off).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Lexer {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (bytes, vobidx_by_state_off) = <(Vec<u8>, Vec<VobIdx>)>::deserialize(deserializer)?;
+        Lexer::from_dfa_bytes(&bytes, vobidx_by_state_off).map_err(D::Error::custom)
+    }
+}
+
 fn vob_and_is_zero(a: &Vob, b: &Vob) -> bool {
     debug_assert!(a.len() == b.len());
     for (a, b) in a.iter_storage().zip(b.iter_storage()) {
@@ -5,29 +5,68 @@ pub use toktrie::{SimpleVob, TokenizerEnv};
 use serde::{Deserialize, Serialize};
 
 mod host;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod native;
 
 #[cfg(feature = "cfg")]
 pub mod cfg;
 #[cfg(feature = "cfg")]
+mod earley;
+#[cfg(feature = "cfg")]
+mod lark;
+#[cfg(feature = "cfg")]
 mod lex;
 
 #[cfg(feature = "rx")]
 pub mod rx;
+#[cfg(feature = "rx")]
+pub mod json_schema;
 
+pub mod attention;
+pub mod branch;
+pub mod combinators;
 pub mod dlex;
+pub mod healing;
 
+pub mod fuzzy_substring;
 pub mod substring;
 
 pub type TokenId = toktrie::TokenId;
 
+/// Check if the recognizer accepts `s` followed by EOS; its state is restored afterwards.
+#[cfg(test)]
+pub(crate) fn accepts(r: &mut dyn recognizer::Recognizer, s: &str) -> bool {
+    let mut num = 0;
+    let mut ok = true;
+    for b in s.bytes() {
+        if !r.try_push_byte(b) {
+            ok = false;
+            break;
+        }
+        num += 1;
+    }
+    ok = ok && r.special_allowed(toktrie::SpecialToken::EndOfSentence);
+    r.pop_bytes(num);
+    r.trie_finished();
+    ok
+}
+
+pub use attention::{AttentionMask, TokenTags};
+pub use branch::{Branch, BranchSampling, Splice};
+
 pub use host::{
-    aici_stop, arg_bytes, arg_string, get_config, host_trie, self_seq_id, tokenize, tokenize_bytes,
-    StorageCmd, StorageOp, StorageResp, VariableStorage, WasmTokenizerEnv,
+    aici_stop, arg_bytes, arg_string, blob_cache_enabled, blob_cache_get, blob_cache_put,
+    emit_event, emit_event_json, get_config, host_trie, now_micros, random_seed, self_seq_id,
+    tokenize, tokenize_bytes, StorageCmd, StorageOp, StorageResp, StorageScope, VariableStorage,
+    WasmTokenizerEnv,
 };
 
 #[cfg(not(target_arch = "wasm32"))]
 pub use host::{set_host, HostInterface};
 
+#[doc(hidden)]
+pub use host::export_guard;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct InitPromptArg {
     pub prompt: Vec<TokenId>,
@@ -60,6 +99,52 @@ pub struct MidProcessArg {
     pub sampled: Option<TokenId>,
     ///
     pub fork_group: Vec<SeqId>,
+    /// Log-probabilities from the previous step, if requested with
+    /// `MidProcessResult::with_logprobs()` and supported by the host.
+    #[serde(default)]
+    pub logprobs: Option<Logprobs>,
+}
+
+/// Maximal `top_k` in `LogprobsRequest`; larger values are clamped by the host.
+/// Logprobs are passed with the JSON arguments, so they need to stay small.
+pub const MAX_LOGPROBS_TOP_K: u32 = 20;
+
+/// Which log-probabilities to compute when sampling the next token.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LogprobsRequest {
+    /// Number of most likely tokens to return (at most `MAX_LOGPROBS_TOP_K`).
+    pub top_k: u32,
+    /// Also return the full distribution (one entry per vocabulary token).
+    /// Requires the `logprobs_full` capability of the host.
+    #[serde(default)]
+    pub full: bool,
+}
+
+impl LogprobsRequest {
+    pub fn top_k(top_k: u32) -> Self {
+        LogprobsRequest {
+            top_k: std::cmp::min(top_k, MAX_LOGPROBS_TOP_K),
+            full: false,
+        }
+    }
+
+    /// Request the full distribution, in addition to `top_k` most likely tokens.
+    pub fn with_full(mut self) -> Self {
+        self.full = true;
+        self
+    }
+}
+
+/// Log-probabilities of the sampled token and its alternatives.
+/// They are computed from the logits before the controller's bias is applied.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Logprobs {
+    pub sampled: f32,
+    /// Top-k tokens, most likely first.
+    pub top: Vec<(TokenId, f32)>,
+    /// One entry per vocabulary token, if requested with `LogprobsRequest::full`.
+    #[serde(default)]
+    pub full: Option<Vec<f32>>,
 }
 
 impl MidProcessArg {
@@ -79,8 +164,6 @@ impl MidProcessArg {
     }
 }
 
-pub use toktrie::{Branch, Splice};
-
 #[derive(Debug)]
 pub struct MidProcessResult {
     /// Fork the request into multiple branches.
@@ -88,21 +171,34 @@ pub struct MidProcessResult {
     /// If multiple branches are returned, they are executed in parallel.
     /// If no branches are returned, the request is terminated.
     pub branches: Vec<Branch<SimpleVob>>,
+    /// Tokens to exclude from attention, starting with the next forward pass
+    /// (the mask applies until a result with a different mask is returned).
+    pub attention_mask: AttentionMask,
+    /// Request log-probabilities of the next sampled token to be passed
+    /// in the following `MidProcessArg`.
+    pub logprobs: Option<LogprobsRequest>,
 }
 
 impl MidProcessResult {
-    pub fn from_branch(branch: Branch<SimpleVob>) -> Self {
+    pub fn from_branch(branch: impl Into<Branch<SimpleVob>>) -> Self {
+        let branch = branch.into();
         if branch.is_stop() {
             Self::stop()
         } else {
             MidProcessResult {
                 branches: vec![branch],
+                attention_mask: AttentionMask::default(),
+                logprobs: None,
             }
         }
     }
 
     pub fn stop() -> Self {
-        MidProcessResult { branches: vec![] }
+        MidProcessResult {
+            branches: vec![],
+            attention_mask: AttentionMask::default(),
+            logprobs: None,
+        }
     }
 
     pub fn sample(set: SimpleVob) -> Self {
@@ -124,12 +220,34 @@ impl MidProcessResult {
     pub fn is_stop(&self) -> bool {
         self.branches.is_empty()
     }
+
+    /// Use given sampling parameters for all branches.
+    pub fn with_sampling(mut self, sampling: BranchSampling) -> Self {
+        for b in self.branches.iter_mut() {
+            b.sampling = sampling;
+        }
+        self
+    }
+
+    pub fn with_attention_mask(mut self, mask: AttentionMask) -> Self {
+        self.attention_mask = mask;
+        self
+    }
+
+    pub fn with_logprobs(mut self, req: LogprobsRequest) -> Self {
+        self.logprobs = Some(req);
+        self
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ProcessResultOffset {
     /// Branches use byte offsets into the bias tensor.
     pub branches: Vec<Branch<usize>>,
+    #[serde(default, skip_serializing_if = "AttentionMask::is_empty")]
+    pub attention_mask: AttentionMask,
+    #[serde(default)]
+    pub logprobs: Option<LogprobsRequest>,
 }
 
 pub trait AiciCtrl {
@@ -156,6 +274,8 @@ pub trait AiciCtrl {
         let res = self.mid_process(arg);
         let mut used_logits = false;
         let res = ProcessResultOffset {
+            attention_mask: res.attention_mask,
+            logprobs: res.logprobs,
             branches: res
                 .branches
                 .into_iter()
@@ -199,23 +319,44 @@ macro_rules! expose {
     };
 }
 
+/// Export the functions the host calls, for controller `$struct_name` created with `$new`
+/// (typically reading the module argument with [arg_bytes()]).
+///
+/// The optional `$warmup` expression runs before the module argument is known,
+/// possibly long before the controller is created (eg., in a warm pool of workers);
+/// it can be used to do expensive setup that doesn't depend on the argument.
 #[macro_export]
 macro_rules! aici_expose_all {
+    ($struct_name:ident, $new:expr, $warmup:expr) => {
+        $crate::aici_expose_all!($struct_name, $new);
+
+        #[no_mangle]
+        pub extern "C" fn aici_warmup() {
+            $crate::export_guard(|| $warmup);
+        }
+    };
     ($struct_name:ident, $new:expr) => {
-        $crate::expose!($struct_name::aici_mid_process() -> ());
-        $crate::expose!($struct_name::aici_init_prompt() -> ());
+        // panics are caught when running natively (see aici_abi::native)
+        #[no_mangle]
+        pub unsafe extern "C" fn aici_mid_process(self_: *mut $struct_name) {
+            $crate::export_guard(|| unsafe { (&mut *self_).aici_mid_process() });
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn aici_init_prompt(self_: *mut $struct_name) {
+            $crate::export_guard(|| unsafe { (&mut *self_).aici_init_prompt() });
+        }
 
         #[no_mangle]
         pub extern "C" fn aici_create() -> *mut $struct_name {
-            let b = Box::new($new);
-            Box::into_raw(b)
+            $crate::export_guard(|| Box::into_raw(Box::new($new))).unwrap_or(std::ptr::null_mut())
         }
 
         #[no_mangle]
         pub extern "C" fn aici_panic() {
-            panic!("aici_panic()")
+            $crate::export_guard(|| panic!("aici_panic()"));
         }
-    }
+    };
 }
 
 #[macro_export]
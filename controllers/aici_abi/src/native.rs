@@ -0,0 +1,125 @@
+//! Running controllers natively, as dynamic libraries loaded by the host, instead of WASM modules.
+//!
+//! The host passes a table of functions corresponding to the `aici_host_*` WASM imports
+//! to `aici_native_init()`, exported from the library.
+//! The functions below forward to that table, so the rest of this crate
+//! (and thus the controller) works the same way as in WASM.
+
+use crate::{host::BlobId, TokenId};
+
+/// Bump on any change to [NativeHostFns].
+pub const NATIVE_ABI_VERSION: u32 = 2;
+
+/// Host functions; see the `extern "C"` block in host.rs for their meaning.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct NativeHostFns {
+    pub read_blob: extern "C" fn(blob: u32, dst: *mut u8, size: u32) -> u32,
+    pub token_trie: extern "C" fn() -> u32,
+    pub module_arg: extern "C" fn() -> u32,
+    pub process_arg: extern "C" fn() -> u32,
+    pub tokenize: extern "C" fn(src: *const u8, src_size: u32) -> u32,
+    pub return_logit_bias: extern "C" fn(src: *const u32) -> u32,
+    pub self_seq_id: extern "C" fn() -> u32,
+    pub return_process_result: extern "C" fn(res: *const u8, res_size: u32),
+    pub storage_cmd: extern "C" fn(cmd: *const u8, cmd_size: u32) -> u32,
+    pub eos_token: extern "C" fn() -> TokenId,
+    pub get_config: extern "C" fn(src: *const u8, src_size: u32) -> i32,
+    pub now_micros: extern "C" fn() -> u64,
+    pub random_seed: extern "C" fn() -> u64,
+    pub emit_event: extern "C" fn(kind: *const u8, kind_size: u32, data: *const u8, data_size: u32),
+    pub blob_cache_get: extern "C" fn(key: *const u8, key_size: u32) -> u32,
+    pub blob_cache_put:
+        extern "C" fn(key: *const u8, key_size: u32, data: *const u8, data_size: u32),
+    /// Unlike in WASM, this returns; the controller then unwinds to the exported function
+    /// it was called from (see `export_guard()`).
+    pub stop: extern "C" fn(),
+}
+
+static mut HOST_FNS: Option<NativeHostFns> = None;
+
+pub(crate) fn set_host_fns(fns: NativeHostFns) {
+    unsafe { HOST_FNS = Some(fns) }
+}
+
+fn fns() -> &'static NativeHostFns {
+    unsafe { HOST_FNS.as_ref().expect("aici_native_init() not called") }
+}
+
+pub(crate) unsafe fn aici_host_read_blob(blob: BlobId, dst: *mut u8, size: u32) -> u32 {
+    (fns().read_blob)(blob.0, dst, size)
+}
+
+pub(crate) unsafe fn aici_host_token_trie() -> BlobId {
+    BlobId((fns().token_trie)())
+}
+
+pub(crate) unsafe fn aici_host_module_arg() -> BlobId {
+    BlobId((fns().module_arg)())
+}
+
+pub(crate) unsafe fn aici_host_process_arg() -> BlobId {
+    BlobId((fns().process_arg)())
+}
+
+pub(crate) unsafe fn aici_host_tokenize(src: *const u8, src_size: u32) -> BlobId {
+    BlobId((fns().tokenize)(src, src_size))
+}
+
+pub(crate) unsafe fn aici_host_return_logit_bias(src: *const u32) -> u32 {
+    (fns().return_logit_bias)(src)
+}
+
+pub(crate) unsafe fn aici_host_self_seq_id() -> u32 {
+    (fns().self_seq_id)()
+}
+
+pub(crate) unsafe fn aici_host_return_process_result(res: *const u8, res_size: u32) {
+    (fns().return_process_result)(res, res_size)
+}
+
+pub(crate) unsafe fn aici_host_storage_cmd(cmd: *const u8, cmd_size: u32) -> BlobId {
+    BlobId((fns().storage_cmd)(cmd, cmd_size))
+}
+
+pub(crate) unsafe fn aici_host_eos_token() -> TokenId {
+    (fns().eos_token)()
+}
+
+pub(crate) unsafe fn aici_host_get_config(src: *const u8, src_size: u32) -> i32 {
+    (fns().get_config)(src, src_size)
+}
+
+pub(crate) unsafe fn aici_host_now_micros() -> u64 {
+    (fns().now_micros)()
+}
+
+pub(crate) unsafe fn aici_host_random_seed() -> u64 {
+    (fns().random_seed)()
+}
+
+pub(crate) unsafe fn aici_host_emit_event(
+    kind: *const u8,
+    kind_size: u32,
+    data: *const u8,
+    data_size: u32,
+) {
+    (fns().emit_event)(kind, kind_size, data, data_size)
+}
+
+pub(crate) unsafe fn aici_host_blob_cache_get(key: *const u8, key_size: u32) -> BlobId {
+    BlobId((fns().blob_cache_get)(key, key_size))
+}
+
+pub(crate) unsafe fn aici_host_blob_cache_put(
+    key: *const u8,
+    key_size: u32,
+    data: *const u8,
+    data_size: u32,
+) {
+    (fns().blob_cache_put)(key, key_size, data, data_size)
+}
+
+pub(crate) unsafe fn aici_host_stop() {
+    (fns().stop)()
+}
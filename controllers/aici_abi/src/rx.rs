@@ -1,27 +1,87 @@
-use std::error::Error;
+use std::{collections::HashMap, error::Error};
 
 use crate::{
-    recognizer::{FunctionalRecognizer, StackRecognizer},
-    toktrie::SpecialToken,
+    host::{blob_cache_enabled, blob_cache_get, blob_cache_put, content_hash},
+    toktrie::{Recognizer, SpecialToken, TokTrie},
+    SimpleVob, TokenId,
 };
-use anyhow::{bail, Result};
+use anyhow::{bail, ensure, Result};
 use regex_automata::{
-    dfa::{dense, Automaton},
-    util::{primitives::StateID, syntax},
+    nfa::thompson::{self, State, WhichCaptures, NFA},
+    util::{
+        alphabet::ByteClasses,
+        look::{Look, LookSet},
+        primitives::StateID,
+        syntax,
+    },
 };
+use serde::{Deserialize, Serialize};
 
-pub type RecRxState = StateID;
+/// State of the lazily built DFA of [RecRx].
+/// State numbers are only valid until the next cache flush, see [RecRx::flush].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RecRxState(u32);
+
+const UNKNOWN: u32 = u32::MAX;
+const DEAD: u32 = u32::MAX - 1;
+
+// default to 16MB of cached states and token masks
+const DEFAULT_SIZE_LIMIT: usize = 16 << 20;
+
+// bump when serialized form of CompiledRx changes
+const RX_FORMAT_VERSION: u32 = 1;
+
+// DFA states computed so far; token masks are not included, since they depend
+// on the tokenizer, while the blob cache is shared by all sessions
+#[derive(Serialize, Deserialize)]
+struct CompiledRx {
+    version: u32,
+    rx: String,
+    size_limit: usize,
+    // the NFA is rebuilt from `rx`; check that the states refer to the same one
+    num_nfa_states: usize,
+    // (nfa_states, prev, accepting) for each DFA state
+    states: Vec<(Vec<u32>, Option<u8>, Option<bool>)>,
+    transitions: Vec<u32>,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct StateKey {
+    // sorted set of NFA states; look-around assertions are resolved
+    // only once the next byte (or end of input) is known
+    nfa_states: Box<[StateID]>,
+    // class of the previous byte (see RecRx::prev_class); None at start
+    prev: Option<u8>,
+}
 
+#[derive(Clone)]
+struct DfaState {
+    key: StateKey,
+    accepting: Option<bool>,
+}
+
+/// Regex recognizer, which only compiles the regex to an NFA upfront.
+/// DFA states are computed from the NFA on demand, when the trie walk
+/// reaches them, and cached (together with token masks, see [RxStackRecognizer::add_bias]).
+/// Once the cache exceeds the size limit, it is flushed between tokens.
 #[derive(Clone)]
 pub struct RecRx {
-    dfa: dense::DFA<Vec<u32>>,
+    rx: String,
+    nfa: NFA,
+    classes: ByteClasses,
+    prev_class: [u8; 256],
+    states: Vec<DfaState>,
+    state_ids: HashMap<StateKey, u32>,
+    transitions: Vec<u32>,
+    masks: HashMap<u32, Vec<TokenId>>,
+    memory: usize,
+    size_limit: usize,
     info: String,
 }
 
-pub type RxStackRecognizer = StackRecognizer<StateID, RecRx>;
-
 impl RecRx {
     pub fn from_rx(rx: &str, size_limit: Option<usize>) -> Result<Self> {
+        let source = rx.to_string();
         let rx = if rx.ends_with("$") {
             rx.to_string()
         } else {
@@ -32,45 +92,221 @@ impl RecRx {
         } else {
             rx
         };
-        // default to 16MB - it takes about 1s to build
-        let size_limit = size_limit.unwrap_or(16 << 20);
+        let size_limit = size_limit.unwrap_or(DEFAULT_SIZE_LIMIT);
         let t0 = std::time::Instant::now();
-        let cfg = dense::Config::new()
-            .start_kind(regex_automata::dfa::StartKind::Anchored)
-            .dfa_size_limit(Some(size_limit))
-            .determinize_size_limit(Some(size_limit));
-        let dfa = dense::Builder::new()
-            .configure(cfg)
+        let nfa = thompson::Compiler::new()
             .syntax(syntax::Config::new().unicode(false).utf8(false))
+            .configure(
+                thompson::Config::new()
+                    .utf8(false)
+                    .which_captures(WhichCaptures::None)
+                    .nfa_size_limit(Some(size_limit)),
+            )
             .build(&rx);
-        let dfa = match dfa {
-            Ok(dfa) => dfa,
+        let nfa = match nfa {
+            Ok(nfa) => nfa,
             Err(e) => {
                 if let Some(e) = e.source() {
-                    if let Some(e) = e.source() {
-                        bail!("error building dfa(2): {}", e)
-                    } else {
-                        bail!("error building dfa(1): {}", e)
-                    }
+                    bail!("error building nfa(1): {}", e)
                 } else {
-                    bail!("error building dfa(0): {}", e)
+                    bail!("error building nfa(0): {}", e)
                 }
             }
         };
-        let time = t0.elapsed();
-        let mb_per_s = dfa.memory_usage() as f64 / time.as_secs_f64() / 1024.0 / 1024.0;
-        let info = format!(
-            "dfa: {} bytes; time {:?}; {:.3} MB/s",
-            dfa.memory_usage(),
-            time,
-            mb_per_s
+
+        let looks = nfa.look_set_any();
+        if looks.contains_word_unicode() {
+            bail!("Unicode word boundaries are not supported");
+        }
+
+        // The DFA state remembers what kind of byte came before,
+        // so that look-around assertions can be checked.
+        // For plain regexes (only ^ and $), it's all the same.
+        let mut prev_class = [0u8; 256];
+        if !looks
+            .subtract(LookSet::empty().insert(Look::Start).insert(Look::End))
+            .is_empty()
+        {
+            for b in 0..=255u8 {
+                prev_class[b as usize] = match b {
+                    b'\n' | b'\r' => b,
+                    _ if b.is_ascii_alphanumeric() || b == b'_' => b'a',
+                    _ => b' ',
+                };
+            }
+        }
+
+        // transitions are cached per byte class; make sure bytes in the same class
+        // also agree on prev_class, otherwise use one class per byte
+        let mut classes = *nfa.byte_classes();
+        let mut class_rep = vec![None; 256];
+        for b in 0..=255u8 {
+            let c = classes.get(b) as usize;
+            match class_rep[c] {
+                None => class_rep[c] = Some(prev_class[b as usize]),
+                Some(p) if p == prev_class[b as usize] => {}
+                Some(_) => {
+                    classes = ByteClasses::singletons();
+                    break;
+                }
+            }
+        }
+
+        let mut r = RecRx {
+            info: String::new(),
+            rx: source,
+            nfa,
+            classes,
+            prev_class,
+            states: Vec::new(),
+            state_ids: HashMap::default(),
+            transitions: Vec::new(),
+            masks: HashMap::default(),
+            memory: 0,
+            size_limit,
+        };
+        let initial = r.explore(&[r.nfa.start_anchored()], None);
+        r.intern(StateKey {
+            nfa_states: initial,
+            prev: None,
+        });
+
+        r.info = format!(
+            "nfa: {} states, {} bytes; {} byte classes; time {:?}",
+            r.nfa.states().len(),
+            r.nfa.memory_usage(),
+            r.alphabet_len(),
+            t0.elapsed()
+        );
+
+        Ok(r)
+    }
+
+    /// Like [RecRx::from_rx], but first looks for the DFA in the host blob cache
+    /// (see [crate::blob_cache_get]).
+    /// If it's not there, the DFA states are computed upfront (up to half of `size_limit`)
+    /// and stored in the cache, so that following requests using the same regex
+    /// don't have to compute them again.
+    /// If the host doesn't keep a blob cache, this is the same as [RecRx::from_rx]
+    /// (with states computed lazily).
+    pub fn from_rx_cached(rx: &str, size_limit: Option<usize>) -> Result<Self> {
+        if !blob_cache_enabled() {
+            return Self::from_rx(rx, size_limit);
+        }
+        let size_limit = size_limit.unwrap_or(DEFAULT_SIZE_LIMIT);
+        let key = format!(
+            "rx{}-{:016x}",
+            RX_FORMAT_VERSION,
+            content_hash(format!("{size_limit}:{rx}").as_bytes())
+        );
+        if let Some(bytes) = blob_cache_get(&key) {
+            match Self::from_bytes(&bytes) {
+                // the key is only a hash, so check for collisions
+                Ok(r) if r.rx == rx && r.size_limit == size_limit => return Ok(r),
+                Ok(_) => println!("cached regex {key} is for a different regex"),
+                Err(e) => println!("cached regex {key}: {e}"),
+            }
+        }
+        let mut r = Self::from_rx(rx, Some(size_limit))?;
+        r.precompute(size_limit / 2);
+        blob_cache_put(&key, &r.to_bytes()?);
+        Ok(r)
+    }
+
+    /// Serialize the regex and DFA states computed so far (but not token masks),
+    /// to be loaded with [RecRx::from_bytes].
+    /// The bytes are only meant to be read by the same build of the controller.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let compiled = CompiledRx {
+            version: RX_FORMAT_VERSION,
+            rx: self.rx.clone(),
+            size_limit: self.size_limit,
+            num_nfa_states: self.nfa.states().len(),
+            states: self
+                .states
+                .iter()
+                .map(|st| {
+                    (
+                        st.key.nfa_states.iter().map(|s| s.as_u32()).collect(),
+                        st.key.prev,
+                        st.accepting,
+                    )
+                })
+                .collect(),
+            transitions: self.transitions.clone(),
+        };
+        Ok(bincode::serialize(&compiled)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        // check the version first, the rest may not even deserialize
+        let version: u32 = bincode::deserialize(bytes)?;
+        ensure!(
+            version == RX_FORMAT_VERSION,
+            "compiled regex has format {version}, expecting {RX_FORMAT_VERSION}"
+        );
+        let compiled: CompiledRx = bincode::deserialize(bytes)?;
+        let mut r = Self::from_rx(&compiled.rx, Some(compiled.size_limit))?;
+        let num_nfa_states = r.nfa.states().len();
+        ensure!(
+            compiled.num_nfa_states == num_nfa_states,
+            "compiled regex has {} NFA states, expecting {}",
+            compiled.num_nfa_states,
+            num_nfa_states
+        );
+        let num_states = compiled.states.len();
+        ensure!(
+            num_states > 0 && compiled.transitions.len() == num_states * r.alphabet_len(),
+            "compiled regex has invalid transition table"
+        );
+        ensure!(
+            compiled
+                .transitions
+                .iter()
+                .all(|t| *t == UNKNOWN || *t == DEAD || (*t as usize) < num_states),
+            "compiled regex has invalid transitions"
         );
 
-        if let Err(e) = dfa.start_state(&anchored_start()) {
-            bail!("DFA has no start state; {}", e)
+        let initial = r.states[0].key.clone();
+        r.states.clear();
+        r.state_ids.clear();
+        r.transitions.clear();
+        r.memory = 0;
+        for (idx, (nfa_states, prev, accepting)) in compiled.states.into_iter().enumerate() {
+            ensure!(
+                nfa_states.iter().all(|s| (*s as usize) < num_nfa_states),
+                "compiled regex has invalid NFA state"
+            );
+            let nfa_states = nfa_states
+                .into_iter()
+                .map(|s| StateID::new(s as usize).unwrap())
+                .collect();
+            let id = r.intern(StateKey { nfa_states, prev });
+            ensure!(id as usize == idx, "compiled regex has duplicate states");
+            r.states[idx].accepting = accepting;
         }
+        ensure!(
+            r.states[0].key == initial,
+            "compiled regex has different initial state"
+        );
+        r.transitions = compiled.transitions;
+        r.info = format!("{}; {} states from cache", r.info, num_states);
+        Ok(r)
+    }
 
-        Ok(Self { dfa, info })
+    /// Compute DFA states reachable from the initial state (breadth-first),
+    /// until the cache takes `max_memory` bytes or all states are known.
+    pub fn precompute(&mut self, max_memory: usize) {
+        let mut idx = 0;
+        while idx < self.states.len() && self.memory < max_memory {
+            let state = RecRxState(idx as u32);
+            // transitions are cached per byte class, so this only computes each one once
+            for b in 0..=255u8 {
+                let _ = self.try_append(state, b);
+            }
+            self.is_accepting(state);
+            idx += 1;
+        }
     }
 
     pub fn info(&self) -> &str {
@@ -78,37 +314,334 @@ impl RecRx {
     }
 
     pub fn to_stack_recognizer(self) -> RxStackRecognizer {
-        StackRecognizer::from(self)
+        RxStackRecognizer {
+            rx: self,
+            stack: vec![RecRxState(0)],
+        }
     }
-}
 
-fn anchored_start() -> regex_automata::util::start::Config {
-    regex_automata::util::start::Config::new().anchored(regex_automata::Anchored::Yes)
-}
+    /// Approximate number of bytes used by cached DFA states and token masks.
+    pub fn cache_size(&self) -> usize {
+        self.memory
+    }
 
-impl FunctionalRecognizer<RecRxState> for RecRx {
-    fn initial(&self) -> RecRxState {
-        self.dfa
-            .start_state(&anchored_start())
-            .expect("dfa has no start state")
+    pub fn num_cached_states(&self) -> usize {
+        self.states.len()
     }
 
-    #[inline(always)]
-    fn try_append(&self, state: RecRxState, byte: u8) -> Option<RecRxState> {
-        let next = self.dfa.next_state(state, byte);
-        if self.dfa.is_dead_state(next) {
+    pub fn initial(&self) -> RecRxState {
+        RecRxState(0)
+    }
+
+    fn alphabet_len(&self) -> usize {
+        // the last class is end-of-input, which we don't use
+        self.classes.alphabet_len() - 1
+    }
+
+    /// Follow epsilon transitions from `seeds`.
+    /// When `ctx` gives the bytes surrounding current position, look-around assertions
+    /// are followed if they hold; otherwise they are kept in the result for later.
+    fn explore(&self, seeds: &[StateID], ctx: Option<(&[u8], usize)>) -> Box<[StateID]> {
+        let mut visited = vec![false; self.nfa.states().len()];
+        let mut stack = seeds.to_vec();
+        let mut res = Vec::new();
+        while let Some(sid) = stack.pop() {
+            if visited[sid.as_usize()] {
+                continue;
+            }
+            visited[sid.as_usize()] = true;
+            match self.nfa.state(sid) {
+                State::ByteRange { .. }
+                | State::Sparse(_)
+                | State::Dense(_)
+                | State::Match { .. } => res.push(sid),
+                State::Look { look, next } => match ctx {
+                    None => res.push(sid),
+                    Some((haystack, at)) => {
+                        if self.nfa.look_matcher().matches(*look, haystack, at) {
+                            stack.push(*next)
+                        }
+                    }
+                },
+                State::Union { alternates } => stack.extend(alternates.iter().rev()),
+                State::BinaryUnion { alt1, alt2 } => {
+                    stack.push(*alt2);
+                    stack.push(*alt1);
+                }
+                State::Capture { next, .. } => stack.push(*next),
+                State::Fail => {}
+            }
+        }
+        res.sort_unstable();
+        res.into_boxed_slice()
+    }
+
+    fn intern(&mut self, key: StateKey) -> u32 {
+        if let Some(id) = self.state_ids.get(&key) {
+            return *id;
+        }
+        let id = self.states.len() as u32;
+        self.memory += 2 * key.nfa_states.len() * std::mem::size_of::<StateID>()
+            + self.alphabet_len() * std::mem::size_of::<u32>()
+            + 64;
+        self.state_ids.insert(key.clone(), id);
+        self.states.push(DfaState {
+            key,
+            accepting: None,
+        });
+        self.transitions
+            .extend(std::iter::repeat(UNKNOWN).take(self.alphabet_len()));
+        id
+    }
+
+    fn compute_transition(&mut self, state: u32, byte: u8) -> u32 {
+        let key = &self.states[state as usize].key;
+        let (buf, at) = match key.prev {
+            Some(p) => ([p, byte], 1),
+            None => ([byte, 0], 0),
+        };
+        let resolved = self.explore(&key.nfa_states, Some((&buf[0..at + 1], at)));
+        let seeds = resolved
+            .iter()
+            .filter_map(|sid| match self.nfa.state(*sid) {
+                State::ByteRange { trans } => {
+                    if trans.matches_byte(byte) {
+                        Some(trans.next)
+                    } else {
+                        None
+                    }
+                }
+                State::Sparse(sparse) => sparse.matches_byte(byte),
+                State::Dense(dense) => dense.matches_byte(byte),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let next = self.explore(&seeds, None);
+        if next.is_empty() {
+            DEAD
+        } else {
+            self.intern(StateKey {
+                nfa_states: next,
+                prev: Some(self.prev_class[byte as usize]),
+            })
+        }
+    }
+
+    pub fn try_append(&mut self, state: RecRxState, byte: u8) -> Option<RecRxState> {
+        let idx = state.0 as usize * self.alphabet_len() + self.classes.get(byte) as usize;
+        let mut next = self.transitions[idx];
+        if next == UNKNOWN {
+            next = self.compute_transition(state.0, byte);
+            self.transitions[idx] = next;
+        }
+        if next == DEAD {
             None
         } else {
-            Some(next)
+            Some(RecRxState(next))
         }
     }
 
-    #[inline(always)]
-    fn special_allowed(&self, state: RecRxState, tok: SpecialToken) -> bool {
-        let state = self.dfa.next_eoi_state(state);
+    pub fn is_accepting(&mut self, state: RecRxState) -> bool {
+        let st = &self.states[state.0 as usize];
+        if let Some(acc) = st.accepting {
+            return acc;
+        }
+        let (buf, at) = match st.key.prev {
+            Some(p) => ([p], 1),
+            None => ([0], 0),
+        };
+        let acc = self
+            .explore(&st.key.nfa_states, Some((&buf[0..at], at)))
+            .iter()
+            .any(|sid| matches!(self.nfa.state(*sid), State::Match { .. }));
+        self.states[state.0 as usize].accepting = Some(acc);
+        acc
+    }
+
+    pub fn special_allowed(&mut self, state: RecRxState, tok: SpecialToken) -> bool {
         match tok {
-            SpecialToken::EndOfSentence => self.dfa.is_match_state(state),
+            SpecialToken::EndOfSentence => self.is_accepting(state),
             _ => false,
         }
     }
+
+    /// Drop all cached states and masks, except for `live`, which becomes the initial state.
+    /// Returns the new number of `live`.
+    pub fn flush(&mut self, live: RecRxState) -> RecRxState {
+        let key = self.states[live.0 as usize].key.clone();
+        self.states.clear();
+        self.state_ids.clear();
+        self.transitions.clear();
+        self.masks.clear();
+        self.memory = 0;
+        RecRxState(self.intern(key))
+    }
+}
+
+/// Stack-based recognizer for [RecRx], to be used with trie walks.
+/// Unlike generic `StackRecognizer`, it flushes the DFA cache (when over the limit)
+/// on `collapse()`, and can cache token masks per DFA state.
+#[derive(Clone)]
+pub struct RxStackRecognizer {
+    rx: RecRx,
+    stack: Vec<RecRxState>,
+}
+
+impl RxStackRecognizer {
+    pub fn recognizer(&self) -> &RecRx {
+        &self.rx
+    }
+
+    pub fn recognizer_mut(&mut self) -> &mut RecRx {
+        &mut self.rx
+    }
+
+    fn top(&self) -> RecRxState {
+        *self.stack.last().unwrap()
+    }
+
+    /// Same as `trie.compute_bias(self, logits)`, but reuses the mask
+    /// if it was already computed for the current DFA state.
+    pub fn compute_bias(&mut self, trie: &TokTrie, logits: &mut SimpleVob) {
+        logits.set_all(false);
+        self.add_bias(trie, logits);
+    }
+
+    /// Same as `trie.add_bias(self, toks, &[])`, with per-state mask caching.
+    pub fn add_bias(&mut self, trie: &TokTrie, toks: &mut SimpleVob) {
+        let state = self.top().0;
+        if !self.rx.masks.contains_key(&state) {
+            let mut mask = trie.alloc_token_set();
+            trie.add_bias(self, &mut mask, &[]);
+            let allowed = (0..mask.len() as u32)
+                .filter(|t| mask.is_allowed(*t))
+                .collect::<Vec<_>>();
+            self.rx.memory += allowed.len() * std::mem::size_of::<TokenId>() + 64;
+            self.rx.masks.insert(state, allowed);
+        }
+        for tok in &self.rx.masks[&state] {
+            toks.allow_token(*tok);
+        }
+    }
+}
+
+impl Recognizer for RxStackRecognizer {
+    #[inline(always)]
+    fn pop_bytes(&mut self, num: usize) {
+        self.stack.truncate(self.stack.len() - num);
+    }
+
+    fn collapse(&mut self) {
+        let mut state = self.top();
+        if self.rx.memory > self.rx.size_limit {
+            state = self.rx.flush(state);
+        }
+        self.stack.clear();
+        self.stack.push(state);
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        let state = self.top();
+        self.rx.special_allowed(state, tok)
+    }
+
+    fn trie_finished(&mut self) {
+        assert!(self.stack.len() == 1);
+    }
+
+    #[inline(always)]
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        let state = self.top();
+        match self.rx.try_append(state, byte) {
+            Some(next) => {
+                self.stack.push(next);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn walk(rx: &mut RecRx, state: RecRxState, s: &str) -> Option<RecRxState> {
+        s.bytes().try_fold(state, |st, b| rx.try_append(st, b))
+    }
+
+    fn matches(rx: &mut RecRx, s: &str) -> bool {
+        let initial = rx.initial();
+        match walk(rx, initial, s) {
+            Some(st) => rx.is_accepting(st),
+            None => false,
+        }
+    }
+
+    #[test]
+    fn lazy_matching() {
+        let mut rx = RecRx::from_rx("a[bc]+d", None).unwrap();
+        assert_eq!(rx.num_cached_states(), 1);
+        assert!(matches(&mut rx, "abcd"));
+        assert!(matches(&mut rx, "acd"));
+        assert!(!matches(&mut rx, "ad"));
+        assert!(!matches(&mut rx, "abc"));
+        assert!(walk(&mut rx, RecRxState(0), "abx").is_none());
+        assert!(rx.num_cached_states() > 1);
+        assert!(rx.cache_size() > 0);
+    }
+
+    #[test]
+    fn word_boundaries() {
+        let mut rx = RecRx::from_rx(r"[a-z]+\b[ ]?[0-9]*", None).unwrap();
+        assert!(matches(&mut rx, "foo 12"));
+        assert!(matches(&mut rx, "foo"));
+        assert!(!matches(&mut rx, "foo12"));
+
+        let mut rx = RecRx::from_rx(r"a\bb", None).unwrap();
+        assert!(!matches(&mut rx, "ab"));
+    }
+
+    #[test]
+    fn flush_keeps_live_state() {
+        let mut rx = RecRx::from_rx("(foo|bar)baz", None).unwrap();
+        let initial = rx.initial();
+        let st = walk(&mut rx, initial, "foob").unwrap();
+        let st = rx.flush(st);
+        assert_eq!(rx.num_cached_states(), 1);
+        let st = walk(&mut rx, st, "az").unwrap();
+        assert!(rx.is_accepting(st));
+        assert!(walk(&mut rx, st, "z").is_none());
+    }
+
+    #[test]
+    fn compiled_round_trip() {
+        let mut rx = RecRx::from_rx("(foo|bar)+[0-9]{1,3}", None).unwrap();
+        rx.precompute(usize::MAX);
+        let num_states = rx.num_cached_states();
+        assert!(num_states > 5);
+        let bytes = rx.to_bytes().unwrap();
+
+        let mut rx2 = RecRx::from_bytes(&bytes).unwrap();
+        assert_eq!(rx2.num_cached_states(), num_states);
+        assert!(matches(&mut rx2, "foobar12"));
+        assert!(!matches(&mut rx2, "foobar1234"));
+        assert!(!matches(&mut rx2, "fo1"));
+        // everything was computed upfront
+        assert_eq!(rx2.num_cached_states(), num_states);
+        assert_eq!(rx2.to_bytes().unwrap(), bytes);
+
+        // a partially explored DFA is fine too
+        let mut rx = RecRx::from_rx("(foo|bar)+[0-9]{1,3}", None).unwrap();
+        assert!(matches(&mut rx, "foo1"));
+        let mut rx2 = RecRx::from_bytes(&rx.to_bytes().unwrap()).unwrap();
+        assert_eq!(rx2.num_cached_states(), rx.num_cached_states());
+        assert!(matches(&mut rx2, "barfoo999"));
+
+        let mut bad = bytes.clone();
+        bad[0] ^= 1;
+        let err = RecRx::from_bytes(&bad).err().unwrap();
+        assert!(err.to_string().contains("expecting"));
+        assert!(RecRx::from_bytes(&bytes[0..bytes.len() - 1]).is_err());
+    }
 }
@@ -1,4 +1,7 @@
+use crate::{gguf, tiktoken};
 use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::path::Path;
 use tokenizers::{FromPretrainedParameters, Tokenizer};
 
 pub use toktrie_hf_tokenizers::{ByteTokenizer, ByteTokenizerEnv};
@@ -84,7 +87,7 @@ pub fn list_tokenizers() -> String {
             .collect::<Vec<_>>()
             .join("\n"),
         "You can also use a HuggingFace model name, in format 'user/modelname',",
-        "or a local file in format './path/to/tokenizer.json'."
+        "or a local file: './path/to/tokenizer.json', './model.gguf' or 'file:cl100k_base.tiktoken'."
     )
 }
 
@@ -130,8 +133,19 @@ pub fn find_tokenizer(mut name: &str) -> Result<ByteTokenizer> {
 
     log::info!("loading tokenizer: {}", name);
 
-    let loaded = if name.starts_with(".") || name.starts_with("/") {
-        Tokenizer::from_file(name)
+    let local = local_path(name);
+    if let Some(path) = local {
+        if path.ends_with(".gguf") || path.ends_with(".tiktoken") {
+            return from_vocab_file(path).map_err(|e| {
+                let msg = format!("can't load tokenizer {}: {}", name, e);
+                println!("{}\n{}", msg, list_tokenizers());
+                anyhow!("{}", msg)
+            });
+        }
+    }
+
+    let loaded = if let Some(path) = local {
+        Tokenizer::from_file(path)
     } else {
         let mut name2 = name.to_string();
         let mut args = FromPretrainedParameters::default();
@@ -150,8 +164,132 @@ pub fn find_tokenizer(mut name: &str) -> Result<ByteTokenizer> {
             return Err(anyhow!("{}", msg));
         }
         Ok(t) => {
-            let bt = ByteTokenizer::from_tokenizer(t)?;
+            let mut bt = ByteTokenizer::from_tokenizer(t)?;
+            if let Some(path) = local {
+                if let Some(eos_token) = eos_from_config(path, &bt) {
+                    bt.eos_token = eos_token;
+                }
+            }
             Ok(bt)
         }
     }
 }
+
+// only explicit paths are local files, so that files in the current directory
+// don't shadow built-in tokenizers or HuggingFace models
+fn local_path(name: &str) -> Option<&str> {
+    if let Some(path) = name.strip_prefix("file:") {
+        Some(path)
+    } else if name.starts_with(".") || name.starts_with("/") {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+// tokenizer.json doesn't say which token is EOS; tokenizer_config.json next to it does
+fn eos_from_config(tokenizer_json: &str, bt: &ByteTokenizer) -> Option<u32> {
+    let path = Path::new(tokenizer_json).with_file_name("tokenizer_config.json");
+    let cfg: Value = serde_json::from_slice(&std::fs::read(path).ok()?).ok()?;
+    let eos = match &cfg["eos_token"] {
+        Value::String(s) => s.as_str(),
+        v => v["content"].as_str()?,
+    };
+    bt.hf_tokenizer.token_to_id(eos)
+}
+
+// GGUF and tiktoken files are converted to HF tokenizer.json first
+fn from_vocab_file(name: &str) -> Result<ByteTokenizer> {
+    let (json, eos_token) = if name.ends_with(".gguf") {
+        gguf::tokenizer_json(name)?
+    } else {
+        tiktoken::tokenizer_json(name)?
+    };
+    let hft = Tokenizer::from_bytes(serde_json::to_vec(&json)?).map_err(|e| anyhow!("{e}"))?;
+    let mut bt = ByteTokenizer::from_tokenizer(hft)?;
+    bt.eos_token = eos_token;
+    Ok(bt)
+}
+
+/// Pre-tokenization regex of cl100k_base (GPT-4), also used by Llama 3.
+pub(crate) const CL100K_SPLIT_REGEX: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// Pre-tokenization regex of o200k_base (GPT-4o).
+pub(crate) const O200K_SPLIT_REGEX: &str = concat!(
+    r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n/]*|\s*[\r\n]+|\s+(?!\S)|\s+"
+);
+
+/// GPT-2 mapping of bytes to printable characters, used by byte-level BPE vocabularies.
+pub(crate) fn byte_to_char_map() -> Vec<char> {
+    let mut next_char = 256;
+    (0..=255u32)
+        .map(|b| {
+            let printable = (0x21..=0x7E).contains(&b)
+                || (0xA1..=0xAC).contains(&b)
+                || (0xAE..=0xFF).contains(&b);
+            if printable {
+                char::from_u32(b).unwrap()
+            } else {
+                next_char += 1;
+                char::from_u32(next_char - 1).unwrap()
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn added_token(id: u32, content: &str, special: bool) -> Value {
+    json!({
+        "id": id,
+        "content": content,
+        "single_word": false,
+        "lstrip": false,
+        "rstrip": false,
+        "normalized": !special,
+        "special": special
+    })
+}
+
+/// HF tokenizer.json for byte-level BPE (GPT-2 style) vocabulary.
+/// Without `split_regex` the GPT-2 pre-tokenization is used.
+pub(crate) fn byte_level_bpe_json(
+    vocab: serde_json::Map<String, Value>,
+    merges: Vec<String>,
+    added_tokens: Vec<Value>,
+    split_regex: Option<&str>,
+) -> Value {
+    let pre_tokenizer = match split_regex {
+        Some(regex) => json!({
+            "type": "Sequence",
+            "pretokenizers": [
+                { "type": "Split", "pattern": { "Regex": regex }, "behavior": "Isolated", "invert": false },
+                { "type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": false }
+            ]
+        }),
+        None => json!({
+            "type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true
+        }),
+    };
+    json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": added_tokens,
+        "normalizer": null,
+        "pre_tokenizer": pre_tokenizer,
+        "post_processor": null,
+        "decoder": { "type": "ByteLevel", "add_prefix_space": true, "trim_offsets": true, "use_regex": true },
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": null,
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": false,
+            "byte_fallback": false,
+            "vocab": vocab,
+            "merges": merges
+        }
+    })
+}
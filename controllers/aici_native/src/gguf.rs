@@ -0,0 +1,478 @@
+//! Extracting the tokenizer from GGUF model files (as used by llama.cpp).
+//! Only the metadata is read; the tokenizer is converted to HF tokenizer.json format.
+
+use crate::{
+    bintokens::{added_token, byte_level_bpe_json, CL100K_SPLIT_REGEX},
+    HashMap,
+};
+use anyhow::{anyhow, bail, ensure, Result};
+use serde_json::{json, Value};
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+};
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+
+// see llama_token_type in llama.cpp
+const TOKEN_TYPE_NORMAL: i64 = 1;
+const TOKEN_TYPE_CONTROL: i64 = 3;
+const TOKEN_TYPE_USER_DEFINED: i64 = 4;
+
+enum GgufValue {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Array(Vec<GgufValue>),
+}
+
+struct GgufReader<R: Read> {
+    r: R,
+    // lengths in the header are not trusted to be below this when allocating
+    file_len: usize,
+}
+
+impl<R: Read> GgufReader<R> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.r.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u64()? as usize;
+        ensure!(len < (1 << 30) && len <= self.file_len, "string too long");
+        let mut buf = vec![0u8; len];
+        self.r.read_exact(&mut buf)?;
+        // some vocabularies contain partial UTF-8 sequences
+        Ok(String::from_utf8(buf)
+            .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).to_string()))
+    }
+
+    fn value(&mut self, tp: u32) -> Result<GgufValue> {
+        let v = match tp {
+            0 => GgufValue::Int(u8::from_le_bytes(self.bytes()?) as i64),
+            1 => GgufValue::Int(i8::from_le_bytes(self.bytes()?) as i64),
+            2 => GgufValue::Int(u16::from_le_bytes(self.bytes()?) as i64),
+            3 => GgufValue::Int(i16::from_le_bytes(self.bytes()?) as i64),
+            4 => GgufValue::Int(u32::from_le_bytes(self.bytes()?) as i64),
+            5 => GgufValue::Int(i32::from_le_bytes(self.bytes()?) as i64),
+            6 => GgufValue::Float(f32::from_le_bytes(self.bytes()?) as f64),
+            7 => GgufValue::Int(u8::from_le_bytes(self.bytes()?) as i64),
+            8 => GgufValue::Str(self.string()?),
+            9 => {
+                let elt_tp = self.u32()?;
+                let len = self.u64()? as usize;
+                ensure!(len < (1 << 28), "array too long");
+                // every element takes at least a byte
+                let mut elts = Vec::with_capacity(len.min(self.file_len));
+                for _ in 0..len {
+                    elts.push(self.value(elt_tp)?);
+                }
+                GgufValue::Array(elts)
+            }
+            10 => GgufValue::Int(u64::from_le_bytes(self.bytes()?) as i64),
+            11 => GgufValue::Int(i64::from_le_bytes(self.bytes()?)),
+            12 => GgufValue::Float(f64::from_le_bytes(self.bytes()?)),
+            _ => bail!("invalid GGUF value type {tp}"),
+        };
+        Ok(v)
+    }
+}
+
+struct GgufMetadata {
+    kv: HashMap<String, GgufValue>,
+}
+
+impl GgufMetadata {
+    fn read(filename: &str) -> Result<Self> {
+        let file = File::open(filename)?;
+        let file_len = file.metadata()?.len() as usize;
+        let mut r = GgufReader {
+            r: BufReader::new(file),
+            file_len,
+        };
+        ensure!(&r.bytes::<4>()? == GGUF_MAGIC, "not a GGUF file");
+        let version = r.u32()?;
+        ensure!(version >= 2, "unsupported GGUF version {version}");
+        let _num_tensors = r.u64()?;
+        let num_kv = r.u64()?;
+        let mut kv = HashMap::default();
+        for _ in 0..num_kv {
+            let key = r.string()?;
+            let tp = r.u32()?;
+            let value = r.value(tp)?;
+            kv.insert(key, value);
+        }
+        Ok(GgufMetadata { kv })
+    }
+
+    fn get(&self, key: &str) -> Result<&GgufValue> {
+        self.kv.get(key).ok_or_else(|| anyhow!("missing {key}"))
+    }
+
+    fn int(&self, key: &str) -> Result<i64> {
+        match self.get(key)? {
+            GgufValue::Int(v) => Ok(*v),
+            _ => bail!("{key} is not an integer"),
+        }
+    }
+
+    fn str(&self, key: &str) -> Result<&str> {
+        match self.get(key)? {
+            GgufValue::Str(v) => Ok(v),
+            _ => bail!("{key} is not a string"),
+        }
+    }
+
+    fn array(&self, key: &str) -> Result<&[GgufValue]> {
+        match self.get(key)? {
+            GgufValue::Array(v) => Ok(v),
+            _ => bail!("{key} is not an array"),
+        }
+    }
+
+    fn str_array(&self, key: &str) -> Result<Vec<&str>> {
+        self.array(key)?
+            .iter()
+            .map(|v| match v {
+                GgufValue::Str(s) => Ok(s.as_str()),
+                _ => bail!("{key} is not a string array"),
+            })
+            .collect()
+    }
+
+    fn num_array(&self, key: &str) -> Result<Vec<f64>> {
+        self.array(key)?
+            .iter()
+            .map(|v| match v {
+                GgufValue::Int(v) => Ok(*v as f64),
+                GgufValue::Float(v) => Ok(*v),
+                _ => bail!("{key} is not a numeric array"),
+            })
+            .collect()
+    }
+}
+
+/// Returns HF tokenizer.json and the EOS token id.
+pub fn tokenizer_json(filename: &str) -> Result<(Value, u32)> {
+    let meta = GgufMetadata::read(filename)?;
+    let model = meta.str("tokenizer.ggml.model")?;
+    let tokens = meta.str_array("tokenizer.ggml.tokens")?;
+    let token_types = match meta.num_array("tokenizer.ggml.token_type") {
+        Ok(v) => v.into_iter().map(|t| t as i64).collect(),
+        Err(_) => vec![TOKEN_TYPE_NORMAL; tokens.len()],
+    };
+    ensure!(
+        token_types.len() == tokens.len(),
+        "token_type length mismatch"
+    );
+    let eos_token = meta.int("tokenizer.ggml.eos_token_id")? as u32;
+
+    let added_tokens = tokens
+        .iter()
+        .zip(token_types.iter())
+        .enumerate()
+        .filter(|(_, (_, tp))| **tp == TOKEN_TYPE_CONTROL || **tp == TOKEN_TYPE_USER_DEFINED)
+        .map(|(id, (tok, tp))| added_token(id as u32, tok, *tp == TOKEN_TYPE_CONTROL))
+        .collect::<Vec<_>>();
+
+    let vocab = tokens
+        .iter()
+        .enumerate()
+        .map(|(id, tok)| (tok.to_string(), json!(id)))
+        .collect::<serde_json::Map<_, _>>();
+
+    let json = match model {
+        // byte-level BPE; tokens are already mapped to printable characters
+        "gpt2" => {
+            let merges = meta
+                .str_array("tokenizer.ggml.merges")?
+                .iter()
+                .map(|s| s.to_string())
+                .collect();
+            let split_regex = match meta.str("tokenizer.ggml.pre") {
+                // Llama 3 uses the same pre-tokenization as cl100k
+                Ok("llama-bpe") | Ok("llama3") => Some(CL100K_SPLIT_REGEX),
+                _ => None,
+            };
+            byte_level_bpe_json(vocab, merges, added_tokens, split_regex)
+        }
+        // SentencePiece BPE with byte fallback
+        "llama" => {
+            let scores = meta.num_array("tokenizer.ggml.scores")?;
+            ensure!(scores.len() == tokens.len(), "scores length mismatch");
+            let unk_token = meta
+                .int("tokenizer.ggml.unknown_token_id")
+                .ok()
+                .and_then(|id| tokens.get(id as usize))
+                .map(|s| s.to_string());
+            let merges = spm_merges(&tokens, &token_types, &scores);
+            json!({
+                "version": "1.0",
+                "truncation": null,
+                "padding": null,
+                "added_tokens": added_tokens,
+                "normalizer": {
+                    "type": "Sequence",
+                    "normalizers": [
+                        { "type": "Prepend", "prepend": "\u{2581}" },
+                        { "type": "Replace", "pattern": { "String": " " }, "content": "\u{2581}" }
+                    ]
+                },
+                "pre_tokenizer": null,
+                "post_processor": null,
+                "decoder": {
+                    "type": "Sequence",
+                    "decoders": [
+                        { "type": "Replace", "pattern": { "String": "\u{2581}" }, "content": " " },
+                        { "type": "ByteFallback" },
+                        { "type": "Fuse" },
+                        { "type": "Strip", "content": " ", "start": 1, "stop": 0 }
+                    ]
+                },
+                "model": {
+                    "type": "BPE",
+                    "dropout": null,
+                    "unk_token": unk_token,
+                    "continuing_subword_prefix": null,
+                    "end_of_word_suffix": null,
+                    "fuse_unk": true,
+                    "byte_fallback": true,
+                    "vocab": vocab,
+                    "merges": merges
+                }
+            })
+        }
+        _ => bail!("unsupported GGUF tokenizer model {model:?}"),
+    };
+
+    Ok((json, eos_token))
+}
+
+// SentencePiece doesn't store merges; every split of a token into two other tokens is a merge,
+// with priority given by the score of the merged token
+fn spm_merges(tokens: &[&str], token_types: &[i64], scores: &[f64]) -> Vec<String> {
+    let ids: HashMap<&str, usize> = tokens
+        .iter()
+        .enumerate()
+        .filter(|(id, _)| token_types[*id] == TOKEN_TYPE_NORMAL)
+        .map(|(id, tok)| (*tok, id))
+        .collect();
+    let mut merges = vec![];
+    for (id, tok) in tokens.iter().enumerate() {
+        if token_types[id] != TOKEN_TYPE_NORMAL {
+            continue;
+        }
+        for (pos, _) in tok.char_indices().skip(1) {
+            let (left, right) = tok.split_at(pos);
+            if let (Some(l), Some(r)) = (ids.get(left), ids.get(right)) {
+                merges.push((-scores[id], *l, *r, format!("{left} {right}")));
+            }
+        }
+    }
+    merges.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+    merges.into_iter().map(|m| m.3).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bintokens::byte_to_char_map;
+    use tokenizers::Tokenizer;
+
+    enum Kv<'a> {
+        Str(&'a str),
+        U32(u32),
+        Strs(Vec<String>),
+        I32s(Vec<i32>),
+        F32s(Vec<f32>),
+    }
+
+    fn write_str(out: &mut Vec<u8>, s: &str) {
+        out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        out.extend_from_slice(s.as_bytes());
+    }
+
+    fn write_gguf(name: &str, kvs: &[(&str, Kv)]) -> String {
+        let mut out = GGUF_MAGIC.to_vec();
+        out.extend_from_slice(&3u32.to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes());
+        out.extend_from_slice(&(kvs.len() as u64).to_le_bytes());
+        for (key, value) in kvs {
+            write_str(&mut out, key);
+            let array_header = |out: &mut Vec<u8>, tp: u32, len: usize| {
+                out.extend_from_slice(&9u32.to_le_bytes());
+                out.extend_from_slice(&tp.to_le_bytes());
+                out.extend_from_slice(&(len as u64).to_le_bytes());
+            };
+            match value {
+                Kv::Str(s) => {
+                    out.extend_from_slice(&8u32.to_le_bytes());
+                    write_str(&mut out, s);
+                }
+                Kv::U32(v) => {
+                    out.extend_from_slice(&4u32.to_le_bytes());
+                    out.extend_from_slice(&v.to_le_bytes());
+                }
+                Kv::Strs(v) => {
+                    array_header(&mut out, 8, v.len());
+                    v.iter().for_each(|s| write_str(&mut out, s));
+                }
+                Kv::I32s(v) => {
+                    array_header(&mut out, 5, v.len());
+                    v.iter()
+                        .for_each(|x| out.extend_from_slice(&x.to_le_bytes()));
+                }
+                Kv::F32s(v) => {
+                    array_header(&mut out, 6, v.len());
+                    v.iter()
+                        .for_each(|x| out.extend_from_slice(&x.to_le_bytes()));
+                }
+            }
+        }
+        let path = std::env::temp_dir().join(format!("aici-{}-{}.gguf", name, std::process::id()));
+        std::fs::write(&path, out).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn strs(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn load(json: &Value) -> Tokenizer {
+        Tokenizer::from_bytes(serde_json::to_vec(json).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn gguf_gpt2_round_trip() {
+        let mut tokens = byte_to_char_map()
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>();
+        tokens.extend(strs(&["he", "ll", "hell", "<|end|>"]));
+        let mut types = vec![TOKEN_TYPE_NORMAL as i32; tokens.len()];
+        *types.last_mut().unwrap() = TOKEN_TYPE_CONTROL as i32;
+        let path = write_gguf(
+            "gpt2",
+            &[
+                ("general.name", Kv::Str("test")),
+                ("tokenizer.ggml.model", Kv::Str("gpt2")),
+                ("tokenizer.ggml.tokens", Kv::Strs(tokens)),
+                ("tokenizer.ggml.token_type", Kv::I32s(types)),
+                (
+                    "tokenizer.ggml.merges",
+                    Kv::Strs(strs(&["h e", "l l", "he ll"])),
+                ),
+                ("tokenizer.ggml.eos_token_id", Kv::U32(259)),
+            ],
+        );
+        let (json, eos) = tokenizer_json(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(eos, 259);
+
+        let tok = load(&json);
+        let ids = tok.encode("hell<|end|>", false).unwrap().get_ids().to_vec();
+        assert_eq!(ids, vec![258, 259]);
+        let text = "hello, world";
+        let ids = tok.encode(text, false).unwrap().get_ids().to_vec();
+        assert_eq!(ids[0], 258);
+        assert_eq!(tok.decode(&ids, false).unwrap(), text);
+    }
+
+    #[test]
+    fn gguf_llama_round_trip() {
+        let tokens = strs(&[
+            "<unk>",
+            "<s>",
+            "</s>",
+            "\u{2581}",
+            "h",
+            "e",
+            "l",
+            "o",
+            "\u{2581}h",
+            "\u{2581}he",
+            "ll",
+            "\u{2581}hell",
+            "\u{2581}hello",
+        ]);
+        let mut types = vec![TOKEN_TYPE_NORMAL as i32; tokens.len()];
+        types[0] = 2; // unknown
+        types[1] = TOKEN_TYPE_CONTROL as i32;
+        types[2] = TOKEN_TYPE_CONTROL as i32;
+        // longer tokens are merged later
+        let scores = tokens.iter().map(|t| -(t.len() as f32)).collect();
+        let path = write_gguf(
+            "llama",
+            &[
+                ("tokenizer.ggml.model", Kv::Str("llama")),
+                ("tokenizer.ggml.tokens", Kv::Strs(tokens)),
+                ("tokenizer.ggml.token_type", Kv::I32s(types)),
+                ("tokenizer.ggml.scores", Kv::F32s(scores)),
+                ("tokenizer.ggml.unknown_token_id", Kv::U32(0)),
+                ("tokenizer.ggml.eos_token_id", Kv::U32(2)),
+            ],
+        );
+        let (json, eos) = tokenizer_json(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(eos, 2);
+
+        let tok = load(&json);
+        let ids = tok.encode("hello", false).unwrap().get_ids().to_vec();
+        assert_eq!(ids, vec![12]);
+        assert_eq!(tok.decode(&ids, false).unwrap(), "hello");
+        let ids = tok.encode("hello hell", false).unwrap().get_ids().to_vec();
+        assert_eq!(ids, vec![12, 11]);
+    }
+
+    #[test]
+    fn not_gguf() {
+        let path = std::env::temp_dir().join(format!("aici-not-{}.gguf", std::process::id()));
+        std::fs::write(&path, b"GGML1234").unwrap();
+        let r = tokenizer_json(&path.to_string_lossy());
+        std::fs::remove_file(&path).unwrap();
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn lengths_beyond_file() {
+        let values = [
+            // string of almost 1GB
+            [
+                8u32.to_le_bytes().as_slice(),
+                &((1u64 << 30) - 1).to_le_bytes(),
+            ]
+            .concat(),
+            // array of almost 2^28 strings
+            [
+                9u32.to_le_bytes().as_slice(),
+                &8u32.to_le_bytes(),
+                &((1u64 << 28) - 1).to_le_bytes(),
+            ]
+            .concat(),
+        ];
+        for (idx, value) in values.iter().enumerate() {
+            let mut out = GGUF_MAGIC.to_vec();
+            out.extend_from_slice(&3u32.to_le_bytes());
+            out.extend_from_slice(&0u64.to_le_bytes());
+            out.extend_from_slice(&1u64.to_le_bytes());
+            write_str(&mut out, "tokenizer.ggml.tokens");
+            out.extend_from_slice(value);
+            let path =
+                std::env::temp_dir().join(format!("aici-long-{}-{}.gguf", idx, std::process::id()));
+            std::fs::write(&path, out).unwrap();
+            let r = GgufMetadata::read(&path.to_string_lossy());
+            std::fs::remove_file(&path).unwrap();
+            assert!(r.is_err());
+        }
+    }
+}
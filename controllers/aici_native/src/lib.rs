@@ -1,5 +1,7 @@
 pub mod bintokens;
+mod gguf;
 mod log;
+mod tiktoken;
 pub mod variables;
 
 pub use log::*;
@@ -0,0 +1,188 @@
+//! Converting tiktoken vocabulary files (eg. `cl100k_base.tiktoken`) to HF tokenizer.json format.
+
+use crate::{
+    bintokens::{
+        added_token, byte_level_bpe_json, byte_to_char_map, CL100K_SPLIT_REGEX, O200K_SPLIT_REGEX,
+    },
+    HashMap,
+};
+use anyhow::{anyhow, ensure, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use serde_json::{json, Value};
+
+// number of ranks in the tiktoken files
+const CL100K_NUM_RANKS: usize = 100256;
+const O200K_NUM_RANKS: usize = 199998;
+
+// tiktoken files only contain the ranks; special tokens are defined in the tiktoken library
+fn special_tokens(num_ranks: usize) -> Vec<(&'static str, u32)> {
+    match num_ranks {
+        CL100K_NUM_RANKS => vec![
+            ("<|endoftext|>", 100257),
+            ("<|fim_prefix|>", 100258),
+            ("<|fim_middle|>", 100259),
+            ("<|fim_suffix|>", 100260),
+            ("<|endofprompt|>", 100276),
+        ],
+        O200K_NUM_RANKS => vec![("<|endoftext|>", 199999), ("<|endofprompt|>", 200018)],
+        n => vec![("<|endoftext|>", n as u32)],
+    }
+}
+
+/// Returns HF tokenizer.json and the EOS token id.
+pub fn tokenizer_json(filename: &str) -> Result<(Value, u32)> {
+    let text = std::fs::read_to_string(filename)?;
+    let mut ranks: HashMap<Vec<u8>, u32> = HashMap::default();
+    for (lineno, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        let err = || anyhow!("invalid line {}", lineno + 1);
+        let token = BASE64.decode(parts.next().ok_or_else(err)?)?;
+        let rank = parts.next().ok_or_else(err)?.parse::<u32>()?;
+        ranks.insert(token, rank);
+    }
+    ensure!(ranks.len() > 256, "too few tokens");
+
+    let byte_map = byte_to_char_map();
+    let encode = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .map(|b| byte_map[*b as usize])
+            .collect::<String>()
+    };
+
+    let mut by_rank = ranks.iter().collect::<Vec<_>>();
+    by_rank.sort_by_key(|(_, rank)| **rank);
+
+    let vocab = by_rank
+        .iter()
+        .map(|(tok, rank)| (encode(tok), json!(rank)))
+        .collect::<serde_json::Map<_, _>>();
+
+    let merges = by_rank
+        .iter()
+        .filter(|(tok, _)| tok.len() > 1)
+        .filter_map(|(tok, rank)| {
+            let parts = bpe_split(&ranks, tok, **rank);
+            // tokens that can't be constructed by merges are only used via added tokens
+            if parts.len() == 2 {
+                Some(format!("{} {}", encode(&parts[0]), encode(&parts[1])))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    let specials = special_tokens(ranks.len());
+    let eos_token = specials[0].1;
+    let added_tokens = specials
+        .iter()
+        .map(|(name, id)| added_token(*id, name, true))
+        .collect();
+
+    // like the tiktoken library, we pick pre-tokenization by vocabulary;
+    // older ones (r50k, p50k) use the GPT-2 one
+    let split_regex = match ranks.len() {
+        O200K_NUM_RANKS => Some(O200K_SPLIT_REGEX),
+        n if n >= CL100K_NUM_RANKS => Some(CL100K_SPLIT_REGEX),
+        _ => None,
+    };
+
+    Ok((
+        byte_level_bpe_json(vocab, merges, added_tokens, split_regex),
+        eos_token,
+    ))
+}
+
+// run BPE on the token, only using merges with rank lower than the token itself;
+// this recovers the last merge that created the token
+fn bpe_split(ranks: &HashMap<Vec<u8>, u32>, token: &[u8], max_rank: u32) -> Vec<Vec<u8>> {
+    let mut parts = token.iter().map(|b| vec![*b]).collect::<Vec<_>>();
+    loop {
+        let mut best: Option<(u32, usize)> = None;
+        for i in 0..parts.len() - 1 {
+            let mut merged = parts[i].clone();
+            merged.extend_from_slice(&parts[i + 1]);
+            if let Some(rank) = ranks.get(&merged) {
+                if *rank < max_rank && best.map_or(true, |(r, _)| *rank < r) {
+                    best = Some((*rank, i));
+                }
+            }
+        }
+        match best {
+            Some((_, i)) => {
+                let right = parts.remove(i + 1);
+                parts[i].extend_from_slice(&right);
+            }
+            None => break,
+        }
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokenizers::Tokenizer;
+
+    fn write_fixture(name: &str, extra: &[&[u8]]) -> String {
+        let mut lines = (0..=255u8)
+            .map(|b| vec![b])
+            .chain(extra.iter().map(|t| t.to_vec()))
+            .enumerate()
+            .map(|(rank, tok)| format!("{} {}\n", BASE64.encode(tok), rank))
+            .collect::<String>();
+        lines.push('\n');
+        let path =
+            std::env::temp_dir().join(format!("aici-{}-{}.tiktoken", name, std::process::id()));
+        std::fs::write(&path, lines).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn load(json: &Value) -> Tokenizer {
+        Tokenizer::from_bytes(serde_json::to_vec(json).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn tiktoken_round_trip() {
+        let path = write_fixture("round-trip", &[b"he", b"ll", b"hell", b"hello", b" w"]);
+        let (json, eos) = tokenizer_json(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // special tokens come after the ranks
+        assert_eq!(eos, 261);
+        assert_eq!(json["model"]["merges"][0], "h e");
+        // "hell" is built from "he" and "ll", not "hel" and "l"
+        assert_eq!(json["model"]["merges"][2], "he ll");
+
+        let tok = load(&json);
+        let ids = tok.encode("hello", false).unwrap().get_ids().to_vec();
+        assert_eq!(ids, vec![259]);
+        let text = "hello world\n\u{1F600}";
+        let ids = tok.encode(text, false).unwrap().get_ids().to_vec();
+        assert_eq!(ids[0..2], [259, 260]);
+        assert_eq!(tok.decode(&ids, false).unwrap(), text);
+    }
+
+    #[test]
+    fn split_regexes() {
+        let vocab = (0..=255u8)
+            .map(|b| (byte_to_char_map()[b as usize].to_string(), json!(b)))
+            .chain(std::iter::once(("oW".to_string(), json!(256))))
+            .collect::<serde_json::Map<_, _>>();
+        let merges = vec!["o W".to_string()];
+        let encode = |regex| {
+            let json = byte_level_bpe_json(vocab.clone(), merges.clone(), vec![], Some(regex));
+            load(&json)
+                .encode("HelloWorld", false)
+                .unwrap()
+                .get_ids()
+                .to_vec()
+        };
+        // cl100k keeps words together, while o200k splits them on case change
+        assert!(encode(CL100K_SPLIT_REGEX).contains(&256));
+        assert!(!encode(O200K_SPLIT_REGEX).contains(&256));
+    }
+}
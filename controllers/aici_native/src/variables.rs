@@ -9,15 +9,18 @@ pub struct Variables {
 impl Variables {
     pub fn process_cmd(&mut self, cmd: StorageCmd) -> StorageResp {
         match cmd {
-            StorageCmd::ReadVar { name } => match self.variables.get(&name).map(|x| x.clone()) {
-                None => StorageResp::VariableMissing {},
-                Some((version, value)) => StorageResp::ReadVar { value, version },
-            },
+            StorageCmd::ReadVar { name, .. } => {
+                match self.variables.get(&name).map(|x| x.clone()) {
+                    None => StorageResp::VariableMissing {},
+                    Some((version, value)) => StorageResp::ReadVar { value, version },
+                }
+            }
             StorageCmd::WriteVar {
                 name,
                 value,
                 when_version_is,
                 op,
+                ..
             } => {
                 let curr = self.variables.get(&name).map(|x| x.clone());
                 match curr {
@@ -50,7 +53,60 @@ impl Variables {
                     },
                 }
             }
+            StorageCmd::Watch {
+                names,
+                after_version,
+            } => {
+                for name in names {
+                    if let Some((version, _)) = self.variables.get(&name) {
+                        if *version > after_version {
+                            let version = *version;
+                            return StorageResp::Changed { name, version };
+                        }
+                    }
+                }
+                StorageResp::Watching {}
+            }
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watch(names: &[&str], after_version: u64) -> StorageCmd {
+        StorageCmd::Watch {
+            names: names.iter().map(|n| n.to_string()).collect(),
+            after_version,
+        }
+    }
+
+    #[test]
+    fn watch_reports_newer_versions() {
+        let mut vars = Variables::default();
+        // missing variables have version 0
+        assert!(matches!(
+            vars.process_cmd(watch(&["x", "y"], 0)),
+            StorageResp::Watching {}
+        ));
+        vars.process_cmd(StorageCmd::WriteVar {
+            name: "y".to_string(),
+            value: b"1".to_vec(),
+            op: StorageOp::Set,
+            when_version_is: None,
+            scope: Default::default(),
+        });
+        match vars.process_cmd(watch(&["x", "y"], 0)) {
+            StorageResp::Changed { name, version } => {
+                assert_eq!(name, "y");
+                assert_eq!(version, 1);
+            }
+            r => panic!("unexpected {r:?}"),
+        }
+        assert!(matches!(
+            vars.process_cmd(watch(&["x", "y"], 1)),
+            StorageResp::Watching {}
+        ));
+    }
+}
@@ -10,7 +10,18 @@
 */
 
 use aici_abi::{
-    aici_expose_all, bytes::limit_str, cfg::CfgParser, host_trie, rx::{RecRx, RxStackRecognizer}, SimpleVob, tokenize_bytes, toktrie::{Recognizer, SpecialToken, TokTrie}, AiciCtrl, Branch, InitPromptArg, InitPromptResult, MidProcessArg, MidProcessResult, TokenId, VariableStorage
+    aici_expose_all,
+    bytes::limit_str,
+    cfg::CfgParser,
+    fuzzy_substring::{FuzzySubStrMatcher, FuzzySubStrOptions},
+    healing::{heal_tokens, healed, Healed},
+    host_trie,
+    json_schema::json_schema_recognizer,
+    rx::{RecRx, RxStackRecognizer},
+    tokenize_bytes,
+    toktrie::{Recognizer, SpecialToken, TokTrie},
+    AiciCtrl, AttentionMask, Branch, BranchSampling, InitPromptArg, InitPromptResult,
+    LogprobsRequest, MidProcessArg, MidProcessResult, SimpleVob, TokenId, VariableStorage,
 };
 use core::panic;
 use serde::{Deserialize, Serialize};
@@ -168,6 +179,22 @@ pub struct StepAttributes {
 
     /// Label this step, so that it can be backtracked to later.
     label: Option<LabelName>,
+
+    /// Request log-probabilities of this many most likely tokens (at most 20)
+    /// for every token generated in this step.
+    /// They are appended to `logprobs_var` as JSON, one line per token.
+    logprobs: Option<u32>,
+
+    /// Also store the full distribution (one entry per token) with `logprobs`;
+    /// requires host support.
+    logprobs_full: Option<bool>,
+
+    /// Variable to store log-probabilities in; defaults to `logprobs`.
+    logprobs_var: Option<VarName>,
+
+    /// Override `top_p`, `top_k` and `min_p` of the request for this step.
+    #[serde(default)]
+    sampling: BranchSampling,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -188,6 +215,11 @@ pub enum Step {
         /// First backtrack to this label, and then generate text.
         following: Option<LabelName>,
 
+        /// Leave the last token(s) of the text to be generated as part of the next step
+        /// (which has to be a `Gen` without `inner`); can't be used with `following`.
+        #[serde(default)]
+        token_healing: bool,
+
         /// Common attributes
         #[serde(flatten)]
         attrs: StepAttributes,
@@ -203,7 +235,7 @@ pub enum Step {
         attrs: StepAttributes,
     },
 
-    // Generate text. It can be constrained with a regex or a yacc grammar.
+    // Generate text. It can be constrained with a regex, a yacc grammar, or a JSON schema.
     // The length can be constrained in several ways.
     Gen {
         /// Generate string that matches the regex.
@@ -212,6 +244,19 @@ pub enum Step {
         /// Generate string that matches the yacc grammar.
         yacc: Option<String>,
 
+        /// Generate JSON that matches the schema (a subset of JSON Schema is supported).
+        json_schema: Option<serde_json::Value>,
+
+        /// Generate a substring of this text, starting and ending at word boundaries,
+        /// followed by `substring_end`.
+        substring: Option<Expr>,
+
+        /// What to generate after the substring; defaults to nothing.
+        substring_end: Option<String>,
+
+        /// Allow the substring to differ from the text (edits, whitespace and case).
+        substring_fuzzy: Option<FuzzySubStrOptions>,
+
         /// Constraints to apply in the middle of the generation.
         #[serde(default)]
         inner: Vec<InnerConstraint>,
@@ -243,6 +288,7 @@ pub enum Step {
     Fork { branches: Vec<Vec<Step>> },
 
     /// Wait for all listed variables to be set.
+    /// The sequence is suspended (doesn't take part in generation) in the meantime.
     Wait { vars: Vec<VarName> },
 
     /// Stop the sequence (makes most sense in a Fork).
@@ -307,20 +353,26 @@ impl Debug for Step {
                 text,
                 attrs,
                 following,
+                token_healing,
             } => {
                 write!(
                     f,
-                    "Fixed({text:?}{attrs:?}){}",
+                    "Fixed({text:?}{attrs:?}){}{}",
                     following
                         .as_ref()
                         .map(|l| format!(" following:{}", l.0))
                         .unwrap_or_default(),
+                    if *token_healing { " healing" } else { "" },
                 )
             }
             Step::Choose { options, attrs } => write!(f, "Choose({options:?}{attrs:?})"),
             Step::Gen {
                 rx,
                 yacc,
+                json_schema,
+                substring,
+                substring_end,
+                substring_fuzzy,
                 inner,
                 stop_at,
                 max_tokens,
@@ -336,6 +388,18 @@ impl Debug for Step {
                 if let Some(yacc) = yacc {
                     write!(f, "yacc:{:?} ", limit_str(yacc, 200))?;
                 }
+                if let Some(schema) = json_schema {
+                    write!(f, "json_schema:{} ", limit_str(&schema.to_string(), 200))?;
+                }
+                if let Some(substring) = substring {
+                    write!(f, "substring:{:?} ", substring)?;
+                    if let Some(end) = substring_end {
+                        write!(f, "substring_end:{:?} ", end)?;
+                    }
+                    if let Some(fuzzy) = substring_fuzzy {
+                        write!(f, "substring_fuzzy:{:?} ", fuzzy)?;
+                    }
+                }
                 if inner.len() > 0 {
                     write!(f, "inner:")?;
                     for ic in inner {
@@ -371,6 +435,9 @@ pub struct Program {
 enum StepSpecific {
     Options { tokens: Vec<Vec<TokenId>> },
     ExpandOptions { text: Expr, many: bool },
+    ExpandSubStr { source: Expr },
+    SubStr { matcher: FuzzySubStrMatcher },
+    Healed { rec: Healed<dyn Recognizer> },
     Inner { constraints: Vec<InnerConstraint> },
     Rx { rx: RxStackRecognizer },
     Cfg { cfg: CfgParser },
@@ -392,6 +459,11 @@ struct StepState {
     // if true, this step was derived from the next step
     is_derived: bool,
 
+    // if true, the last token(s) are still to be moved to the next step
+    token_healing: bool,
+    // number of bytes generated for this step that belong to the previous one
+    healed_bytes: usize,
+
     mask_tags: Vec<TagName>,
     attrs: StepAttributes,
 
@@ -564,6 +636,8 @@ impl StepState {
             num_bytes: 0,
             following: None,
             is_derived: false,
+            token_healing: false,
+            healed_bytes: 0,
         }
     }
 
@@ -606,6 +680,7 @@ impl StepState {
                 text,
                 attrs,
                 following,
+                token_healing,
             } => Self::new_with_attrs(
                 s,
                 attrs,
@@ -614,7 +689,10 @@ impl StepState {
                     many: false,
                 },
             )
-            .with(|s| s.following = following.clone()),
+            .with(|s| {
+                s.following = following.clone();
+                s.token_healing = *token_healing;
+            }),
 
             Step::Choose { options, attrs } => Self::new_with_attrs(
                 s,
@@ -628,32 +706,54 @@ impl StepState {
             Step::Gen {
                 rx,
                 yacc,
+                json_schema,
+                substring,
                 stop_at,
                 inner,
                 max_tokens,
                 max_bytes,
                 max_words,
                 mask_tags,
+                substring_fuzzy,
                 attrs,
+                ..
             } => {
-                let spec = match (yacc, rx) {
-                    (None, None) if inner.len() > 0 => StepSpecific::Inner {
+                if let Some(fuzzy) = substring_fuzzy {
+                    fuzzy.validate().expect("invalid substring_fuzzy");
+                }
+                let spec = match (yacc, rx, json_schema) {
+                    (None, None, None) if substring.is_some() && inner.len() == 0 => {
+                        StepSpecific::ExpandSubStr {
+                            source: substring.clone().unwrap(),
+                        }
+                    }
+                    _ if substring.is_some() => {
+                        panic!(
+                            "can't have substring= and either yacc=, rx=, json_schema= or inner="
+                        )
+                    }
+                    (None, None, None) if inner.len() > 0 => StepSpecific::Inner {
                         constraints: inner.clone(),
                     },
                     _ if inner.len() > 0 => {
-                        panic!("can't have inner= and either yacc= or rx=")
+                        panic!("can't have inner= and either yacc=, rx= or json_schema=")
                     }
-                    (Some(_), Some(_)) => {
-                        panic!("can't have both yacc= and rx=")
+                    (Some(_), Some(_), _) | (Some(_), _, Some(_)) | (_, Some(_), Some(_)) => {
+                        panic!("can't have more than one of yacc=, rx= and json_schema=")
                     }
-                    (Some(yacc), None) => StepSpecific::Cfg {
-                        cfg: CfgParser::from_yacc(yacc).expect("invalid grammar"),
+                    (None, None, Some(schema)) => StepSpecific::Rx {
+                        rx: json_schema_recognizer(schema).expect("invalid JSON schema"),
+                    },
+                    (Some(yacc), None, None) => StepSpecific::Cfg {
+                        cfg: CfgParser::from_grammar_cached(yacc).expect("invalid grammar"),
                     },
                     _ => {
                         let defl = "(.|\n)+".to_string();
                         let rx = rx.as_deref().unwrap_or(&defl);
                         StepSpecific::Rx {
-                            rx: RecRx::from_rx(&rx, None).unwrap().to_stack_recognizer(),
+                            rx: RecRx::from_rx_cached(&rx, None)
+                                .unwrap()
+                                .to_stack_recognizer(),
                         }
                     }
                 };
@@ -702,7 +802,7 @@ impl StepState {
             StepSpecific::Fork { .. } => false,
             StepSpecific::Wait { .. } => false,
             StepSpecific::Stop => false,
-            StepSpecific::ExpandOptions { .. } => {
+            StepSpecific::ExpandOptions { .. } | StepSpecific::ExpandSubStr { .. } => {
                 assert!(self.num_tokens == 0);
                 false
                 // if optional {
@@ -727,6 +827,14 @@ impl StepState {
                 rx.special_allowed(SpecialToken::EndOfSentence)
                     && (optional || (0..=255).all(|byte| !rx.byte_allowed(byte)))
             }
+            StepSpecific::SubStr { matcher } => {
+                matcher.special_allowed(SpecialToken::EndOfSentence)
+                    && (optional || (0..=255).all(|byte| !matcher.byte_allowed(byte)))
+            }
+            StepSpecific::Healed { rec } => {
+                rec.special_allowed(SpecialToken::EndOfSentence)
+                    && (optional || (0..=255).all(|byte| !rec.byte_allowed(byte)))
+            }
         }
     }
 
@@ -738,31 +846,24 @@ impl StepState {
         self.check_eos(false)
     }
 
-    #[allow(dead_code)]
-    fn attention_mask(&self, ctx: &RunnerCtx) -> Vec<f32> {
+    fn attention_mask(&self, ctx: &RunnerCtx) -> AttentionMask {
         if self.mask_tags.len() == 0 {
-            vec![]
+            AttentionMask::default()
         } else {
-            let mut mask = vec![1.0f32; ctx.tokens.len()];
-            for (idx, tok) in ctx.tokens.iter().enumerate() {
-                if self.mask_tags.contains(&tok.tag) {
-                    println!(
-                        "masking t[{}] = {:?} tag={:?}",
-                        idx,
-                        ctx.trie.token_str(tok.id),
-                        tok.tag
-                    );
-                    mask[idx] = 0.0;
-                }
-            }
-            mask
+            AttentionMask::from_flags(
+                ctx.tokens
+                    .iter()
+                    .map(|tok| self.mask_tags.contains(&tok.tag)),
+            )
         }
     }
 
     fn advance(&mut self, runner: &RunnerCtx, token: TokenId) -> Option<StepState> {
         let nbytes = runner.trie.token(token).len();
+        let num_healed = std::cmp::min(self.healed_bytes, nbytes);
+        self.healed_bytes -= num_healed;
         self.num_tokens += 1;
-        self.num_bytes += nbytes;
+        self.num_bytes += nbytes - num_healed;
         let sidx = runner.bytes.len() - nbytes;
 
         for idx in sidx.saturating_sub(1)..runner.bytes.len().saturating_sub(1) {
@@ -780,6 +881,7 @@ impl StepState {
 
         match &mut self.specific {
             StepSpecific::ExpandOptions { .. } => panic!("advance on ExpandOptions"),
+            StepSpecific::ExpandSubStr { .. } => panic!("advance on ExpandSubStr"),
             StepSpecific::Fork { .. } => panic!("advance on fork"),
             StepSpecific::Wait { .. } => panic!("advance on wait"),
             StepSpecific::Stop => {}
@@ -788,6 +890,8 @@ impl StepState {
             }
             StepSpecific::Cfg { cfg } => runner.trie.append_token(cfg, token).unwrap(),
             StepSpecific::Rx { rx } => runner.trie.append_token(rx, token).unwrap(),
+            StepSpecific::SubStr { matcher } => runner.trie.append_token(matcher, token).unwrap(),
+            StepSpecific::Healed { rec } => runner.trie.append_token(rec, token).unwrap(),
             StepSpecific::Inner { constraints } => {
                 for c in constraints {
                     let pos = runner.string_position(sidx, &c.after);
@@ -835,6 +939,7 @@ impl StepState {
         }
         match &mut self.specific {
             StepSpecific::ExpandOptions { .. } => false,
+            StepSpecific::ExpandSubStr { .. } => false,
             StepSpecific::Fork { .. } => false,
             StepSpecific::Wait { .. } => false,
             StepSpecific::Stop => false,
@@ -844,6 +949,8 @@ impl StepState {
             }
             StepSpecific::Cfg { cfg } => trie.token_allowed(cfg, token),
             StepSpecific::Rx { rx } => trie.token_allowed(rx, token),
+            StepSpecific::SubStr { matcher } => trie.token_allowed(matcher, token),
+            StepSpecific::Healed { rec } => trie.token_allowed(rec, token),
         }
     }
 
@@ -877,6 +984,24 @@ impl StepState {
                     .collect::<Vec<_>>();
                 self.specific = StepSpecific::Options { tokens }
             }
+            StepSpecific::ExpandSubStr { source } => {
+                let source = runner.expand(source);
+                let (end_str, options) = match &self.ast {
+                    Step::Gen {
+                        substring_end,
+                        substring_fuzzy,
+                        ..
+                    } => (
+                        substring_end.clone().unwrap_or_default(),
+                        substring_fuzzy.clone().unwrap_or_default(),
+                    ),
+                    _ => panic!("ExpandSubStr on non-Gen step"),
+                };
+                let matcher =
+                    FuzzySubStrMatcher::new(&String::from_utf8_lossy(&source), &end_str, options)
+                        .expect("invalid substring_fuzzy");
+                self.specific = StepSpecific::SubStr { matcher }
+            }
             _ => {}
         }
     }
@@ -887,6 +1012,7 @@ impl StepState {
                 toks.allow_token(trie.special_token(SpecialToken::EndOfSentence));
             }
             StepSpecific::ExpandOptions { .. } => {}
+            StepSpecific::ExpandSubStr { .. } => {}
             StepSpecific::Wait { .. } => {}
             StepSpecific::Fork { .. } => {}
             StepSpecific::Inner { .. } => {
@@ -902,17 +1028,48 @@ impl StepState {
                 }
             }
             StepSpecific::Rx { rx } => {
-                trie.add_bias(rx, toks, &[]);
+                rx.add_bias(trie, toks);
             }
             StepSpecific::Cfg { cfg } => {
-                trie.add_bias(cfg, toks, &[]);
+                cfg.add_bias(trie, toks);
+            }
+            StepSpecific::SubStr { matcher } => {
+                trie.add_bias(matcher, toks, &[]);
+            }
+            StepSpecific::Healed { rec } => {
+                trie.add_bias(rec, toks, &[]);
+            }
+        }
+    }
+}
+
+// token healing hands the healed bytes to the next step, which has to be able to take them
+fn check_token_healing(steps: &[Step]) {
+    for (idx, step) in steps.iter().enumerate() {
+        match step {
+            Step::Fixed {
+                token_healing: true,
+                following,
+                ..
+            } => {
+                assert!(
+                    following.is_none(),
+                    "token_healing can't be used with following="
+                );
+                match steps.get(idx + 1) {
+                    Some(Step::Gen { inner, .. }) if inner.is_empty() => {}
+                    _ => panic!("token_healing requires the next step to be a Gen without inner="),
+                }
             }
+            Step::Fork { branches } => branches.iter().for_each(|b| check_token_healing(b)),
+            _ => {}
         }
     }
 }
 
 impl Runner {
     pub fn new(program: Program) -> Self {
+        check_token_healing(&program.steps);
         let mut states = program
             .steps
             .iter()
@@ -1090,8 +1247,10 @@ impl Runner {
         let mut can_ff = true;
         let mut all_eos = true;
 
-        for state in &mut self.states[self.state_idx..] {
-            state.concretize(&self.ctx);
+        for idx in self.state_idx..self.states.len() {
+            self.states[idx].concretize(&self.ctx);
+            self.heal_boundary(idx);
+            let state = &mut self.states[idx];
             if state.forces_eos() {
                 if all_eos {
                     self.state_idx += 1;
@@ -1120,10 +1279,57 @@ impl Runner {
         }
     }
 
+    /// If state `idx` is a healing Fixed step, move its last token(s) to the start of the next step.
+    fn heal_boundary(&mut self, idx: usize) {
+        if !self.states[idx].token_healing || idx + 1 >= self.states.len() {
+            return;
+        }
+        let (curr, rest) = self.states[idx..].split_at_mut(1);
+        let (curr, next) = (&mut curr[0], &mut rest[0]);
+        curr.token_healing = false;
+        let tokens = match &mut curr.specific {
+            StepSpecific::Options { tokens } if tokens.len() == 1 && curr.num_tokens == 0 => {
+                &mut tokens[0]
+            }
+            _ => return,
+        };
+        let (num_tokens, prefix) = heal_tokens(&self.ctx.trie, tokens, 1);
+        if num_tokens == 0 {
+            return;
+        }
+
+        next.concretize(&self.ctx);
+        let specific = std::mem::replace(&mut next.specific, StepSpecific::Stop);
+        let inner: Box<dyn Recognizer> = match specific {
+            StepSpecific::Rx { rx } => Box::new(rx),
+            StepSpecific::Cfg { cfg } => Box::new(cfg),
+            StepSpecific::SubStr { matcher } => Box::new(matcher),
+            // see check_token_healing()
+            _ => panic!("token healing: next step is not a Gen"),
+        };
+        tokens.truncate(tokens.len() - num_tokens);
+        if LOG_ADVANCE {
+            println!("token healing: {:?}", String::from_utf8_lossy(&prefix));
+        }
+        next.healed_bytes = prefix.len();
+        next.specific = StepSpecific::Healed {
+            rec: healed(prefix, inner),
+        };
+    }
+
     fn maybe_wait(&mut self) -> bool {
         if let StepSpecific::Wait { vars } = &self.curr_state().specific {
-            if vars.iter().any(|name| self.ctx.vars.get(&name.0).is_none()) {
-                println!("wait {vars:?} suspend");
+            let missing = vars
+                .iter()
+                .filter(|name| self.ctx.vars.get(&name.0).is_none())
+                .map(|name| name.0.as_str())
+                .collect::<Vec<_>>();
+            if missing.len() > 0 {
+                // have the host suspend us until one of the missing variables is set
+                match self.ctx.vars.watch(&missing, 0) {
+                    None => println!("wait {vars:?} suspend"),
+                    Some((name, _)) => println!("wait {vars:?}: {name} just set"),
+                }
                 true
             } else {
                 println!("wait {vars:?} done");
@@ -1172,6 +1378,20 @@ impl AiciCtrl for Runner {
             assert!(state.num_tokens == 0);
         }
 
+        // logprobs are for the token sampled in the current step
+        if let Some(lp) = &arg.logprobs {
+            let attrs = &self.curr_state().attrs;
+            if attrs.logprobs.is_some() {
+                let var = attrs
+                    .logprobs_var
+                    .as_ref()
+                    .map_or("logprobs", |v| v.0.as_str());
+                let mut line = serde_json::to_vec(lp).unwrap();
+                line.push(b'\n');
+                self.ctx.vars.append(var, line);
+            }
+        }
+
         // if in wait state, don't do anything...
         if let StepSpecific::Wait { .. } = &self.curr_state().specific {
             // ???
@@ -1240,18 +1460,35 @@ impl AiciCtrl for Runner {
             assert!(branches.len() > 1);
             return MidProcessResult {
                 branches: branches.iter().map(|_| Branch::noop()).collect(),
+                attention_mask: AttentionMask::default(),
+                logprobs: None,
             };
         }
 
-        if self.maybe_wait() {
+        let res = if self.maybe_wait() {
             // this is a bit late in the game, but it's the best we can do
             MidProcessResult::noop()
         } else {
             self.try_backtrack()
+        };
+
+        let mask = self.curr_state().attention_mask(&self.ctx);
+        if LOG_ADVANCE && !mask.is_empty() {
+            println!("attention mask: {:?}", mask.ranges);
+        }
+        let attrs = &self.curr_state().attrs;
+        let res = res.with_attention_mask(mask).with_sampling(attrs.sampling);
+        match attrs.logprobs {
+            Some(k) if attrs.logprobs_full == Some(true) => {
+                res.with_logprobs(LogprobsRequest::top_k(k).with_full())
+            }
+            Some(k) => res.with_logprobs(LogprobsRequest::top_k(k)),
+            None => res,
         }
     }
 }
 
+#[allow(dead_code)]
 fn main() {
     aici_abi::cfg::cfg_test().unwrap();
     //    let _run = sample_prog();
@@ -1,22 +1,29 @@
-use std::sync::Mutex;
+use std::{cell::RefCell, sync::Mutex};
 
 use aici_abi::{
-    aici_stop, host_trie,
+    aici_stop, cfg::CfgParser,
+    combinators::{And, BoxedRecognizer, MaxBytes, Or, Repeat, Seq},
+    healing::healed,
+    host_trie,
     recognizer::{AnythingGoes, StackRecognizer},
+    rx::RxStackRecognizer,
     SimpleVob,
     toktrie::{Recognizer, SpecialToken, TokTrie},
-    AiciCtrl, InitPromptArg, InitPromptResult, MidProcessArg, MidProcessResult, TokenId,
+    AiciCtrl, InitPromptArg, InitPromptResult, Logprobs, MidProcessArg, MidProcessResult, TokenId,
     VariableStorage,
 };
 use rquickjs::{
-    class::Trace, function::IntoArgs, ArrayBuffer, Context, Ctx, FromJs, Function, IntoAtom,
-    IntoJs, Module, Object, Result, Runtime, TypedArray, Value,
+    class::Trace,
+    function::{IntoArgs, Opt},
+    ArrayBuffer, Context, Ctx, Exception, FromJs, Function, IntoAtom, IntoJs, Module, Object,
+    Result, Runtime, TypedArray, Value,
 };
 
 struct ModuleState {
     trie: TokTrie,
     vars: VariableStorage,
     mid_process_result: Option<MidProcessResult>,
+    logprobs: Option<Logprobs>,
 }
 
 unsafe impl Send for ModuleState {}
@@ -26,6 +33,7 @@ lazy_static::lazy_static! {
         trie: host_trie(),
         vars: VariableStorage::new(),
         mid_process_result: None,
+        logprobs: None,
     });
 }
 
@@ -190,6 +198,34 @@ impl Constraint {
     fn new(inner: Box<dyn PyConstraint>) -> Self {
         Self { inner }
     }
+
+    /// The underlying constraint, unless it was already used in a combinator.
+    fn inner(&mut self, ctx: &Ctx<'_>) -> Result<&mut dyn PyConstraint> {
+        if self.inner.is_used() {
+            Err(Exception::throw_type(ctx, "constraint was already used"))
+        } else {
+            Ok(&mut *self.inner)
+        }
+    }
+
+    /// Move the underlying recognizer out; the constraint can't be used afterwards.
+    fn take_recognizer(&mut self, ctx: &Ctx<'_>) -> Result<BoxedRecognizer> {
+        self.inner(ctx)?;
+        let inner = std::mem::replace(&mut self.inner, Box::new(UsedConstraint));
+        Ok(inner.into_recognizer().unwrap())
+    }
+
+    /// Same as `take_recognizer()` on both, but on error neither is changed.
+    fn take_both(
+        &mut self,
+        ctx: &Ctx<'_>,
+        other: &mut Constraint,
+    ) -> Result<(BoxedRecognizer, BoxedRecognizer)> {
+        other.inner(ctx)?;
+        let a = self.take_recognizer(ctx)?;
+        let b = other.take_recognizer(ctx)?;
+        Ok((a, b))
+    }
 }
 
 #[rquickjs::methods]
@@ -200,24 +236,56 @@ impl Constraint {
         Self::new(Box::new(StackRecognizer::from(AnythingGoes {})))
     }
 
-    pub fn eosAllowed(&mut self) -> bool {
-        self.inner.eos_allowed()
+    pub fn eosAllowed(&mut self, ctx: Ctx<'_>) -> Result<bool> {
+        Ok(self.inner(&ctx)?.eos_allowed())
+    }
+
+    pub fn eosForced(&mut self, ctx: Ctx<'_>) -> Result<bool> {
+        Ok(self.inner(&ctx)?.eos_forced())
+    }
+
+    pub fn tokenAllowed(&mut self, ctx: Ctx<'_>, t: TokenId) -> Result<bool> {
+        Ok(self.inner(&ctx)?.token_allowed(t))
+    }
+
+    pub fn appendToken(&mut self, ctx: Ctx<'_>, t: TokenId) -> Result<()> {
+        self.inner(&ctx)?.append_token(t);
+        Ok(())
     }
 
-    pub fn eosForced(&mut self) -> bool {
-        self.inner.eos_forced()
+    pub fn allowTokens(&mut self, ctx: Ctx<'_>, ts: &mut TokenSet) -> Result<()> {
+        self.inner(&ctx)?.allow_tokens(&mut ts.inner);
+        Ok(())
     }
 
-    pub fn tokenAllowed(&mut self, t: TokenId) -> bool {
-        self.inner.token_allowed(t)
+    pub fn intersect(&mut self, ctx: Ctx<'_>, other: &mut Constraint) -> Result<Constraint> {
+        let (a, b) = self.take_both(&ctx, other)?;
+        Ok(Constraint::new(Box::new(And::new(a, b))))
     }
 
-    pub fn appendToken(&mut self, t: TokenId) {
-        self.inner.append_token(t)
+    pub fn union(&mut self, ctx: Ctx<'_>, other: &mut Constraint) -> Result<Constraint> {
+        let (a, b) = self.take_both(&ctx, other)?;
+        Ok(Constraint::new(Box::new(Or::new(a, b))))
     }
 
-    pub fn allowTokens(&mut self, ts: &mut TokenSet) {
-        self.inner.allow_tokens(&mut ts.inner);
+    pub fn followedBy(&mut self, ctx: Ctx<'_>, other: &mut Constraint) -> Result<Constraint> {
+        let (a, b) = self.take_both(&ctx, other)?;
+        Ok(Constraint::new(Box::new(Seq::new(a, b))))
+    }
+
+    pub fn repeat(&mut self, ctx: Ctx<'_>, min: usize, max: Opt<usize>) -> Result<Constraint> {
+        let a = self.take_recognizer(&ctx)?;
+        Ok(Constraint::new(Box::new(Repeat::new(a, min, max.0))))
+    }
+
+    pub fn maxBytes(&mut self, ctx: Ctx<'_>, max_bytes: usize) -> Result<Constraint> {
+        let a = self.take_recognizer(&ctx)?;
+        Ok(Constraint::new(Box::new(MaxBytes::new(a, max_bytes))))
+    }
+
+    pub fn healed(&mut self, ctx: Ctx<'_>, prefix: Buffer) -> Result<Constraint> {
+        let a = self.take_recognizer(&ctx)?;
+        Ok(Constraint::new(Box::new(healed(prefix.0, a))))
     }
 }
 
@@ -251,12 +319,13 @@ mod aici_mod {
 
     pub use super::{Constraint, TokenSet};
 
-    use super::GLOBAL_STATE;
+    use super::{CachedConstraint, GLOBAL_STATE};
     use aici_abi::{
-        aici_stop, cfg::CfgParser, get_config, rx::RecRx, substring::SubStrMatcher,
-        toktrie::SpecialToken, Branch, MidProcessResult, Splice, TokenId,
+        aici_stop, cfg::CfgParser, get_config, json_schema::json_schema_recognizer_from_str,
+        rx::RecRx, substring::SubStrMatcher, toktrie, toktrie::SpecialToken, Branch,
+        BranchSampling, LogprobsRequest, MidProcessResult, Splice, TokenId,
     };
-    use rquickjs::{Ctx, Exception, Object, Result, Value};
+    use rquickjs::{function::Opt, Ctx, Exception, Object, Result, Value};
 
     #[rquickjs::function]
     pub fn selfSeqId() -> u32 {
@@ -284,6 +353,12 @@ mod aici_mod {
         Buffer(bytes)
     }
 
+    #[rquickjs::function]
+    pub fn healTokens(tokens: Vec<TokenId>, max_tokens: Opt<usize>) -> usize {
+        let trie = &mut GLOBAL_STATE.lock().unwrap().trie;
+        aici_abi::healing::heal_tokens(trie, &tokens, max_tokens.0.unwrap_or(1)).0
+    }
+
     #[rquickjs::function]
     pub fn tokenRepr(token: TokenId) -> String {
         let trie = &mut GLOBAL_STATE.lock().unwrap().trie;
@@ -323,18 +398,44 @@ mod aici_mod {
         get_config(name)
     }
 
+    #[rquickjs::function]
+    pub fn nowMicros() -> f64 {
+        aici_abi::now_micros() as f64
+    }
+
+    #[rquickjs::function]
+    pub fn _emitEvent(kind: String, data: String) {
+        aici_abi::emit_event_json(&kind, data.as_bytes());
+    }
+
+    #[rquickjs::function]
+    pub fn randomSeed() -> f64 {
+        // keep it within Number.MAX_SAFE_INTEGER
+        (aici_abi::random_seed() >> 11) as f64
+    }
+
+    #[rquickjs::function]
+    pub fn _logprobs() -> Option<String> {
+        let st = GLOBAL_STATE.lock().unwrap();
+        st.logprobs
+            .as_ref()
+            .map(|lp| serde_json::to_string(lp).unwrap())
+    }
+
     #[rquickjs::function]
     pub fn _midProcessReturn(obj: Object<'_>) {
         let branches: Vec<Object> = obj.get2("branches");
+        let logprobs: Option<u32> = obj.get2("logprobs");
+        let logprobs_full: Option<bool> = obj.get2("logprobsFull");
         let res = MidProcessResult {
             branches: branches
                 .into_iter()
                 .map(|b| {
                     let sample_mask: Option<TokenSet> = b.get2("sampleMask");
                     let splices: Vec<Object> = b.get2("splices");
-                    Branch {
+                    Branch::from(toktrie::Branch {
                         sample_mask: sample_mask.map(|ts| ts.inner),
-                        temperature: None,
+                        temperature: b.get2("temperature"),
                         splices: splices
                             .into_iter()
                             .map(|s| Splice {
@@ -343,9 +444,23 @@ mod aici_mod {
                                 backtrack: s.get2("backtrack"),
                             })
                             .collect(),
-                    }
+                    })
+                    .with_sampling(BranchSampling {
+                        top_p: b.get2("topP"),
+                        top_k: b.get2("topK"),
+                        min_p: b.get2("minP"),
+                    })
                 })
                 .collect(),
+            attention_mask: Default::default(),
+            logprobs: logprobs.map(|k| {
+                let req = LogprobsRequest::top_k(k);
+                if logprobs_full == Some(true) {
+                    req.with_full()
+                } else {
+                    req
+                }
+            }),
         };
 
         let mut st = GLOBAL_STATE.lock().unwrap();
@@ -417,16 +532,24 @@ mod aici_mod {
     #[rquickjs::function]
     pub fn regexConstraint<'js>(ctx: Ctx<'js>, regex: String) -> Result<Constraint> {
         println!("regex constraint: {:?}", regex);
-        let rx = RecRx::from_rx(regex.as_str(), None)
+        let rx = RecRx::from_rx_cached(regex.as_str(), None)
             .map_err(|e| Exception::throw_type(&ctx, &format!("{}", e)))?
             .to_stack_recognizer();
-        Ok(Constraint::new(Box::new(rx)))
+        Ok(Constraint::new(Box::new(CachedConstraint(rx))))
     }
 
     #[rquickjs::function]
     pub fn cfgConstraint<'js>(ctx: Ctx<'js>, cfg: String) -> Result<Constraint> {
-        match CfgParser::from_yacc(cfg.as_str()) {
-            Ok(cfg) => Ok(Constraint::new(Box::new(cfg))),
+        match CfgParser::from_grammar_cached(cfg.as_str()) {
+            Ok(cfg) => Ok(Constraint::new(Box::new(CachedConstraint(cfg)))),
+            Err(e) => Err(Exception::throw_type(&ctx, &format!("{}", e))),
+        }
+    }
+
+    #[rquickjs::function]
+    pub fn jsonSchemaConstraint<'js>(ctx: Ctx<'js>, schema: String) -> Result<Constraint> {
+        match json_schema_recognizer_from_str(&schema) {
+            Ok(rx) => Ok(Constraint::new(Box::new(CachedConstraint(rx)))),
             Err(e) => Err(Exception::throw_type(&ctx, &format!("{}", e))),
         }
     }
@@ -446,9 +569,15 @@ trait PyConstraint {
     fn token_allowed(&mut self, t: TokenId) -> bool;
     fn append_token(&mut self, t: TokenId);
     fn allow_tokens(&mut self, logits: &mut SimpleVob);
+    /// For combining constraints; None if not backed by a recognizer.
+    fn into_recognizer(self: Box<Self>) -> Option<BoxedRecognizer>;
+    /// True for [UsedConstraint]; other methods are not to be called then.
+    fn is_used(&self) -> bool {
+        false
+    }
 }
 
-impl<T: Recognizer> PyConstraint for T {
+impl<T: Recognizer + 'static> PyConstraint for T {
     fn eos_allowed(&mut self) -> bool {
         self.special_allowed(SpecialToken::EndOfSentence)
     }
@@ -472,6 +601,88 @@ impl<T: Recognizer> PyConstraint for T {
         let trie = &mut GLOBAL_STATE.lock().unwrap().trie;
         trie.compute_bias(self, logits)
     }
+
+    fn into_recognizer(self: Box<Self>) -> Option<BoxedRecognizer> {
+        Some(self)
+    }
+}
+
+/// What is left of a constraint after it was combined into another one.
+/// `Constraint` checks `is_used()` first and throws instead of calling these.
+struct UsedConstraint;
+impl PyConstraint for UsedConstraint {
+    fn eos_allowed(&mut self) -> bool {
+        panic!("constraint was already used in a combinator")
+    }
+
+    fn eos_forced(&mut self) -> bool {
+        panic!("constraint was already used in a combinator")
+    }
+
+    fn token_allowed(&mut self, _t: TokenId) -> bool {
+        panic!("constraint was already used in a combinator")
+    }
+
+    fn append_token(&mut self, _t: TokenId) {
+        panic!("constraint was already used in a combinator")
+    }
+
+    fn allow_tokens(&mut self, _logits: &mut SimpleVob) {
+        panic!("constraint was already used in a combinator")
+    }
+
+    fn into_recognizer(self: Box<Self>) -> Option<BoxedRecognizer> {
+        None
+    }
+
+    fn is_used(&self) -> bool {
+        true
+    }
+}
+
+/// Recognizers that cache token masks across steps.
+trait CachedBias: Recognizer {
+    fn cached_bias(&mut self, trie: &TokTrie, logits: &mut SimpleVob);
+}
+
+impl CachedBias for CfgParser {
+    fn cached_bias(&mut self, trie: &TokTrie, logits: &mut SimpleVob) {
+        self.compute_bias(trie, logits)
+    }
+}
+
+impl CachedBias for RxStackRecognizer {
+    fn cached_bias(&mut self, trie: &TokTrie, logits: &mut SimpleVob) {
+        self.compute_bias(trie, logits)
+    }
+}
+
+struct CachedConstraint<T: CachedBias>(T);
+impl<T: CachedBias + 'static> PyConstraint for CachedConstraint<T> {
+    fn eos_allowed(&mut self) -> bool {
+        self.0.eos_allowed()
+    }
+
+    fn eos_forced(&mut self) -> bool {
+        self.0.eos_forced()
+    }
+
+    fn token_allowed(&mut self, t: TokenId) -> bool {
+        self.0.token_allowed(t)
+    }
+
+    fn append_token(&mut self, t: TokenId) {
+        self.0.append_token(t)
+    }
+
+    fn allow_tokens(&mut self, logits: &mut SimpleVob) {
+        let trie = &mut GLOBAL_STATE.lock().unwrap().trie;
+        self.0.cached_bias(trie, logits)
+    }
+
+    fn into_recognizer(self: Box<Self>) -> Option<BoxedRecognizer> {
+        Some(Box::new(self.0))
+    }
 }
 
 pub struct Runner {
@@ -482,10 +693,14 @@ fn _print(msg: String) {
     println!("{msg}");
 }
 
-impl Runner {
-    pub fn new(arg: Vec<u8>) -> Self {
-        let source = String::from_utf8(arg).unwrap();
+thread_local! {
+    // created by Runner::warmup(), before the module argument is known
+    static WARM_RUNNER: RefCell<Option<Runner>> = RefCell::new(None);
+}
 
+impl Runner {
+    // runtime with the aici module loaded, but without user code
+    fn new_base() -> Self {
         let rt = Runtime::new().unwrap();
         let s = Self {
             context: Context::full(&rt).unwrap(),
@@ -503,6 +718,26 @@ impl Runner {
             Module::declare_def::<js_aici_mod, _>(ctx.clone(), "_aici").unwrap();
 
             let _ = ctx.unwrap_js(ctx.clone().compile("aici", aici_js));
+        });
+
+        s
+    }
+
+    /// Create the runtime ahead of time; see `aici_expose_all!()`.
+    pub fn warmup() {
+        lazy_static::initialize(&GLOBAL_STATE);
+        let s = Self::new_base();
+        WARM_RUNNER.with(|w| *w.borrow_mut() = Some(s));
+    }
+
+    pub fn new(arg: Vec<u8>) -> Self {
+        let source = String::from_utf8(arg).unwrap();
+
+        let s = WARM_RUNNER
+            .with(|w| w.borrow_mut().take())
+            .unwrap_or_else(Self::new_base);
+
+        s.with_cb("_new", |ctx| {
             let _ = ctx.unwrap_js(ctx.clone().compile("main", source));
         });
 
@@ -554,6 +789,7 @@ impl AiciCtrl for Runner {
     }
 
     fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
+        GLOBAL_STATE.lock().unwrap().logprobs = arg.logprobs.clone();
         self.with_cb("mid_process", |ctx| {
             let cb: Function = ctx.eval2("globalThis._aici_cb.mid_process");
             let fg: Vec<u32> = arg.fork_group.iter().map(|v| v.0.clone()).collect();
@@ -573,4 +809,4 @@ fn runner_from_env() -> Runner {
     Runner::new(aici_abi::arg_bytes())
 }
 
-aici_abi::aici_expose_all!(Runner, runner_from_env());
+aici_abi::aici_expose_all!(Runner, runner_from_env(), Runner::warmup());
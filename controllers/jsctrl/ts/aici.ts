@@ -4,9 +4,11 @@ import {
   TokenSet,
   tokenize,
   detokenize,
+  healTokens,
   regexConstraint,
   cfgConstraint,
   substrConstraint,
+  jsonSchemaConstraint,
   Constraint,
   getVar,
   setVar,
@@ -16,6 +18,8 @@ import {
   tokenRepr,
   tokensRepr,
   getConfig,
+  nowMicros,
+  randomSeed,
 } from "_aici";
 
 export {
@@ -26,6 +30,8 @@ export {
   setVar,
   appendVar,
   getConfig,
+  nowMicros,
+  randomSeed,
   eosToken,
   tokenRepr,
   tokensRepr,
@@ -38,6 +44,17 @@ type int = number;
 
 let logLevel = 1;
 
+// bytes removed by token healing in FixedTokens, to be generated by the next genTokens()
+let healingPrefix: Buffer = new Uint8Array(0);
+
+function concatBuffers(a: Buffer, b: string | Buffer): Buffer {
+  const bb = typeof b === "string" ? b.toBuffer() : b;
+  const r = new Uint8Array(a.length + bb.length);
+  r.set(a, 0);
+  r.set(bb, a.length);
+  return r;
+}
+
 export function setLogLevel(level: number) {
   logLevel = level;
 }
@@ -106,6 +123,39 @@ export function assert(cond: boolean, msg = "Assertion failed"): asserts cond {
   if (!cond) throw new AssertionError(msg);
 }
 
+/**
+ * Emit a structured event, which the client gets in the `events` list of the response
+ * as `{ kind, data }`. The data has to be JSON-serializable.
+ */
+export function emitEvent(kind: string, data: any) {
+  _aici._emitEvent(kind, JSON.stringify(data));
+}
+
+export interface Logprobs {
+  /**
+   * Log-probability of the sampled token.
+   */
+  sampled: number;
+  /**
+   * `[token, logprob]` pairs, most likely first.
+   */
+  top: [Token, number][];
+  /**
+   * Whole distribution (one entry per token), if `logprobsFull` was set.
+   */
+  full?: number[] | null;
+}
+
+/**
+ * Log-probabilities from the previous step, if requested by setting `logprobs`
+ * in the previous `MidProcessResult` (and supported by the host).
+ * They are computed before the controller's bias is applied.
+ */
+export function logprobs(): Logprobs | undefined {
+  const s = _aici._logprobs();
+  return s === undefined ? undefined : JSON.parse(s);
+}
+
 /**
  * Get list of tokens in the current sequence, including the prompt.
  */
@@ -152,16 +202,29 @@ class Branch {
   // field names used by native
   splices: Splice[];
   sampleMask: TokenSet | null;
+  temperature?: number;
+  topP?: number;
+  topK?: number;
+  minP?: number;
 
+  /**
+   * `temperature`, `topP`, `topK` and `minP` override the sampling parameters
+   * of the request, when sampling from `sampleMask`.
+   */
   constructor({
     splices = [],
     sampleMask = null,
+    ...sampling
   }: {
     splices?: Splice[];
     sampleMask?: TokenSet | null;
-  }) {
+  } & SamplingOptions) {
     this.splices = splices;
     this.sampleMask = sampleMask;
+    this.temperature = sampling.temperature;
+    this.topP = sampling.topP;
+    this.topK = sampling.topK;
+    this.minP = sampling.minP;
   }
 
   /**
@@ -180,18 +243,25 @@ class Branch {
 
 export class MidProcessResult {
   skip_me: boolean;
-  // field name used by native
+  // field names used by native
   branches: Branch[];
+  logprobs?: number;
+  logprobsFull?: boolean;
 
   /**
    * Constructs a MidProcessResult object.
    * @param branches - The list of branches.
+   * @param logprobs - If set, the host will compute log-probabilities of this many
+   * (at most 20) most likely next tokens; read them with `logprobs()` in the following step.
+   * @param logprobsFull - Also return the whole distribution (if the host supports it).
    */
-  constructor(branches: Branch[]) {
+  constructor(branches: Branch[], logprobs?: number, logprobsFull?: boolean) {
     assert(Array.isArray(branches));
     assert(branches.every((b) => b instanceof Branch));
     this.skip_me = false;
     this.branches = branches;
+    this.logprobs = logprobs;
+    this.logprobsFull = logprobsFull;
   }
 
   /**
@@ -201,8 +271,8 @@ export class MidProcessResult {
     return this.branches.length === 1 && this.branches[0].isSplice();
   }
 
-  static bias(bias: TokenSet): MidProcessResult {
-    return new MidProcessResult([new Branch({ sampleMask: bias })]);
+  static bias(bias: TokenSet, sampling: SamplingOptions = {}): MidProcessResult {
+    return new MidProcessResult([new Branch({ sampleMask: bias, ...sampling })]);
   }
 
   static splice(backtrack: number, ff_tokens: Token[]): MidProcessResult {
@@ -324,14 +394,31 @@ export async function $(strings: TemplateStringsArray, ...values: any[]) {
 /**
  * Forces next tokens to be exactly the given text.
  * If following is given, the text replaces everything that follows the label.
+ * If tokenHealing is set, the last token of the text is left to the next genTokens(),
+ * which can then pick a token that crosses the boundary.
  */
 class FixedTokens extends NextToken {
   fixedTokens: Token[];
   following: Label | null;
 
-  constructor(text: string | Buffer, following: Label | null = null) {
+  constructor(
+    text: string | Buffer,
+    following: Label | null = null,
+    tokenHealing = false
+  ) {
     super();
+    if (healingPrefix.length > 0) {
+      text = concatBuffers(healingPrefix, text);
+      healingPrefix = new Uint8Array(0);
+    }
     this.fixedTokens = tokenize(text);
+    if (tokenHealing) {
+      const n = healTokens(this.fixedTokens);
+      if (n > 0) {
+        healingPrefix = detokenize(this.fixedTokens.slice(-n));
+        this.fixedTokens = this.fixedTokens.slice(0, -n);
+      }
+    }
     if (logLevel >= 1) console.log("FIXED", tokensRepr(this.fixedTokens));
     this.following = following;
   }
@@ -375,7 +462,10 @@ class StopToken extends NextToken {
 export class ConstrainedToken extends NextToken {
   _constraint: Constraint | null = null;
 
-  constructor(public mkConstraint: () => Constraint) {
+  constructor(
+    public mkConstraint: () => Constraint,
+    public sampling: SamplingOptions = {}
+  ) {
     super();
   }
 
@@ -391,7 +481,7 @@ export class ConstrainedToken extends NextToken {
         console.log("Constraint doesn't allow any tokens; adding EOS");
       bias.add(eosToken());
     }
-    return MidProcessResult.bias(bias);
+    return MidProcessResult.bias(bias, this.sampling);
   }
 
   override postProcess(backtrack: number, tokens: Token[]) {
@@ -703,7 +793,7 @@ export class ChooseConstraint extends Constraint {
   ptr: number;
   options: Token[][];
 
-  constructor(options: string[]) {
+  constructor(options: (string | Buffer)[]) {
     super();
     this.ptr = 0;
     this.options = options.map((o) => tokenize(o));
@@ -745,17 +835,25 @@ export async function genTokens(options: GenOptions): Promise<Token[]> {
   const {
     regex,
     yacc,
+    jsonSchema,
     substring,
     substringEnd = '"',
     options: optionList,
     storeVar,
     stopAt,
     maxTokens = 20,
+    temperature,
+    topP,
+    topK,
+    minP,
   } = options;
 
+  const prefix = healingPrefix;
+  healingPrefix = new Uint8Array(0);
+
   let constraint: Constraint;
   assert(
-    [regex, substring, yacc, optionList].filter((x) => x !== undefined)
+    [regex, substring, yacc, jsonSchema, optionList].filter((x) => x !== undefined)
       .length <= 1
   );
   if (regex !== undefined) {
@@ -765,13 +863,29 @@ export async function genTokens(options: GenOptions): Promise<Token[]> {
     constraint = substrConstraint(substring, substringEnd);
   } else if (yacc !== undefined) {
     constraint = cfgConstraint(yacc);
+  } else if (jsonSchema !== undefined) {
+    const schema =
+      typeof jsonSchema === "string" ? jsonSchema : JSON.stringify(jsonSchema);
+    constraint = jsonSchemaConstraint(schema);
   } else if (optionList !== undefined) {
-    constraint = new ChooseConstraint(optionList);
+    constraint = new ChooseConstraint(
+      prefix.length > 0
+        ? optionList.map((o) => concatBuffers(prefix, o))
+        : optionList
+    );
   } else {
     constraint = new Constraint();
   }
+  if (prefix.length > 0 && optionList === undefined) {
+    constraint = constraint.healed(prefix);
+  }
 
-  const next_token = new ConstrainedToken(() => constraint!);
+  const next_token = new ConstrainedToken(() => constraint!, {
+    temperature,
+    topP,
+    topK,
+    minP,
+  });
 
   for (let i = 0; i < maxTokens; i++) {
     const tokens = await next_token.run();
@@ -781,7 +895,7 @@ export async function genTokens(options: GenOptions): Promise<Token[]> {
 
       if (logLevel >= 2) console.log("GEN-STEP:", tokensRepr(tokens));
 
-      const text = detokenize(res).decode();
+      const text = detokenize(res).slice(prefix.length).decode();
 
       if (stopAt !== undefined && text.includes(stopAt)) {
         break;
@@ -793,7 +907,8 @@ export async function genTokens(options: GenOptions): Promise<Token[]> {
     }
   }
 
-  if (storeVar !== undefined) setVar(storeVar, detokenize(res));
+  if (storeVar !== undefined)
+    setVar(storeVar, detokenize(res).slice(prefix.length));
 
   if (logLevel >= 1) console.log("GEN", tokensRepr(res));
 
@@ -801,8 +916,9 @@ export async function genTokens(options: GenOptions): Promise<Token[]> {
 }
 
 export async function gen(options: GenOptions): Promise<string> {
+  const prefix = healingPrefix;
   const tokens = await genTokens(options);
-  return detokenize(tokens).decode();
+  return detokenize(tokens).slice(prefix.length).decode();
 }
 
 export function checkVar(name: string, value: string): void {
@@ -829,6 +945,7 @@ export const helpers = {
   regex_constraint: regexConstraint,
   cfg_constraint: cfgConstraint,
   substr_constraint: substrConstraint,
+  json_schema_constraint: jsonSchemaConstraint,
   FixedTokens,
   StopToken,
   panic,
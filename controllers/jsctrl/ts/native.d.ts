@@ -38,10 +38,20 @@ declare function waitVars(...vars: string[]): Promise<Buffer[]>;
  */
 declare function start(f: () => Promise<void>): void;
 
+/**
+ * Sampling parameters overriding the ones of the request.
+ */
+interface SamplingOptions {
+  temperature?: number;
+  topP?: number;
+  topK?: number;
+  minP?: number;
+}
+
 /**
  * Specifies options for gen() and genTokens().
  */
-interface GenOptions {
+interface GenOptions extends SamplingOptions {
   /**
    * Make sure the generated text is one of the options.
    */
@@ -54,6 +64,11 @@ interface GenOptions {
    * Make sure the generated text matches given yacc-like grammar.
    */
   yacc?: string;
+  /**
+   * Make sure the generated text is JSON matching the given schema (object or JSON text).
+   * Only a subset of JSON Schema is supported.
+   */
+  jsonSchema?: string | object;
   /**
    * Make sure the generated text is a substring of the given string.
    */
@@ -85,6 +100,8 @@ declare function gen(options: GenOptions): Promise<string>;
 
 /**
  * Generate a list of tokens that matches given constraints.
+ * If the preceding `FixedTokens` used `tokenHealing`, the first returned token
+ * also covers the healed text, which is not included in `storeVar` nor checked for `stopAt`.
  */
 declare function genTokens(options: GenOptions): Promise<Token[]>;
 
@@ -143,6 +160,13 @@ declare module "_aici" {
    */
   function detokenize(tokens: number[]): Buffer;
 
+  /**
+   * Return how many tokens (at most `maxTokens`, default 1) to remove from the end of `tokens`
+   * for token healing. Tokens are only removed when a longer token starts with their bytes.
+   * Use `Constraint.healed()` to have the generation start with the removed bytes.
+   */
+  function healTokens(tokens: number[], maxTokens?: number): number;
+
   /**
    * Return debug string representation of a given token index
    */
@@ -185,6 +209,18 @@ declare module "_aici" {
    */
   function getConfig(name: string): number;
 
+  /**
+   * Current wall-clock time in microseconds since Unix epoch.
+   * The resolution is limited by the host, and can be zero if the clock is disabled.
+   */
+  function nowMicros(): number;
+
+  /**
+   * Random seed for the current sequence.
+   * It is derived from the request seed and fork index, so it's reproducible.
+   */
+  function randomSeed(): number;
+
   /**
    * Index of the end of sequence token.
    */
@@ -207,6 +243,10 @@ declare module "_aici" {
 
   function _midProcessReturn(midProcessResult: any): void;
 
+  function _emitEvent(kind: string, data: string): void;
+
+  function _logprobs(): string | undefined;
+
   /**
    * Represents a set of tokens.
    * The value is true at indices corresponding to tokens in the set.
@@ -270,6 +310,43 @@ declare module "_aici" {
      * Set ts[] to True at all tokens that are allowed by the constraint.
      */
     allowTokens(ts: TokenSet): void;
+
+    // The methods below consume the constraints they are called with,
+    // using them afterwards throws a TypeError.
+
+    /**
+     * Allow only output that is allowed by both constraints.
+     */
+    intersect(other: Constraint): Constraint;
+
+    /**
+     * Allow output that is allowed by either constraint.
+     */
+    union(other: Constraint): Constraint;
+
+    /**
+     * Output of this constraint followed by output of `other`.
+     * The switch to `other` happens at the first byte not allowed by this constraint,
+     * provided it allows ending there.
+     */
+    followedBy(other: Constraint): Constraint;
+
+    /**
+     * Output of this constraint repeated between `min` and `max` times.
+     * A new repetition starts when the current one can end and the next byte doesn't continue it.
+     */
+    repeat(min: number, max?: number): Constraint;
+
+    /**
+     * Output of this constraint, limited to `maxBytes` bytes.
+     */
+    maxBytes(maxBytes: number): Constraint;
+
+    /**
+     * Output starting with `prefix` (typically removed with `healTokens()`),
+     * followed by output of this constraint.
+     */
+    healed(prefix: string | Buffer): Constraint;
   }
 
   /**
@@ -279,9 +356,17 @@ declare module "_aici" {
   function regexConstraint(pattern: string): Constraint;
 
   /**
-   * A constraint that allows only tokens that match the specified yacc-like grammar.
+   * A constraint that allows only tokens that match the specified grammar.
+   * The grammar is either yacc-like (if it contains a `%%` line) or Lark-style EBNF.
+   */
+  function cfgConstraint(grammar: string): Constraint;
+
+  /**
+   * A constraint that allows only JSON matching the given schema (as JSON text).
+   * Supports types, properties with required, enum, const, anyOf/oneOf, items with minItems/maxItems,
+   * string pattern/minLength/maxLength, and local $ref.
    */
-  function cfgConstraint(yacc_grammar: string): Constraint;
+  function jsonSchemaConstraint(schema: string): Constraint;
 
   /**
    * A constraint that allows only word-substrings of given string.
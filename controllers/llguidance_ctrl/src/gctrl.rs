@@ -1,7 +1,7 @@
 use std::sync::Arc;
 
 use aici_abi::{
-    arg_bytes, get_config,
+    arg_bytes, emit_event, get_config,
     toktrie::{InferenceCapabilities, StepArg},
     AiciCtrl, InitPromptArg, InitPromptResult, MidProcessArg, MidProcessResult,
 };
@@ -56,10 +56,6 @@ impl Runner {
     }
 }
 
-fn json_out<T: Serialize>(obj: &T) {
-    println!("JSON-OUT: {}", serde_json::to_string(obj).unwrap());
-}
-
 impl AiciCtrl for Runner {
     fn init_prompt(&mut self, arg: InitPromptArg) -> InitPromptResult {
         InitPromptResult {
@@ -73,7 +69,7 @@ impl AiciCtrl for Runner {
             sampled: arg.sampled,
         });
         for v in self.reporter.get_progress(&mut self.tok_parser, &r) {
-            json_out(&v);
+            emit_event("progress", &v);
         }
         MidProcessResult::from_branch(r)
     }
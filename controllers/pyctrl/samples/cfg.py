@@ -5,6 +5,8 @@ c_yacc = r"""
 // based on http://www.lysator.liu.se/c/ANSI-C-grammar-y.html
 
 %start translation_unit
+// dangling else, resolved by shifting
+%expect 1
 %%
 
 SKIP
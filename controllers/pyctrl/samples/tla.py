@@ -3,6 +3,8 @@ import pyaici.server as aici
 apalache = r"""
  
 %start tnl
+// " -> " is right-associative, resolved by shifting
+%expect 2
 %%
 
 List
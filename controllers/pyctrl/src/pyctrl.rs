@@ -1,8 +1,12 @@
 use aici_abi::{
-    aici_stop, host_trie,
-    toktrie::{Recognizer, SpecialToken, TokTrie},
-    AiciCtrl, Branch, InitPromptArg, InitPromptResult, MidProcessArg, MidProcessResult, SimpleVob,
-    Splice, TokenId, VariableStorage,
+    aici_stop,
+    cfg::CfgParser,
+    combinators::BoxedRecognizer,
+    host_trie,
+    rx::RxStackRecognizer,
+    toktrie::{self, Recognizer, SpecialToken, TokTrie},
+    AiciCtrl, Branch, BranchSampling, InitPromptArg, InitPromptResult, Logprobs, LogprobsRequest,
+    MidProcessArg, MidProcessResult, SimpleVob, Splice, TokenId, VariableStorage,
 };
 use anyhow::Result;
 use lazy_static::lazy_static;
@@ -12,6 +16,7 @@ use rustpython_vm::{
     VirtualMachine,
 };
 use std::{
+    cell::RefCell,
     ops::Deref,
     sync::{Arc, Mutex},
     vec,
@@ -21,6 +26,7 @@ struct ModuleState {
     cb_obj: Option<PyObjectRef>,
     trie: TokTrie,
     vars: VariableStorage,
+    logprobs: Option<Logprobs>,
 }
 
 unsafe impl Send for ModuleState {}
@@ -30,6 +36,7 @@ lazy_static! {
         cb_obj: None,
         trie: host_trie(),
         vars: VariableStorage::new(),
+        logprobs: None,
         // tokens: vec![],
         // bytes: vec![],
     });
@@ -45,10 +52,16 @@ fn get_cb_obj() -> PyObjectRef {
 
 #[rustpython_derive::pymodule]
 mod _aici {
-    use crate::{ConstraintWrapper, PyConstraint, VmExt, GLOBAL_STATE};
+    use crate::{
+        CachedConstraint, ConstraintWrapper, PyConstraint, UsedConstraint, VmExt, GLOBAL_STATE,
+    };
     use aici_abi::{
         cfg::CfgParser,
+        combinators::{And, BoxedRecognizer, MaxBytes, Or, Repeat, Seq},
         dlex::{self, DynamicLexerRec},
+        fuzzy_substring::{FuzzySubStrMatcher, FuzzySubStrOptions},
+        healing::{self, healed},
+        json_schema::json_schema_recognizer,
         recognizer::{AnythingGoes, StackRecognizer},
         rx::RecRx,
         substring::SubStrMatcher,
@@ -59,15 +72,15 @@ mod _aici {
     use rustpython_derive::pyclass;
     use rustpython_vm::{
         atomic_func,
-        builtins::{PyStrRef, PyTypeRef},
-        function::{ArgStrOrBytesLike, FuncArgs},
+        builtins::{PyStr, PyStrRef, PyTypeRef},
+        function::{ArgStrOrBytesLike, FuncArgs, OptionalArg},
         protocol::PySequenceMethods,
         types::{AsSequence, Constructor, Representable},
         Py, PyObjectRef, PyPayload, PyRef, PyResult, VirtualMachine,
     };
     use std::{
         fmt::Debug,
-        sync::{Arc, Mutex},
+        sync::{Arc, Mutex, MutexGuard},
     };
 
     #[pyfunction]
@@ -103,6 +116,17 @@ mod _aici {
         bytes
     }
 
+    #[pyfunction]
+    fn heal_tokens(
+        tokens: PyObjectRef,
+        max_tokens: OptionalArg<usize>,
+        vm: &VirtualMachine,
+    ) -> usize {
+        let tokens = vm.to_u32_list(tokens);
+        let trie = &mut GLOBAL_STATE.lock().unwrap().trie;
+        healing::heal_tokens(trie, &tokens, max_tokens.unwrap_or(1)).0
+    }
+
     #[pyfunction]
     fn tokens_repr(tokens: PyObjectRef, vm: &VirtualMachine) -> String {
         let tokens = vm.to_u32_list(tokens);
@@ -150,6 +174,62 @@ mod _aici {
         Ok(v)
     }
 
+    #[pyfunction]
+    fn now_micros() -> u64 {
+        aici_abi::now_micros()
+    }
+
+    #[pyfunction]
+    fn random_seed() -> u64 {
+        aici_abi::random_seed()
+    }
+
+    #[pyfunction]
+    fn emit_event(kind: PyStrRef, data: PyObjectRef, vm: &VirtualMachine) -> PyResult<()> {
+        let data = vm.to_json(data)?;
+        aici_abi::emit_event(kind.as_str(), &data);
+        Ok(())
+    }
+
+    #[pyfunction]
+    fn logprobs(vm: &VirtualMachine) -> PyObjectRef {
+        match &GLOBAL_STATE.lock().unwrap().logprobs {
+            None => vm.ctx.none(),
+            Some(lp) => {
+                let top = lp
+                    .top
+                    .iter()
+                    .map(|(t, l)| {
+                        vm.ctx
+                            .new_tuple(vec![
+                                vm.ctx.new_int(*t).into(),
+                                vm.ctx.new_float(*l as f64).into(),
+                            ])
+                            .into()
+                    })
+                    .collect();
+                let full = match &lp.full {
+                    None => vm.ctx.none(),
+                    Some(full) => vm
+                        .ctx
+                        .new_list(
+                            full.iter()
+                                .map(|l| vm.ctx.new_float(*l as f64).into())
+                                .collect(),
+                        )
+                        .into(),
+                };
+                vm.ctx
+                    .new_tuple(vec![
+                        vm.ctx.new_float(lp.sampled as f64).into(),
+                        vm.ctx.new_list(top).into(),
+                        full,
+                    ])
+                    .into()
+            }
+        }
+    }
+
     #[pyattr]
     #[pyclass(name)]
     #[derive(PyPayload)]
@@ -167,35 +247,114 @@ mod _aici {
             Constraint(Mutex::new(Box::new(obj)))
         }
 
+        /// Lock the underlying constraint, unless it was already used in a combinator.
+        fn inner(&self, vm: &VirtualMachine) -> PyResult<MutexGuard<'_, Box<dyn PyConstraint>>> {
+            let inner = self.0.lock().unwrap();
+            if inner.is_used() {
+                Err(vm.new_runtime_error("constraint was already used".to_string()))
+            } else {
+                Ok(inner)
+            }
+        }
+
+        /// Move the underlying recognizer out; the constraint can't be used afterwards.
+        fn take_recognizer(&self, vm: &VirtualMachine) -> PyResult<BoxedRecognizer> {
+            drop(self.inner(vm)?);
+            let inner = std::mem::replace(&mut *self.0.lock().unwrap(), Box::new(UsedConstraint));
+            Ok(inner.into_recognizer().unwrap())
+        }
+
+        /// Same as `take_recognizer()` on both, but on error neither is changed.
+        fn take_both(
+            &self,
+            other: &Constraint,
+            vm: &VirtualMachine,
+        ) -> PyResult<(BoxedRecognizer, BoxedRecognizer)> {
+            if std::ptr::eq(self, other) {
+                return Err(vm.new_value_error("can't combine constraint with itself".to_string()));
+            }
+            drop(other.inner(vm)?);
+            let a = self.take_recognizer(vm)?;
+            let b = other.take_recognizer(vm)?;
+            Ok((a, b))
+        }
+
         #[pymethod]
-        fn eos_allowed(&self) -> bool {
-            let mut s = self.0.lock().unwrap();
-            s.eos_allowed()
+        fn eos_allowed(&self, vm: &VirtualMachine) -> PyResult<bool> {
+            Ok(self.inner(vm)?.eos_allowed())
         }
 
         #[pymethod]
-        fn eos_forced(&self) -> bool {
-            let mut s = self.0.lock().unwrap();
-            s.eos_forced()
+        fn eos_forced(&self, vm: &VirtualMachine) -> PyResult<bool> {
+            Ok(self.inner(vm)?.eos_forced())
         }
 
         #[pymethod]
-        fn token_allowed(&self, t: TokenId) -> bool {
-            let mut s = self.0.lock().unwrap();
-            s.token_allowed(t)
+        fn token_allowed(&self, t: TokenId, vm: &VirtualMachine) -> PyResult<bool> {
+            Ok(self.inner(vm)?.token_allowed(t))
         }
 
         #[pymethod]
-        fn append_token(&self, t: TokenId) {
-            let mut s = self.0.lock().unwrap();
-            s.append_token(t)
+        fn append_token(&self, t: TokenId, vm: &VirtualMachine) -> PyResult<()> {
+            self.inner(vm)?.append_token(t);
+            Ok(())
         }
 
         #[pymethod]
-        fn allow_tokens(&self, ts: PyRef<TokenSet>) {
-            let mut s = self.0.lock().unwrap();
+        fn allow_tokens(&self, ts: PyRef<TokenSet>, vm: &VirtualMachine) -> PyResult<()> {
+            let mut s = self.inner(vm)?;
             let mut ts = ts.0.lock().unwrap();
             s.allow_tokens(&mut *ts);
+            Ok(())
+        }
+
+        #[pymethod]
+        fn intersect(&self, other: PyRef<Constraint>, vm: &VirtualMachine) -> PyResult<Constraint> {
+            let (a, b) = self.take_both(&other, vm)?;
+            Ok(Constraint::new(And::new(a, b)))
+        }
+
+        #[pymethod]
+        fn union(&self, other: PyRef<Constraint>, vm: &VirtualMachine) -> PyResult<Constraint> {
+            let (a, b) = self.take_both(&other, vm)?;
+            Ok(Constraint::new(Or::new(a, b)))
+        }
+
+        #[pymethod]
+        fn followed_by(
+            &self,
+            other: PyRef<Constraint>,
+            vm: &VirtualMachine,
+        ) -> PyResult<Constraint> {
+            let (a, b) = self.take_both(&other, vm)?;
+            Ok(Constraint::new(Seq::new(a, b)))
+        }
+
+        #[pymethod]
+        fn repeat(
+            &self,
+            min: usize,
+            max: OptionalArg<Option<usize>>,
+            vm: &VirtualMachine,
+        ) -> PyResult<Constraint> {
+            let a = self.take_recognizer(vm)?;
+            Ok(Constraint::new(Repeat::new(
+                a,
+                min,
+                max.into_option().flatten(),
+            )))
+        }
+
+        #[pymethod]
+        fn max_bytes(&self, max_bytes: usize, vm: &VirtualMachine) -> PyResult<Constraint> {
+            let a = self.take_recognizer(vm)?;
+            Ok(Constraint::new(MaxBytes::new(a, max_bytes)))
+        }
+
+        #[pymethod]
+        fn healed(&self, prefix: ArgStrOrBytesLike, vm: &VirtualMachine) -> PyResult<Constraint> {
+            let a = self.take_recognizer(vm)?;
+            Ok(Constraint::new(healed(prefix.borrow_bytes().to_vec(), a)))
         }
     }
 
@@ -218,6 +377,12 @@ mod _aici {
             lexer.recognizer_mut().add(word.as_str().as_bytes());
         }
 
+        #[pymethod]
+        fn add_lines(&self, lines: PyStrRef) -> usize {
+            let mut lexer = self.0.lock().unwrap();
+            lexer.recognizer_mut().add_lines(lines.as_str())
+        }
+
         #[pymethod]
         fn constraint(&self) -> PyResult<Constraint> {
             Ok(Constraint::new(ConstraintWrapper(self.0.clone())))
@@ -225,13 +390,14 @@ mod _aici {
     }
 
     impl Constructor for DynamicLexer {
-        type Args = (Option<PyStrRef>,);
+        type Args = (Option<PyStrRef>, OptionalArg<bool>);
         fn py_new(cls: PyTypeRef, arg: Self::Args, vm: &VirtualMachine) -> PyResult {
             let id_chars = match arg.0 {
                 Some(id_chars) => id_chars.as_str().chars().collect(),
                 None => vec![],
             };
-            let lexer = dlex::DynamicLexer::new(&id_chars).to_stack_recognizer();
+            let case_insensitive = arg.1.unwrap_or(false);
+            let lexer = dlex::DynamicLexer::new(&id_chars, case_insensitive).to_stack_recognizer();
             DynamicLexer(Arc::new(Mutex::new(lexer)))
                 .into_ref_with_type(vm, cls)
                 .map(Into::into)
@@ -240,24 +406,54 @@ mod _aici {
 
     #[pyfunction(name = "RegexConstraint")]
     fn regex_constraint(regex: PyStrRef, vm: &VirtualMachine) -> PyResult<Constraint> {
-        let rx = RecRx::from_rx(regex.as_str(), None)
+        let rx = RecRx::from_rx_cached(regex.as_str(), None)
             .map_err(|e| vm.new_runtime_error(format!("{}", e)))?
             .to_stack_recognizer();
-        Ok(Constraint::new(rx))
+        Ok(Constraint::new(CachedConstraint(rx)))
     }
 
     #[pyfunction(name = "CfgConstraint")]
     fn cfg_constraint(cfg: PyStrRef, vm: &VirtualMachine) -> PyResult<Constraint> {
-        match CfgParser::from_yacc(cfg.as_str()) {
-            Ok(cfg) => Ok(Constraint::new(cfg)),
+        match CfgParser::from_grammar_cached(cfg.as_str()) {
+            Ok(cfg) => Ok(Constraint::new(CachedConstraint(cfg))),
             Err(e) => Err(vm.new_runtime_error(format!("{}", e))),
         }
     }
 
+    #[pyfunction(name = "JsonSchemaConstraint")]
+    fn json_schema_constraint(schema: PyObjectRef, vm: &VirtualMachine) -> PyResult<Constraint> {
+        let schema = match schema.payload_if_subclass::<PyStr>(vm) {
+            Some(s) => serde_json::from_str(s.as_str())
+                .map_err(|e| vm.new_runtime_error(format!("invalid JSON schema: {}", e)))?,
+            None => vm.to_json(schema)?,
+        };
+        let rx =
+            json_schema_recognizer(&schema).map_err(|e| vm.new_runtime_error(format!("{}", e)))?;
+        Ok(Constraint::new(CachedConstraint(rx)))
+    }
+
     #[pyfunction(name = "SubStrConstraint")]
-    fn substr_constraint(templ: PyStrRef, end_str: PyStrRef) -> PyResult<Constraint> {
-        let rx = SubStrMatcher::new(templ.as_str(), end_str.as_str()).to_stack_recognizer();
-        Ok(Constraint::new(rx))
+    fn substr_constraint(
+        templ: PyStrRef,
+        end_str: PyStrRef,
+        max_edits: OptionalArg<u8>,
+        normalize: OptionalArg<bool>,
+        vm: &VirtualMachine,
+    ) -> PyResult<Constraint> {
+        let normalize = normalize.unwrap_or(false);
+        let options = FuzzySubStrOptions {
+            max_edits: max_edits.unwrap_or(0),
+            normalize_whitespace: normalize,
+            case_insensitive: normalize,
+        };
+        if options.is_exact() {
+            let rx = SubStrMatcher::new(templ.as_str(), end_str.as_str()).to_stack_recognizer();
+            Ok(Constraint::new(rx))
+        } else {
+            let rx = FuzzySubStrMatcher::new(templ.as_str(), end_str.as_str(), options)
+                .map_err(|e| vm.new_value_error(format!("{}", e)))?;
+            Ok(Constraint::new(rx))
+        }
     }
 
     impl Constructor for Constraint {
@@ -385,9 +581,24 @@ pub struct Runner {
     interpreter: rustpython_vm::Interpreter,
 }
 
+thread_local! {
+    // created by Runner::warmup(), before the module argument is known
+    static WARM_INTERPRETER: RefCell<Option<rustpython_vm::Interpreter>> = RefCell::new(None);
+}
+
+fn run_source(vm: &VirtualMachine, source: &str, name: &str) {
+    let r = vm
+        .compile(source, rustpython_vm::compiler::Mode::Exec, name.to_owned())
+        .map_err(|err| vm.new_syntax_error(&err, Some(source)))
+        .and_then(|code_obj| vm.run_code_obj(code_obj, vm.new_scope_with_builtins()));
+    if let Err(e) = r {
+        vm.print_exception(e.clone());
+        panic!("Python Exception: {e:?}");
+    }
+}
+
 impl Runner {
-    pub fn new(arg: Vec<u8>) -> Self {
-        let source = String::from_utf8(arg).unwrap();
+    fn new_interpreter() -> rustpython_vm::Interpreter {
         let interpreter = rustpython_vm::Interpreter::with_init(Default::default(), |vm| {
             vm.add_native_module(
                 "pyaici.server_native".to_owned(),
@@ -423,28 +634,27 @@ impl Runner {
             ];
             vm.add_frozen(frozen_vec.into_iter());
         });
+        // importing the runtime (and the parts of stdlib it uses) is the slow part of startup
+        interpreter.enter(|vm| run_source(vm, "import pyaici.server", "<warmup>"));
+        interpreter
+    }
+
+    /// Create the interpreter ahead of time; see `aici_expose_all!()`.
+    pub fn warmup() {
+        lazy_static::initialize(&GLOBAL_STATE);
+        let interpreter = Self::new_interpreter();
+        WARM_INTERPRETER.with(|w| *w.borrow_mut() = Some(interpreter));
+    }
+
+    pub fn new(arg: Vec<u8>) -> Self {
+        let source = String::from_utf8(arg).unwrap();
+        let interpreter = WARM_INTERPRETER
+            .with(|w| w.borrow_mut().take())
+            .unwrap_or_else(Self::new_interpreter);
         interpreter.enter(|vm| {
-            let scope = vm.new_scope_with_builtins();
-
-            let r = vm
-                .compile(
-                    &source,
-                    rustpython_vm::compiler::Mode::Exec,
-                    "<arg>".to_owned(),
-                )
-                .map_err(|err| vm.new_syntax_error(&err, Some(&source)))
-                .and_then(|code_obj| vm.run_code_obj(code_obj, scope));
-
-            match r {
-                Ok(_) => {
-                    // make sure the callback is registered
-                    let _ = get_cb_obj();
-                }
-                Err(e) => {
-                    vm.print_exception(e.clone());
-                    panic!("Python Exception: {e:?}");
-                }
-            }
+            run_source(vm, &source, "<arg>");
+            // make sure the callback is registered
+            let _ = get_cb_obj();
         });
         Self { interpreter }
     }
@@ -456,9 +666,15 @@ trait PyConstraint {
     fn token_allowed(&mut self, t: TokenId) -> bool;
     fn append_token(&mut self, t: TokenId);
     fn allow_tokens(&mut self, logits: &mut SimpleVob);
+    /// For combining constraints; None if not backed by a recognizer.
+    fn into_recognizer(self: Box<Self>) -> Option<BoxedRecognizer>;
+    /// True for [UsedConstraint]; other methods are not to be called then.
+    fn is_used(&self) -> bool {
+        false
+    }
 }
 
-impl<T: Recognizer> PyConstraint for T {
+impl<T: Recognizer + 'static> PyConstraint for T {
     fn eos_allowed(&mut self) -> bool {
         self.special_allowed(SpecialToken::EndOfSentence)
     }
@@ -482,10 +698,14 @@ impl<T: Recognizer> PyConstraint for T {
         let trie = &mut GLOBAL_STATE.lock().unwrap().trie;
         trie.compute_bias(self, logits)
     }
+
+    fn into_recognizer(self: Box<Self>) -> Option<BoxedRecognizer> {
+        Some(self)
+    }
 }
 
 struct ConstraintWrapper<T: Recognizer>(Arc<Mutex<T>>);
-impl<T: Recognizer> PyConstraint for ConstraintWrapper<T> {
+impl<T: Recognizer + 'static> PyConstraint for ConstraintWrapper<T> {
     fn eos_allowed(&mut self) -> bool {
         self.0.lock().unwrap().eos_allowed()
     }
@@ -505,6 +725,112 @@ impl<T: Recognizer> PyConstraint for ConstraintWrapper<T> {
     fn allow_tokens(&mut self, logits: &mut SimpleVob) {
         self.0.lock().unwrap().allow_tokens(logits)
     }
+
+    fn into_recognizer(self: Box<Self>) -> Option<BoxedRecognizer> {
+        Some(Box::new(SharedRecognizer(self.0)))
+    }
+}
+
+/// Recognizer shared with another object (e.g., DynamicLexer), when used in a combinator.
+struct SharedRecognizer<T: Recognizer>(Arc<Mutex<T>>);
+impl<T: Recognizer> Recognizer for SharedRecognizer<T> {
+    fn pop_bytes(&mut self, num: usize) {
+        self.0.lock().unwrap().pop_bytes(num)
+    }
+
+    fn collapse(&mut self) {
+        self.0.lock().unwrap().collapse()
+    }
+
+    fn special_allowed(&mut self, tok: SpecialToken) -> bool {
+        self.0.lock().unwrap().special_allowed(tok)
+    }
+
+    fn trie_finished(&mut self) {
+        self.0.lock().unwrap().trie_finished()
+    }
+
+    fn try_push_byte(&mut self, byte: u8) -> bool {
+        self.0.lock().unwrap().try_push_byte(byte)
+    }
+}
+
+/// What is left of a constraint after it was combined into another one.
+/// `Constraint` checks `is_used()` first and raises an exception instead of calling these.
+struct UsedConstraint;
+impl PyConstraint for UsedConstraint {
+    fn eos_allowed(&mut self) -> bool {
+        panic!("constraint was already used in a combinator")
+    }
+
+    fn eos_forced(&mut self) -> bool {
+        panic!("constraint was already used in a combinator")
+    }
+
+    fn token_allowed(&mut self, _t: TokenId) -> bool {
+        panic!("constraint was already used in a combinator")
+    }
+
+    fn append_token(&mut self, _t: TokenId) {
+        panic!("constraint was already used in a combinator")
+    }
+
+    fn allow_tokens(&mut self, _logits: &mut SimpleVob) {
+        panic!("constraint was already used in a combinator")
+    }
+
+    fn into_recognizer(self: Box<Self>) -> Option<BoxedRecognizer> {
+        None
+    }
+
+    fn is_used(&self) -> bool {
+        true
+    }
+}
+
+/// Recognizers that cache token masks across steps.
+trait CachedBias: Recognizer {
+    fn cached_bias(&mut self, trie: &TokTrie, logits: &mut SimpleVob);
+}
+
+impl CachedBias for CfgParser {
+    fn cached_bias(&mut self, trie: &TokTrie, logits: &mut SimpleVob) {
+        self.compute_bias(trie, logits)
+    }
+}
+
+impl CachedBias for RxStackRecognizer {
+    fn cached_bias(&mut self, trie: &TokTrie, logits: &mut SimpleVob) {
+        self.compute_bias(trie, logits)
+    }
+}
+
+struct CachedConstraint<T: CachedBias>(T);
+impl<T: CachedBias + 'static> PyConstraint for CachedConstraint<T> {
+    fn eos_allowed(&mut self) -> bool {
+        self.0.eos_allowed()
+    }
+
+    fn eos_forced(&mut self) -> bool {
+        self.0.eos_forced()
+    }
+
+    fn token_allowed(&mut self, t: TokenId) -> bool {
+        self.0.token_allowed(t)
+    }
+
+    fn append_token(&mut self, t: TokenId) {
+        self.0.append_token(t)
+    }
+
+    fn allow_tokens(&mut self, logits: &mut SimpleVob) {
+        let trie = &mut GLOBAL_STATE.lock().unwrap().trie;
+        self.0.cached_bias(trie, logits)
+    }
+
+    fn into_recognizer(self: Box<Self>) -> Option<BoxedRecognizer> {
+        Some(Box::new(self.0))
+    }
 }
 
 trait VmExt {
@@ -589,6 +915,75 @@ trait VmExt {
             .collect::<Vec<_>>()
     }
 
+    fn to_json(&self, obj: PyObjectRef) -> PyResult<serde_json::Value> {
+        use serde_json::Value;
+        let vm = self.get_vm();
+        // check bools first, as they are also ints
+        let v = if vm.is_none(&obj) {
+            Value::Null
+        } else if obj.is(&vm.ctx.true_value) {
+            Value::Bool(true)
+        } else if obj.is(&vm.ctx.false_value) {
+            Value::Bool(false)
+        } else if let Some(v) = obj.payload_if_subclass::<PyInt>(vm) {
+            let v = v.as_bigint();
+            match v.to_i64() {
+                Some(v) => Value::from(v),
+                None => serde_json::Number::from_f64(v.to_f64().unwrap_or(f64::NAN))
+                    .map(Value::Number)
+                    .ok_or_else(|| {
+                        vm.new_value_error(format!("int {} is too large for JSON", v))
+                    })?,
+            }
+        } else if let Some(v) = obj.payload_if_subclass::<PyFloat>(vm) {
+            serde_json::Number::from_f64(v.to_f64())
+                .map(Value::Number)
+                .ok_or_else(|| {
+                    vm.new_value_error(format!("float {} is not JSON compliant", v.to_f64()))
+                })?
+        } else if let Some(v) = obj.payload_if_subclass::<PyStr>(vm) {
+            Value::String(v.as_str().to_string())
+        } else if let Some(v) = obj.payload_if_subclass::<PyList>(vm) {
+            Value::Array(
+                v.borrow_vec()
+                    .iter()
+                    .map(|x| self.to_json(x.clone()))
+                    .collect::<PyResult<_>>()?,
+            )
+        } else if let Some(v) = obj.payload_if_subclass::<PyTuple>(vm) {
+            Value::Array(
+                v.as_slice()
+                    .iter()
+                    .map(|x| self.to_json(x.clone()))
+                    .collect::<PyResult<_>>()?,
+            )
+        } else if let Some(v) = obj.downcast_ref::<PyDict>() {
+            Value::Object(
+                v.into_iter()
+                    .map(|(k, v)| {
+                        let k = k
+                            .payload_if_subclass::<PyStr>(vm)
+                            .ok_or_else(|| {
+                                vm.new_type_error(format!(
+                                    "JSON keys must be str, not {}",
+                                    k.class().name()
+                                ))
+                            })?
+                            .as_str()
+                            .to_string();
+                        Ok((k, self.to_json(v)?))
+                    })
+                    .collect::<PyResult<_>>()?,
+            )
+        } else {
+            return Err(vm.new_type_error(format!(
+                "object of type {} is not JSON serializable",
+                obj.class().name()
+            )));
+        };
+        Ok(v)
+    }
+
     fn new_int_list<T: Into<BigInt> + ToPrimitive + Clone>(&self, lst: &Vec<T>) -> PyRef<PyList> {
         let vm = self.get_vm();
         let elts = lst
@@ -617,6 +1012,7 @@ impl AiciCtrl for Runner {
 
     fn mid_process(&mut self, arg: MidProcessArg) -> MidProcessResult {
         let obj = get_cb_obj();
+        GLOBAL_STATE.lock().unwrap().logprobs = arg.logprobs.clone();
         self.interpreter.enter(|vm| {
             let fork_group = vm.new_int_list(&arg.fork_group.iter().map(|v| v.0.clone()).collect());
             let tokens = vm.new_int_list(&arg.tokens);
@@ -649,14 +1045,50 @@ impl AiciCtrl for Runner {
                     }
                 });
 
-                Branch {
+                let opt_f32 = |name: &'static str| {
+                    let v = vm.attr(&b, name);
+                    if vm.is_none(&v) {
+                        None
+                    } else {
+                        Some(vm.to_f64(v) as f32)
+                    }
+                };
+                let top_k = vm.attr(&b, "top_k");
+                let sampling = BranchSampling {
+                    top_p: opt_f32("top_p"),
+                    top_k: if vm.is_none(&top_k) {
+                        None
+                    } else {
+                        Some(vm.to_u32(top_k))
+                    },
+                    min_p: opt_f32("min_p"),
+                };
+
+                Branch::from(toktrie::Branch {
                     sample_mask,
-                    temperature: None,
+                    temperature: opt_f32("temperature"),
                     splices,
-                }
+                })
+                .with_sampling(sampling)
             });
 
-            MidProcessResult { branches }
+            let logprobs = vm.attr(&r, "logprobs");
+            let logprobs = if vm.is_none(&logprobs) {
+                None
+            } else {
+                let req = LogprobsRequest::top_k(vm.to_u32(logprobs));
+                if vm.to_bool_strict(vm.attr(&r, "logprobs_full")) {
+                    Some(req.with_full())
+                } else {
+                    Some(req)
+                }
+            };
+
+            MidProcessResult {
+                branches,
+                attention_mask: Default::default(),
+                logprobs,
+            }
         })
     }
 }
@@ -665,4 +1097,4 @@ fn runner_from_env() -> Runner {
     Runner::new(aici_abi::arg_bytes())
 }
 
-aici_abi::aici_expose_all!(Runner, runner_from_env());
+aici_abi::aici_expose_all!(Runner, runner_from_env(), Runner::warmup());
@@ -68,12 +68,23 @@ def gen(
     *,
     rx: Optional[str] = None,
     yacc: Optional[str] = None,
+    json_schema: Optional[dict] = None,
+    substring: Optional[Union[str, dict]] = None,
+    substring_end: Optional[str] = None,
+    substring_max_edits: int = 0,
+    substring_normalize: bool = False,
     inner: Optional[dict] = None,
     stop_at: Optional[str] = None,
     max_tokens: Optional[int] = None,
     max_words: Optional[int] = None,
     max_bytes: Optional[int] = None,
     mask_tags: Optional[List[str]] = None,
+    logprobs: Optional[int] = None,
+    logprobs_full: bool = False,
+    logprobs_var: Optional[str] = None,
+    top_p: Optional[float] = None,
+    top_k: Optional[int] = None,
+    min_p: Optional[float] = None,
     stmts: Optional[List[dict]] = None,
     append_to_var: Optional[str] = None,
     set_var: Optional[str] = None,
@@ -82,8 +93,17 @@ def gen(
     """
     Generate output with given constraints.
     `rx` is a regular expression to match. If `yacc` is given, it is a yacc grammar to parse.
+    If `json_schema` is given, the output is JSON matching the schema.
+    If `substring` (a string or an expression) is given, the output is a substring of it,
+    starting and ending at word boundaries, followed by `substring_end`.
+    The substring can differ from the source in up to `substring_max_edits` bytes,
+    and `substring_normalize` makes whitespace and case not matter.
     `stop_at` is a string to stop at.
     If `max_tokens` is given, stop after that many tokens; similarly for `max_words` and `max_bytes`.
+    With `logprobs`, log-probabilities of that many most likely tokens are appended
+    to `logprobs_var` (default `logprobs`) for every generated token, as JSON lines.
+    With `logprobs_full`, the whole distribution is included as well (if the host supports it).
+    `top_p`, `top_k` and `min_p` override the sampling parameters of the request.
     """
     if not stmts:
         stmts = []
@@ -100,17 +120,38 @@ def gen(
         inner = [{"after": k, "options": v} for k, v in inner.items()]
     else:
         inner = []
+    if isinstance(substring, str):
+        substring = e_str(substring)
+    substring_fuzzy = None
+    if substring_max_edits or substring_normalize:
+        substring_fuzzy = {
+            "max_edits": substring_max_edits,
+            "normalize_whitespace": substring_normalize,
+            "case_insensitive": substring_normalize,
+        }
 
     return {
         "Gen": {
             "rx": rx,
             "yacc": yacc,
+            "json_schema": json_schema,
+            "substring": substring,
+            "substring_end": substring_end,
+            "substring_fuzzy": substring_fuzzy,
             "inner": inner,
             "stop_at": stop_at,
             "max_tokens": max_tokens,
             "max_words": max_words,
             "max_bytes": max_bytes,
             "mask_tags": mask_tags,
+            "logprobs": logprobs,
+            "logprobs_full": logprobs_full,
+            "logprobs_var": logprobs_var,
+            "sampling": {
+                "top_p": top_p,
+                "top_k": top_k,
+                "min_p": min_p,
+            },
             "stmts": stmts,
         }
     }
@@ -167,9 +208,13 @@ def fixed(
     expand_vars=False,
     following: Optional[str] = None,
     tag: Optional[str] = None,
+    token_healing: bool = False,
 ):
     """
     Generate fixed text. Same as `choose([text])`.
+    With `token_healing`, the last token of the text is instead generated by the following `gen()`,
+    which can then pick a token that crosses the boundary.
+    The next step then has to be a `gen()` (without `inner`), and `following` can't be used.
     """
     if isinstance(text, str):
         if expand_vars:
@@ -181,6 +226,7 @@ def fixed(
             "text": text,
             "tag": tag,
             "following": following,
+            "token_healing": token_healing,
         }
     }
 
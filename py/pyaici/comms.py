@@ -20,6 +20,8 @@ from typing import List, Union, Dict, Any, Optional, Tuple, Set
 # macOS has 31 character name limit, so keep this short
 # (Linux has 255)
 DEFAULT_SHM_PREF = "/aici0-"
+# must match AICI_PROTOCOL_VERSION in aicirt/src/api.rs
+AICI_PROTOCOL_VERSION = 2
 
 
 class BenchTimer:
@@ -308,6 +310,7 @@ class MidResult:
     storage: List[dict]
     logs: str
     micros: int
+    fuel: int
     branches: List[Branch]
 
     @staticmethod
@@ -317,6 +320,7 @@ class MidResult:
             storage=obj["storage"],
             logs=obj["logs"],
             micros=obj["micros"],
+            fuel=obj.get("fuel", 0),
             branches=[
                 Branch.from_json(q)
                 for q in (obj.get("result", None) or {}).get("branches", [])
@@ -426,6 +430,7 @@ class AiciRunner:
         atexit.register(cleanup)
 
         self.cmd.exec("ping")
+        self.capabilities = self._check_capabilities()
         resp = self.cmd.exec("tokens")
         self.vocab_size = resp["data"]["vocab_size"]
         self.eos_token_id = resp["data"]["eos_token_id"]
@@ -433,6 +438,24 @@ class AiciRunner:
 
         AiciRunner.instance = self
 
+    def _check_capabilities(self) -> dict:
+        try:
+            caps = self.cmd.exec("capabilities")["data"]
+        except Exception as e:
+            raise RuntimeError(
+                f"aicirt doesn't support capabilities (too old?): {e}")
+        if caps["protocol_version"] != AICI_PROTOCOL_VERSION:
+            raise RuntimeError(
+                f"aicirt protocol version mismatch: {caps['protocol_version']} "
+                f"(expecting {AICI_PROTOCOL_VERSION})")
+        if caps["bias_dtype"] != self.dtype:
+            raise RuntimeError(
+                f"aicirt uses {caps['bias_dtype']} bias, expecting {self.dtype}")
+        if caps["futex"]:
+            raise RuntimeError("aicirt uses futex channels, expecting semaphores")
+        print(f"aicirt {caps['aicirt_version']}, caps: {caps['inference_caps']}")
+        return caps
+
     def fast_api(self):
         self.side_cmd.bad_response = bad_response_fast_api
         self.cmd.bad_response = bad_response_fast_api
@@ -536,6 +559,8 @@ class AiciRunner:
         prompt: Union[str, list],
         module_id: str,
         module_arg: Union[str, dict, None],
+        tokenizer: Optional[str] = None,
+        seed: Optional[int] = None,
     ):
         """
         Create a new instance of a given module.
@@ -545,6 +570,8 @@ class AiciRunner:
             prompt (str or list): The prompt to use.
             module_id (str): The ID of the WASM constraint module (SHA256 hash).
             module_arg (str or dict): The argument for the module.
+            tokenizer (str, optional): Built-in tokenizer to use instead of the default one.
+            seed (int, optional): Seed for the controller's random number generation.
         """
         return self._save_instantiate_result(
             req_id,
@@ -555,6 +582,8 @@ class AiciRunner:
                     "prompt": prompt,
                     "module_id": module_id,
                     "module_arg": module_arg,
+                    "tokenizer": tokenizer,
+                    "seed": seed,
                 },
             ),
         )
@@ -565,6 +594,8 @@ class AiciRunner:
         prompt: List[int],
         module_id: str,
         module_arg: Union[str, dict, None],
+        tokenizer: Optional[str] = None,
+        seed: Optional[int] = None,
     ):
         """
         Create a new instance of a given module.
@@ -574,6 +605,8 @@ class AiciRunner:
             prompt (str or list): The prompt to use.
             module_id (str): The ID of the WASM constraint module (SHA256 hash).
             module_arg (str or dict): The argument for the module.
+            tokenizer (str, optional): Built-in tokenizer to use instead of the default one.
+            seed (int, optional): Seed for the controller's random number generation.
         """
         return self._save_instantiate_result(
             req_id,
@@ -584,6 +617,8 @@ class AiciRunner:
                     "prompt": prompt,
                     "module_id": module_id,
                     "module_arg": module_arg,
+                    "tokenizer": tokenizer,
+                    "seed": seed,
                 },
             ),
         )
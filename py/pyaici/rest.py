@@ -162,13 +162,13 @@ def print_logs(logs: str, prefix=""):
     if logs and logs[-1] == "\n":
         logs = logs[:-1]
     for ln in logs.split("\n"):
-        if ln.startswith("JSON-OUT: "):
-            j = json.loads(ln[10:])
-            if "hex" in j:
-                j["hex"] = "..."
-            print(f"{prefix}JSON-OUT: ", json.dumps(j))
-        else:
-            print(f"{prefix}{ln}")
+        print(f"{prefix}{ln}")
+
+def print_event(ev: dict, prefix=""):
+    data = ev["data"]
+    if isinstance(data, dict) and "hex" in data:
+        data = {**data, "hex": "..."}
+    print(f"{prefix}EVENT {ev['kind']}: ", json.dumps(data))
 
 def run_controller(
     *,
@@ -177,6 +177,7 @@ def run_controller(
     prompt="",
     temperature: Optional[float] = None,
     max_tokens: Optional[int] = 200,
+    seed: Optional[int] = None,
     base_url: Optional[str] = None,
 ):
     data = {
@@ -185,6 +186,7 @@ def run_controller(
         "prompt": prompt,
         "max_tokens": max_tokens,
         "temperature": temperature,
+        "seed": seed,
     }
     t0 = time.time()
     resp = req("post",
@@ -198,6 +200,7 @@ def run_controller(
     logs = [""]
     full_resp = []
     json_out = [[]]
+    events = [[]]
     storage = {}
     res = {
         "request": data,
@@ -205,6 +208,7 @@ def run_controller(
         "text": texts,
         "logs": logs,
         "json_out": json_out,
+        "events": events,
         "raw_storage": storage,
         "error": None,
         "usage": {},
@@ -244,23 +248,27 @@ def run_controller(
                     texts.append("")
                     logs.append("")
                     json_out.append([])
+                    events.append([])
                 for s in ch.get("storage", []):
                     w = s.get("WriteVar", None)
                     if w:
                         storage[w["name"]] = w["value"]
                 err = ch.get("error", "")
 
-                for ln in ch["logs"].split("\n"):
-                    ln: str
-                    if ln.startswith("JSON-OUT: "):
-                        j = json.loads(ln[10:])
-                        json_out[idx].append(j)
+                for ev in ch.get("events", []):
+                    events[idx].append(ev)
+                    if ev["kind"] == "progress":
+                        json_out[idx].append(ev["data"])
 
                 if log_level > 2:
                     print_logs(ch["logs"], f"[{idx}]: ")
+                    for ev in ch.get("events", []):
+                        print_event(ev, f"[{idx}]: ")
                 elif idx == 0:
                     if log_level > 1:
                         print_logs(ch["logs"])
+                        for ev in ch.get("events", []):
+                            print_event(ev)
                     elif log_level > 0:
                         print(ch["text"], end="")
                         sys.stdout.flush()
@@ -13,16 +13,22 @@ from pyaici.server_native import (
     detokenize,
     RegexConstraint,
     CfgConstraint,
+    JsonSchemaConstraint,
     SubStrConstraint,
     DynamicLexer,
     Constraint,
     get_config,
+    now_micros,
+    random_seed,
+    emit_event,
+    logprobs,
     get_var,
     set_var,
     append_var,
     eos_token,
     token_repr,
     tokens_repr,
+    heal_tokens,
 )
 import pyaici.server_native as _aici
 
@@ -31,6 +37,9 @@ SeqId = int
 
 log_level = 1
 
+# bytes removed by token healing in FixedTokens(), to be generated by the next gen_tokens()
+_healing_prefix = b""
+
 
 def all_tokens():
     ts = TokenSet()
@@ -75,9 +84,21 @@ class Branch:
     def __init__(self,
                  *,
                  splices: List[Splice] = [],
-                 sample_mask: Optional[TokenSet] = None) -> None:
+                 sample_mask: Optional[TokenSet] = None,
+                 temperature: Optional[float] = None,
+                 top_p: Optional[float] = None,
+                 top_k: Optional[int] = None,
+                 min_p: Optional[float] = None) -> None:
+        """
+        `temperature`, `top_p`, `top_k` and `min_p` override the sampling parameters
+        of the request, when sampling from `sample_mask`.
+        """
         self.sample_mask = sample_mask
         self.splices = splices
+        self.temperature = temperature
+        self.top_p = top_p
+        self.top_k = top_k
+        self.min_p = min_p
 
     def is_splice(self) -> bool:
         return len(self.splices) == 1 and self.splices[0].when_sampled == []
@@ -97,16 +118,33 @@ def get_prompt_len() -> int:
 
 class MidProcessResult:
 
-    def __init__(self, branches: List[Branch]):
+    def __init__(
+        self,
+        branches: List[Branch],
+        logprobs: Optional[int] = None,
+        logprobs_full: bool = False,
+    ):
+        """
+        If `logprobs` is set, the host will compute log-probabilities of the
+        `logprobs` (at most 20) most likely next tokens; read them with `logprobs()`
+        in the following step.
+        With `logprobs_full`, the whole distribution is returned as well
+        (if the host supports it).
+        """
         self.skip_me = False
         self.branches = branches
+        self.logprobs = logprobs
+        self.logprobs_full = logprobs_full
 
     def is_splice(self) -> bool:
         return len(self.branches) == 1 and self.branches[0].is_splice()
 
     @classmethod
-    def bias(cls, bias: TokenSet):
-        return cls([Branch(sample_mask=bias)])
+    def bias(cls, bias: TokenSet, **sampling: Any):
+        """
+        Sample from `bias`; `sampling` are passed to `Branch()` (`temperature`, `top_p` etc.).
+        """
+        return cls([Branch(sample_mask=bias, **sampling)])
 
     @classmethod
     def splice(cls, backtrack: int, ff_tokens: List[Token]):
@@ -210,13 +248,27 @@ class FixedTokens(NextToken):
 
     def __init__(self,
                  text: Union[str, bytes],
-                 following: Optional["Label"] = None):
+                 following: Optional["Label"] = None,
+                 token_healing: bool = False):
         """
         Forces next tokens to be exactly the given text.
         If following is given, the text replaces everything that follows the label.
+        If token_healing is set, the last token of the text is left to the next gen_tokens(),
+        which can then pick a token that crosses the boundary.
         """
         super().__init__()
+        global _healing_prefix
+        if _healing_prefix:
+            if isinstance(text, str):
+                text = text.encode()
+            text = _healing_prefix + text
+            _healing_prefix = b""
         self.fixed_tokens: List[Token] = tokenize(text)
+        if token_healing:
+            n = heal_tokens(self.fixed_tokens)
+            if n > 0:
+                _healing_prefix = detokenize(self.fixed_tokens[-n:])
+                del self.fixed_tokens[-n:]
         if log_level >= 1:
             print(f"FIXED {tokens_repr(self.fixed_tokens)}")
         self.following = following
@@ -251,13 +303,16 @@ class StopToken(NextToken):
 
 class ConstrainedToken(NextToken):
 
-    def __init__(self, mk_constraint: Callable[[], Constraint]):
+    def __init__(self, mk_constraint: Callable[[], Constraint],
+                 **sampling: Any):
         """
         Generates a token that satisfies the given constraint.
         The constraint will be constructed in mid_process() phase, which has slightly longer time limit.
+        `sampling` is passed to `MidProcessResult.bias()`.
         """
         super().__init__()
         self.mk_constraint = mk_constraint
+        self.sampling = sampling
         self._constraint: Optional[Constraint] = None
 
     def mid_process(self) -> MidProcessResult:
@@ -273,7 +328,7 @@ class ConstrainedToken(NextToken):
             if log_level >= 1:
                 print("Constraint doesn't allow any tokens; adding EOS")
             bias[eos_token()] = True
-        return MidProcessResult.bias(bias)
+        return MidProcessResult.bias(bias, **self.sampling)
 
     def post_process(self, backtrack: int, tokens: List[int]):
         assert self._constraint
@@ -553,33 +608,65 @@ class ChooseConstraint(Constraint):
 async def gen_tokens(
     regex: Optional[str] = None,
     yacc: Optional[str] = None,
+    json_schema: Optional[Union[str, Dict[str, Any]]] = None,
     substring: Optional[str] = None,
     substring_end: str = '"',
+    substring_max_edits: int = 0,
+    substring_normalize: bool = False,
     options: Optional[List[str]] = None,
     store_var: Optional[str] = None,
     stop_at: Optional[str] = None,
     max_tokens=20,
+    temperature: Optional[float] = None,
+    top_p: Optional[float] = None,
+    top_k: Optional[int] = None,
+    min_p: Optional[float] = None,
 ) -> List[Token]:
     """
     Generates tokens with the given constraint.
     If `stop_at` is given, the generation stops when the given text is generated. The stop text is included in result.
     If `store_var` is given, the generated tokens are stored in the variable.
-    `regex` and `options` are mutually exclusive.
+    `regex`, `yacc`, `json_schema`, `substring`, and `options` are mutually exclusive.
+    `substring_max_edits` and `substring_normalize` allow the substring to differ from `substring`,
+    see `SubStrConstraint`.
+    If the preceding `FixedTokens()` used `token_healing`, the first returned token
+    also covers the healed text, which is not included in `store_var` nor checked for `stop_at`.
+    `temperature`, `top_p`, `top_k` and `min_p` override the sampling parameters of the request.
     """
+    global _healing_prefix
+    prefix = _healing_prefix
+    _healing_prefix = b""
     res: List[Token] = []
-    assert len([x for x in [regex, options, yacc, substring] if x is not None
-                ]) <= 1
+    assert len([
+        x for x in [regex, options, yacc, json_schema, substring]
+        if x is not None
+    ]) <= 1
+    if prefix and options is not None:
+        options = [prefix + o.encode() for o in options]
     if regex is not None:
         next_token = ConstrainedToken(lambda: RegexConstraint(regex))
     elif substring is not None:
         next_token = ConstrainedToken(
-            lambda: SubStrConstraint(substring, substring_end))
+            lambda: SubStrConstraint(substring, substring_end,
+                                     substring_max_edits, substring_normalize))
     elif yacc is not None:
         next_token = ConstrainedToken(lambda: CfgConstraint(yacc))
+    elif json_schema is not None:
+        next_token = ConstrainedToken(
+            lambda: JsonSchemaConstraint(json_schema))
     elif options is not None:
         next_token = ConstrainedToken(lambda: ChooseConstraint(options))
     else:
         next_token = ConstrainedToken(lambda: Constraint())
+    next_token.sampling = {
+        "temperature": temperature,
+        "top_p": top_p,
+        "top_k": top_k,
+        "min_p": min_p,
+    }
+    if prefix and options is None:
+        mk_constraint = next_token.mk_constraint
+        next_token.mk_constraint = lambda: mk_constraint().healed(prefix)
     for _ in range(max_tokens):
         tokens = await next_token
         if tokens:
@@ -590,7 +677,7 @@ async def gen_tokens(
 
             # this may get slow when the output is veeeeeery long
             # not a problem for a few k tokens
-            text = detokenize(res).decode(errors="replace")
+            text = detokenize(res)[len(prefix):].decode(errors="replace")
 
             if stop_at is not None:
                 if stop_at in text:
@@ -598,7 +685,7 @@ async def gen_tokens(
         if next_token.finished:
             break
     if store_var is not None:
-        set_var(store_var, detokenize(res))
+        set_var(store_var, detokenize(res)[len(prefix):])
     if log_level >= 1:
         print("GEN:", tokens_repr(res))
     return res
@@ -608,8 +695,9 @@ async def gen_text(**kwargs: Any) -> str:
     """
     Same as gen_tokens(), but tries to decode the output as text.
     """
+    prefix = _healing_prefix
     tokens = await gen_tokens(**kwargs)
-    return detokenize(tokens).decode(errors="replace")
+    return detokenize(tokens)[len(prefix):].decode(errors="replace")
 
 
 def check_var(name: str, value: str):
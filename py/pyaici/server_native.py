@@ -1,7 +1,7 @@
 # Type stubs
 
 from __future__ import annotations
-from typing import Any, Sequence, List
+from typing import Any, Dict, Optional, Sequence, List, Tuple, Union
 import pyaici.server as aici
 
 
@@ -26,6 +26,15 @@ def detokenize(tokens: List[int]) -> bytes:
     ...
 
 
+def heal_tokens(tokens: List[int], max_tokens: int = 1) -> int:
+    """
+    Return how many tokens (at most `max_tokens`) to remove from the end of `tokens`
+    for token healing. Tokens are only removed when a longer token starts with their bytes.
+    Use `Constraint.healed()` to have the generation start with the removed bytes.
+    """
+    ...
+
+
 def token_repr(token: int) -> str:
     """
     Return debug string representation of a given token index
@@ -84,6 +93,43 @@ def get_config(name: str) -> int:
     ...
 
 
+def now_micros() -> int:
+    """
+    Current wall-clock time in microseconds since Unix epoch.
+    The resolution is limited by the host, and can be zero if the clock is disabled.
+    """
+    ...
+
+
+def random_seed() -> int:
+    """
+    Random seed for the current sequence.
+    It is derived from the request seed and fork index, so it's reproducible.
+    """
+    ...
+
+
+def emit_event(kind: str, data: Any) -> None:
+    """
+    Emit a structured event, which the client gets in the `events` list of the response
+    as `{"kind": kind, "data": data}`.
+    The data can consist of None, bools, numbers, strings, lists, tuples and dicts with string keys.
+    """
+    ...
+
+
+def logprobs() -> Optional[Tuple[float, List[Tuple[int, float]], Optional[List[float]]]]:
+    """
+    Log-probabilities from the previous step, if requested by setting `logprobs`
+    in the previous `MidProcessResult` (and supported by the host).
+    Returns `(sampled, top, full)`, where `sampled` is the log-probability of the sampled token,
+    `top` is a list of `(token, logprob)` pairs, most likely first,
+    and `full` is the whole distribution (one entry per token) if `logprobs_full` was set, or None.
+    The log-probabilities are computed before the controller's bias is applied.
+    """
+    ...
+
+
 class TokenSet(Sequence[bool]):
     """
     Represents a set of tokens.
@@ -159,6 +205,49 @@ class Constraint:
         """
         ...
 
+    # The methods below combine native constraints (not Python subclasses of Constraint).
+    # They consume the constraints they are called with, using them afterwards raises RuntimeError.
+
+    def intersect(self, other: "Constraint") -> "Constraint":
+        """
+        Allow only output that is allowed by both constraints.
+        """
+        ...
+
+    def union(self, other: "Constraint") -> "Constraint":
+        """
+        Allow output that is allowed by either constraint.
+        """
+        ...
+
+    def followed_by(self, other: "Constraint") -> "Constraint":
+        """
+        Output of this constraint followed by output of `other`.
+        The switch to `other` happens at the first byte not allowed by this constraint,
+        provided it allows ending there.
+        """
+        ...
+
+    def repeat(self, min: int, max: Optional[int] = None) -> "Constraint":
+        """
+        Output of this constraint repeated between `min` and `max` times.
+        A new repetition starts when the current one can end and the next byte doesn't continue it.
+        """
+        ...
+
+    def max_bytes(self, max_bytes: int) -> "Constraint":
+        """
+        Output of this constraint, limited to `max_bytes` bytes.
+        """
+        ...
+
+    def healed(self, prefix: Union[str, bytes]) -> "Constraint":
+        """
+        Output starting with `prefix` (typically removed with `heal_tokens()`),
+        followed by output of this constraint.
+        """
+        ...
+
 
 class RegexConstraint(Constraint):
     """
@@ -172,10 +261,22 @@ class RegexConstraint(Constraint):
 
 class CfgConstraint(Constraint):
     """
-    A constraint that allows only tokens that match the specified yacc-like grammar.
+    A constraint that allows only tokens that match the specified grammar.
+    The grammar is either yacc-like (if it contains a `%%` line) or Lark-style EBNF.
     """
 
-    def __init__(self, yacc_grammar: str):
+    def __init__(self, grammar: str):
+        ...
+
+
+class JsonSchemaConstraint(Constraint):
+    """
+    A constraint that allows only JSON matching the given schema (a dict, or JSON text).
+    Supports types, properties with required, enum, const, anyOf/oneOf, items with minItems/maxItems,
+    string pattern/minLength/maxLength, and local $ref.
+    """
+
+    def __init__(self, schema: Union[str, Dict[str, Any]]):
         ...
 
 
@@ -184,7 +285,19 @@ class SubStrConstraint(Constraint):
     A constraint that allows only word-substrings of given string.
     """
 
-    def __init__(self, template: str, stop_at: str):
+    def __init__(self,
+                 template: str,
+                 stop_at: str,
+                 max_edits: int = 0,
+                 normalize: bool = False):
+        """
+        The generated substring starts and ends at word boundaries of `template`,
+        and is followed by `stop_at`.
+        If `max_edits` (at most 16) is given, the substring can differ from `template` by up to that many
+        inserted, deleted or replaced bytes.
+        If `normalize` is set, runs of whitespace are treated as single space
+        and ASCII letters are compared case-insensitively.
+        """
         ...
 
 class DynamicLexer:
@@ -192,24 +305,38 @@ class DynamicLexer:
     A lexer with a set of valid identifiers, that can be used as a Constraint.
     """
 
-    def __init__(self, additional_id_chars: str):
+    def __init__(self, additional_id_chars: str, case_insensitive: bool = False):
         """
-        Normally, identifiers match /[a-zA-Z_][a-zA-Z0-9_]*/.
+        Normally, identifiers match /[a-zA-Z_][a-zA-Z0-9_]*/, where letters and digits
+        also include non-ASCII (Unicode) ones.
         If additional_id_chars is not empty, the chars are additionally allowed anywhere in the identifier.
         For example, use "$" for JavaScript, or "'" for ML-like languages.
         You can add "." but it will interfere with floats.
+        If case_insensitive is set, identifiers are matched ignoring case.
+        Both arguments are positional-only.
         """
         ...
 
     def add(self, identifier: str):
         """
         Allow given identifier.
+        It can also be a phrase with spaces and punctuation, like "New York" or "Coca-Cola Co.";
+        it then has to be generated exactly like that (modulo case).
+        """
+        ...
+
+    def add_lines(self, lines: str) -> int:
+        """
+        Allow every non-empty line of `lines` (with whitespace stripped) as an identifier or phrase.
+        This is much faster than calling add() in a loop, when loading large lists.
+        Returns the number of lines added.
         """
         ...
     
     def constraint(self) -> Constraint:
         """
         This always returns the same constraint.
+        It only allows EOS right after an identifier (not at the start, nor after separators).
         """
         ...
 
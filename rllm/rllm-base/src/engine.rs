@@ -9,9 +9,9 @@ use crate::{
     AiciBias as _, HashMap, LoaderArgs, LogitsProcessor, ModelExec, Scheduler, SchedulerOutputs,
     SequenceManager, TBlockSpaceManager as _,
 };
-use aici_abi::{toktrie::TokTrie, Splice};
+use aici_abi::{toktrie::TokTrie, Logprobs, LogprobsRequest, Splice, MAX_LOGPROBS_TOP_K};
 use aicirt::{
-    api::{AiciMidOp, AiciMidProcessReq, ModuleInstId, SequenceResult},
+    api::{AiciMidOp, AiciMidProcessReq, AiciMidProcessResp, ModuleInstId, SequenceResult},
     with_timer, TimerRef, TimerSet,
 };
 use anyhow::{bail, Error as E, Result};
@@ -351,6 +351,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
         }
 
         let mid_res = self.aicirt.as_mut().unwrap().finish_mid_process()?;
+        self.aici_watches(sched_out, &mid_res);
 
         for sg in sched_out.next_seq_groups.iter_mut() {
             if sg.sampling_params.controller.is_none() {
@@ -368,9 +369,16 @@ impl<ME: ModelExec> RllmEngine<ME> {
                             self.scheduler.finish_seq(seq, FinishReason::AiciStop);
                             continue;
                         }
+                        if !resp.attention_mask.is_empty() && !ME::supports_attention_mask() {
+                            log::warn!("{}: attention masking not supported", seq.seq_id);
+                            self.scheduler.finish_seq(seq, FinishReason::Failed);
+                            continue;
+                        }
                         for (idx, b) in resp.branches.iter().enumerate() {
                             if idx == 0 {
                                 seq.aici_sampling = Some(b.clone());
+                                seq.aici_logprobs = resp.logprobs;
+                                seq.attention_mask = resp.attention_mask.clone();
                                 seq.mid_op = Some(seq.defl_mid_op());
                             } else {
                                 let new_id = self.seq_mgr.new_sequence();
@@ -380,6 +388,8 @@ impl<ME: ModelExec> RllmEngine<ME> {
                                 seq_id_mapping.insert(copy.seq_id.to_num(), seq.seq_id.to_num());
                                 sg.max_index += 1;
                                 copy.aici_sampling = Some(b.clone());
+                                copy.aici_logprobs = resp.logprobs;
+                                copy.attention_mask = resp.attention_mask.clone();
                                 copy.mid_op = Some(AiciMidOp {
                                     clone_id: Some(seq.seq_id.to_num()),
                                     clone_idx: Some(idx),
@@ -409,6 +419,35 @@ impl<ME: ModelExec> RllmEngine<ME> {
         ))
     }
 
+    /// Suspend, resume, or finish (when deadlocked) sequences waiting on variables.
+    fn aici_watches(&self, sched_out: &mut SchedulerOutputs, mid_res: &AiciMidProcessResp) {
+        if mid_res.suspend.is_empty() && mid_res.resume.is_empty() && mid_res.deadlocked.is_empty()
+        {
+            return;
+        }
+
+        let mut update = |seq: &mut Sequence| {
+            let id = seq.seq_id.to_num();
+            if mid_res.suspend.contains(&id) {
+                seq.aici_waiting = true;
+            }
+            if mid_res.resume.contains(&id) {
+                seq.aici_waiting = false;
+            }
+            if let Some(msg) = mid_res.deadlocked.get(&id) {
+                seq.aici_waiting = false;
+                seq.aici_logs.push(SequenceResult::from_error(msg.clone()));
+                self.scheduler.finish_seq(seq, FinishReason::Deadlock);
+            }
+        };
+
+        // sequences running in this step are not in scheduler queues
+        for sg in sched_out.next_seq_groups.iter_mut() {
+            sg.seqs.iter_mut().for_each(&mut update);
+        }
+        self.scheduler.for_each_seq(&mut update);
+    }
+
     fn check_expected(&mut self, mut logits: Vec<f32>, req_id: &str, seq: &mut Sequence) -> Token {
         let exp = seq.expected.as_ref().unwrap();
         let idx = seq.get_len() - exp.prompt.len();
@@ -501,6 +540,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
 
                 let mut info = "";
                 let mut sampled = None;
+                let mut logprobs = None;
 
                 let splice = match &seq.aici_sampling {
                     Some(b) if b.sample_mask.is_none() => {
@@ -511,6 +551,11 @@ impl<ME: ModelExec> RllmEngine<ME> {
                         s.clone()
                     }
                     _ => {
+                        // logprobs are computed before the controller's bias is applied
+                        let log_probs = seq
+                            .aici_logprobs
+                            .map(|_| log_softmax(ME::tensor_to_vec1(&logits)));
+
                         match &seq.aici_sampling {
                             Some(b) => {
                                 let seq_idx = b.sample_mask.unwrap();
@@ -518,6 +563,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
                                 if let Some(t) = b.temperature {
                                     sg.logits_processor.set_temperature(t);
                                 }
+                                sg.logits_processor.set_branch_sampling(&b.sampling);
                             }
                             None => {}
                         }
@@ -533,6 +579,9 @@ impl<ME: ModelExec> RllmEngine<ME> {
                         };
 
                         sampled = Some(next_token);
+                        if let (Some(req), Some(log_probs)) = (&seq.aici_logprobs, log_probs) {
+                            logprobs = Some(select_logprobs(log_probs, req, next_token));
+                        }
 
                         let splices = seq
                             .aici_sampling
@@ -595,6 +644,7 @@ impl<ME: ModelExec> RllmEngine<ME> {
                     seq.mid_op.as_mut().unwrap().tokens = splice.ff_tokens;
                     seq.mid_op.as_mut().unwrap().backtrack = splice.backtrack;
                     seq.mid_op.as_mut().unwrap().sampled = sampled;
+                    seq.mid_op.as_mut().unwrap().logprobs = logprobs;
                 }
 
                 if !sg.sampling_params.ignore_eos && has_eos {
@@ -809,3 +859,80 @@ impl<ME: ModelExec> RllmEngine<ME> {
         }
     }
 }
+
+fn log_softmax(mut logits: Vec<f32>) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln() + max;
+    logits.iter_mut().for_each(|l| *l -= log_sum);
+    logits
+}
+
+fn select_logprobs(log_probs: Vec<f32>, req: &LogprobsRequest, sampled: Token) -> Logprobs {
+    let mut top = log_probs
+        .iter()
+        .enumerate()
+        .map(|(t, l)| (t as Token, *l))
+        .collect::<Vec<_>>();
+    let k = std::cmp::min(
+        std::cmp::min(req.top_k, MAX_LOGPROBS_TOP_K) as usize,
+        top.len(),
+    );
+    if k < top.len() {
+        top.select_nth_unstable_by(k, |a, b| b.1.total_cmp(&a.1));
+        top.truncate(k);
+    }
+    top.sort_by(|a, b| b.1.total_cmp(&a.1));
+    Logprobs {
+        sampled: log_probs[sampled as usize],
+        top,
+        full: if req.full { Some(log_probs) } else { None },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_softmax_normalizes() {
+        let lp = log_softmax(vec![1.0, 2.0, 3.0]);
+        let sum: f32 = lp.iter().map(|l| l.exp()).sum();
+        assert!((sum - 1.0).abs() < 1e-5);
+        assert!(lp[2] > lp[1] && lp[1] > lp[0]);
+    }
+
+    #[test]
+    fn select_logprobs_top_k() {
+        let lp = log_softmax(vec![0.5, 3.0, 1.0, 2.0]);
+        let r = select_logprobs(lp.clone(), &LogprobsRequest::top_k(2), 2);
+        assert_eq!(r.sampled, lp[2]);
+        assert!(r.full.is_none());
+        assert_eq!(
+            r.top.iter().map(|(t, _)| *t).collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+    }
+
+    #[test]
+    fn select_logprobs_clamps_top_k() {
+        let lp = log_softmax((0..100).map(|i| i as f32).collect());
+        let r = select_logprobs(
+            lp,
+            &LogprobsRequest {
+                top_k: 1000,
+                full: false,
+            },
+            0,
+        );
+        assert_eq!(r.top.len(), MAX_LOGPROBS_TOP_K as usize);
+        assert_eq!(r.top[0].0, 99);
+    }
+
+    #[test]
+    fn select_logprobs_full() {
+        let lp = log_softmax(vec![0.5, 3.0, 1.0, 2.0]);
+        let r = select_logprobs(lp.clone(), &LogprobsRequest::top_k(1).with_full(), 0);
+        assert_eq!(r.top, vec![(1, lp[1])]);
+        assert_eq!(r.full, Some(lp));
+    }
+}
@@ -79,6 +79,11 @@ pub trait ModelExec: Sized {
         -> Self::AiciBias;
 
     fn sample(&self, processor: &mut LogitsProcessor, logits: &Self::Tensor) -> Result<u32>;
+
+    /// Whether `Sequence::attention_mask` is honored by `run()`.
+    fn supports_attention_mask() -> bool {
+        false
+    }
 }
 
 pub trait TBlockSpaceManager<ME: ModelExec> {
@@ -6,12 +6,14 @@ use aici_abi::{
 };
 use aicirt::{
     api::{
-        AiciMidProcessReq, AiciMidProcessResp, AuthInfo, GetTagsResp, InstantiateReq, MkModuleReq,
-        MkModuleResp, SequenceResult, SetTagsReq, TokensResp,
+        AiciMidProcessReq, AiciMidProcessResp, AuthInfo, CapabilitiesResp, GetTagsResp,
+        InstantiateReq, MkModuleReq, MkModuleResp, SequenceResult, SetTagsReq, TokensResp,
+        AICI_PROTOCOL_VERSION,
     },
     futexshm::ClientChannel,
     msgchannel::MessageChannel,
     shm::{Shm, Unlink},
+    transport::{read_token_file, socket_connect, BusyWait, MsgReceiver, MsgSender, SocketAddr},
     user_error,
 };
 use anyhow::Result;
@@ -28,10 +30,8 @@ use tokio::{signal::unix::SignalKind, sync::oneshot};
 
 pub struct CmdChannel {
     cmd_pending: bool,
-    cmd_ch: ClientChannel,
-    resp_ch: ClientChannel,
-    #[allow(dead_code)]
-    busy_wait_duration: Duration,
+    cmd_ch: Box<dyn MsgSender>,
+    resp_ch: Box<dyn MsgReceiver>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -51,21 +51,45 @@ impl CmdChannel {
         suff: &str,
         busy_wait_duration: Duration,
     ) -> Result<Self> {
+        let cmd_ch = build_ch(&format!("{}cmd{}", pref, suff), json_size)?;
+        let resp_ch = build_ch(&format!("{}resp{}", pref, suff), json_size)?;
+        Ok(Self {
+            cmd_pending: false,
+            cmd_ch: Box::new(cmd_ch),
+            resp_ch: Box::new(BusyWait {
+                ch: resp_ch,
+                busy_wait_duration,
+            }),
+        })
+    }
+
+    /// Connect to aicirt started with `--listen`; `channel` is either "main" or "side".
+    pub fn connect(
+        addr: &SocketAddr,
+        channel: &str,
+        token: &str,
+        max_msg_bytes: usize,
+    ) -> Result<Self> {
+        let (tx, rx) = socket_connect(addr, channel, token, max_msg_bytes)?;
         Ok(Self {
             cmd_pending: false,
-            cmd_ch: build_ch(&format!("{}cmd{}", pref, suff), json_size)?,
-            resp_ch: build_ch(&format!("{}resp{}", pref, suff), json_size)?,
-            busy_wait_duration,
+            cmd_ch: Box::new(tx),
+            resp_ch: Box::new(rx),
         })
     }
 
     pub fn send_bytes(&mut self, data: &[u8]) -> Result<()> {
         assert!(!self.cmd_pending);
         self.cmd_pending = true;
-        self.cmd_ch.send_req(data)?;
+        self.cmd_ch.send_msg(data)?;
         Ok(())
     }
 
+    /// Receive a binary message, that follows some responses.
+    pub fn recv_bytes(&mut self) -> Result<Vec<u8>> {
+        self.resp_ch.recv_msg()
+    }
+
     pub fn exec<T: Serialize, R>(&mut self, op: &str, data: T) -> Result<R>
     where
         R: for<'d> Deserialize<'d>,
@@ -86,7 +110,7 @@ impl CmdChannel {
         R: for<'d> Deserialize<'d>,
     {
         assert!(self.cmd_pending);
-        let bytes = self.resp_ch.recv_resp(Duration::MAX).unwrap();
+        let bytes = self.resp_ch.recv_msg()?;
         self.cmd_pending = false;
         let mut resp: Value = serde_json::from_slice(&bytes)?;
         if resp["type"] != "ok" {
@@ -114,11 +138,14 @@ impl CmdChannel {
 
 pub struct AiciRtIface {
     cmd: CmdChannel,
+    pub capabilities: CapabilitiesResp,
     pub pending_mid_size: usize,
     pub bin_shm: Shm,
+    // set when talking over a socket; logit biases are then copied into (private) bin_shm
+    inline_bias: bool,
     pub side_cmd: AsyncCmdChannel,
     #[allow(dead_code)]
-    child: Child,
+    child: Option<Child>,
 }
 
 pub struct Args {
@@ -129,6 +156,14 @@ pub struct Args {
     pub shm_prefix: String,
     pub busy_wait_time: u64,
     pub add_args: Vec<String>,
+    /// Connect to aicirt listening on this address (see `aicirt --listen`), instead of starting it.
+    pub connect: Option<String>,
+    /// File with the token required by aicirt (see `aicirt --listen-token-file`); only with `connect`.
+    pub token_file: Option<String>,
+    /// Engine session in aicirt (see `aicirt --session`); only with `connect`.
+    pub session: Option<String>,
+    /// The engine supports attention masking (see `ModelExec::supports_attention_mask`).
+    pub attention_mask: bool,
 }
 
 pub fn kill_self() {
@@ -139,12 +174,97 @@ pub fn kill_self() {
 
 impl AiciRtIface {
     pub fn start_aicirt(args: &Args, tok_trie: &TokTrie) -> Result<Self> {
-        let busy_wait_time = Duration::from_millis(args.busy_wait_time);
-        let shm_name = MessageChannel::shm_name(&(args.shm_prefix.clone() + "bin"));
-        let cmd = CmdChannel::new(args.json_size, &args.shm_prefix, "", busy_wait_time)?;
-        let side_cmd = AsyncCmdChannel::new(args.json_size, &args.shm_prefix, "-side")?;
-        let bin_shm = Shm::new(&shm_name, args.bin_size * M, Unlink::Pre)?;
+        let (mut cmd, side_cmd, bin_shm, child) = match &args.connect {
+            Some(addr) => {
+                let addr = SocketAddr::parse(addr)?;
+                let (main, side) = match &args.session {
+                    Some(name) => (format!("main:{name}"), format!("side:{name}")),
+                    None => ("main".to_string(), "side".to_string()),
+                };
+                let token = match &args.token_file {
+                    Some(path) => read_token_file(path)?,
+                    None => String::new(),
+                };
+                // responses are followed by binary logit biases
+                let max_msg_bytes = std::cmp::max(args.json_size, args.bin_size) * M;
+                let cmd = CmdChannel::connect(&addr, &main, &token, max_msg_bytes)?;
+                let side_cmd = AsyncCmdChannel::from_cmd(CmdChannel::connect(
+                    &addr,
+                    &side,
+                    &token,
+                    max_msg_bytes,
+                )?);
+                (cmd, side_cmd, None, None)
+            }
+            None => {
+                if args.session.is_some() {
+                    anyhow::bail!("aicirt session can only be used when connecting to aicirt");
+                }
+                let busy_wait_time = Duration::from_millis(args.busy_wait_time);
+                let shm_name = MessageChannel::shm_name(&(args.shm_prefix.clone() + "bin"));
+                let cmd = CmdChannel::new(args.json_size, &args.shm_prefix, "", busy_wait_time)?;
+                let side_cmd = AsyncCmdChannel::new(args.json_size, &args.shm_prefix, "-side")?;
+                let bin_shm = Shm::new(&shm_name, args.bin_size * M, Unlink::Pre)?;
+                let child = Self::spawn_aicirt(args)?;
+                (cmd, side_cmd, Some(bin_shm), Some(child))
+            }
+        };
+
+        let _: Value = cmd.exec("ping", json!({}))?;
+
+        let capabilities: CapabilitiesResp = cmd.exec("capabilities", json!({})).map_err(|e| {
+            anyhow::anyhow!(
+                "{} doesn't support capabilities (too old?): {e}",
+                args.aicirt
+            )
+        })?;
+        Self::check_capabilities(&capabilities)?;
+        if capabilities.inference_caps.attention_mask && !args.attention_mask {
+            log::warn!("aicirt allows attention masking, but the engine doesn't support it");
+        }
+        let session = args.session.clone().unwrap_or_default();
+        if capabilities.session != session {
+            anyhow::bail!(
+                "aicirt session mismatch: {:?} (expecting {:?})",
+                capabilities.session,
+                session
+            );
+        }
+
+        let inline_bias = bin_shm.is_none();
+        let bin_shm = match bin_shm {
+            Some(shm) => shm,
+            None => Shm::anon(capabilities.limits.bin_size_bytes)?,
+        };
+
+        let mut r = Self {
+            cmd,
+            capabilities,
+            side_cmd,
+            bin_shm,
+            inline_bias,
+            child,
+            pending_mid_size: usize::MAX,
+        };
+
+        let tokens: TokensResp = r
+            .cmd
+            .exec("tokens", json!({}))
+            .map_err(|e| anyhow::anyhow!("check for pending aicirt processes! {e}"))?;
+
+        // well, this is somewhat unlikely as we're passing the same tokenizer name down...
+        if tokens.vocab_size != tok_trie.info().vocab_size {
+            return Err(anyhow::anyhow!(
+                "Vocab size mismatch: {:?} != {:?}",
+                tokens,
+                tok_trie.info()
+            ));
+        }
+
+        Ok(r)
+    }
 
+    fn spawn_aicirt(args: &Args) -> Result<Child> {
         let mut cmd_bld = Command::new(&args.aicirt);
         cmd_bld
             .arg("--tokenizer")
@@ -158,7 +278,12 @@ impl AiciRtIface {
             .arg("--futex")
             .arg("--cap-fork")
             .arg("--cap-ff-tokens")
-            .arg("--cap-backtrack");
+            .arg("--cap-backtrack")
+            .arg("--cap-logprobs")
+            .arg("--cap-logprobs-full");
+        if args.attention_mask {
+            cmd_bld.arg("--cap-attention-mask");
+        }
         for a in &args.add_args {
             cmd_bld.arg(a);
         }
@@ -199,31 +324,46 @@ impl AiciRtIface {
             }
         });
 
-        let mut r = Self {
-            cmd,
-            side_cmd,
-            bin_shm,
-            child,
-            pending_mid_size: usize::MAX,
-        };
-
-        let _: Value = r.cmd.exec("ping", json!({}))?;
-
-        let tokens: TokensResp = r
-            .cmd
-            .exec("tokens", json!({}))
-            .map_err(|e| anyhow::anyhow!("check for pending aicirt processes! {e}"))?;
+        Ok(child)
+    }
 
-        // well, this is somewhat unlikely as we're passing the same tokenizer name down...
-        if tokens.vocab_size != tok_trie.info().vocab_size {
-            return Err(anyhow::anyhow!(
-                "Vocab size mismatch: {:?} != {:?}",
-                tokens,
-                tok_trie.info()
-            ));
+    fn check_capabilities(caps: &CapabilitiesResp) -> Result<()> {
+        log::info!(
+            "aicirt {}, protocol v{}; {:?}; {:?}",
+            caps.aicirt_version,
+            caps.protocol_version,
+            caps.inference_caps,
+            caps.limits
+        );
+        if caps.protocol_version != AICI_PROTOCOL_VERSION {
+            anyhow::bail!(
+                "aicirt protocol version mismatch: {} (expecting {})",
+                caps.protocol_version,
+                AICI_PROTOCOL_VERSION
+            );
         }
-
-        Ok(r)
+        // we only read f32 biases, over futex channels
+        if caps.bias_dtype != "f32" {
+            anyhow::bail!("aicirt uses {} bias, expecting f32", caps.bias_dtype);
+        }
+        if !caps.futex && !caps.socket {
+            anyhow::bail!("aicirt doesn't use futex channels");
+        }
+        // requests using these will fail in aicirt, but we can otherwise run fine
+        let inf = &caps.inference_caps;
+        for (name, enabled) in [
+            ("fork", inf.fork),
+            ("backtrack", inf.backtrack),
+            ("ff_tokens", inf.ff_tokens),
+            ("logprobs", inf.logprobs),
+            ("logprobs_full", inf.logprobs_full),
+            ("attention_mask", inf.attention_mask),
+        ] {
+            if !enabled {
+                log::warn!("aicirt: {name} disabled");
+            }
+        }
+        Ok(())
     }
 
     pub fn start_mid_process(&mut self, req: AiciMidProcessReq) -> Result<()> {
@@ -235,6 +375,12 @@ impl AiciRtIface {
     pub fn finish_mid_process(&mut self) -> Result<AiciMidProcessResp> {
         assert!(self.pending_mid_size < usize::MAX);
         let r: AiciMidProcessResp = self.cmd.expect("async:mid_process")?;
+        if self.inline_bias {
+            let bias = self.cmd.recv_bytes()?;
+            self.bin_shm
+                .slice_at_byte_offset::<u8>(r.first_mask_byte_offset, bias.len())
+                .copy_from_slice(&bias);
+        }
         // assert!(r.num_seqs == self.pending_mid_size);
         self.pending_mid_size = usize::MAX;
         Ok(r)
@@ -244,12 +390,20 @@ impl AiciRtIface {
 #[derive(Clone)]
 pub struct AsyncCmdChannel {
     pending_reqs: Arc<Mutex<HashMap<String, oneshot::Sender<Value>>>>,
-    cmd_ch: Arc<Mutex<ClientChannel>>,
+    cmd_ch: Arc<Mutex<Box<dyn MsgSender>>>,
 }
 
 impl AsyncCmdChannel {
     pub fn new(json_size: usize, pref: &str, suff: &str) -> Result<Self> {
-        let cmd = CmdChannel::new(json_size, pref, suff, Duration::ZERO)?;
+        Ok(Self::from_cmd(CmdChannel::new(
+            json_size,
+            pref,
+            suff,
+            Duration::ZERO,
+        )?))
+    }
+
+    pub fn from_cmd(cmd: CmdChannel) -> Self {
         let pending_reqs = Arc::new(Mutex::new(
             HashMap::<String, oneshot::Sender<Value>>::default(),
         ));
@@ -257,7 +411,7 @@ impl AsyncCmdChannel {
             let mut resp_ch = cmd.resp_ch;
             let pending_reqs = pending_reqs.clone();
             thread::spawn(move || loop {
-                let resp = resp_ch.recv_resp2(Duration::ZERO, Duration::MAX).unwrap();
+                let resp = resp_ch.recv_msg().unwrap();
                 let resp: Value = serde_json::from_slice(&resp).unwrap();
                 let rid = resp["$rid"].as_str().unwrap().to_string();
                 let tx = pending_reqs.lock().unwrap().remove(&rid).unwrap();
@@ -265,10 +419,10 @@ impl AsyncCmdChannel {
             });
         }
 
-        Ok(Self {
+        Self {
             pending_reqs,
             cmd_ch: Arc::new(Mutex::new(cmd.cmd_ch)),
-        })
+        }
     }
 
     pub async fn set_tags(&self, req: SetTagsReq, authinfo: AuthInfo) -> Result<GetTagsResp> {
@@ -307,7 +461,7 @@ impl AsyncCmdChannel {
         self.cmd_ch
             .lock()
             .unwrap()
-            .send_req(&serde_json::to_vec(&data)?)?;
+            .send_msg(&serde_json::to_vec(&data)?)?;
 
         let mut resp = rx.await?;
 
@@ -343,3 +497,59 @@ impl AsyncCmdChannel {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caps() -> CapabilitiesResp {
+        serde_json::from_value(json!({
+            "protocol_version": AICI_PROTOCOL_VERSION,
+            "aicirt_version": "0.1.0",
+            "bias_dtype": "f32",
+            "supported_bias_dtypes": ["f32", "f16", "bf16", "bool"],
+            "futex": true,
+            "supported_channels": ["futex", "semaphore"],
+            "inference_caps": { "backtrack": true, "ff_tokens": true, "fork": true },
+            "limits": {
+                "max_memory_bytes": 0,
+                "max_step_ms": 0,
+                "max_init_ms": 0,
+                "max_timeout_steps": 0,
+                "max_forks": 0,
+                "max_step_fuel": 0,
+                "max_init_fuel": 0,
+                "json_size_bytes": 0,
+                "bin_size_bytes": 0
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn accepts_matching_capabilities() {
+        AiciRtIface::check_capabilities(&caps()).unwrap();
+        // missing inference capabilities are only reported
+        let mut c = caps();
+        c.inference_caps.fork = false;
+        AiciRtIface::check_capabilities(&c).unwrap();
+    }
+
+    #[test]
+    fn rejects_mismatched_capabilities() {
+        let mut c = caps();
+        c.protocol_version += 1;
+        assert!(AiciRtIface::check_capabilities(&c).is_err());
+
+        let mut c = caps();
+        c.bias_dtype = "bool".to_string();
+        assert!(AiciRtIface::check_capabilities(&c).is_err());
+
+        let mut c = caps();
+        c.futex = false;
+        assert!(AiciRtIface::check_capabilities(&c).is_err());
+        // sockets don't need futexes
+        c.socket = true;
+        AiciRtIface::check_capabilities(&c).unwrap();
+    }
+}
@@ -1,12 +1,19 @@
 // based on https://github.com/huggingface/candle/blob/main/candle-transformers/src/generation/mod.rs
 
 use crate::config::{SamplingParams, SAMPLING_EPS};
+use aici_abi::BranchSampling;
 use rand::SeedableRng;
 
 pub struct LogitsProcessor {
     pub rng: rand::rngs::StdRng,
     pub temperature: Option<f32>,
     pub top_p: f32,
+    /// 0 means no limit.
+    /// Only set by controllers; the samplers never applied `top_k` from the request.
+    pub top_k: usize,
+    pub min_p: f32,
+    // top_p from the request, restored when a branch doesn't override it
+    req_top_p: f32,
 }
 
 impl LogitsProcessor {
@@ -16,12 +23,14 @@ impl LogitsProcessor {
         } else {
             Some(sampling_params.temperature)
         };
-
         Self {
             rng: rand::rngs::StdRng::from_entropy(),
             // seed_from_u64(42),
             temperature,
             top_p: sampling_params.top_p,
+            top_k: 0,
+            min_p: 0.0,
+            req_top_p: sampling_params.top_p,
         }
     }
 
@@ -32,4 +41,105 @@ impl LogitsProcessor {
             self.temperature = Some(temperature);
         }
     }
+
+    /// Set parameters requested by the controller for the current step.
+    pub fn set_branch_sampling(&mut self, sampling: &BranchSampling) {
+        self.top_p = sampling.top_p.unwrap_or(self.req_top_p);
+        self.top_k = sampling.top_k.map_or(0, |k| k as usize);
+        self.min_p = sampling.min_p.unwrap_or(0.0).clamp(0.0, 1.0);
+    }
+
+    /// Whether any of top_p, top_k, or min_p limits the distribution.
+    pub fn needs_truncation(&self) -> bool {
+        (self.top_p > 0.0 && self.top_p < 1.0) || self.top_k > 0 || self.min_p > 0.0
+    }
+
+    /// Clamp the probabilities of tokens excluded by top_k, top_p and min_p to zero.
+    /// top-p sampling (or "nucleus sampling") samples from the smallest set of
+    /// tokens that exceed probability top_p. This way we never sample tokens that
+    /// have very low probabilities and are less likely to go "off the rails".
+    /// min-p only keeps tokens with probability at least min_p times the top one.
+    /// The most likely token is always kept.
+    pub fn truncate(&self, prs: &mut [f32]) {
+        let top_p = if self.top_p > 0.0 && self.top_p < 1.0 {
+            self.top_p
+        } else {
+            f32::INFINITY
+        };
+        let top_k = if self.top_k == 0 {
+            prs.len()
+        } else {
+            self.top_k
+        };
+
+        // Sort by descending probability.
+        let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
+        argsort_indices.sort_by(|&i, &j| prs[j].total_cmp(&prs[i]));
+
+        let min_pr = prs[argsort_indices[0]] * self.min_p;
+        let mut cumsum = 0.;
+        for (rank, &index) in argsort_indices.iter().enumerate() {
+            if rank >= top_k || cumsum >= top_p || prs[index] < min_pr {
+                prs[index] = 0.0;
+            } else {
+                cumsum += prs[index];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn processor(top_p: f32, top_k: isize) -> LogitsProcessor {
+        let mut params = SamplingParams::default();
+        params.temperature = 1.0;
+        params.top_p = top_p;
+        params.top_k = top_k;
+        LogitsProcessor::new(&params)
+    }
+
+    #[test]
+    fn request_top_k_not_applied() {
+        let p = processor(1.0, 2);
+        assert_eq!(p.top_k, 0);
+        assert!(!p.needs_truncation());
+        let mut prs = vec![0.1, 0.2, 0.3, 0.4];
+        p.truncate(&mut prs);
+        assert_eq!(prs, vec![0.1, 0.2, 0.3, 0.4]);
+    }
+
+    #[test]
+    fn branch_overrides_and_restores() {
+        let mut p = processor(0.5, -1);
+        p.set_branch_sampling(&BranchSampling {
+            top_p: Some(1.0),
+            top_k: Some(2),
+            min_p: None,
+        });
+        assert_eq!(p.top_k, 2);
+        let mut prs = vec![0.1, 0.2, 0.3, 0.4];
+        p.truncate(&mut prs);
+        assert_eq!(prs, vec![0.0, 0.0, 0.3, 0.4]);
+
+        p.set_branch_sampling(&BranchSampling::default());
+        assert_eq!(p.top_p, 0.5);
+        assert_eq!(p.top_k, 0);
+        let mut prs = vec![0.1, 0.2, 0.3, 0.4];
+        p.truncate(&mut prs);
+        assert_eq!(prs, vec![0.0, 0.0, 0.3, 0.4]);
+    }
+
+    #[test]
+    fn min_p_truncation() {
+        let mut p = processor(1.0, -1);
+        p.set_branch_sampling(&BranchSampling {
+            min_p: Some(0.5),
+            ..Default::default()
+        });
+        let mut prs = vec![0.1, 0.15, 0.25, 0.5];
+        p.truncate(&mut prs);
+        assert_eq!(prs, vec![0.0, 0.0, 0.25, 0.5]);
+    }
 }
@@ -404,7 +404,11 @@ impl<ME: ModelExec> Scheduler<ME> {
             seq_groups.append(&mut outputs.next_seq_groups);
         });
         self.for_each_seq(|seq| {
-            if seq.sched_phase == SchedulingPhase::Suspended {
+            if seq.aici_waiting {
+                if seq.sched_phase == SchedulingPhase::Running {
+                    seq.sched_phase = SchedulingPhase::Suspended;
+                }
+            } else if seq.sched_phase == SchedulingPhase::Suspended {
                 seq.sched_phase = SchedulingPhase::Running;
             }
         });
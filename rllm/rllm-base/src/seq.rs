@@ -1,7 +1,7 @@
 use crate::{
     config::SamplingParams, engine::ExpectedGeneration, LogitsProcessor, SeqId, SequenceManager,
 };
-use aici_abi::{toktrie::TokTrie, Branch, TokenId};
+use aici_abi::{toktrie::TokTrie, AttentionMask, Branch, LogprobsRequest, TokenId};
 use aicirt::api::{AiciMidOp, SequenceResult};
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
@@ -22,7 +22,7 @@ pub enum FinishReason {
     Aborted,
     /// The scheduler didn't like the sequence.
     Failed,
-    /// All sequences in the group are suspended.
+    /// All sequences in the group are waiting for each other (see aici_logs for details).
     Deadlock,
 }
 
@@ -60,6 +60,11 @@ pub struct Sequence {
     pub num_kv_computed: usize,
     pub(crate) has_aici: bool,
     pub(crate) aici_sampling: Option<Branch<usize>>,
+    pub(crate) aici_logprobs: Option<LogprobsRequest>,
+    /// Token ranges excluded from attention, as requested by the controller.
+    pub attention_mask: AttentionMask,
+    /// Suspended by the controller until some variable changes.
+    pub(crate) aici_waiting: bool,
     pub aici_logs: Vec<SequenceResult>,
     pub(crate) expected: Option<ExpectedGeneration>,
 
@@ -97,6 +102,9 @@ impl Sequence {
             has_aici: false,
             aici_logs: Vec::new(),
             aici_sampling: None,
+            aici_logprobs: None,
+            attention_mask: AttentionMask::default(),
+            aici_waiting: false,
             mid_op: None,
             expected: None,
         }
@@ -137,6 +145,7 @@ impl Sequence {
             sampled: None,
             backtrack: 0,
             tokens: vec![],
+            logprobs: None,
         }
     }
 
@@ -185,6 +194,9 @@ impl Sequence {
             has_aici: self.has_aici,
             aici_logs: Vec::new(),
             aici_sampling: None,
+            aici_logprobs: None,
+            attention_mask: AttentionMask::default(),
+            aici_waiting: false,
             expected: None,
             mid_op: None,
         }
@@ -11,6 +11,8 @@ pub struct RunRequest {
     pub top_p: Option<f32>,        // defl 1.0
     pub top_k: Option<isize>,      // defl -1
     pub max_tokens: Option<usize>, // defl context size
+    /// Seed for controllers (see `aici_abi::random_seed()`).
+    pub seed: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -44,5 +46,8 @@ pub struct RunForkResponse {
     pub error: String,
     pub logs: String,
     pub storage: Vec<StorageCmd>,
+    /// Structured events emitted by the controller.
+    pub events: Vec<serde_json::Value>,
     pub micros: u64,
+    pub fuel: u64,
 }
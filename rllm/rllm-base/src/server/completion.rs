@@ -105,6 +105,8 @@ async fn run_controller(
                     prompt: json!(token_ids),
                     module_id: mod_id.clone(),
                     module_arg: json!(sampling_params.controller_arg),
+                    tokenizer: None,
+                    seed: request.seed,
                 },
                 auth_info(&req),
             )
@@ -169,6 +171,38 @@ async fn run_controller(
         }));
 }
 
+fn fork_response(choice: &SeqOutput) -> RunForkResponse {
+    RunForkResponse {
+        text: choice.new_text.clone(),
+        index: choice.index,
+        finish_reason: choice.finish_reason.map(|r| r.short_name()),
+        micros: choice.aici_logs.iter().map(|e| e.micros).sum(),
+        fuel: choice.aici_logs.iter().map(|e| e.fuel).sum(),
+        logs: choice
+            .aici_logs
+            .iter()
+            .map(|e| e.logs.clone())
+            .collect::<Vec<_>>()
+            .join(""),
+        error: choice
+            .aici_logs
+            .iter()
+            .map(|e| e.error.clone())
+            .collect::<Vec<_>>()
+            .join(""),
+        storage: choice
+            .aici_logs
+            .iter()
+            .flat_map(|e| e.storage.clone())
+            .collect::<Vec<_>>(),
+        events: choice
+            .aici_logs
+            .iter()
+            .flat_map(|e| e.events.clone())
+            .collect::<Vec<_>>(),
+    }
+}
+
 struct Client {
     initial: Option<InitialRunResponse>,
     rx: Receiver<InferenceResult>,
@@ -202,33 +236,7 @@ impl futures::Stream for Client {
                         ff_tokens: u.prompt_tokens,
                         cost: u.fuel_tokens(),
                     },
-                    forks: so
-                        .seq_outputs
-                        .iter()
-                        .map(|choice| RunForkResponse {
-                            text: choice.new_text.clone(),
-                            index: choice.index,
-                            finish_reason: choice.finish_reason.map(|r| r.short_name()),
-                            micros: choice.aici_logs.iter().map(|e| e.micros).sum(),
-                            logs: choice
-                                .aici_logs
-                                .iter()
-                                .map(|e| e.logs.clone())
-                                .collect::<Vec<_>>()
-                                .join(""),
-                            error: choice
-                                .aici_logs
-                                .iter()
-                                .map(|e| e.error.clone())
-                                .collect::<Vec<_>>()
-                                .join(""),
-                            storage: choice
-                                .aici_logs
-                                .iter()
-                                .flat_map(|e| e.storage.clone())
-                                .collect::<Vec<_>>(),
-                        })
-                        .collect(),
+                    forks: so.seq_outputs.iter().map(fork_response).collect(),
                 };
                 let res = serde_json::to_string(&r).unwrap();
                 let mut res = format!("data: {}\n\n", res);
@@ -242,3 +250,49 @@ impl futures::Stream for Client {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aicirt::api::SequenceResult;
+
+    #[test]
+    fn events_reach_client() {
+        let step = |logs: &str, events: Vec<Value>| SequenceResult {
+            logs: logs.to_string(),
+            events,
+            ..SequenceResult::from_error(String::new())
+        };
+        let choice = SeqOutput {
+            seq_id: 3,
+            index: 1,
+            new_output_tokens: vec![],
+            new_text: "foo".to_string(),
+            output_tokens: vec![],
+            finish_reason: Some(FinishReason::AiciStop),
+            aici_logs: vec![
+                step("a\n", vec![json!({ "kind": "k1", "data": 1 })]),
+                step("", vec![]),
+                step(
+                    "b\n",
+                    vec![
+                        json!({ "kind": "k2", "data": [2] }),
+                        json!({ "kind": "k1", "data": 3 }),
+                    ],
+                ),
+            ],
+        };
+        let r = serde_json::to_value(fork_response(&choice)).unwrap();
+        assert_eq!(r["index"], 1);
+        assert_eq!(r["finish_reason"], "aici-stop");
+        assert_eq!(r["logs"], "a\nb\n");
+        assert_eq!(
+            r["events"],
+            json!([
+                { "kind": "k1", "data": 1 },
+                { "kind": "k2", "data": [2] },
+                { "kind": "k1", "data": 3 },
+            ])
+        );
+    }
+}
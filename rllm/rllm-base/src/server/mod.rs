@@ -190,6 +190,18 @@ pub struct RllmCliArgs {
     #[arg(long, short = 'A', help_heading = "AICI settings")]
     pub aicirt_arg: Vec<String>,
 
+    /// Connect to aicirt started separately with --listen ADDR (unix:PATH or tcp:HOST:PORT)
+    #[arg(long, help_heading = "AICI settings")]
+    pub aicirt_connect: Option<String>,
+
+    /// File with the token aicirt was started with (--listen-token-file); used with --aicirt-connect
+    #[arg(long, help_heading = "AICI settings")]
+    pub aicirt_token_file: Option<String>,
+
+    /// Engine session to use in aicirt started with --session NAME=... (requires --aicirt-connect)
+    #[arg(long, help_heading = "AICI settings")]
+    pub aicirt_session: Option<String>,
+
     /// Specify test-cases (expected/*/*.safetensors)
     #[arg(long, help_heading = "Development")]
     pub test: Vec<String>,
@@ -650,6 +662,10 @@ pub async fn server_main<ME: ModelExec>(
         shm_prefix,
         busy_wait_time: args.busy_wait_time,
         add_args: args.aicirt_arg.clone(),
+        connect: args.aicirt_connect.clone(),
+        token_file: args.aicirt_token_file.clone(),
+        session: args.aicirt_session.clone(),
+        attention_mask: ME::supports_attention_mask(),
     };
     let stats = Arc::new(Mutex::new(ServerStats {
         num_requests: 0,
@@ -214,17 +214,30 @@ fn compute_varlen_attn(
     let k = repeat_kv(config, k);
     let v = repeat_kv(config, v);
 
-    let y = {
-        batch_info.log_tensor("q", &q);
-        batch_info.log_tensor("k", &k);
-        batch_info.log_tensor("v", &v);
+    batch_info.log_tensor("q", &q);
+    batch_info.log_tensor("k", &k);
+    batch_info.log_tensor("v", &v);
 
-        // flash-attn expects (seq_len, nheads, head_dim)
-        let softmax_scale = 1f32 / (config.head_dim as f32).sqrt();
+    // flash-attn expects (seq_len, nheads, head_dim)
+    let softmax_scale = 1f32 / (config.head_dim as f32).sqrt();
+
+    let causal = true;
+
+    let (q_split, k_split) = match &batch_info.masked {
+        Some(m) => (m.q_start, m.k_start),
+        None => (q.size()[0], k.size()[0]),
+    };
+
+    let mut ys = Vec::new();
 
-        let causal = true;
+    if q_split > 0 {
+        let q = q.i((0..q_split, .., ..));
+        let k = k.i((0..k_split, .., ..));
+        let v = v.i((0..k_split, .., ..));
 
-        let y = if config.dtype == DType::BFloat16 || config.dtype == DType::Half {
+        let use_flash = config.dtype == DType::BFloat16 || config.dtype == DType::Half;
+
+        let y = if use_flash {
             let y = kernels::varlen_attn(
                 &q,
                 &k,
@@ -248,6 +261,7 @@ fn compute_varlen_attn(
                     batch_info.max_seqlen_k,
                     softmax_scale,
                     causal,
+                    None,
                 );
                 check_all_close_attn(&y, &y2);
             }
@@ -264,11 +278,29 @@ fn compute_varlen_attn(
                 batch_info.max_seqlen_k,
                 softmax_scale,
                 causal,
+                None,
             )
         };
+        ys.push(y);
+    }
 
-        y
-    };
+    // flash-attn doesn't support arbitrary masks
+    if let Some(m) = &batch_info.masked {
+        ys.push(refkernels::varlen_attn(
+            &q.i((q_split.., .., ..)),
+            &k.i((k_split.., .., ..)),
+            &v.i((k_split.., .., ..)),
+            &m.seqlens_q,
+            &m.seqlens_k,
+            m.max_seqlen_q,
+            m.max_seqlen_k,
+            softmax_scale,
+            causal,
+            Some(m.position_mask.as_slice()),
+        ));
+    }
+
+    let y = Tensor::cat(&ys, 0);
 
     batch_info.log_tensor("y", &v);
 
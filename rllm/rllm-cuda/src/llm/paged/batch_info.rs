@@ -1,10 +1,10 @@
 use super::super::{kernels::to_offsets, tmodel::TModel};
 use super::cache_engine::CacheEngine;
 use super::BlockAllocator;
+use aicirt::api::Token;
 use rllm::{
     config::RllmConfig, seq::SchedulingPhase, util::pad_to_multiple, HashMap, SchedulerOutputs,
 };
-use aicirt::api::Token;
 use std::{
     fmt::Debug,
     sync::{Arc, Mutex},
@@ -26,6 +26,8 @@ pub struct BatchInfo {
     pub max_seqlen_q: usize,
     pub max_seqlen_k: usize,
     pub seq_id_to_idx: HashMap<usize, usize>, // seq_id -> index into seqlens_*
+    // multi-token entries using attention masking; None when there are none
+    pub masked: Option<MaskedBatch>,
 
     pub infer_log: Mutex<Vec<(String, Tensor)>>,
     pub step_no: usize,
@@ -42,6 +44,21 @@ pub struct BatchInfo {
     pub q_multi: i64,
 }
 
+/// Multi-token entries with attention masks.
+/// Flash-attn doesn't support arbitrary masks, so these come after the other multi-token
+/// entries (in `tokens` and `gather_mapping`) and are computed separately;
+/// `seqlens_*` in [BatchInfo] then only cover the unmasked entries.
+pub struct MaskedBatch {
+    pub seqlens_q: Tensor, // u32, [num_masked + 1]
+    pub seqlens_k: Tensor, // u32, [num_masked + 1]
+    pub max_seqlen_q: usize,
+    pub max_seqlen_k: usize,
+    pub q_start: i64, // first token of masked entries
+    pub k_start: i64, // first masked entry in gather_mapping
+    // token position ranges excluded from attention, for each masked entry
+    pub position_mask: Vec<Vec<(usize, usize)>>,
+}
+
 impl BatchInfo {
     pub fn log_tensor(&self, key: &str, value: &Tensor) {
         if false {
@@ -89,6 +106,10 @@ impl Debug for BatchInfo {
             .field("paged_max_context_len", &self.paged_max_context_len)
             .field("seqlen_multi", &self.seqlen_multi)
             .field("q_multi", &self.q_multi)
+            .field(
+                "masked",
+                &self.masked.as_ref().map(|m| m.position_mask.len()),
+            )
             .finish()
     }
 }
@@ -102,6 +123,7 @@ struct BatchEntry {
     seq_id: usize,
     query_pos_token: Vec<(usize, Token)>,
     kv_slots: Vec<usize>,
+    position_mask: Vec<(usize, usize)>,
 }
 
 impl BatchInfoBuilder {
@@ -142,6 +164,12 @@ impl BatchInfoBuilder {
                         .map(|idx| (idx, seq.get_token(idx)))
                         .collect(),
                     kv_slots: alloc.get_block_idxes(seq.seq_id, k_len),
+                    position_mask: seq
+                        .attention_mask
+                        .ranges
+                        .iter()
+                        .map(|(s, e)| (*s as usize, *e as usize))
+                        .collect(),
                 });
 
                 seq.sync_computed_kv();
@@ -166,6 +194,7 @@ impl BatchInfoBuilder {
                 seq_id,
                 query_pos_token: (0..1).map(|_| (idx, fake_token)).collect(),
                 kv_slots: (0..avg_len).map(|_| fake_slot).collect(),
+                position_mask: vec![],
             });
         }
 
@@ -177,6 +206,7 @@ impl BatchInfoBuilder {
                 seq_id,
                 query_pos_token: (0..seq_len).map(|idx| (idx, fake_token)).collect(),
                 kv_slots: (0..seq_len).map(|_| fake_slot).collect(),
+                position_mask: vec![],
             });
         }
 
@@ -202,28 +232,25 @@ impl BatchInfoBuilder {
         let mut gather_mapping: Vec<i32> = Vec::new();
         let mut slot_mapping: Vec<i32> = Vec::new();
         let mut seq_id_to_idx: HashMap<usize, usize> = HashMap::default();
+        let mut masked_seqlens_q: Vec<usize> = Vec::new();
+        let mut masked_seqlens_k: Vec<usize> = Vec::new();
+        let mut position_mask: Vec<Vec<(usize, usize)>> = Vec::new();
+        let mut masked_start = (0, 0);
 
         let mut paged_block_tables: Vec<Vec<i32>> = Vec::new();
         let mut paged_context_lens: Vec<i32> = Vec::new();
 
-        let num_multitoken = if self.config.model.cache.paged_attn_kernel_v > 0 {
-            // sort single-token entries to the back
-            let (single, multi) = std::mem::take(&mut self.entries)
-                .into_iter()
-                .partition::<Vec<_>, _>(|e| e.query_pos_token.len() == 1);
-            let multi_len = multi.len();
-            self.entries = multi;
-            self.entries.extend(single);
-            multi_len
-        } else {
-            self.entries.len()
-        };
+        let paged = self.config.model.cache.paged_attn_kernel_v > 0;
+        let (num_multitoken, num_unmasked) = order_entries(&mut self.entries, paged);
 
         let mut first_single_token = 0;
 
         let max_seq = self.config.scheduler.max_model_len;
         let mut idx = 0;
         for e in &self.entries {
+            if idx == num_unmasked {
+                masked_start = (tokens.len(), gather_mapping.len());
+            }
             seq_id_to_idx.insert(e.seq_id, idx);
             let query = &e.query_pos_token;
             let off = e.kv_slots.len() - query.len();
@@ -239,8 +266,14 @@ impl BatchInfoBuilder {
                     gather_mapping.push(*slot as i32);
                 }
                 first_single_token = tokens.len();
-                seqlens_q.push(query.len());
-                seqlens_k.push(e.kv_slots.len());
+                if idx < num_unmasked {
+                    seqlens_q.push(query.len());
+                    seqlens_k.push(e.kv_slots.len());
+                } else {
+                    masked_seqlens_q.push(query.len());
+                    masked_seqlens_k.push(e.kv_slots.len());
+                    position_mask.push(e.position_mask.clone());
+                }
             } else {
                 let ctx_size = e.kv_slots.len();
                 paged_context_lens.push(ctx_size as i32);
@@ -259,7 +292,7 @@ impl BatchInfoBuilder {
             idx += 1;
         }
 
-        assert!(seqlens_q.len() + paged_context_lens.len() > 0);
+        assert!(seqlens_q.len() + masked_seqlens_q.len() + paged_context_lens.len() > 0);
 
         let device = self.config.model.device;
         let (max_seqlen_q, seqlens_q) = to_offsets(seqlens_q.into_iter(), device);
@@ -291,6 +324,22 @@ impl BatchInfoBuilder {
             .reshape(&[num_paged, paged_block_tables_max_len as i64]);
         let paged_context_lens = Tensor::from_slice(paged_context_lens.as_slice()).to(device);
 
+        let masked = if position_mask.is_empty() {
+            None
+        } else {
+            let (max_seqlen_q, seqlens_q) = to_offsets(masked_seqlens_q.into_iter(), device);
+            let (max_seqlen_k, seqlens_k) = to_offsets(masked_seqlens_k.into_iter(), device);
+            Some(MaskedBatch {
+                seqlens_q,
+                seqlens_k,
+                max_seqlen_q,
+                max_seqlen_k,
+                q_start: masked_start.0 as i64,
+                k_start: masked_start.1 as i64,
+                position_mask,
+            })
+        };
+
         BatchInfo {
             tokens,
             positions,
@@ -305,6 +354,7 @@ impl BatchInfoBuilder {
             max_seqlen_k,
             kv_cache,
             seq_id_to_idx,
+            masked,
             infer_log: Mutex::new(Vec::new()),
             step_no,
             paged_block_size: self.config.model.cache.block_size,
@@ -315,6 +365,27 @@ impl BatchInfoBuilder {
     }
 }
 
+/// Sort entries into: multi-token ones without attention mask, multi-token ones with a mask,
+/// and then, when `paged` attention is used, single-token ones (paged attention doesn't
+/// support masking, so masked single-token entries are treated as multi-token).
+/// Returns the number of multi-token and unmasked multi-token entries.
+fn order_entries(entries: &mut Vec<BatchEntry>, paged: bool) -> (usize, usize) {
+    let (single, multi) = std::mem::take(entries)
+        .into_iter()
+        .partition::<Vec<_>, _>(|e| {
+            paged && e.query_pos_token.len() == 1 && e.position_mask.is_empty()
+        });
+    let (unmasked, masked) = multi
+        .into_iter()
+        .partition::<Vec<_>, _>(|e| e.position_mask.is_empty());
+    let num_unmasked = unmasked.len();
+    let num_multitoken = num_unmasked + masked.len();
+    entries.extend(unmasked);
+    entries.extend(masked);
+    entries.extend(single);
+    (num_multitoken, num_unmasked)
+}
+
 struct FakeKVCache {
     k: Tensor,
     v: Tensor,
@@ -325,3 +396,49 @@ impl CacheIface for FakeKVCache {
         (self.k.shallow_clone(), self.v.shallow_clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(seq_id: usize, q_len: usize, masked: bool) -> BatchEntry {
+        BatchEntry {
+            seq_id,
+            query_pos_token: (0..q_len).map(|idx| (idx, 1)).collect(),
+            kv_slots: (0..q_len).collect(),
+            position_mask: if masked { vec![(0, 1)] } else { vec![] },
+        }
+    }
+
+    fn ids(entries: &[BatchEntry]) -> Vec<usize> {
+        entries.iter().map(|e| e.seq_id).collect()
+    }
+
+    fn mk_entries() -> Vec<BatchEntry> {
+        vec![
+            entry(0, 1, false),
+            entry(1, 3, true),
+            entry(2, 3, false),
+            entry(3, 1, true),
+            entry(4, 1, false),
+            entry(5, 2, false),
+        ]
+    }
+
+    #[test]
+    fn masked_entries_batched_separately() {
+        let mut entries = mk_entries();
+        let (num_multi, num_unmasked) = order_entries(&mut entries, true);
+        assert_eq!(ids(&entries), vec![2, 5, 1, 3, 0, 4]);
+        assert_eq!((num_multi, num_unmasked), (4, 2));
+
+        let mut entries = mk_entries();
+        let (num_multi, num_unmasked) = order_entries(&mut entries, false);
+        assert_eq!(ids(&entries), vec![0, 2, 4, 5, 1, 3]);
+        assert_eq!((num_multi, num_unmasked), (6, 4));
+
+        let mut entries = vec![entry(0, 2, false), entry(1, 1, false)];
+        assert_eq!(order_entries(&mut entries, true), (1, 1));
+        assert_eq!(ids(&entries), vec![0, 1]);
+    }
+}
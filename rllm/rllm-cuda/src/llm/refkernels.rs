@@ -66,6 +66,7 @@ pub fn varlen_attn(
     max_seqlen_k: usize,
     softmax_scale: f32,
     causal: bool,
+    position_mask: Option<&[Vec<(usize, usize)>]>,
 ) -> Tensor {
     let seqlens_q = to_vec1::<i32>(seqlens_q);
     let seqlens_k = to_vec1::<i32>(seqlens_k);
@@ -107,6 +108,19 @@ pub fn varlen_attn(
             .i((.., len_k - len_q..))
             .masked_fill_(&mask, f64::NEG_INFINITY);
 
+        if let Some(position_mask) = position_mask {
+            // only mask the context; the queries always attend to themselves
+            let ctx_len = (len_k - len_q) as usize;
+            for (start, end) in position_mask[i].iter() {
+                let end = std::cmp::min(*end, ctx_len) as i64;
+                if (*start as i64) < end {
+                    let _ = attn_bias
+                        .i((.., *start as i64..end))
+                        .fill_(f64::NEG_INFINITY);
+                }
+            }
+        }
+
         let attn0 = Tensor::scaled_dot_product_attention(
             &q,
             &k,
@@ -54,6 +54,10 @@ impl ModelExec for TModel {
         args.verify_args()
     }
 
+    fn supports_attention_mask() -> bool {
+        true
+    }
+
     fn load_rllm_engine(
         args: rllm::LoaderArgs,
         model_args: Self::ModelLoaderArgs,
@@ -179,14 +183,14 @@ impl ModelExec for TModel {
                 let logits = logits / (temperature as f64);
                 let prs = logits.softmax(-1, DType::Float);
 
-                let top_p = state.top_p;
-                if top_p <= 0.0 || top_p >= 1.0 {
+                if !state.needs_truncation() {
                     // simply sample from the predicted probability distribution
                     prs.multinomial(1, false).int64_value(&[]) as u32
                 } else {
-                    // top-p (nucleus) sampling, clamping the least likely tokens to zero
+                    // top-p/top-k/min-p sampling, clamping the least likely tokens to zero
                     let mut prs: Vec<f32> = to_vec1(&prs);
-                    self.sample_topp(state, &mut prs, top_p as f32)?
+                    state.truncate(&mut prs);
+                    self.sample_multinomial(state, &prs)?
                 }
             }
         };
@@ -240,33 +244,6 @@ impl TModel {
         let next_token = distr.sample(&mut state.rng) as u32;
         Ok(next_token)
     }
-
-    fn sample_topp(
-        &self,
-        state: &mut LogitsProcessor,
-        prs: &mut Vec<f32>,
-        top_p: f32,
-    ) -> Result<u32> {
-        // top-p sampling (or "nucleus sampling") samples from the smallest set of
-        // tokens that exceed probability top_p. This way we never sample tokens that
-        // have very low probabilities and are less likely to go "off the rails".
-        let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
-
-        // Sort by descending probability.
-        argsort_indices.sort_by(|&i, &j| prs[j].partial_cmp(&prs[i]).unwrap());
-
-        // Clamp smaller probabilities to zero.
-        let mut cumsum = 0.;
-        for index in &argsort_indices {
-            if cumsum >= top_p {
-                prs[*index] = 0.0;
-            } else {
-                cumsum += prs[*index];
-            }
-        }
-        // Sample with clamped probabilities.
-        self.sample_multinomial(state, prs)
-    }
 }
 
 pub struct TchAiciBias {
@@ -159,13 +159,11 @@ impl ModelExec for TModel {
                 for idx in 0..prs.len() {
                     prs[idx] /= sum;
                 }
-                let top_p = state.top_p;
-                if top_p <= 0.0 || top_p >= 1.0 {
-                    self.sample_multinomial(state, &prs)?
-                } else {
-                    // top-p (nucleus) sampling, clamping the least likely tokens to zero
-                    self.sample_topp(state, &mut prs, top_p as f32)?
+                if state.needs_truncation() {
+                    // top-p/top-k/min-p sampling, clamping the least likely tokens to zero
+                    state.truncate(&mut prs);
                 }
+                self.sample_multinomial(state, &prs)?
             }
         };
         Ok(next_token)
@@ -231,33 +229,6 @@ impl TModel {
         let next_token = distr.sample(&mut state.rng) as u32;
         Ok(next_token)
     }
-
-    fn sample_topp(
-        &self,
-        state: &mut LogitsProcessor,
-        prs: &mut Vec<f32>,
-        top_p: f32,
-    ) -> Result<u32> {
-        // top-p sampling (or "nucleus sampling") samples from the smallest set of
-        // tokens that exceed probability top_p. This way we never sample tokens that
-        // have very low probabilities and are less likely to go "off the rails".
-        let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
-
-        // Sort by descending probability.
-        argsort_indices.sort_by(|&i, &j| prs[j].partial_cmp(&prs[i]).unwrap());
-
-        // Clamp smaller probabilities to zero.
-        let mut cumsum = 0.;
-        for index in &argsort_indices {
-            if cumsum >= top_p {
-                prs[*index] = 0.0;
-            } else {
-                cumsum += prs[*index];
-            }
-        }
-        // Sample with clamped probabilities.
-        self.sample_multinomial(state, prs)
-    }
 }
 
 pub struct CppAiciBias {